@@ -1,7 +1,6 @@
 // Subject to the ISC licence (LICENSE-ISC.txt).
 
 extern crate byteorder;
-extern crate rustc_serialize;
 extern crate docopt;
 #[macro_use(try_read)]
 extern crate pippin;
@@ -14,11 +13,14 @@ extern crate pippin_app_tests;
 use std::path::{Path};
 use std::process::exit;
 use std::cmp::{min, max};
+use std::env;
+use std::result;
+use std::str::FromStr;
 
-use docopt::Docopt;
+use docopt::{Docopt, ArgvMap};
 use rand::distributions::{IndependentSample, LogNormal};
 
-use pippin::{PartId, Partition, StateT, MutStateT, Result};
+use pippin::{PartId, Partition, StateT, MutStateT, RepairReport, Result};
 use pippin::{discover, fileio};
 use pippin::repo::Repository;
 use pippin::merge::*;
@@ -34,6 +36,7 @@ may be either a directory or a data file.
 
 Usage:
   sequences [options] PATH
+  sequences [options] PATH --query-len MIN MAX
 
 Options:
   -h --help             Show this message.
@@ -44,13 +47,21 @@ Options:
   -l --list NUM         List NUM entries (in random order)
   -g --generate NUM     Generate NUM new sequences and add to the repo.
   -R --repeat N         Repeat N times.
+  --verify              Check the headers and checksums of all data files and
+                        report damage, exiting with status 2 if any is found.
+                        Nothing else is done.
+  --repair              Recover what can be read from damaged files, write a
+                        new snapshot and quarantine damaged files, then
+                        verify. Nothing else is done.
+  --query-len           After loading, print all sequences with length from
+                        MIN to MAX (inclusive).
 
 Note that you shouldn't try to create a partition with `-p`, then load that
 partition alongside others as part of a repository; at least not without making
 sure the repository name and partition number are correct.
 ";
 
-#[derive(Debug, RustcDecodable)]
+#[derive(Debug)]
 #[allow(non_snake_case)]
 struct Args {
     arg_PATH: String,
@@ -60,31 +71,141 @@ struct Args {
     flag_create: bool,
     flag_snapshot: bool,
     flag_repeat: Option<usize>,
+    flag_query_len: bool,
+    flag_verify: bool,
+    flag_repair: bool,
+    arg_MIN: Option<usize>,
+    arg_MAX: Option<usize>,
+}
+
+impl Args {
+    fn from_argv(argv: &ArgvMap) -> result::Result<Args, String> {
+        Ok(Args {
+            arg_PATH: argv.get_str("PATH").to_string(),
+            flag_partition: num_arg(argv, "--partition")?,
+            flag_list: num_arg(argv, "--list")?,
+            flag_generate: num_arg(argv, "--generate")?,
+            flag_create: argv.get_bool("--create"),
+            flag_snapshot: argv.get_bool("--snapshot"),
+            flag_repeat: num_arg(argv, "--repeat")?,
+            flag_query_len: argv.get_bool("--query-len"),
+            flag_verify: argv.get_bool("--verify"),
+            flag_repair: argv.get_bool("--repair"),
+            arg_MIN: num_arg(argv, "MIN")?,
+            arg_MAX: num_arg(argv, "MAX")?,
+        })
+    }
+}
+
+// Parse an optional numeric argument; an empty value means it was not given
+fn num_arg<T: FromStr>(argv: &ArgvMap, key: &str) -> result::Result<Option<T>, String> {
+    let s = argv.get_str(key);
+    if s.is_empty() {
+        return Ok(None);
+    }
+    s.parse().map(Some).map_err(|_| format!("{}: expected a number, found '{}'", key, s))
 }
 
 fn main() {
     env_logger::init().unwrap();
-    
-    let args: Args = Docopt::new(USAGE)
-            .and_then(|d| d.decode())
+    exit(run_cli(env::args()));
+}
+
+// Parse the command line `argv` (including program name) and do what it asks.
+// Returns the exit status.
+fn run_cli<I, S>(argv: I) -> i32 where I: IntoIterator<Item = S>, S: AsRef<str> {
+    let argv = Docopt::new(USAGE)
+            .and_then(|d| d.argv(argv).parse())
             .unwrap_or_else(|e| e.exit());
+    let args = match Args::from_argv(&argv) {
+        Ok(args) => args,
+        Err(msg) => {
+            println!("Error: {}", msg);
+            return 1;
+        }
+    };
+    
+    if args.flag_verify || args.flag_repair {
+        return match check(Path::new(&args.arg_PATH), args.flag_partition, args.flag_repair) {
+            Ok(true) => 0,
+            Ok(false) => 2,
+            Err(e) => {
+                println!("Error: {}", e);
+                1
+            }
+        };
+    }
     
     let repetitions = args.flag_repeat.unwrap_or(1);
+    let query = if args.flag_query_len {
+        Some((args.arg_MIN.unwrap_or(0), args.arg_MAX.unwrap_or(0)))
+    } else {
+        None
+    };
     
     let result = run(Path::new(&args.arg_PATH), args.flag_partition,
             args.flag_list, args.flag_generate, args.flag_create,
-            args.flag_snapshot, repetitions);
+            args.flag_snapshot, repetitions, query);
     if let Err(e) = result {
         println!("Error: {}", e);
-        exit(1);
+        return 1;
+    }
+    0
+}
+
+// Verify data files, first repairing if `repair` is true. Returns true if no
+// damage was found (or remains after repair).
+// part_num: as for run()
+fn check(path: &Path, part_num: Option<u64>, repair: bool) -> Result<bool> {
+    let reports = if let Some(pn) = part_num {
+        let part_id = if pn != 0 { Some(PartId::from_num(pn)) } else { None };
+        let io = Box::new(discover::part_from_path(path, part_id)?);
+        let mut part = Partition::<Sequence>::open(io)?;
+        if repair && !part.verify()?.is_ok() {
+            let report = part.repair(None)?;
+            print_repair(part.part_id(), &report);
+        }
+        vec![part.verify()?]
+    } else {
+        let rt = SeqRepo::new(discover::repo_from_path(path)?);
+        let mut repo = Repository::open(rt)?;
+        if repair {
+            for (part_id, report) in repo.repair()? {
+                print_repair(part_id, &report);
+            }
+        }
+        repo.verify()?
+    };
+    
+    let mut ok = true;
+    for report in reports {
+        println!("Partition {}: {} files OK, {} damaged",
+                report.part_id, report.files_ok, report.damaged.len());
+        ok = ok && report.is_ok();
+        for (ss, cl, e) in report.damaged {
+            match cl {
+                None => println!("  snapshot {}: {}", ss, e),
+                Some(cl) => println!("  snapshot {} log {}: {}", ss, cl, e),
+            }
+        }
+    }
+    Ok(ok)
+}
+
+fn print_repair(part_id: PartId, report: &RepairReport) {
+    println!("Partition {}: repaired; recovered {} commits, dropped {}; wrote snapshot {}",
+            part_id, report.commits_recovered, report.commits_dropped, report.snapshot);
+    for name in &report.quarantined {
+        println!("  quarantined {}", name);
     }
 }
 
 // part_num: None for repo mode, Some(PN) for partition mode, where PN may be
 // 0 (auto mode) or a partition number
+// query: if given, (min, max) length of sequences to print
 fn run(path: &Path, part_num: Option<u64>,
          list_n: Option<usize>, generate_n: Option<usize>, create: bool,
-        snapshot: bool, repetitions: usize) -> Result<()>
+        snapshot: bool, repetitions: usize, query: Option<(usize, usize)>) -> Result<()>
 {
     let solver1 = AncestorSolver2W::new();
     let solver2 = RenamingSolver2W::new();
@@ -138,6 +259,19 @@ fn run(path: &Path, part_num: Option<u64>,
             }
         }
         
+        if let Some((min_len, max_len)) = query {
+            // Single partition: nothing to route, so check every element
+            let tip = part.tip()?;
+            let mut found: Vec<_> = tip.elts_iter()
+                    .filter(|&(_, ref seq)| min_len <= seq.len() && seq.len() <= max_len)
+                    .collect();
+            found.sort_by_key(|&(id, _)| id);
+            println!("Found {} sequences with length from {} to {}", found.len(), min_len, max_len);
+            for (id, elt) in found {
+                println!("Element {}: {:?}" , id, *elt);
+            }
+        }
+        
         for _ in 0..repetitions {
             let mut state = {
                 let tip = part.tip()?;
@@ -147,7 +281,7 @@ fn run(path: &Path, part_num: Option<u64>,
             generate(&mut state);
             println!("Done modifying state");
             part.push_state(state, None)?;
-            part.write(false, None)?;
+            part.write(None)?;
         }
         
         if snapshot {
@@ -176,13 +310,22 @@ fn run(path: &Path, part_num: Option<u64>,
             //TODO: how do we iterate over all elements of a repo?
         }
         
+        if let Some((min_len, max_len)) = query {
+            // The classifier tells us which partitions to search:
+            let found = query_len(&mut repo, min_len, max_len)?;
+            println!("Found {} sequences with length from {} to {}", found.len(), min_len, max_len);
+            for (id, elt) in found {
+                println!("Element {}: {:?}" , id, *elt);
+            }
+        }
+        
         for _ in 0..repetitions {
             let mut state = repo.clone_state()?;
             println!("Found {} partitions with {} elements", state.num_parts(), state.num_avail());
             generate(&mut state);
             println!("Done modifying state");
             repo.merge_in(state, None)?;
-            repo.write_all(false)?;
+            repo.write_full()?;
         }
         
         if snapshot {
@@ -192,3 +335,35 @@ fn run(path: &Path, part_num: Option<u64>,
     
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use pippin_app_tests::util::mk_temp_dir;
+    use super::run_cli;
+    
+    #[test]
+    fn verify_and_repair() {
+        let tmp_dir = mk_temp_dir("sequences_cli");
+        let path = tmp_dir.to_str().expect("path");
+        assert_eq!(run_cli(&["sequences", "-c", "-g", "20", "-R", "2", path]), 0);
+        assert_eq!(run_cli(&["sequences", "--verify", path]), 0);
+        
+        // Damage the checksum at the end of the last commit log:
+        let mut logs: Vec<_> = fs::read_dir(path).expect("read dir")
+            .map(|entry| entry.expect("entry").path())
+            .filter(|path| path.extension().map_or(false, |ext| ext == "piplog"))
+            .collect();
+        logs.sort();
+        let log = logs.pop().expect("log file");
+        let mut file = OpenOptions::new().write(true).open(&log).expect("open log");
+        file.seek(SeekFrom::End(-1)).expect("seek");
+        file.write_all(&[0xFF]).expect("write");
+        drop(file);
+        
+        assert_eq!(run_cli(&["sequences", "--verify", path]), 2);
+        assert_eq!(run_cli(&["sequences", "--repair", path]), 0);
+        assert_eq!(run_cli(&["sequences", "--verify", path]), 0);
+    }
+}
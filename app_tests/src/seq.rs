@@ -5,19 +5,20 @@
 //! directly into a real application.
 
 use std::io::Write;
-use std::cmp::min;
 use std::u32;
-use std::collections::hash_map::{HashMap, Entry};
+use std::collections::hash_map::HashMap;
 use std::mem::size_of;
 use std::fmt::Debug;
+use std::cmp::{min, max};
+use std::sync::Arc;
 
-use rand::Rng;
+use rand::{self, Rng, ChaChaRng, SeedableRng};
 use rand::distributions::{IndependentSample, Range, Normal, LogNormal};
-use byteorder::{ByteOrder, LittleEndian, ReadBytesExt, WriteBytesExt};
+use byteorder::{ByteOrder, BigEndian, LittleEndian, ReadBytesExt, WriteBytesExt};
 
 use pippin::*;
 use pippin::repo::{ClassifyFallback, RepoDivideError};
-use pippin::error::{ReadError, OtherError};
+use pippin::error::{ReadError, OtherError, ClassifierError};
 
 
 // —————  Sequence type itself  —————
@@ -36,6 +37,31 @@ impl Sequence {
     pub fn len(&self) -> usize {
         self.v.len()
     }
+    
+    /// Decode values from (part of) a serialised sequence, e.g. as returned by
+    /// `PartState::read_element_range`. The buffer must start on a value
+    /// boundary (a multiple of `size_of::<R>()` bytes into the sequence).
+    pub fn decode_values(buf: &[u8]) -> Result<Vec<R>> {
+        let mut v = Vec::new();
+        Sequence::decode_values_into(buf, &mut v)?;
+        Ok(v)
+    }
+    
+    /// As `decode_values`, but replace the contents of `v`, reusing its
+    /// allocation.
+    pub fn decode_values_into(buf: &[u8], v: &mut Vec<R>) -> Result<()> {
+        if buf.len() % size_of::<R>() != 0 {
+            return OtherError::err("invalid data length");
+        }
+        let mut r: &mut &[u8] = &mut &buf[..];
+        let n = buf.len() / size_of::<R>();
+        v.clear();
+        v.reserve(n);
+        for _ in 0..n {
+            v.push(r.read_f64::<LittleEndian>()?);
+        }
+        Ok(())
+    }
 }
 impl From<Vec<R>> for Sequence {
     fn from(v: Vec<R>) -> Self {
@@ -51,16 +77,26 @@ impl ElementT for Sequence {
         Ok(())
     }
     fn read_buf(buf: &[u8]) -> Result<Self> {
-        if buf.len() % size_of::<R>() != 0 {
-            return OtherError::err("invalid data length");
-        }
-        let mut r: &mut &[u8] = &mut &buf[..];
-        let n = buf.len() / size_of::<R>();
-        let mut v = Vec::with_capacity(n);
-        for _ in 0..n {
-            v.push(r.read_f64::<LittleEndian>()?);
+        Ok(Sequence{ v: Sequence::decode_values(buf)? })
+    }
+    fn read_into(buf: &[u8], target: &mut Self) -> Result<()> {
+        Sequence::decode_values_into(buf, &mut target.v)
+    }
+    fn fingerprint(&self) -> Option<u64> {
+        // Length and end values; sequences differing only in the middle
+        // share a fingerprint
+        let ends = match (self.v.first(), self.v.last()) {
+            (Some(first), Some(last)) => first.to_bits() ^ last.to_bits().rotate_left(32),
+            _ => 0,
+        };
+        Some((self.v.len() as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15) ^ ends)
+    }
+    fn validate(&self) -> Result<()> {
+        // Note: empty sequences are allowed (existing data includes them).
+        if self.v.iter().any(|x| x.is_nan()) {
+            return OtherError::err("sequence contains NaN");
         }
-        Ok(Sequence{ v: v })
+        Ok(())
     }
 }
 
@@ -185,12 +221,19 @@ impl Generator for GeneratorEnum {
 
 // —————  RepoT type and supporting types  —————
 
+/// Minimum number of elements in a partition before `SeqClassifier` allows
+/// it to be divided.
+pub const MIN_DIVIDE_ELTS: usize = 10;
+
 /// Data type implementing pippin's `ClassifierT` (stores information about
 /// classifications).
 #[derive(Clone)]
 pub struct SeqClassifier {
+    // Copy of partition information from the `SeqRepo`
+    parts: HashMap<PartId, PartInfo>,
     // For each class, the partition identifier and the min length of
-    // sequences in the class. Ordered by min length, increasing.
+    // sequences in the class. Ordered by min length, increasing. Derived from
+    // `parts` by `on_load()`.
     classes: Vec<(usize, PartId)>,
 }
 impl ClassifierT for SeqClassifier {
@@ -213,11 +256,87 @@ impl ClassifierT for SeqClassifier {
         // classify() only returns None if something is broken; stop
         ClassifyFallback::Fail
     }
+    fn should_divide(&self, state: &PartState<Sequence>) -> bool {
+        // Division splits at the median length, which is only useful given
+        // enough elements of differing lengths
+        if state.num_avail() < MIN_DIVIDE_ELTS {
+            return false;
+        }
+        let mut lens = state.elts_iter().map(|(_, seq)| seq.v.len());
+        let first = lens.next();
+        lens.any(|len| Some(len) != first)
+    }
+    fn on_load(&mut self) {
+        let mut classes = Vec::with_capacity(self.parts.len());
+        for (part_id, part) in &self.parts {
+            if !part.retired {
+                classes.push((part.min_len as usize, part_id.clone()));
+            }
+        }
+        // Note: there *could* be overlap of ranges. We can't do much if there
+        // is and it won't cause failures later, so ignore this possibility.
+        classes.sort_by(|a, b| a.0.cmp(&b.0));
+        self.classes = classes;
+    }
 }
 
 
-/// Each classification has a PartId, a max PartId, a min length, a max length
-/// and a version number. The PartId is stored as the key.
+impl SeqClassifier {
+    /// Get the partitions to which sequences with lengths from `min_len` to
+    /// `max_len` (inclusive) are assigned, in order of length.
+    /// 
+    /// This requires `on_load()` to have been called (as done by
+    /// `Repository::classifier()`).
+    pub fn parts_for_lens(&self, min_len: usize, max_len: usize) -> Vec<PartId> {
+        let mut parts = Vec::new();
+        for (i, &(lower, part_id)) in self.classes.iter().enumerate() {
+            // The class covers lengths from lower to the next lower bound - 1
+            let next = self.classes.get(i + 1).map(|class| class.0);
+            if lower <= max_len && next.map_or(true, |next| next > min_len) {
+                parts.push(part_id);
+            }
+        }
+        parts
+    }
+    
+    /// Get the partitions still in use, in order of identifier. Retired
+    /// partitions (those which have been divided) are excluded: they are
+    /// never assigned new elements.
+    pub fn active_parts(&self) -> Vec<PartId> {
+        let mut parts: Vec<PartId> = self.parts.iter()
+            .filter(|&(_, part)| !part.retired)
+            .map(|(id, _)| *id)
+            .collect();
+        parts.sort();
+        parts
+    }
+}
+
+/// Find all sequences with lengths from `min_len` to `max_len` (inclusive).
+/// 
+/// Only those partitions to which the classifier assigns such lengths are
+/// searched; these are loaded if necessary (see `Repository::load_part()`).
+/// Results are sorted by identifier. Fails if a partition searched requires
+/// a merge.
+pub fn query_len<IO: RepoT<SeqClassifier>>(repo: &mut Repository<SeqClassifier, IO>,
+        min_len: usize, max_len: usize) -> Result<Vec<(EltId, Arc<Sequence>)>>
+{
+    let mut result = Vec::new();
+    for part_id in repo.classifier().parts_for_lens(min_len, max_len) {
+        let part = repo.load_part(part_id, None)?;
+        for (id, seq) in part.tip()?.elts_iter() {
+            if min_len <= seq.len() && seq.len() <= max_len {
+                result.push((id, seq.clone()));
+            }
+        }
+    }
+    result.sort_by_key(|&(id, _)| id);
+    Ok(result)
+}
+
+
+/// Each classification has a PartId, a max PartId, a min length, a max length,
+/// a version number and a "retired" flag. The PartId is stored as the key.
 #[derive(Clone)]
 pub struct PartInfo {
     max_part_id: PartId,
@@ -225,43 +344,120 @@ pub struct PartInfo {
     ver: u32,
     min_len: u32,
     max_len: u32,
+    // True once the partition no longer receives elements (e.g. after it was
+    // divided); its length range is then meaningless
+    retired: bool,
+}
+
+/// Find the median of a uniform random sample of (up to) `n` values from
+/// `iter`. Returns `None` if `iter` yields nothing.
+pub fn sample_median<R: Rng, I: Iterator<Item = u32>>(rng: &mut R, iter: I, n: usize)
+        -> Option<u32>
+{
+    let mut sample = rand::sample(rng, iter, n);
+    if sample.is_empty() {
+        return None;
+    }
+    sample.sort();
+    Some(sample[sample.len() / 2])
 }
 
 /// Type implementing pippin's `SeqRepo`.
 pub struct SeqRepo<IO: RepoIO> {
-    csf: SeqClassifier,
     io: IO,
     parts: HashMap<PartId, PartInfo>,
+    // Number of sequence lengths sampled by `divide`
+    sample_size: usize,
+    // Seed used for sampling; if None a new random generator is used
+    sample_seed: Option<u32>,
+    // Partition identifiers allocated by the repository (see `set_part_alloc`)
+    alloc: PartIdAlloc,
 }
 impl<IO: RepoIO> SeqRepo<IO> {
     /// Create an new `RepoT` around a given I/O device.
     pub fn new(r: IO) -> SeqRepo<IO> {
         SeqRepo {
-            csf: SeqClassifier { classes: Vec::new() },
             io: r,
             parts: HashMap::new(),
+            sample_size: 999,
+            sample_seed: None,
+            alloc: PartIdAlloc::new(),
         }
     }
     
-    fn set_classifier(&mut self) {
-        let mut classes = Vec::with_capacity(self.parts.len());
-        for (part_id, part) in &self.parts {
-            if part.max_len > part.min_len {
-                classes.push((part.min_len as usize, part_id.clone()));
-            }
+    /// Configure how `divide()` chooses where to split: it uses the median
+    /// length of a uniform random sample of `size` sequences (default 999).
+    /// If `seed` is given, the same sample is chosen each time (for
+    /// reproducibility).
+    pub fn set_divide_sample(&mut self, size: usize, seed: Option<u32>) {
+        self.sample_size = size;
+        self.sample_seed = seed;
+    }
+    
+    /// Make a classifier assigning sequences with lengths from `min_len` to
+    /// `max_len` (inclusive) to each partition listed in `ranges` as
+    /// `(part_id, min_len, max_len)`; other partitions are unchanged. Version
+    /// numbers of listed partitions are increased.
+    /// 
+    /// This is intended for use with `Repository::replace_classifier()`,
+    /// installing the result with `use_classifier()`.
+    pub fn classifier_with_ranges(&self, ranges: &[(PartId, u32, u32)]) -> Result<SeqClassifier> {
+        let mut parts = self.parts.clone();
+        for &(id, min_len, max_len) in ranges {
+            let ver = match parts.get(&id) {
+                None => 0,
+                Some(pi) => match pi.ver.checked_add(1) {
+                    Some(ver) => ver,
+                    None => return OtherError::err("version number overflow"),
+                },
+            };
+            let max_part_id = parts.get(&id).map_or(id, |pi| pi.max_part_id);
+            parts.insert(id, PartInfo {
+                max_part_id: max_part_id,
+                ver: ver,
+                min_len: min_len,
+                max_len: max_len,
+                retired: false,
+            });
         }
-        // Note: there *could* be overlap of ranges. We can't do much if there
-        // is and it won't cause failures later, so ignore this possibility.
-        classes.sort_by(|a, b| a.0.cmp(&b.0));
-        self.csf.classes = classes;
+        Ok(SeqClassifier { parts: parts, classes: Vec::new() })
     }
+    
+    /// Replace classification data with that of `classifier`.
+    pub fn use_classifier(&mut self, classifier: &SeqClassifier) {
+        self.parts = classifier.parts.clone();
+    }
+    
+    // Classification data for one partition is stored as a user field. The
+    // current format ("version 2") is:
+    // 
+    // *   `SCP2`
+    // *   a sequence of fields, each a 4-byte tag, a big-endian `u32` length
+    //     then a value of this length; fields with unknown tags are skipped
+    //     (allowing new fields to be added), others are:
+    // *   `PART`: partition identifier (`u64`, required)
+    // *   `MAXP`: max partition identifier (`u64`, required)
+    // *   `VER `: version (`u32`, required)
+    // *   `LENS`: min and max length (two `u32`, required)
+    // *   `FLAG`: flags (`u32`; bit 0: retired)
+    // 
+    // Version 1 data is 32 bytes: `SCPI` or `SCPR` (retired), then version,
+    // min and max length (each a little-endian `u32`), then identifier and
+    // max identifier (each a little-endian `u64`). This is still read.
     fn read_ud(v: &Vec<u8>) -> Result<(PartId, PartInfo), ReadError> {
-        if v.len() != 32 {
-            return Err(ReadError::new("incorrect length", 0, (0, v.len())));
+        if v.len() >= 4 && v[0..4] == *b"SCP2" {
+            return Self::read_ud_v2(v);
         }
-        if v[0..4] != *b"SCPI" {
-            return Err(ReadError::new("unknown data (expected SCPI)", 0, (0, 4)));
+        if v.len() >= 4 && v[0..4] != *b"SCPI" && v[0..4] != *b"SCPR" {
+            return Err(ReadError::new_wrap(Box::new(ClassifierError::Identifier), 0, (0, 4)));
         }
+        if v.len() != 32 {
+            let e = ClassifierError::format(32, v.len());
+            return Err(ReadError::new_wrap(Box::new(e), 0, (0, v.len())));
+        }
+        // "SCPR" marks a retired partition; older versions instead set
+        // max_len = min_len under "SCPI", which is read as retired too
+        let retired = v[0..4] == *b"SCPR";
         let ver = LittleEndian::read_u32(&v[4..8]);
         let min_len = LittleEndian::read_u32(&v[8..12]);
         let max_len = LittleEndian::read_u32(&v[12..16]);
@@ -272,57 +468,141 @@ impl<IO: RepoIO> SeqRepo<IO> {
             ver: ver,
             min_len: min_len,
             max_len: max_len,
+            retired: retired || max_len <= min_len,
         };
         Ok((id, pi))
     }
+    
+    fn read_ud_v2(v: &Vec<u8>) -> Result<(PartId, PartInfo), ReadError> {
+        let format_err = |expected, pos| ReadError::new_wrap(
+                Box::new(ClassifierError::format(expected, v.len())), pos, (0, 8));
+        let (mut id, mut max_id, mut ver, mut lens, mut flags) = (None, None, None, None, 0);
+        let mut pos = 4;
+        while pos < v.len() {
+            if pos + 8 > v.len() {
+                return Err(format_err(pos + 8, pos));
+            }
+            let len = BigEndian::read_u32(&v[pos + 4..pos + 8]) as usize;
+            let start = pos + 8;
+            if start + len > v.len() {
+                return Err(format_err(start + len, pos));
+            }
+            let field = &v[start..start + len];
+            let expected = match &v[pos..pos + 4] {
+                b"PART" | b"MAXP" => 8,
+                b"VER " | b"FLAG" => 4,
+                b"LENS" => 8,
+                _ => len,   // unknown field: skip
+            };
+            if len != expected {
+                return Err(format_err(start + expected, pos));
+            }
+            match &v[pos..pos + 4] {
+                b"PART" => {
+                    id = Some(try_read!(PartId::try_from(BigEndian::read_u64(field)), start, (0, 8)));
+                },
+                b"MAXP" => {
+                    max_id = Some(try_read!(PartId::try_from(BigEndian::read_u64(field)), start, (0, 8)));
+                },
+                b"VER " => { ver = Some(BigEndian::read_u32(field)); },
+                b"LENS" => {
+                    lens = Some((BigEndian::read_u32(&field[0..4]), BigEndian::read_u32(&field[4..8])));
+                },
+                b"FLAG" => { flags = BigEndian::read_u32(field); },
+                _ => {},
+            }
+            pos = start + len;
+        }
+        match (id, max_id, ver, lens) {
+            (Some(id), Some(max_id), Some(ver), Some((min_len, max_len))) => {
+                Ok((id, PartInfo {
+                    max_part_id: max_id,
+                    ver: ver,
+                    min_len: min_len,
+                    max_len: max_len,
+                    retired: flags & 1 != 0,
+                }))
+            },
+            _ => Err(ReadError::new("classifier data is missing a required field", 0, (0, 4))),
+        }
+    }
+    
+    fn write_ud(id: PartId, pi: &PartInfo) -> Vec<u8> {
+        fn field(buf: &mut Vec<u8>, tag: &[u8], value: &[u8]) {
+            buf.extend_from_slice(tag);
+            buf.write_u32::<BigEndian>(value.len() as u32).expect("write to Vec");
+            buf.extend_from_slice(value);
+        }
+        let mut buf = Vec::from(&b"SCP2"[..]);
+        let mut value = [0u8; 8];
+        BigEndian::write_u64(&mut value, id.into());
+        field(&mut buf, b"PART", &value);
+        BigEndian::write_u64(&mut value, pi.max_part_id.into());
+        field(&mut buf, b"MAXP", &value);
+        BigEndian::write_u32(&mut value, pi.ver);
+        field(&mut buf, b"VER ", &value[0..4]);
+        BigEndian::write_u32(&mut value[0..4], pi.min_len);
+        BigEndian::write_u32(&mut value[4..8], pi.max_len);
+        field(&mut buf, b"LENS", &value);
+        BigEndian::write_u32(&mut value, if pi.retired { 1 } else { 0 });
+        field(&mut buf, b"FLAG", &value[0..4]);
+        buf
+    }
 }
 impl<IO: RepoIO> UserFields for SeqRepo<IO> {
     fn write_user_fields(&mut self, _part_id: PartId, _is_log: bool) -> Vec<UserData> {
-        let mut ud = Vec::with_capacity(self.parts.len());
-        for (id,pi) in &self.parts {
-            let mut buf = Vec::from(&b"SCPI4...8...12..16..-...24..-..."[..]);
-            LittleEndian::write_u32(&mut buf[4..], pi.ver);
-            LittleEndian::write_u32(&mut buf[8..], pi.min_len);
-            LittleEndian::write_u32(&mut buf[12..], pi.max_len);
-            LittleEndian::write_u64(&mut buf[16..], (*id).into());
-            LittleEndian::write_u64(&mut buf[24..], pi.max_part_id.into());
-            ud.push(UserData::Data(buf));
+        // Sort by PartId so that output does not depend on HashMap ordering
+        let mut parts: Vec<_> = self.parts.iter().collect();
+        parts.sort_by_key(|&(id, _)| *id);
+        let mut ud = Vec::with_capacity(parts.len());
+        for (id,pi) in parts {
+            ud.push(UserData::Data(Self::write_ud(*id, pi)));
         }
         ud
     }
     fn read_user_fields(&mut self, user: Vec<UserData>, _part_id: PartId, _is_log: bool) {
         for ud in user {
-            let (id, pi) = match ud {
-                UserData::Data(v) => {
-                    match Self::read_ud(&v) {
-                        Ok(result) => result,
-                        Err(e) => {
-                            warn!("Error parsing user data: {}", e.display(&v));
-                            continue;
-                        },
-                    }
-                },
+            let v = match ud {
+                UserData::Data(v) => v,
                 UserData::Text(t) => {
                     warn!("Encounted user text: {}", t);
                     continue;
                 },
             };
-            match self.parts.entry(id) {
-                Entry::Vacant(entry) => {
-                    entry.insert(pi);
-                },
-                Entry::Occupied(entry) => {
-                    if pi.ver > entry.get().ver {
-                        let e = entry.into_mut();
-                        e.max_part_id = pi.max_part_id;
-                        e.ver = pi.ver;
-                        e.min_len = pi.min_len;
-                        e.max_len = pi.max_len;
-                    }
+            let (id, mut pi) = match Self::read_ud(&v) {
+                Ok(result) => result,
+                Err(e) => {
+                    warn!("Error parsing user data: {}", e.display(&v));
+                    continue;
                 },
+            };
+            // Compare and merge in the current format (data may be older):
+            let v = Self::write_ud(id, &pi);
+            let current = self.parts.get(&id).map(|cur| (cur.ver, Self::write_ud(id, cur)));
+            if let Some((ver, cur)) = current {
+                if pi.ver < ver || cur == v {
+                    continue;
+                }
+                if pi.ver == ver {
+                    // Same version but different data: a genuine conflict
+                    let merged = self.merge_classifier_conflict(id, cur, v);
+                    pi = match Self::read_ud(&merged) {
+                        Ok((m_id, m_pi)) => {
+                            if m_id != id {
+                                warn!("Merged classifier data has wrong partition identifier");
+                                continue;
+                            }
+                            m_pi
+                        },
+                        Err(e) => {
+                            warn!("Error parsing merged user data: {}", e.display(&merged));
+                            continue;
+                        },
+                    };
+                }
             }
+            self.parts.insert(id, pi);
         }
-        self.set_classifier();
     }
 }
 impl<IO: RepoIO> RepoT<SeqClassifier> for SeqRepo<IO> {
@@ -330,7 +610,10 @@ impl<IO: RepoIO> RepoT<SeqClassifier> for SeqRepo<IO> {
         &mut self.io
     }
     fn clone_classifier(&self) -> SeqClassifier {
-        self.csf.clone()
+        // Build the class table too, so the copy classifies as this does
+        let mut classifier = SeqClassifier { parts: self.parts.clone(), classes: Vec::new() };
+        classifier.on_load();
+        classifier
     }
     fn init_first(&mut self) -> Result<PartId> {
         assert!(self.parts.is_empty());
@@ -340,70 +623,1057 @@ impl<IO: RepoIO> RepoT<SeqClassifier> for SeqRepo<IO> {
             ver: 0,
             min_len: 0,
             max_len: u32::MAX,
+            retired: false,
         });
-        self.set_classifier();
         Ok(p_id)
     }
+    fn set_part_alloc(&mut self, alloc: &PartIdAlloc) {
+        self.alloc = alloc.clone();
+    }
     fn divide(&mut self, part: &Partition<Sequence>) ->
         Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>
     {
         let tip = part.tip().map_err(|e| RepoDivideError::Other(Box::new(e)))?;
         // 1: choose new lengths to use for partitioning
-        // Algorithm: sample up to 999 lengths, find the median
-        if tip.num_avail() < 1 { return Err(RepoDivideError::NotSubdivisible); }
-        let mut lens = Vec::with_capacity(min(999, tip.num_avail()));
-         for (_, elt) in tip.elts_iter() {
-            let seq: &Sequence = elt;
+        // Algorithm: sample lengths uniformly, find the median
+        let lens = tip.elts_iter().map(|(_, seq)| {
             assert!(seq.v.len() <= u32::MAX as usize);
-            lens.push(seq.v.len() as u32);
-            if lens.len() >= 999 { break; }
-        }
-        lens.sort();
-        let mid_point = lens.len() / 2;
-        let median = lens[mid_point];
+            seq.v.len() as u32
+        });
+        let median = match self.sample_seed {
+            Some(seed) => sample_median(&mut ChaChaRng::from_seed(&[seed]), lens, self.sample_size),
+            None => sample_median(&mut rand::thread_rng(), lens, self.sample_size),
+        };
+        let median = match median {
+            Some(median) => median,
+            None => return Err(RepoDivideError::NotSubdivisible),
+        };
         // 1st new class uses existing lower-bound; 2nd uses median as its lower bound
         
         // 2: find new partition numbers
         let old_id = part.part_id();
         let old_num = old_id.into_num();
-        let (max_num, min_len, max_len) = match self.parts.get(&old_id) {
+        let (max_num, min_len, max_len, old_ver) = match self.parts.get(&old_id) {
             Some(part) => 
-                (part.max_part_id.into_num(), part.min_len, part.max_len),
+                (part.max_part_id.into_num(), part.min_len, part.max_len, part.ver),
             None => {
                 return Err(RepoDivideError::msg("missing info"));
             },
         };
-        if max_num < old_num + 2 {
+        if old_num.checked_add(2).map_or(true, |n| max_num < n) {
             // Not enough numbers
             // TODO: steal numbers from other partitions
             return Err(RepoDivideError::NotSubdivisible);
         }
-        let num1 = old_num + 1;
-        let num2 = num1 + (max_num - old_num) / 2;
+        // Use the lowest free number for the first and a free number from the
+        // middle of the remaining range for the second. Since
+        // max_num >= old_num + 2, old_num < num1 < num2 when successful.
+        let num1 = match self.alloc.next_free(PartId::from_num(old_num + 1)) {
+            Some(id) => id.into_num(),
+            None => return Err(RepoDivideError::NotSubdivisible),
+        };
+        let mid = max(num1 + 1, old_num + 1 + (max_num - old_num) / 2);
+        let num2 = match self.alloc.next_free(PartId::from_num(min(mid, max_num))) {
+            Some(id) if mid <= max_num && id.into_num() <= max_num => id.into_num(),
+            _ => return Err(RepoDivideError::NotSubdivisible),
+        };
         let (id1, id2) = (PartId::from_num(num1), PartId::from_num(num2));
         
+        // Both new classes must be non-empty and distinguishable from a
+        // retired range: min_len < median - 1 and median < max_len.
+        let split = match median.checked_sub(1) {
+            Some(split) if split > min_len && median < max_len => split,
+            _ => return Err(RepoDivideError::msg("median length does not split partition's range")),
+        };
+        
         // 3: update and report
-        let ver = self.parts.get(&id1).map_or(0, |pi| pi.ver + 1);
+        let ver1 = next_ver(self.parts.get(&id1))?;
+        let ver2 = next_ver(self.parts.get(&id2))?;
+        let old_ver = old_ver.checked_add(1).ok_or(RepoDivideError::msg("version number overflow"))?;
         self.parts.insert(id1, PartInfo {
             max_part_id: PartId::from_num(num2 - 1),
-            ver: ver,
+            ver: ver1,
             min_len: min_len,
-            max_len: median - 1,
+            max_len: split,
+            retired: false,
         });
-        let ver = self.parts.get(&id2).map_or(0, |pi| pi.ver + 1);
         self.parts.insert(id2, PartInfo {
             max_part_id: PartId::from_num(max_num),
-            ver: ver,
+            ver: ver2,
             min_len: median,
             max_len: max_len,
+            retired: false,
         });
         if let Some(pi) = self.parts.get_mut(&old_id) {
             pi.max_part_id = old_id;
-            pi.ver = pi.ver + 1;
-            pi.max_len = pi.min_len;    // mark as no longer in use
+            pi.ver = old_ver;
+            pi.retired = true;
         }
-        self.set_classifier();
         //TODO: what happens with return value?
         Ok((vec![id1, id2], vec![]))
     }
+    fn release_part(&mut self, part: &Partition<Sequence>) -> bool {
+        // A retired partition's identifiers are given to the live partition
+        // whose range precedes them, thus may be used when that is divided
+        let id = part.part_id();
+        let max_id = match self.parts.get(&id) {
+            Some(pi) if pi.retired => pi.max_part_id,
+            _ => return false,
+        };
+        let prev = self.parts.iter()
+            .find(|&(_, pi)| !pi.retired && pi.max_part_id.into_num() + 1 == id.into_num())
+            .map(|(prev, _)| *prev);
+        let prev = match prev.and_then(|prev| self.parts.get_mut(&prev)) {
+            Some(pi) => pi,
+            None => return false,
+        };
+        prev.ver = match prev.ver.checked_add(1) {
+            Some(ver) => ver,
+            None => return false,
+        };
+        prev.max_part_id = max_id;
+        self.parts.remove(&id);
+        true
+    }
+}
+
+// Version for a new partition's info: one more than the current version, if any
+fn next_ver(current: Option<&PartInfo>) -> Result<u32, RepoDivideError> {
+    match current {
+        None => Ok(0),
+        Some(pi) => pi.ver.checked_add(1).ok_or(RepoDivideError::msg("version number overflow")),
+    }
+}
+
+#[test]
+fn user_fields_stable() {
+    use pippin::fileio::RepoFileIO;
+    
+    let infos: Vec<_> = (1..20).map(|n| (PartId::from_num(n), PartInfo {
+        max_part_id: PartId::from_num(n),
+        ver: n as u32,
+        min_len: n as u32 * 10,
+        max_len: n as u32 * 10 + 9,
+        retired: false,
+    })).collect();
+    // Insert into two maps in different orders:
+    let mut repo1 = SeqRepo::new(RepoFileIO::new("unused"));
+    for &(id, ref pi) in infos.iter() {
+        repo1.parts.insert(id, pi.clone());
+    }
+    let mut repo2 = SeqRepo::new(RepoFileIO::new("unused"));
+    for &(id, ref pi) in infos.iter().rev() {
+        repo2.parts.insert(id, pi.clone());
+    }
+    
+    let ud1 = repo1.write_user_fields(PartId::from_num(1), false);
+    assert_eq!(ud1, repo1.write_user_fields(PartId::from_num(1), false));
+    assert_eq!(ud1, repo2.write_user_fields(PartId::from_num(1), false));
+}
+
+#[test]
+fn clone_classifier_classifies() {
+    use pippin::fileio::RepoFileIO;
+    
+    let mut rt = SeqRepo::new(RepoFileIO::new("unused"));
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let classifier = rt.classifier_with_ranges(&[(p1, 0, 9), (p2, 10, u32::MAX)]).expect("ranges");
+    rt.use_classifier(&classifier);
+    
+    // Usable without calling `on_load()`:
+    let classifier = rt.clone_classifier();
+    assert_eq!(classifier.classify(&Sequence::from(vec![1.0; 5])), Some(p1));
+    assert_eq!(classifier.classify(&Sequence::from(vec![1.0; 25])), Some(p2));
+}
+
+#[test]
+fn classifier_read_errors() {
+    use pippin::fileio::RepoFileIO;
+    type Repo = SeqRepo<RepoFileIO>;
+    
+    let pi = PartInfo {
+        max_part_id: PartId::from_num(3),
+        ver: 2,
+        min_len: 10,
+        max_len: 19,
+        retired: false,
+    };
+    let buf = Repo::write_ud(PartId::from_num(1), &pi);
+    let (id, pi2) = Repo::read_ud(&buf).expect("read_ud");
+    assert_eq!(id, PartId::from_num(1));
+    assert_eq!((pi2.max_part_id, pi2.ver, pi2.min_len, pi2.max_len), (PartId::from_num(3), 2, 10, 19));
+    
+    let kind = |v: &Vec<u8>| match Repo::read_ud(v) {
+        Ok(_) => panic!("expected error"),
+        Err(e) => e.wrapped().and_then(|e| e.downcast_ref::<ClassifierError>()).cloned(),
+    };
+    let short = buf[0..30].to_vec();
+    assert_eq!(kind(&short), Some(ClassifierError::Format { expected: 36, actual: 30 }));
+    let mut bad = buf.clone();
+    bad[0] = b'X';
+    assert_eq!(kind(&bad), Some(ClassifierError::Identifier));
+    
+    // A known field with the wrong length is an error:
+    let mut bad = buf.clone();
+    bad.extend_from_slice(b"FLAG\x00\x00\x00\x02\x00\x01");
+    assert_eq!(kind(&bad), Some(ClassifierError::Format { expected: buf.len() + 12, actual: bad.len() }));
+    // As is a missing field:
+    assert!(Repo::read_ud(&buf[0..20].to_vec()).is_err());
+    
+    // Version 1 data:
+    let v1 = write_ud_v1(PartId::from_num(1), &pi);
+    let (id, pi2) = Repo::read_ud(&v1).expect("read_ud");
+    assert_eq!(id, PartId::from_num(1));
+    assert_eq!((pi2.max_part_id, pi2.ver, pi2.min_len, pi2.max_len, pi2.retired),
+            (PartId::from_num(3), 2, 10, 19, false));
+    assert_eq!(kind(&v1[0..20].to_vec()), Some(ClassifierError::Format { expected: 32, actual: 20 }));
+}
+
+#[test]
+fn classifier_format_v2() {
+    use pippin::fileio::RepoFileIO;
+    type Repo = SeqRepo<RepoFileIO>;
+    
+    let id = PartId::from_num(2);
+    let pi = PartInfo {
+        max_part_id: PartId::from_num(7),
+        ver: 5,
+        min_len: 3,
+        max_len: 1000,
+        retired: true,
+    };
+    let buf = Repo::write_ud(id, &pi);
+    assert_eq!(&buf[0..4], b"SCP2");
+    let (id2, pi2) = Repo::read_ud(&buf).expect("read_ud");
+    assert_eq!(id2, id);
+    assert_eq!((pi2.max_part_id, pi2.ver, pi2.min_len, pi2.max_len, pi2.retired),
+            (PartId::from_num(7), 5, 3, 1000, true));
+    
+    // Unknown fields are skipped, wherever they appear:
+    let mut ext = Vec::from(&buf[0..4]);
+    ext.extend_from_slice(b"NEW?\x00\x00\x00\x03abc");
+    ext.extend_from_slice(&buf[4..]);
+    ext.extend_from_slice(b"MORE\x00\x00\x00\x00");
+    let (id2, pi2) = Repo::read_ud(&ext).expect("read_ud");
+    assert_eq!(id2, id);
+    assert_eq!((pi2.max_part_id, pi2.ver, pi2.min_len, pi2.max_len, pi2.retired),
+            (PartId::from_num(7), 5, 3, 1000, true));
+    
+    // The same data in versions 1 and 2 does not conflict:
+    let mut repo = SeqRepo::new(RepoFileIO::new("unused"));
+    repo.read_user_fields(vec![UserData::Data(write_ud_v1(id, &pi)), UserData::Data(buf.clone())],
+            id, false);
+    assert_eq!(repo.write_user_fields(id, false), vec![UserData::Data(buf)]);
+}
+
+// Write classification data in the old (version 1) format
+#[cfg(test)]
+fn write_ud_v1(id: PartId, pi: &PartInfo) -> Vec<u8> {
+    let mut buf = vec![0; 32];
+    buf[0..4].copy_from_slice(if pi.retired { b"SCPR" } else { b"SCPI" });
+    LittleEndian::write_u32(&mut buf[4..8], pi.ver);
+    LittleEndian::write_u32(&mut buf[8..12], pi.min_len);
+    LittleEndian::write_u32(&mut buf[12..16], pi.max_len);
+    LittleEndian::write_u64(&mut buf[16..24], id.into());
+    LittleEndian::write_u64(&mut buf[24..32], pi.max_part_id.into());
+    buf
+}
+
+#[test]
+fn read_element_range() {
+    use std::sync::Arc;
+    
+    let part_id = PartId::from_num(1);
+    let seq: Vec<R> = Arithmetic{ start: 1.0, step: 0.5 }.generate(1000);
+    let mut state = PartState::<Sequence>::new(part_id, None).clone_mut();
+    let id = state.insert_with_id(part_id.elt_id(1), Arc::new(Sequence::from(seq.clone())))
+            .expect("insert");
+    let state = PartState::from_mut(state, None);
+    
+    let size = size_of::<R>();
+    let bytes = state.read_element_range(id, 400 * size, 25 * size).expect("read range");
+    assert_eq!(bytes.len(), 25 * size);
+    assert_eq!(Sequence::decode_values(&bytes).expect("decode"), &seq[400..425]);
+    
+    assert!(state.read_element_range(id, 990 * size, 20 * size).is_err());
+    assert!(state.read_element_range(part_id.elt_id(2), 0, 0).is_err());
+}
+
+#[test]
+fn empty_sequence() {
+    use std::sync::Arc;
+    use std::path::Path;
+    use pippin::discover;
+    use pippin::fileio::PartFileIO;
+    use util::mk_temp_dir;
+    
+    let tmp_dir = mk_temp_dir("seq_empty_sequence");
+    let dir: &Path = tmp_dir.as_ref();
+    let part_id = PartId::from_num(1);
+    let io = PartFileIO::new_empty(part_id, dir.join("pn1"));
+    let mut part = Partition::<Sequence>::create(Box::new(io), "empty seq", None, None)
+            .expect("create");
+    let mut state = part.tip().expect("tip").clone_mut();
+    let empty = state.insert(Sequence::from(vec![])).expect("insert");
+    let full = state.insert_rc(Arc::new(Sequence::from(vec![1.0, 2.0]))).expect("insert");
+    let removed = state.insert(Sequence::from(vec![])).expect("insert");
+    state.remove(removed).expect("remove");
+    part.push_state(state, None).expect("push");
+    part.write_fast(None).expect("write");
+    
+    // An empty element is present, unlike a removed one:
+    let check = || {
+        let io = discover::part_from_path(dir, None).expect("discover");
+        let mut part = Partition::<Sequence>::open(Box::new(io)).expect("open");
+        part.load_latest(None, None).expect("load");
+        let tip = part.tip().expect("tip");
+        assert_eq!(tip.num_avail(), 2);
+        assert_eq!(tip.get(empty).expect("get empty"), &Sequence::from(vec![]));
+        assert_eq!(tip.get(full).expect("get full").len(), 2);
+        assert!(!tip.is_avail(removed));
+    };
+    check();    // from the log
+    part.write_snapshot(None).expect("write snapshot");
+    check();    // from the snapshot
+}
+
+#[test]
+fn snapshot_dedup_fingerprint() {
+    use std::fs::File;
+    use std::io::Read;
+    use std::path::Path;
+    use pippin::{PartitionBuilder, discover};
+    use pippin::fileio::PartFileIO;
+    use util::mk_temp_dir;
+    
+    let tmp_dir = mk_temp_dir("seq_dedup_fingerprint");
+    let dir: &Path = tmp_dir.as_ref();
+    let part_id = PartId::from_num(1);
+    let io = PartFileIO::new_empty(part_id, dir.join("pn1"));
+    let mut part = PartitionBuilder::new().snapshot_dedup(true)
+            .create::<Sequence>(Box::new(io), "dedup").expect("create");
+    
+    // `a` and `b` share a fingerprint but not content; `c` is a copy of `a`
+    let a = Sequence::from(vec![1.0, 2.0, 3.0, 4.0]);
+    let b = Sequence::from(vec![1.0, 5.0, 6.0, 4.0]);
+    assert_eq!(a.fingerprint(), b.fingerprint());
+    let mut state = part.tip().expect("tip").clone_mut();
+    let ids: Vec<_> = vec![a, b, Sequence::from(vec![1.0, 2.0, 3.0, 4.0]), Sequence::from(vec![7.0])]
+            .into_iter().map(|seq| state.insert(seq).expect("insert")).collect();
+    part.push_state(state, None).expect("push");
+    part.write_snapshot(None).expect("write snapshot");
+    
+    // Only the copy refers to other data:
+    let mut data = Vec::new();
+    File::open(dir.join("pn1-ss1.pip")).expect("open").read_to_end(&mut data).expect("read");
+    assert_eq!(data.windows(8).filter(|w| *w == b"SAMEAS\x00\x00").count(), 1);
+    
+    let io = discover::part_from_path(dir, None).expect("discover");
+    let mut part2 = Partition::<Sequence>::open(Box::new(io)).expect("open");
+    part2.load_latest(None, None).expect("load");
+    let (tip, tip2) = (part.tip().expect("tip"), part2.tip().expect("tip"));
+    assert_eq!(tip, tip2);
+    for id in ids {
+        assert_eq!(tip.get(id).expect("get"), tip2.get(id).expect("get"));
+    }
+}
+
+#[test]
+fn sample_median_uniform() {
+    // Skewed data, ordered such that the first values are all short
+    let lens: Vec<u32> = (0..10_000u32).map(|i| i * i / 10_000).collect();
+    let true_median = lens[lens.len() / 2];
+    let first_n = {
+        let mut v = lens[0..999].to_vec();
+        v.sort();
+        v[v.len() / 2]
+    };
+    let mut rng = ChaChaRng::from_seed(&[5]);
+    let sampled = sample_median(&mut rng, lens.iter().cloned(), 999).expect("sample");
+    let diff = |x: u32| (x as i64 - true_median as i64).abs();
+    assert!(diff(sampled) < diff(first_n));
+    
+    // Same seed, same result:
+    let mut rng = ChaChaRng::from_seed(&[5]);
+    assert_eq!(sample_median(&mut rng, lens.iter().cloned(), 999), Some(sampled));
+    assert_eq!(sample_median(&mut rng, Vec::new().into_iter(), 999), None);
+}
+
+#[test]
+fn classifier_conflict() {
+    use std::cmp::min;
+    use pippin::fileio::RepoFileIO;
+    
+    let id = PartId::from_num(4);
+    let a = SeqRepo::<RepoFileIO>::write_ud(id, &PartInfo {
+        max_part_id: PartId::from_num(9), ver: 3, min_len: 0, max_len: 100, retired: false });
+    let b = SeqRepo::<RepoFileIO>::write_ud(id, &PartInfo {
+        max_part_id: PartId::from_num(9), ver: 3, min_len: 0, max_len: 50, retired: false });
+    assert!(a != b);
+    
+    // Read the divergent data in both orders; result should be the same
+    let mut repo1 = SeqRepo::new(RepoFileIO::new("unused"));
+    repo1.read_user_fields(vec![UserData::Data(a.clone()), UserData::Data(b.clone())], id, false);
+    let mut repo2 = SeqRepo::new(RepoFileIO::new("unused"));
+    repo2.read_user_fields(vec![UserData::Data(b.clone()), UserData::Data(a.clone())], id, false);
+    let ud1 = repo1.write_user_fields(id, false);
+    assert_eq!(ud1, repo2.write_user_fields(id, false));
+    assert_eq!(ud1, vec![UserData::Data(min(a, b))]);
+}
+
+#[test]
+fn validate_on_insert() {
+    use std::f64::NAN;
+    use pippin::error::ElementOp;
+    
+    let part_id = PartId::from_num(1);
+    let mut state = PartState::<Sequence>::new(part_id, None).clone_mut();
+    let id = state.insert(Sequence::from(vec![1.0, 2.0])).expect("insert");
+    assert_eq!(state.num_avail(), 1);
+    
+    assert_eq!(state.insert(Sequence::from(vec![1.0, NAN])), Err(ElementOp::Invalid));
+    assert_eq!(state.num_avail(), 1);
+    assert_eq!(state.replace(id, Sequence::from(vec![NAN])), Err(ElementOp::Invalid));
+    assert_eq!(*state.get(id).expect("get"), Sequence::from(vec![1.0, 2.0]));
+    
+    state.insert(Sequence::from(vec![])).expect("insert empty");
+    assert_eq!(state.num_avail(), 2);
+}
+
+#[test]
+fn validate_on_redact() {
+    use std::f64::NAN;
+    use pippin::error::ElementOp;
+    
+    let part_id = PartId::from_num(1);
+    let mut state = PartState::<Sequence>::new(part_id, None).clone_mut();
+    state.insert(Sequence::from(vec![1.0, 2.0])).expect("insert");
+    let state = PartState::from_mut(state, None);
+    
+    let policy = |_, _: &Sequence| Some(Sequence::from(vec![NAN]));
+    assert_eq!(state.redacted(&policy).err(), Some(ElementOp::Invalid));
+    let policy = |_, _: &Sequence| Some(Sequence::from(vec![0.0]));
+    assert_eq!(state.redacted(&policy).expect("redacted").num_avail(), 1);
+}
+
+#[test]
+fn manifest_export_import() {
+    use pippin::fileio::RepoFileIO;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_manifest");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    let ranges = [(0, 10), (10, 100), (100, u32::MAX)];
+    for n in 1..4 {
+        let part_id = PartId::from_num(n);
+        rt.io.new_part(part_id, format!("pn{}", n)).expect("new part");
+        let part_io = rt.io.make_part_io(part_id).expect("make part io");
+        Partition::<Sequence>::create(part_io, "seq_manifest", None, None).expect("create");
+        // Classification as if partition 1 had been divided:
+        let (min_len, max_len) = ranges[n as usize - 1];
+        rt.parts.insert(part_id, PartInfo {
+            max_part_id: PartId::from_num(3),
+            ver: n as u32,
+            min_len: min_len,
+            max_len: max_len,
+            retired: false,
+        });
+    }
+    let expected = rt.write_user_fields(PartId::from_num(1), false);
+    
+    let mut repo = Repository::open(rt).expect("open");
+    repo.load_latest(None).expect("load");
+    let mut buf = Vec::new();
+    repo.export_manifest(&mut buf).expect("export");
+    
+    let mut rt2 = SeqRepo::new(RepoFileIO::new("unused"));
+    let manifest = Repository::<SeqClassifier, _>::import_manifest(&mut rt2, &mut &buf[..])
+            .expect("import");
+    assert_eq!(manifest.name, "seq_manifest");
+    let ids: Vec<_> = manifest.parts.iter().map(|p| p.part_id.into_num()).collect();
+    assert_eq!(ids, vec![1, 2, 3]);
+    assert!(manifest.parts.iter().all(|p| p.tips.len() == 1));
+    assert_eq!(rt2.write_user_fields(PartId::from_num(1), false), expected);
+}
+
+#[test]
+fn divide_degenerate() {
+    use pippin::fileio::RepoFileIO;
+    use pippin::part::DummyPartIO;
+    
+    // Create partition 1 holding sequences of the given lengths, and a
+    // `SeqRepo` with partition 1 covering all lengths
+    let setup = |lens: &[usize]| {
+        let part_id = PartId::from_num(1);
+        let mut rt = SeqRepo::new(RepoFileIO::new("unused"));
+        rt.init_first().expect("init");
+        let io = Box::new(DummyPartIO::new(part_id));
+        let mut part = Partition::<Sequence>::create(io, "divide", None, None).expect("create");
+        let mut state = part.tip().expect("tip").clone_mut();
+        for len in lens {
+            state.insert(Sequence::from(vec![1.0; *len])).expect("insert");
+        }
+        part.push_state(state, None).expect("push");
+        rt.set_divide_sample(999, Some(1));
+        (rt, part)
+    };
+    let is_msg = |r: Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>| match r {
+        Err(RepoDivideError::Other(_)) => true,
+        _ => false,
+    };
+    
+    // No elements:
+    let (mut rt, part) = setup(&[]);
+    match rt.divide(&part) {
+        Err(RepoDivideError::NotSubdivisible) => {},
+        _ => panic!("expected NotSubdivisible"),
+    }
+    
+    // All lengths zero, thus median zero:
+    let (mut rt, part) = setup(&[0, 0, 0]);
+    assert!(is_msg(rt.divide(&part)));
+    // Single element of length one: first class would be empty
+    let (mut rt, part) = setup(&[1]);
+    assert!(is_msg(rt.divide(&part)));
+    
+    // A failed divide leaves classification unchanged:
+    assert_eq!(rt.parts.len(), 1);
+    assert_eq!(rt.parts[&PartId::from_num(1)].ver, 0);
+    
+    // Single element of reasonable length:
+    let (mut rt, part) = setup(&[5]);
+    let (new_ids, _) = rt.divide(&part).expect("divide");
+    assert_eq!(new_ids.len(), 2);
+    assert_eq!(rt.parts[&new_ids[0]].max_len, 4);
+    assert_eq!(rt.parts[&new_ids[1]].min_len, 5);
+    
+    // No partition numbers available:
+    let (mut rt, part) = setup(&[5]);
+    rt.parts.get_mut(&PartId::from_num(1)).expect("part info").max_part_id = PartId::from_num(2);
+    match rt.divide(&part) {
+        Err(RepoDivideError::NotSubdivisible) => {},
+        _ => panic!("expected NotSubdivisible"),
+    }
+}
+
+#[test]
+fn classifier_should_divide() {
+    let part_id = PartId::from_num(1);
+    let classifier = SeqClassifier { parts: HashMap::new(), classes: Vec::new() };
+    let mut state = PartState::<Sequence>::new(part_id, None).clone_mut();
+    for _ in 0..MIN_DIVIDE_ELTS {
+        state.insert(Sequence::from(vec![1.0, 2.0])).expect("insert");
+    }
+    // Enough elements, but all the same length:
+    let same_len = PartState::from_mut(state, None);
+    assert!(!classifier.should_divide(&same_len));
+    let mut state = same_len.clone_mut();
+    state.insert(Sequence::from(vec![1.0])).expect("insert");
+    assert!(classifier.should_divide(&PartState::from_mut(state, None)));
+    
+    let mut state = PartState::<Sequence>::new(part_id, None).clone_mut();
+    state.insert(Sequence::from(vec![1.0])).expect("insert");
+    state.insert(Sequence::from(vec![1.0, 2.0])).expect("insert");
+    assert!(!classifier.should_divide(&PartState::from_mut(state, None)));
+}
+
+#[test]
+fn replace_classifier() {
+    use pippin::fileio::RepoFileIO;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_replace_classifier");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    for n in 1..3 {
+        let part_id = PartId::from_num(n);
+        rt.io.new_part(part_id, format!("pn{}", n)).expect("new part");
+        let part_io = rt.io.make_part_io(part_id).expect("make part io");
+        Partition::<Sequence>::create(part_io, "seq_replace", None, None).expect("create");
+    }
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let initial = rt.classifier_with_ranges(&[(p1, 0, 9), (p2, 10, u32::MAX)]).expect("ranges");
+    rt.use_classifier(&initial);
+    // Lengths 2 to 4 are not classified:
+    let orphaning = rt.classifier_with_ranges(&[(p1, 5, 19), (p2, 20, u32::MAX)]).expect("ranges");
+    let replacement = rt.classifier_with_ranges(&[(p1, 0, 19), (p2, 20, u32::MAX)]).expect("ranges");
+    
+    let mut repo = Repository::open(rt).expect("open");
+    repo.load_latest(None).expect("load");
+    let mut state = repo.clone_state().expect("clone state");
+    for len in &[2, 15, 25] {
+        state.insert(Sequence::from(vec![1.0; *len])).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    
+    // Sorted lengths of sequences in each partition:
+    fn lens<R: RepoT<SeqClassifier>>(repo: &Repository<SeqClassifier, R>) -> Vec<Vec<usize>> {
+        let mut parts: Vec<_> = repo.partitions().collect();
+        parts.sort_by_key(|p| p.part_id());
+        parts.iter().map(|p| {
+            let mut lens: Vec<_> = p.tip().expect("tip").elts_iter()
+                    .map(|(_, seq)| seq.len()).collect();
+            lens.sort();
+            lens
+        }).collect()
+    }
+    assert_eq!(lens(&repo), vec![vec![2], vec![15, 25]]);
+    
+    assert!(repo.replace_classifier(orphaning, |_| panic!("installed")).is_err());
+    assert_eq!(lens(&repo), vec![vec![2], vec![15, 25]]);
+    
+    let installed = replacement.clone();
+    let moved = repo.replace_classifier(replacement, |rt| rt.use_classifier(&installed))
+            .expect("replace");
+    assert_eq!(moved, 1);
+    assert_eq!(lens(&repo), vec![vec![2, 15], vec![25]]);
+    
+    // New classification is used on reload:
+    let io = discover::repo_from_path(tmp_dir.to_path_buf()).expect("discover");
+    let mut repo = Repository::open(SeqRepo::new(io)).expect("reopen");
+    repo.load_latest(None).expect("load");
+    assert_eq!(lens(&repo), vec![vec![2, 15], vec![25]]);
+    let mut state = repo.clone_state().expect("clone state");
+    let id = state.insert(Sequence::from(vec![1.0; 18])).expect("insert");
+    assert_eq!(id.part_id(), p1);
+}
+
+#[test]
+fn query_len_range() {
+    use pippin::fileio::RepoFileIO;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_query_len");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    for n in 1..3 {
+        let part_id = PartId::from_num(n);
+        rt.io.new_part(part_id, format!("pn{}", n)).expect("new part");
+        let part_io = rt.io.make_part_io(part_id).expect("make part io");
+        Partition::<Sequence>::create(part_io, "seq_query", None, None).expect("create");
+    }
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let classifier = rt.classifier_with_ranges(&[(p1, 0, 9), (p2, 10, u32::MAX)]).expect("ranges");
+    rt.use_classifier(&classifier);
+    
+    let mut repo = Repository::open(rt).expect("open");
+    repo.load_latest(None).expect("load");
+    let mut state = repo.clone_state().expect("clone state");
+    for len in &[1, 3, 5, 8, 12, 15, 25] {
+        state.insert(Sequence::from(vec![1.0; *len])).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write");
+    
+    let classifier = repo.classifier();
+    assert_eq!(classifier.parts_for_lens(4, 12), vec![p1, p2]);
+    assert_eq!(classifier.parts_for_lens(0, 9), vec![p1]);
+    assert_eq!(classifier.parts_for_lens(10, 10), vec![p2]);
+    
+    let lens = |result: Vec<(EltId, Arc<Sequence>)>| {
+        let mut lens: Vec<_> = result.iter().map(|&(_, ref seq)| seq.len()).collect();
+        lens.sort();
+        lens
+    };
+    assert_eq!(lens(query_len(&mut repo, 4, 12).expect("query")), vec![5, 8, 12]);
+    assert_eq!(lens(query_len(&mut repo, 13, 20).expect("query")), vec![15]);
+    assert!(query_len(&mut repo, 30, 40).expect("query").is_empty());
+    
+    // Only the partition holding the lengths queried is loaded:
+    assert!(repo.unload_all(false));
+    assert_eq!(lens(query_len(&mut repo, 2, 6).expect("query")), vec![3, 5]);
+    assert!(repo.is_loaded(p1));
+    assert!(!repo.is_loaded(p2));
+}
+
+#[test]
+fn find_with_hint() {
+    use pippin::ClassifierT;
+    use pippin::fileio::RepoFileIO;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_find");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    for n in 1..3 {
+        let part_id = PartId::from_num(n);
+        rt.io.new_part(part_id, format!("pn{}", n)).expect("new part");
+        let part_io = rt.io.make_part_io(part_id).expect("make part io");
+        Partition::<Sequence>::create(part_io, "seq_find", None, None).expect("create");
+    }
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let classifier = rt.classifier_with_ranges(&[(p1, 0, 9), (p2, 10, u32::MAX)]).expect("ranges");
+    rt.use_classifier(&classifier);
+    
+    let mut repo = Repository::open(rt).expect("open");
+    repo.load_latest(None).expect("load");
+    let mut state = repo.clone_state().expect("clone state");
+    for &(len, x) in &[(3, 1.0), (5, 2.0), (8, 2.0), (12, 2.0), (15, 1.0)] {
+        state.insert(Sequence::from(vec![x; len])).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write");
+    assert!(repo.unload_all(false));
+    
+    // Short sequences of twos; the hint comes from classifying an example:
+    let hint = repo.classifier().classify(&Sequence::from(vec![2.0; 4]));
+    assert_eq!(hint, Some(p1));
+    let is_two = |seq: &Sequence| seq.v.first() == Some(&2.0);
+    {
+        let found = repo.find(hint, &is_two).expect("find");
+        let lens: Vec<_> = found.iter().map(|&(part_id, _, seq)| (part_id, seq.len())).collect();
+        assert_eq!(lens.len(), 2);
+        assert!(lens.contains(&(p1, 5)) && lens.contains(&(p1, 8)));
+    }
+    assert!(repo.is_loaded(p1));
+    assert!(!repo.is_loaded(p2));
+    
+    // Without a hint, all partitions are searched:
+    assert_eq!(repo.find(None, &is_two).expect("find").len(), 3);
+    assert!(repo.is_loaded(p2));
+}
+
+#[test]
+fn memory_budget() {
+    use pippin::fileio::RepoFileIO;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_memory_budget");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    for n in 1..4 {
+        let part_id = PartId::from_num(n);
+        rt.io.new_part(part_id, format!("pn{}", n)).expect("new part");
+        let part_io = rt.io.make_part_io(part_id).expect("make part io");
+        Partition::<Sequence>::create(part_io, "seq_budget", None, None).expect("create");
+    }
+    let parts: Vec<_> = (1..4).map(PartId::from_num).collect();
+    let classifier = rt.classifier_with_ranges(&[(parts[0], 0, 9), (parts[1], 10, 19),
+            (parts[2], 20, u32::MAX)]).expect("ranges");
+    rt.use_classifier(&classifier);
+    
+    let mut repo = Repository::open(rt).expect("open");
+    repo.load_latest(None).expect("load");
+    let mut state = repo.clone_state().expect("clone state");
+    let mut elts = vec![];
+    for len in 1..30 {
+        let v = Arithmetic{ start: len as R, step: 1.0 }.generate(len);
+        elts.push((state.insert(Sequence::from(v.clone())).expect("insert"), v));
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write");
+    let max_usage = repo.partitions().map(|p| p.approx_memory_usage()).max().expect("max");
+    assert!(repo.partitions().all(|p| p.approx_memory_usage() > 0));
+    
+    // Room for only one partition at a time:
+    repo.set_memory_budget(max_usage);
+    let usage = |repo: &Repository<_, _>| repo.partitions().map(|p| p.approx_memory_usage()).sum::<usize>();
+    let num_loaded = |repo: &Repository<_, _>| parts.iter().filter(|p| repo.is_loaded(**p)).count();
+    repo.load_latest(None).expect("load");
+    assert_eq!(num_loaded(&repo), 1);
+    assert!(usage(&repo) <= max_usage);
+    
+    for _ in 0..2 {
+        for &(id, ref v) in &elts {
+            let elt = repo.get_element(id).expect("get element");
+            assert_eq!(elt.v, *v);
+            assert!(repo.is_loaded(id.part_id()));
+            assert_eq!(num_loaded(&repo), 1);
+            assert!(usage(&repo) <= max_usage);
+        }
+    }
+    
+    // Without a budget, partitions stay loaded:
+    repo.set_memory_budget(0);
+    repo.load_latest(None).expect("load");
+    assert_eq!(num_loaded(&repo), 3);
+}
+
+#[test]
+fn compact_all() {
+    use pippin::CompactPolicy;
+    use pippin::fileio::RepoFileIO;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_compact_all");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    for n in 1..4 {
+        let part_id = PartId::from_num(n);
+        rt.io.new_part(part_id, format!("pn{}", n)).expect("new part");
+        let part_io = rt.io.make_part_io(part_id).expect("make part io");
+        Partition::<Sequence>::create(part_io, "seq_compact", None, None).expect("create");
+    }
+    let parts: Vec<_> = (1..4).map(PartId::from_num).collect();
+    let classifier = rt.classifier_with_ranges(&[(parts[0], 0, 9), (parts[1], 10, 19),
+            (parts[2], 20, u32::MAX)]).expect("ranges");
+    rt.use_classifier(&classifier);
+    
+    let mut repo = Repository::open(rt).expect("open");
+    repo.load_latest(None).expect("load");
+    let mut state = repo.clone_state().expect("clone state");
+    for len in 1..30 {
+        state.insert(Arithmetic{ start: len as R, step: 1.0 }.generate(len).into()).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_fast().expect("write");
+    
+    // Add to the first partition only:
+    let mut state = repo.clone_state().expect("clone state");
+    for i in 0..100 {
+        let len = 1 + i % 9;
+        state.insert(Arithmetic{ start: i as R, step: 0.5 }.generate(len).into()).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_fast().expect("write");
+    
+    let stats = repo.stats().expect("stats");
+    let logs: Vec<u64> = stats.parts.iter().map(|p| p.files.cl_bytes).collect();
+    assert!(logs[0] > logs[1] && logs[0] > logs[2]);
+    let num_elts = stats.num_elts;
+    
+    // Only the first partition exceeds the threshold:
+    let policy = CompactPolicy { min_log_bytes: max(logs[1], logs[2]) };
+    let report = repo.compact_all(policy).expect("compact");
+    assert_eq!(report.compacted, vec![parts[0]]);
+    assert_eq!(report.bytes_reclaimed, stats.parts[0].files.total_bytes());
+    let stats = repo.stats().expect("stats");
+    assert_eq!((stats.parts[0].files.num_ss, stats.parts[0].files.num_cl), (1, 0));
+    assert_eq!(stats.parts[1].files.cl_bytes, logs[1]);
+    assert_eq!(stats.parts[2].files.cl_bytes, logs[2]);
+    
+    // Nothing left to compact; the compacted partition still loads:
+    assert!(repo.compact_all(policy).expect("compact").compacted.is_empty());
+    assert!(repo.unload_all(false));
+    repo.load_latest(None).expect("load");
+    assert_eq!(repo.stats().expect("stats").num_elts, num_elts);
+}
+
+#[test]
+fn auto_divide() {
+    use pippin::fileio::RepoFileIO;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_auto_divide");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    rt.set_divide_sample(999, Some(1));
+    let mut repo = Repository::create(rt, "seq_auto_divide", None).expect("create");
+    repo.set_auto_divide_threshold(15);
+    
+    // Below the threshold nothing happens:
+    let mut state = repo.clone_state().expect("clone state");
+    for len in 0..15 {
+        state.insert(Sequence::from(vec![1.0; len])).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write");
+    assert_eq!(repo.partitions().count(), 1);
+    
+    let mut state = repo.clone_state().expect("clone state");
+    for len in 15..20 {
+        state.insert(Sequence::from(vec![1.0; len])).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write");
+    assert_eq!(repo.partitions().count(), 3);
+    
+    // Elements were moved from the old partition to the two new ones:
+    let p1 = PartId::from_num(1);
+    let mut total = 0;
+    for part in repo.partitions() {
+        let num = part.num_avail().expect("num_avail");
+        if part.part_id() == p1 {
+            assert_eq!(num, 0);
+        } else {
+            assert!(num > 0);
+        }
+        total += num;
+    }
+    assert_eq!(total, 20);
+    
+    // New elements go to the new partitions:
+    let mut state = repo.clone_state().expect("clone state");
+    let id = state.insert(Sequence::from(vec![1.0; 3])).expect("insert");
+    assert!(id.part_id() != p1);
+}
+
+#[test]
+fn retired_partition() {
+    use pippin::fileio::RepoFileIO;
+    use pippin::discover;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_retired");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    rt.set_divide_sample(999, Some(1));
+    let mut repo = Repository::create(rt, "seq_retired", None).expect("create");
+    repo.set_auto_divide_threshold(10);
+    let mut state = repo.clone_state().expect("clone state");
+    for len in 0..20 {
+        state.insert(Sequence::from(vec![1.0; len])).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write");
+    assert_eq!(repo.partitions().count(), 3);
+    
+    // After reload, p1 is known to be retired:
+    let p1 = PartId::from_num(1);
+    let io = discover::repo_from_path(tmp_dir.to_path_buf()).expect("discover");
+    let mut repo = Repository::open(SeqRepo::new(io)).expect("reopen");
+    repo.load_latest(None).expect("load");
+    let active = repo.classifier().active_parts();
+    assert_eq!(active.len(), 2);
+    assert!(!active.contains(&p1));
+    assert!(!repo.classifier().parts_for_lens(0, 1000).contains(&p1));
+    
+    let mut state = repo.clone_state().expect("clone state");
+    for len in &[0, 1, 5, 10, 19, 100] {
+        let id = state.insert(Sequence::from(vec![1.0; *len])).expect("insert");
+        assert!(id.part_id() != p1);
+    }
+    
+    // Data written before the flag existed marks retirement by max = min:
+    let mut buf = write_ud_v1(p1, &PartInfo {
+        max_part_id: PartId::from_num(9), ver: 1, min_len: 0, max_len: 100, retired: true });
+    assert_eq!(&buf[0..4], b"SCPR");
+    assert!(SeqRepo::<RepoFileIO>::read_ud(&buf).expect("read_ud").1.retired);
+    buf[0..4].copy_from_slice(b"SCPI");
+    assert!(!SeqRepo::<RepoFileIO>::read_ud(&buf).expect("read_ud").1.retired);
+    LittleEndian::write_u32(&mut buf[12..], 0);
+    assert!(SeqRepo::<RepoFileIO>::read_ud(&buf).expect("read_ud").1.retired);
+}
+
+#[test]
+fn reclaim_part_ids() {
+    use pippin::fileio::RepoFileIO;
+    use pippin::discover;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_reclaim");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    rt.set_divide_sample(999, Some(1));
+    let p = PartId::from_num;
+    for n in &[1, 3, 4] {
+        rt.io.new_part(p(*n), format!("pn{}", n)).expect("new part");
+        let part_io = rt.io.make_part_io(p(*n)).expect("make part io");
+        Partition::<Sequence>::create(part_io, "seq_reclaim", None, None).expect("create");
+    }
+    // Partition 1 may only use identifier 2; 3 is retired and empty:
+    let info = |max, min_len, max_len, retired| PartInfo {
+        max_part_id: p(max), ver: 0, min_len: min_len, max_len: max_len, retired: retired };
+    rt.parts.insert(p(1), info(2, 0, 9, false));
+    rt.parts.insert(p(3), info(3, 10, 19, true));
+    rt.parts.insert(p(4), info(PartId::max_num(), 10, u32::MAX, false));
+    
+    let mut repo = Repository::open(rt).expect("open");
+    repo.load_latest(None).expect("load");
+    repo.set_auto_divide_threshold(5);
+    let mut state = repo.clone_state().expect("clone state");
+    for i in 0..12 {
+        let id = state.insert(Sequence::from(vec![1.0; 1 + i % 9])).expect("insert");
+        assert_eq!(id.part_id(), p(1));
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    
+    // Partition 1 cannot be divided without a second identifier:
+    repo.write_full().expect("write");
+    assert_eq!(repo.partitions().count(), 3);
+    
+    assert_eq!(repo.reclaim_part_ids().expect("reclaim"), vec![p(3)]);
+    assert!(!repo.part_alloc().is_allocated(p(3)));
+    assert_eq!(repo.partitions().count(), 2);
+    assert!(repo.reclaim_part_ids().expect("reclaim").is_empty());
+    
+    // Once reopened, the freed identifier is used by a division:
+    let io = discover::repo_from_path(tmp_dir.to_path_buf()).expect("discover");
+    let mut rt = SeqRepo::new(io);
+    rt.set_divide_sample(999, Some(1));
+    let mut repo = Repository::open(rt).expect("reopen");
+    repo.load_latest(None).expect("load");
+    repo.set_auto_divide_threshold(5);
+    repo.write_full().expect("write");
+    let mut ids: Vec<_> = repo.partitions().map(|part| part.part_id()).collect();
+    ids.sort();
+    assert_eq!(ids, vec![p(1), p(2), p(3), p(4)]);
+    assert_eq!(repo.iter_elements().count(), 12);
+}
+
+#[test]
+fn reclaim_keeps_moves() {
+    use pippin::fileio::RepoFileIO;
+    use pippin::discover;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_reclaim_moves");
+    let mut rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    rt.set_divide_sample(999, Some(1));
+    let mut repo = Repository::create(rt, "seq_reclaim", None).expect("create");
+    repo.set_auto_divide_threshold(10);
+    let mut state = repo.clone_state().expect("clone state");
+    let mut ids = vec![];
+    for len in 0..20 {
+        ids.push(state.insert(Sequence::from(vec![1.0; len])).expect("insert"));
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write");
+    
+    // Partition 1 is retired by division, leaving only move records:
+    let p1 = PartId::from_num(1);
+    assert_eq!(repo.partitions().count(), 3);
+    assert!(ids.iter().all(|id| id.part_id() == p1));
+    {
+        let tip = repo.partitions().find(|part| part.part_id() == p1)
+                .expect("partition 1").tip().expect("tip");
+        assert_eq!(tip.num_avail(), 0);
+        assert_eq!(tip.moved_iter().count(), 20);
+    }
+    
+    // It is not released, thus old identifiers still resolve:
+    assert!(repo.reclaim_part_ids().expect("reclaim").is_empty());
+    assert!(repo.part_alloc().is_allocated(p1));
+    let io = discover::repo_from_path(tmp_dir.to_path_buf()).expect("discover");
+    let mut repo = Repository::open(SeqRepo::new(io)).expect("reopen");
+    for (len, id) in ids.into_iter().enumerate() {
+        assert_eq!(*repo.get_element(id).expect("get element"), Sequence::from(vec![1.0; len]));
+    }
+}
+
+#[test]
+fn verify_and_repair() {
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use pippin::fileio::RepoFileIO;
+    use pippin::discover;
+    
+    let tmp_dir = ::util::mk_temp_dir("seq_verify");
+    let rt = SeqRepo::new(RepoFileIO::new(tmp_dir.to_path_buf()));
+    let mut repo = Repository::create(rt, "seq_verify", None).expect("create");
+    for n in 0..2 {
+        let mut state = repo.clone_state().expect("clone state");
+        for len in 1..10 {
+            state.insert(Arithmetic{ start: n as R, step: 1.0 }.generate(len).into()).expect("insert");
+        }
+        repo.merge_in(state, None).expect("merge_in");
+        repo.write_full().expect("write");
+    }
+    let reports = repo.verify().expect("verify");
+    assert_eq!(reports.len(), 1);
+    assert!(reports[0].is_ok());
+    let files_ok = reports[0].files_ok;
+    
+    // Damage the checksum at the end of the last commit log:
+    let mut logs: Vec<_> = fs::read_dir(&tmp_dir).expect("read dir")
+        .map(|entry| entry.expect("entry").path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "piplog"))
+        .collect();
+    logs.sort();
+    let log = logs.pop().expect("log file");
+    let mut file = OpenOptions::new().write(true).open(&log).expect("open log");
+    file.seek(SeekFrom::End(-1)).expect("seek");
+    file.write_all(&[0xFF]).expect("write");
+    drop(file);
+    
+    let io = discover::repo_from_path(tmp_dir.to_path_buf()).expect("discover");
+    let mut repo = Repository::open(SeqRepo::new(io)).expect("open");
+    let reports = repo.verify().expect("verify");
+    assert!(!reports[0].is_ok());
+    assert_eq!(reports[0].files_ok, files_ok - 1);
+    assert_eq!(reports[0].damaged.len(), 1);
+    assert!(reports[0].damaged[0].1.is_some());
+    
+    let repaired = repo.repair().expect("repair");
+    assert_eq!(repaired.len(), 1);
+    assert_eq!(repaired[0].1.quarantined.len(), 1);
+    assert!(repo.verify().expect("verify").iter().all(|r| r.is_ok()));
+    assert!(repo.repair().expect("repair").is_empty());
+    
+    let io = discover::repo_from_path(tmp_dir.to_path_buf()).expect("discover");
+    let mut repo = Repository::open(SeqRepo::new(io)).expect("open");
+    repo.load_latest(None).expect("load");
+    assert!(repo.iter_elements().count() >= 9);
 }
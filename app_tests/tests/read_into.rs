@@ -0,0 +1,65 @@
+// Subject to the ISC licence (LICENSE-ISC.txt).
+
+//! Check that `ElementT::read_into()` reuses an element's allocation.
+//! 
+//! This is a separate test binary since it replaces the global allocator;
+//! keep it to a single test so that no other test allocates concurrently.
+
+extern crate pippin;
+extern crate pippin_app_tests;
+
+use std::alloc::{GlobalAlloc, System, Layout};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use pippin::ElementT;
+use pippin_app_tests::seq::Sequence;
+
+
+// Counts allocations (including reallocations)
+struct CountingAlloc;
+
+static ALLOCS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        ALLOCS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAlloc = CountingAlloc;
+
+#[test]
+fn read_into_reuses_allocation() {
+    let bufs: Vec<Vec<u8>> = (0..20).map(|i| {
+        let seq = Sequence::from(vec![i as f64; 50 - i]);
+        let mut buf = Vec::new();
+        seq.write_buf(&mut &mut buf).expect("write_buf");
+        buf
+    }).collect();
+    
+    // The first read allocates space for the longest sequence:
+    let mut target = Sequence::from(vec![]);
+    Sequence::read_into(&bufs[0], &mut target).expect("read_into");
+    assert_eq!(target.len(), 50);
+    
+    let before = ALLOCS.load(Ordering::SeqCst);
+    for (i, buf) in bufs.iter().enumerate() {
+        Sequence::read_into(buf, &mut target).expect("read_into");
+        assert_eq!(target.len(), 50 - i);
+    }
+    assert_eq!(ALLOCS.load(Ordering::SeqCst), before);
+    
+    assert_eq!(target, Sequence::read_buf(&bufs[19]).expect("read_buf"));
+    
+    // Errors are reported as by read_buf:
+    assert!(Sequence::read_into(&bufs[0][0..12], &mut target).is_err());
+}
@@ -0,0 +1,1188 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Test Pippin operations on multi-partition repositories
+
+extern crate pippin;
+extern crate pippin_app_tests;
+
+use std::result;
+use std::any::Any;
+use std::io::{self, Read, Write, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::fs;
+use std::ptr;
+use std::collections::{HashMap, HashSet};
+use std::rc::Rc;
+use std::cell::Cell;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use pippin::{PartId, Partition, PartIO, PartState, UserFields, UserData, StateT, MutStateT, Repository};
+use pippin::{RepoIO, RepoT, ClassifierT, PartIdAlloc};
+use pippin::repo::{RepoDivideError, Tier, median_key};
+use pippin::fileio::RepoFileIO;
+use pippin::discover;
+use pippin::error::{Result, Cancelled, PartErrors, OtherError, AlreadyExists, make_io_err};
+use pippin_app_tests::util;
+
+
+/// Classifies strings by their first letter: those starting with a vowel go in
+/// partition 1, everything else in partition 2.
+#[derive(Clone)]
+struct VowelClassifier;
+impl ClassifierT for VowelClassifier {
+    type Element = String;
+    fn classify(&self, elt: &String) -> Option<PartId> {
+        let vowel = elt.chars().next().map_or(false, |c| "aeiou".contains(c));
+        Some(PartId::from_num(if vowel { 1 } else { 2 }))
+    }
+    fn key(&self, elt: &String) -> Option<u64> {
+        Some(elt.len() as u64)
+    }
+}
+
+/// Fixed two-partition `RepoT`.
+struct TwoPartRepo {
+    io: Box<RepoIO>,
+}
+impl UserFields for TwoPartRepo {
+    fn write_user_fields(&mut self, _: PartId, _: bool) -> Vec<UserData> {
+        vec![]
+    }
+    fn read_user_fields(&mut self, _: Vec<UserData>, _: PartId, _: bool) {}
+}
+impl RepoT<VowelClassifier> for TwoPartRepo {
+    fn io(&mut self) -> &mut RepoIO {
+        &mut *self.io
+    }
+    fn clone_classifier(&self) -> VowelClassifier {
+        VowelClassifier
+    }
+    fn init_first(&mut self) -> Result<PartId> {
+        Ok(PartId::from_num(1))
+    }
+    fn divide(&mut self, _: &Partition<String>) ->
+        result::Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>
+    {
+        Err(RepoDivideError::NotSubdivisible)
+    }
+}
+
+/// As `TwoPartRepo`, but sets `cancel` whenever user fields are read (i.e.
+/// while a partition is being loaded).
+struct CancellingRepo {
+    io: Box<RepoIO>,
+    cancel: Arc<AtomicBool>,
+}
+impl UserFields for CancellingRepo {
+    fn write_user_fields(&mut self, _: PartId, _: bool) -> Vec<UserData> {
+        vec![]
+    }
+    fn read_user_fields(&mut self, _: Vec<UserData>, _: PartId, _: bool) {
+        self.cancel.store(true, Ordering::SeqCst);
+    }
+}
+impl RepoT<VowelClassifier> for CancellingRepo {
+    fn io(&mut self) -> &mut RepoIO {
+        &mut *self.io
+    }
+    fn clone_classifier(&self) -> VowelClassifier {
+        VowelClassifier
+    }
+    fn init_first(&mut self) -> Result<PartId> {
+        Ok(PartId::from_num(1))
+    }
+    fn divide(&mut self, _: &Partition<String>) ->
+        result::Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>
+    {
+        Err(RepoDivideError::NotSubdivisible)
+    }
+}
+
+/// As `VowelClassifier`, but `should_divide()` returns `allow`.
+#[derive(Clone)]
+struct VetoClassifier {
+    allow: bool,
+}
+impl ClassifierT for VetoClassifier {
+    type Element = String;
+    fn classify(&self, elt: &String) -> Option<PartId> {
+        VowelClassifier.classify(elt)
+    }
+    fn should_divide(&self, _: &PartState<String>) -> bool {
+        self.allow
+    }
+}
+
+/// Asks for every partition to be divided, counting calls to `divide()`
+/// (which always fails with `NotSubdivisible`).
+struct DividingRepo {
+    io: Box<RepoIO>,
+    allow: bool,
+    divides: Rc<Cell<usize>>,
+}
+impl UserFields for DividingRepo {
+    fn write_user_fields(&mut self, _: PartId, _: bool) -> Vec<UserData> {
+        vec![]
+    }
+    fn read_user_fields(&mut self, _: Vec<UserData>, _: PartId, _: bool) {}
+}
+impl RepoT<VetoClassifier> for DividingRepo {
+    fn io(&mut self) -> &mut RepoIO {
+        &mut *self.io
+    }
+    fn clone_classifier(&self) -> VetoClassifier {
+        VetoClassifier { allow: self.allow }
+    }
+    fn init_first(&mut self) -> Result<PartId> {
+        Ok(PartId::from_num(1))
+    }
+    fn should_divide(&mut self, _: PartId, _: &Partition<String>) -> bool {
+        true
+    }
+    fn divide(&mut self, _: &Partition<String>) ->
+        result::Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>
+    {
+        self.divides.set(self.divides.get() + 1);
+        Err(RepoDivideError::NotSubdivisible)
+    }
+}
+
+/// Divides partition 1 (only) up to `divides_left` times, each time creating
+/// one new partition with the lowest free identifier.
+struct AllocRepo {
+    io: Box<RepoIO>,
+    alloc: PartIdAlloc,
+    divides_left: usize,
+}
+impl UserFields for AllocRepo {
+    fn write_user_fields(&mut self, _: PartId, _: bool) -> Vec<UserData> {
+        vec![]
+    }
+    fn read_user_fields(&mut self, _: Vec<UserData>, _: PartId, _: bool) {}
+}
+impl RepoT<VetoClassifier> for AllocRepo {
+    fn io(&mut self) -> &mut RepoIO {
+        &mut *self.io
+    }
+    fn clone_classifier(&self) -> VetoClassifier {
+        VetoClassifier { allow: true }
+    }
+    fn init_first(&mut self) -> Result<PartId> {
+        Ok(PartId::from_num(1))
+    }
+    fn should_divide(&mut self, part_id: PartId, _: &Partition<String>) -> bool {
+        part_id == PartId::from_num(1) && self.divides_left > 0
+    }
+    fn set_part_alloc(&mut self, alloc: &PartIdAlloc) {
+        self.alloc = alloc.clone();
+    }
+    fn divide(&mut self, _: &Partition<String>) ->
+        result::Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>
+    {
+        self.divides_left -= 1;
+        let id = self.alloc.next_free(PartId::from_num(1)).expect("free id");
+        Ok((vec![id], vec![]))
+    }
+}
+
+/// Wraps a `RepoFileIO`; creation of log files for partition `fail` fails.
+struct FailingRepoIO {
+    inner: RepoFileIO,
+    fail: PartId,
+}
+impl RepoIO for FailingRepoIO {
+    fn as_any(&self) -> &Any { self }
+    fn num_parts(&self) -> usize { self.inner.num_parts() }
+    fn parts(&self) -> Vec<PartId> { self.inner.parts() }
+    fn has_part(&self, pn: PartId) -> bool { self.inner.has_part(pn) }
+    fn new_part(&mut self, num: PartId, prefix: String) -> Result<()> {
+        self.inner.new_part(num, prefix)
+    }
+    fn make_part_io(&self, num: PartId) -> Result<Box<PartIO>> {
+        let io = self.inner.make_part_io(num)?;
+        Ok(if num == self.fail { Box::new(FailingPartIO { inner: io }) } else { io })
+    }
+    fn write_journal(&mut self, data: &[u8]) -> Result<()> { self.inner.write_journal(data) }
+    fn read_journal(&self) -> Result<Option<Vec<u8>>> { self.inner.read_journal() }
+    fn clear_journal(&mut self) -> Result<()> { self.inner.clear_journal() }
+}
+struct FailingPartIO {
+    inner: Box<PartIO>,
+}
+impl PartIO for FailingPartIO {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.inner.part_id() }
+    fn ss_len(&self) -> usize { self.inner.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.inner.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.inner.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.inner.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.inner.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.inner.new_ss(ss_num)
+    }
+    fn finish_ss(&mut self, ss_num: usize) -> Result<()> { self.inner.finish_ss(ss_num) }
+    fn discard_ss(&mut self, ss_num: usize) -> Result<()> { self.inner.discard_ss(ss_num) }
+    fn append_ss_cl<'a>(&'a mut self, _: usize, _: usize) -> Result<Option<Box<Write+'a>>> {
+        make_io_err(ErrorKind::Other, "simulated failure")
+    }
+    fn new_ss_cl<'a>(&'a mut self, _: usize, _: usize) -> Result<Option<Box<Write+'a>>> {
+        make_io_err(ErrorKind::Other, "simulated failure")
+    }
+}
+
+/// Wraps a `PartIO`; writing more than `limit` bytes to a snapshot fails, as
+/// when the disk is full.
+struct ShortWritePartIO {
+    inner: Box<PartIO>,
+    limit: usize,
+}
+struct ShortWriter<'a> {
+    inner: Box<Write+'a>,
+    remaining: usize,
+}
+impl<'a> Write for ShortWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if buf.len() > self.remaining {
+            return Err(io::Error::new(ErrorKind::Other, "simulated disk full"));
+        }
+        self.remaining -= buf.len();
+        self.inner.write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> { self.inner.flush() }
+}
+impl PartIO for ShortWritePartIO {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.inner.part_id() }
+    fn ss_len(&self) -> usize { self.inner.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.inner.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.inner.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.inner.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.inner.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        let limit = self.limit;
+        Ok(self.inner.new_ss(ss_num)?.map(|w|
+                Box::new(ShortWriter { inner: w, remaining: limit }) as Box<Write+'a>))
+    }
+    fn finish_ss(&mut self, ss_num: usize) -> Result<()> { self.inner.finish_ss(ss_num) }
+    fn discard_ss(&mut self, ss_num: usize) -> Result<()> { self.inner.discard_ss(ss_num) }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.inner.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.inner.new_ss_cl(ss_num, cl_num)
+    }
+}
+
+/// Wraps a `RepoFileIO`, counting calls to `flush()`.
+struct FlushCountingIO {
+    inner: RepoFileIO,
+    flushes: Rc<Cell<usize>>,
+}
+impl RepoIO for FlushCountingIO {
+    fn as_any(&self) -> &Any { self }
+    fn num_parts(&self) -> usize { self.inner.num_parts() }
+    fn parts(&self) -> Vec<PartId> { self.inner.parts() }
+    fn has_part(&self, pn: PartId) -> bool { self.inner.has_part(pn) }
+    fn new_part(&mut self, num: PartId, prefix: String) -> Result<()> {
+        self.inner.new_part(num, prefix)
+    }
+    fn make_part_io(&self, num: PartId) -> Result<Box<PartIO>> { self.inner.make_part_io(num) }
+    fn flush(&mut self) -> Result<()> {
+        self.flushes.set(self.flushes.get() + 1);
+        self.inner.flush()
+    }
+}
+
+/// Create partitions 1 and 2 under `dir`.
+fn make_parts(dir: &Path, name: &str) -> RepoFileIO {
+    init_parts(RepoFileIO::new(dir), name)
+}
+
+/// Create partitions 1 and 2 in `io`.
+fn init_parts(mut io: RepoFileIO, name: &str) -> RepoFileIO {
+    for num in 1..3 {
+        let part_id = PartId::from_num(num);
+        io.new_part(part_id, format!("pn{}", num)).expect("new part");
+        let part_io = io.make_part_io(part_id).expect("make part io");
+        Partition::<String>::create(part_io, name, None, None)
+                .expect("create partition");
+    }
+    io
+}
+
+/// Create partitions 1 and 2 under `dir`, then open and load the repository.
+fn make_repo(dir: &Path, name: &str) -> Repository<VowelClassifier, TwoPartRepo> {
+    let io = make_parts(dir, name);
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    repo.load_latest(None).expect("load");
+    repo
+}
+
+
+// —————  tests  —————
+#[test]
+fn iter_elements() {
+    let tmp_dir = util::mk_temp_dir("repo_iter_elements");
+    let mut repo = make_repo(tmp_dir.as_ref(), "iter_elements");
+    
+    let words = ["apple", "egg", "orange", "banana", "cherry", "damson", "fig"];
+    let mut state = repo.clone_state().expect("clone state");
+    let mut ids = HashSet::new();
+    for word in &words {
+        ids.insert(state.insert(word.to_string()).expect("insert"));
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    
+    let mut seen = HashSet::new();
+    for (part_id, id, elt) in repo.iter_elements() {
+        assert_eq!(part_id, id.part_id());
+        assert_eq!(Some(part_id), VowelClassifier.classify(elt));
+        assert!(seen.insert(id), "element visited twice");
+    }
+    assert_eq!(seen, ids);
+    assert_eq!(repo.iter_elements().filter(|x| x.0 == PartId::from_num(1)).count(), 3);
+}
+
+#[test]
+fn rename() {
+    let tmp_dir = util::mk_temp_dir("repo_rename");
+    {
+        let mut repo = make_repo(tmp_dir.as_ref(), "old name");
+        let mut state = repo.clone_state().expect("clone state");
+        for word in &["ant", "bee", "eel", "yak"] {
+            state.insert(word.to_string()).expect("insert");
+        }
+        repo.merge_in(state, None).expect("merge_in");
+        repo.write_full().expect("write");
+        
+        assert!(repo.rename("a new name which is too long").is_err());
+        repo.rename("new name").expect("rename");
+        assert_eq!(repo.name(), "new name");
+    }
+    
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    assert_eq!(repo.name(), "new name");
+    repo.load_latest(None).expect("load");
+    assert_eq!(repo.iter_elements().count(), 4);
+    for part in repo.partitions_mut() {
+        assert_eq!(part.get_repo_name().expect("get name"), "new name");
+        // History from before the rename remains readable:
+        part.load_all(None, None).expect("load all");
+        assert!(part.verify().expect("verify").is_ok());
+    }
+    assert_eq!(repo.iter_elements().count(), 4);
+}
+
+#[test]
+fn rename_interrupted() {
+    let tmp_dir = util::mk_temp_dir("repo_rename_interrupted");
+    {
+        let mut repo = make_repo(tmp_dir.as_ref(), "old name");
+        let mut state = repo.clone_state().expect("clone state");
+        for word in &["ant", "bee", "eel", "yak"] {
+            state.insert(word.to_string()).expect("insert");
+        }
+        repo.merge_in(state, None).expect("merge_in");
+        repo.write_full().expect("write");
+        
+        // Rename only the first partition, as if interrupted:
+        for part in repo.partitions_mut() {
+            if part.part_id() == PartId::from_num(1) {
+                part.rename("new name", None).expect("rename partition");
+            }
+        }
+    }
+    
+    {
+        let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+        let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+        assert_eq!(repo.name(), "new name");
+        repo.load_latest(None).expect("load");
+        assert_eq!(repo.iter_elements().count(), 4);
+        repo.rename("new name").expect("complete rename");
+    }
+    
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    assert_eq!(repo.name(), "new name");
+    for part in repo.partitions_mut() {
+        assert_eq!(part.get_repo_name().expect("get name"), "new name");
+        part.load_all(None, None).expect("load all");
+        assert!(part.verify().expect("verify").is_ok());
+    }
+    assert_eq!(repo.iter_elements().count(), 4);
+}
+
+#[test]
+fn write_atomic_recovery() {
+    let tmp_dir = util::mk_temp_dir("repo_write_atomic");
+    let words = ["ant", "bee", "eel", "yak", "owl"];
+    {
+        let io = make_parts(tmp_dir.as_ref(), "write_atomic");
+        let io = FailingRepoIO { inner: io, fail: PartId::from_num(2) };
+        let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+        repo.load_latest(None).expect("load");
+        let mut state = repo.clone_state().expect("clone state");
+        for word in &words {
+            state.insert(word.to_string()).expect("insert");
+        }
+        repo.merge_in(state, None).expect("merge_in");
+        
+        // Partition 1 may or may not get written, but partition 2 cannot be:
+        assert!(repo.write_atomic().is_err());
+    }
+    assert!(RepoFileIO::new(tmp_dir.as_ref()).read_journal().expect("read journal").is_some());
+    
+    // Opening read-only leaves the journal alone:
+    let mut io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    io.set_readonly(true);
+    Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open read-only");
+    assert!(RepoFileIO::new(tmp_dir.as_ref()).read_journal().expect("read journal").is_some());
+    
+    // Opening the repository completes the write:
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    assert!(RepoFileIO::new(tmp_dir.as_ref()).read_journal().expect("read journal").is_none());
+    
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    repo.load_latest(None).expect("load");
+    let found: HashSet<_> = repo.iter_elements().map(|(_, _, elt)| elt.clone()).collect();
+    assert_eq!(found, words.iter().map(|w| w.to_string()).collect());
+}
+
+#[test]
+fn datasets() {
+    let tmp_dir = util::mk_temp_dir("repo_datasets");
+    let mut io = RepoFileIO::new(tmp_dir.as_ref());
+    assert!(io.set_dataset(Some("not-valid".to_string())).is_err());
+    
+    // Two datasets using the same partition numbers in one directory:
+    let datasets = [("alpha", ["apple", "bear"]), ("beta", ["igloo", "yew"])];
+    for &(dataset, ref words) in &datasets {
+        let mut io = RepoFileIO::new(tmp_dir.as_ref());
+        io.set_dataset(Some(dataset.to_string())).expect("set dataset");
+        let io = init_parts(io, dataset);
+        let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+        repo.load_latest(None).expect("load");
+        let mut state = repo.clone_state().expect("clone state");
+        for word in words {
+            state.insert(word.to_string()).expect("insert");
+        }
+        repo.merge_in(state, None).expect("merge_in");
+        repo.write_full().expect("write");
+    }
+    
+    for &(dataset, ref words) in &datasets {
+        let io = discover::dataset_from_path(tmp_dir.as_ref(), dataset).expect("discover");
+        assert_eq!(io.dataset(), Some(dataset));
+        assert_eq!(io.num_parts(), 2);
+        let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+        assert_eq!(repo.name(), dataset);
+        repo.load_latest(None).expect("load");
+        let found: HashSet<_> = repo.iter_elements().map(|(_, _, elt)| elt.clone()).collect();
+        assert_eq!(found, words.iter().map(|w| w.to_string()).collect());
+    }
+    // Without a filter the datasets cannot be told apart
+    assert!(discover::repo_from_path(tmp_dir.as_ref()).is_err());
+    
+    // Custom prefixes which look like datasets are not treated as such
+    let custom_dir = util::mk_temp_dir("repo_custom_prefixes");
+    let mut io = RepoFileIO::new(custom_dir.as_ref());
+    for &(num, prefix) in &[(1, "a-pn1"), (2, "b-pn2")] {
+        let part_id = PartId::from_num(num);
+        io.new_part(part_id, prefix.to_string()).expect("new part");
+        let part_io = io.make_part_io(part_id).expect("make part io");
+        Partition::<String>::create(part_io, "custom", None, None)
+                .expect("create partition");
+    }
+    let io = discover::repo_from_path(custom_dir.as_ref()).expect("discover");
+    assert_eq!(io.dataset(), None);
+    assert_eq!(io.num_parts(), 2);
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    repo.load_latest(None).expect("load");
+}
+
+#[test]
+fn move_element() {
+    let tmp_dir = util::mk_temp_dir("repo_move_element");
+    let mut repo = make_repo(tmp_dir.as_ref(), "move_element");
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    
+    let mut state = repo.clone_state().expect("clone state");
+    let id = state.insert("apple".to_string()).expect("insert");
+    repo.merge_in(state, None).expect("merge_in");
+    assert_eq!(id.part_id(), p1);
+    
+    // Same partition: no change
+    assert_eq!(repo.move_element(id, p1, p1).expect("move to same"), id);
+    // Wrong source partition
+    assert!(repo.move_element(id, p2, p1).is_err());
+    
+    let new_id = repo.move_element(id, p1, p2).expect("move");
+    assert_eq!(new_id.part_id(), p2);
+    let state = repo.clone_state().expect("clone state");
+    assert_eq!(state.get(new_id).expect("get moved"), "apple");
+    assert!(!state.is_avail(id));
+    for part in repo.partitions() {
+        let tip = part.tip().expect("tip");
+        if part.part_id() == p1 {
+            assert_eq!(tip.num_avail(), 0);
+            assert_eq!(tip.is_moved(id), Some(new_id));
+        } else {
+            assert_eq!(tip.num_avail(), 1);
+        }
+    }
+    
+    // Source no longer has the element
+    assert!(repo.move_element(id, p1, p2).is_err());
+}
+
+#[test]
+fn get_moved_element() {
+    let tmp_dir = util::mk_temp_dir("repo_get_moved_element");
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let (apple, egg, moved) = {
+        let mut repo = make_repo(tmp_dir.as_ref(), "get_moved");
+        let mut state = repo.clone_state().expect("clone state");
+        let apple = state.insert("apple".to_string()).expect("insert");
+        let egg = state.insert("egg".to_string()).expect("insert");
+        repo.merge_in(state, None).expect("merge_in");
+        let moved = repo.move_element(egg, p1, p2).expect("move");
+        repo.write_fast().expect("write");
+        (apple, egg, moved)
+    };
+    
+    let open = || {
+        let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+        Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo")
+    };
+    // Each identifier is routed to its partition, loading no other:
+    let mut repo = open();
+    assert_eq!(*repo.get_element(apple).expect("get element"), "apple");
+    assert!(repo.is_loaded(p1) && !repo.is_loaded(p2));
+    let mut repo = open();
+    assert_eq!(*repo.get_element(moved).expect("get element"), "egg");
+    assert!(!repo.is_loaded(p1) && repo.is_loaded(p2));
+    
+    // The old identifier leads to the new partition:
+    let mut repo = open();
+    assert_eq!(*repo.get_element(egg).expect("get element"), "egg");
+    assert!(repo.is_loaded(p1) && repo.is_loaded(p2));
+    assert!(repo.get_element(p1.elt_id(12345)).is_err());
+    
+    // An element moved back passes through its first partition twice:
+    let back = repo.move_element(moved, p2, p1).expect("move back");
+    assert_eq!(back.part_id(), p1);
+    assert_eq!(*repo.get_element(egg).expect("get element"), "egg");
+}
+
+#[test]
+fn merge_required_partitions() {
+    let tmp_dir = util::mk_temp_dir("repo_merge_required_partitions");
+    let mut repo = make_repo(tmp_dir.as_ref(), "merge_required");
+    let p2 = PartId::from_num(2);
+    assert!(repo.merge_required_partitions().is_empty());
+    
+    // Make two independent commits on partition 2 only:
+    for part in repo.partitions_mut() {
+        if part.part_id() != p2 { continue; }
+        let mut s1 = part.tip().expect("tip").clone_mut();
+        let mut s2 = part.tip().expect("tip").clone_mut();
+        s1.insert("banana".to_string()).expect("insert");
+        s2.insert("cherry".to_string()).expect("insert");
+        part.push_state(s1, None).expect("push");
+        part.push_state(s2, None).expect("push");
+    }
+    assert!(repo.merge_required());
+    assert_eq!(repo.merge_required_partitions(), vec![p2]);
+}
+
+#[test]
+fn checkpoint() {
+    let tmp_dir = util::mk_temp_dir("repo_checkpoint");
+    let mut repo = make_repo(tmp_dir.as_ref(), "checkpoint");
+    let mut state = repo.clone_state().expect("clone state");
+    for word in &["ant", "bee", "eel", "yak"] {
+        state.insert(word.to_string()).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    
+    let checkpoint = repo.checkpoint().expect("checkpoint");
+    let manifest = &checkpoint.manifest;
+    assert_eq!(manifest.name, "checkpoint");
+    assert_eq!(manifest.parts.len(), 2);
+    for mpart in &manifest.parts {
+        let part = repo.partitions().find(|p| p.part_id() == mpart.part_id).expect("partition");
+        assert_eq!(mpart.tips, vec![part.tip_key().expect("tip").clone()]);
+    }
+    
+    // Reloading from the snapshots gives the same tips:
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    repo.load_latest(None).expect("load");
+    for mpart in &manifest.parts {
+        let part = repo.partitions().find(|p| p.part_id() == mpart.part_id).expect("partition");
+        assert_eq!(mpart.tips, vec![part.tip_key().expect("tip").clone()]);
+    }
+}
+
+#[test]
+fn load_on_demand() {
+    let tmp_dir = util::mk_temp_dir("repo_load_on_demand");
+    let mut io = make_parts(tmp_dir.as_ref(), "on_demand");
+    let (p1, p2, p3) = (PartId::from_num(1), PartId::from_num(2), PartId::from_num(3));
+    io.new_part(p3, "pn3".to_string()).expect("new part");
+    let part_io = io.make_part_io(p3).expect("make part io");
+    Partition::<String>::create(part_io, "on_demand", None, None)
+            .expect("create partition");
+    let id = {
+        let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+        repo.load_latest(None).expect("load");
+        let mut state = repo.clone_state().expect("clone state");
+        let id = state.insert("yak".to_string()).expect("insert");
+        repo.merge_in(state, None).expect("merge_in");
+        repo.write_fast().expect("write");
+        id
+    };
+    assert_eq!(id.part_id(), p2);
+    
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    assert_eq!(repo.partitions().count(), 3);
+    assert!(!repo.is_loaded(p1) && !repo.is_loaded(p2) && !repo.is_loaded(p3));
+    
+    assert_eq!(*repo.get_element(id).expect("get element"), "yak");
+    assert!(!repo.is_loaded(p1));
+    assert!(repo.is_loaded(p2));
+    assert!(!repo.is_loaded(p3));
+    
+    // There is no partition 4:
+    let p4 = PartId::from_num(4);
+    assert!(!repo.is_loaded(p4));
+    assert!(repo.get_element(p4.elt_id(1)).is_err());
+    assert!(repo.load_part(p4, None).is_err());
+    
+    let state = repo.clone_part_state(p3).expect("clone part state");
+    assert_eq!(state.num_avail(), 0);
+    assert!(repo.is_loaded(p3));
+    assert!(!repo.is_loaded(p1));
+}
+
+#[test]
+fn classifier_divide_veto() {
+    let tmp_dir = util::mk_temp_dir("repo_divide_veto");
+    let io = make_parts(tmp_dir.as_ref(), "divide_veto");
+    let divides = Rc::new(Cell::new(0));
+    let rt = DividingRepo { io: Box::new(io), allow: false, divides: divides.clone() };
+    let mut repo = Repository::open(rt).expect("open repo");
+    repo.load_latest(None).expect("load");
+    repo.write_full().expect("write_full");
+    assert_eq!(divides.get(), 0);
+    
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let rt = DividingRepo { io: Box::new(io), allow: true, divides: divides.clone() };
+    let mut repo = Repository::open(rt).expect("open repo");
+    repo.load_latest(None).expect("load");
+    repo.write_full().expect("write_full");
+    assert_eq!(divides.get(), 2);
+}
+
+#[test]
+fn flush_after_write() {
+    let tmp_dir = util::mk_temp_dir("repo_flush");
+    let flushes = Rc::new(Cell::new(0));
+    let io = FlushCountingIO { inner: make_parts(tmp_dir.as_ref(), "flush"), flushes: flushes.clone() };
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    repo.load_latest(None).expect("load");
+    let mut state = repo.clone_state().expect("clone state");
+    state.insert("owl".to_string()).expect("insert");
+    repo.merge_in(state, None).expect("merge_in");
+    assert_eq!(flushes.get(), 0);
+    
+    repo.write_fast().expect("write_fast");
+    assert_eq!(flushes.get(), 1);
+    repo.write_snapshot_all().expect("write_snapshot_all");
+    assert_eq!(flushes.get(), 2);
+}
+
+#[test]
+fn cancel_load() {
+    let tmp_dir = util::mk_temp_dir("repo_cancel_load");
+    let io = make_parts(tmp_dir.as_ref(), "cancel_load");
+    let cancel = Arc::new(AtomicBool::new(false));
+    let rt = CancellingRepo { io: Box::new(io), cancel: cancel.clone() };
+    let mut repo = Repository::open(rt).expect("open repo");
+    repo.set_cancel_flag(Some(cancel.clone()));
+    
+    // The flag is set while loading the first partition; the second is skipped
+    let e = repo.load_latest(None).unwrap_err();
+    assert!(e.downcast_ref::<Cancelled>().is_some());
+    let loaded: Vec<_> = repo.partitions().filter(|p| p.is_loaded()).collect();
+    assert_eq!(loaded.len(), 1);
+    assert!(loaded[0].is_ready());
+    assert_eq!(loaded[0].tip().expect("tip").num_avail(), 0);
+    
+    // Writing is also cancelled
+    assert!(repo.write_fast().is_err());
+    
+    repo.set_cancel_flag(None);
+    repo.load_latest(None).expect("load");
+    assert!(repo.partitions().all(|p| p.is_ready()));
+}
+
+#[test]
+fn part_alloc() {
+    let tmp_dir = util::mk_temp_dir("repo_part_alloc");
+    let p = |n| PartId::from_num(n);
+    let part_files = |n: u64| fs::read_dir(&tmp_dir).expect("read dir")
+        .filter(|entry| entry.as_ref().expect("entry").file_name()
+            .to_string_lossy().starts_with(&format!("pn{}-", n)))
+        .count();
+    {
+        let rt = AllocRepo {
+            io: Box::new(RepoFileIO::new(tmp_dir.as_ref())),
+            alloc: PartIdAlloc::new(),
+            divides_left: 3,
+        };
+        let mut repo = Repository::create(rt, "part_alloc", None).expect("create");
+        for _ in 0..3 {
+            repo.write_full().expect("write_full");
+        }
+        assert_eq!(repo.part_alloc().ranges(), vec![(p(1), p(4))]);
+        assert_eq!(repo.part_alloc().next_free(p(1)), Some(p(5)));
+        
+        assert!(part_files(3) > 0);
+        assert!(repo.delete_partition(p(7)).is_err());
+        repo.delete_partition(p(3)).expect("delete partition");
+        assert!(!repo.part_alloc().is_allocated(p(3)));
+        assert_eq!(part_files(3), 0);
+    }
+    
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let rt = AllocRepo { io: Box::new(io), alloc: PartIdAlloc::new(), divides_left: 1 };
+    let mut repo = Repository::open(rt).expect("open repo");
+    repo.load_latest(None).expect("load");
+    assert_eq!(repo.part_alloc().ranges(), vec![(p(1), p(2)), (p(4), p(4))]);
+    
+    // The freed identifier is reused:
+    repo.write_full().expect("write_full");
+    assert!(repo.part_alloc().is_allocated(p(3)));
+    assert_eq!(repo.partitions().count(), 4);
+}
+
+#[test]
+fn stats() {
+    let tmp_dir = util::mk_temp_dir("repo_stats");
+    let mut repo = make_repo(tmp_dir.as_ref(), "stats");
+    let mut state = repo.clone_state().expect("clone state");
+    for word in &["ant", "bee", "eel", "owl", "yak"] {
+        state.insert(word.to_string()).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_full().expect("write_full");
+    
+    let stats = repo.stats().expect("stats");
+    assert_eq!(stats.num_parts, 2);
+    assert_eq!(stats.num_ready, 2);
+    assert_eq!(stats.num_elts, 5);
+    assert_eq!(stats.parts[0].num_elts, Some(3));
+    assert_eq!(stats.parts[1].num_elts, Some(2));
+    assert_eq!(stats.num_merge_required, 0);
+    let file_bytes = fs::read_dir(&tmp_dir).expect("read dir")
+        .map(|entry| entry.expect("entry").path())
+        .filter(|path| path.extension().map_or(false, |ext| ext == "pip" || ext == "piplog"))
+        .map(|path| fs::metadata(path).expect("metadata").len())
+        .fold(0, |a, b| a + b);
+    assert!(file_bytes > 0);
+    assert_eq!(stats.total_bytes, file_bytes);
+    
+    // Two independent commits on partition 2 require a merge:
+    let p2 = PartId::from_num(2);
+    for part in repo.partitions_mut() {
+        if part.part_id() != p2 { continue; }
+        let mut s1 = part.tip().expect("tip").clone_mut();
+        let mut s2 = part.tip().expect("tip").clone_mut();
+        s1.insert("gnu".to_string()).expect("insert");
+        s2.insert("hen".to_string()).expect("insert");
+        part.push_state(s1, None).expect("push");
+        part.push_state(s2, None).expect("push");
+    }
+    let stats = repo.stats().expect("stats");
+    assert_eq!(stats.num_merge_required, 1);
+    assert_eq!(stats.num_ready, 1);
+    assert_eq!(stats.num_elts, 3);
+    assert_eq!(stats.parts[1].num_unsaved, 2);
+}
+
+#[test]
+fn replicate() {
+    let src_dir = util::mk_temp_dir("repo_replicate_src");
+    let dst_dir = util::mk_temp_dir("repo_replicate_dst");
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let mut src = make_repo(src_dir.as_ref(), "replicate");
+    let mut state = src.clone_state().expect("clone state");
+    for word in &["apple", "banana"] {
+        state.insert(word.to_string()).expect("insert");
+    }
+    src.merge_in(state, None).expect("merge_in");
+    src.write_full().expect("write_full");
+    
+    // The destination starts with a copy of partition 1 only:
+    for entry in fs::read_dir(&src_dir).expect("read dir") {
+        let entry = entry.expect("entry");
+        if entry.file_name().to_string_lossy().starts_with("pn1-") {
+            let dst_path: &Path = dst_dir.as_ref();
+            fs::copy(entry.path(), dst_path.join(entry.file_name())).expect("copy");
+        }
+    }
+    let io = discover::repo_from_path(dst_dir.as_ref()).expect("discover");
+    let mut dst = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    dst.load_latest(None).expect("load");
+    assert_eq!(dst.partitions().count(), 1);
+    
+    let mut state = src.clone_state().expect("clone state");
+    for word in &["egg", "cherry"] {
+        state.insert(word.to_string()).expect("insert");
+    }
+    src.merge_in(state, None).expect("merge_in");
+    
+    let sums: HashMap<_, _> = dst.partitions()
+        .map(|part| (part.part_id(), part.tip_key().expect("tip").clone()))
+        .collect();
+    let mut buf = Vec::new();
+    src.export_since(sums, &mut buf).expect("export");
+    assert!(!dst.import_stream(&mut &buf[..]).expect("import"));
+    // Importing again changes nothing:
+    assert!(!dst.import_stream(&mut &buf[..]).expect("import again"));
+    
+    assert!(dst.part_alloc().is_allocated(p2));
+    for part_id in &[p1, p2] {
+        let tip = |repo: &Repository<VowelClassifier, TwoPartRepo>| repo.partitions()
+            .find(|part| part.part_id() == *part_id).expect("partition")
+            .tip_key().expect("tip").clone();
+        assert_eq!(tip(&dst), tip(&src));
+    }
+    assert_eq!(dst.iter_elements().count(), 4);
+    
+    // A stream from another repository is rejected:
+    let other_dir = util::mk_temp_dir("repo_replicate_other");
+    let mut other = make_repo(other_dir.as_ref(), "other");
+    let mut buf = Vec::new();
+    other.export_since(HashMap::new(), &mut buf).expect("export");
+    assert!(dst.import_stream(&mut &buf[..]).is_err());
+}
+
+#[test]
+fn export_redacted() {
+    let tmp_dir = util::mk_temp_dir("repo_export_redacted");
+    let mut repo = make_repo(tmp_dir.as_ref(), "redacted");
+    let mut state = repo.clone_state().expect("clone state");
+    for word in &["apple", "banana", "egg", "cherry", "damson"] {
+        state.insert(word.to_string()).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    let tips: Vec<_> = repo.partitions().map(|p| p.tip_key().expect("tip").clone()).collect();
+    let contains = |buf: &[u8], word: &str| buf.windows(word.len()).any(|w| w == word.as_bytes());
+    
+    // Omit words starting with 'b' or 'c'; mask "damson":
+    let policy = |_, elt: &String| match elt.chars().next() {
+        Some('b') | Some('c') => None,
+        _ if elt == "damson" => Some("(redacted)".to_string()),
+        _ => Some(elt.clone()),
+    };
+    let mut buf = Vec::new();
+    repo.export_redacted(&policy, &mut buf).expect("export");
+    for word in &["apple", "egg", "(redacted)"] {
+        assert!(contains(&buf, word), "missing: {}", word);
+    }
+    for word in &["banana", "cherry", "damson"] {
+        assert!(!contains(&buf, word), "not redacted: {}", word);
+    }
+    
+    // Live data is unaffected:
+    assert_eq!(repo.iter_elements().count(), 5);
+    let after: Vec<_> = repo.partitions().map(|p| p.tip_key().expect("tip").clone()).collect();
+    assert_eq!(after, tips);
+    
+    // Without redaction, all elements are exported:
+    let mut buf = Vec::new();
+    repo.export_redacted(&|_, elt: &String| Some(elt.clone()), &mut buf).expect("export");
+    assert!(contains(&buf, "banana") && contains(&buf, "damson"));
+}
+
+#[test]
+fn create_existing() {
+    let tmp_dir = util::mk_temp_dir("repo_create_existing");
+    let new_rt = || TwoPartRepo { io: Box::new(RepoFileIO::new(tmp_dir.as_ref())) };
+    let num_files = || fs::read_dir(&tmp_dir).expect("read dir").count();
+    {
+        let mut repo = Repository::create(new_rt(), "existing", None).expect("create");
+        let mut state = repo.clone_state().expect("clone state");
+        state.insert("apple".to_string()).expect("insert");
+        repo.merge_in(state, None).expect("merge_in");
+        repo.write_full().expect("write_full");
+    }
+    let files = num_files();
+    
+    // Creating again fails without touching files, with or without a record
+    // of allocated partitions:
+    let e = Repository::create(new_rt(), "existing", None).err().expect("create fails");
+    assert!(e.downcast_ref::<AlreadyExists>().is_some());
+    let tmp_path: &Path = tmp_dir.as_ref();
+    fs::remove_file(tmp_path.join("partids.pipa")).expect("remove");
+    let e = Repository::create(new_rt(), "existing", None).err().expect("create fails");
+    assert!(e.downcast_ref::<AlreadyExists>().is_some());
+    assert_eq!(num_files(), files - 1);
+    let io = discover::repo_from_path(tmp_path).expect("discover");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open");
+    repo.load_latest(None).expect("load");
+    assert_eq!(repo.iter_elements().count(), 1);
+    
+    // Forcing creation replaces the old data:
+    let mut repo = Repository::create_force(new_rt(), "existing", None).expect("create_force");
+    assert_eq!(repo.iter_elements().count(), 0);
+    repo.write_full().expect("write_full");
+    let io = discover::repo_from_path(tmp_path).expect("discover");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open");
+    repo.load_latest(None).expect("load");
+    assert_eq!(repo.iter_elements().count(), 0);
+}
+
+#[test]
+fn tiers() {
+    let tmp_dir = util::mk_temp_dir("repo_tiers");
+    let p2 = PartId::from_num(2);
+    let part_files = |dir: &Path| fs::read_dir(dir).expect("read dir")
+        .filter(|entry| entry.as_ref().expect("entry").file_name()
+            .to_string_lossy().starts_with("pn2-"))
+        .count();
+    let cold_path = {
+        let dir: &Path = tmp_dir.as_ref();
+        dir.join("cold")
+    };
+    {
+        let mut io = make_parts(tmp_dir.as_ref(), "tiers");
+        io.set_cold_dir(Some(PathBuf::from("cold")));
+        let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+        repo.load_latest(None).expect("load");
+        let mut state = repo.clone_state().expect("clone state");
+        for word in &["apple", "banana"] {
+            state.insert(word.to_string()).expect("insert");
+        }
+        repo.merge_in(state, None).expect("merge_in");
+        repo.partitions_mut().find(|part| part.part_id() == p2).expect("p2")
+            .set_snapshot_dedup(true);
+        
+        repo.set_tier(p2, Tier::Cold).expect("set tier");
+        let part = repo.partitions().find(|part| part.part_id() == p2).expect("p2");
+        assert!(part.is_loaded());
+        assert!(part.config().snapshot_dedup);
+        let mut state = repo.clone_state().expect("clone state");
+        state.insert("cherry".to_string()).expect("insert");
+        repo.merge_in(state, None).expect("merge_in");
+        repo.write_full().expect("write_full");
+    }
+    assert_eq!(part_files(tmp_dir.as_ref()), 0);
+    assert!(part_files(&cold_path) >= 2);
+    
+    // Cold partitions are still discovered:
+    let mut io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    io.set_cold_dir(Some(PathBuf::from("cold")));
+    assert_eq!(io.tier(p2), Tier::Cold);
+    assert_eq!(io.tier(PartId::from_num(1)), Tier::Hot);
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    repo.load_latest(None).expect("load");
+    assert_eq!(repo.iter_elements().count(), 3);
+    assert_eq!(repo.iter_elements().filter(|x| x.0 == p2).count(), 2);
+}
+
+#[test]
+fn discover_dirs() {
+    let dir1 = util::mk_temp_dir("repo_discover_dirs1");
+    let dir2 = util::mk_temp_dir("repo_discover_dirs2");
+    let (path1, path2): (&Path, &Path) = (dir1.as_ref(), dir2.as_ref());
+    for &(num, path) in &[(1, path1), (2, path2)] {
+        let part_id = PartId::from_num(num);
+        let mut io = RepoFileIO::new(path);
+        io.new_part(part_id, format!("pn{}", num)).expect("new part");
+        let part_io = io.make_part_io(part_id).expect("make part io");
+        Partition::<String>::create(part_io, "discover_dirs", None, None).expect("create partition");
+    }
+    
+    let io = discover::repo_from_dirs(&[path1, path2]).expect("discover");
+    assert_eq!(io.num_parts(), 2);
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    repo.load_latest(None).expect("load");
+    let mut state = repo.clone_state().expect("clone state");
+    for word in &["apple", "banana"] {
+        state.insert(word.to_string()).expect("insert");
+    }
+    repo.merge_in(state, None).expect("merge_in");
+    repo.write_fast().expect("write_fast");
+    
+    // Files of the same partition in both directories are rejected:
+    fs::copy(path1.join("pn1-ss0.pip"), path2.join("pn1-ss0.pip")).expect("copy");
+    assert!(discover::repo_from_dirs(&[path1, path2]).is_err());
+}
+
+#[test]
+fn divide_median_key() {
+    let part_id = PartId::from_num(2);
+    let mut state = PartState::<String>::new(part_id, None).clone_mut();
+    let words = ["by", "cat", "frog", "horse", "kitten", "giraffe", "elephant", "crocodile"];
+    for word in &words {
+        state.insert(word.to_string()).expect("insert");
+    }
+    let state = PartState::from_mut(state, None);
+    
+    let split = median_key(&VowelClassifier, &state).expect("median");
+    let lower = state.elts_iter().filter(|&(_, elt)| (elt.len() as u64) < split).count();
+    assert_eq!(split, 6);
+    assert_eq!((lower, words.len() - lower), (4, 4));
+    
+    // Mostly equal keys: both halves are still non-empty
+    let mut state = state.clone_mut();
+    state.clear();
+    for word in &["ant", "bee", "cow", "dog", "eel", "yak", "zebra"] {
+        state.insert(word.to_string()).expect("insert");
+    }
+    let state = PartState::from_mut(state, None);
+    assert_eq!(median_key(&VowelClassifier, &state), Some(5));
+    
+    // No split is possible with a single distinct key
+    let mut state = state.clone_mut();
+    state.clear();
+    state.insert("owl".to_string()).expect("insert");
+    state.insert("emu".to_string()).expect("insert");
+    let state = PartState::from_mut(state, None);
+    assert_eq!(median_key(&VowelClassifier, &state), None);
+}
+
+#[test]
+fn for_each_partition() {
+    let tmp_dir = util::mk_temp_dir("repo_for_each_part");
+    let io = make_parts(tmp_dir.as_ref(), "for_each_part");
+    let mut repo = Repository::open(TwoPartRepo { io: Box::new(io) }).expect("open repo");
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    
+    // Partitions are loaded on demand; all are visited:
+    let mut visited = vec![];
+    repo.for_each_partition_mut(|part_id, part| {
+        visited.push(part_id);
+        part.write_snapshot(None)
+    }).expect("for_each_partition_mut");
+    assert_eq!(visited, vec![p1, p2]);
+    assert!(repo.is_loaded(p1) && repo.is_loaded(p2));
+    
+    // A failure is reported without stopping other partitions:
+    let mut visited = vec![];
+    let e = repo.for_each_partition_mut(|part_id, part| {
+        visited.push(part_id);
+        if part_id == p1 {
+            return OtherError::err("failed");
+        }
+        part.write_snapshot(None)
+    }).unwrap_err();
+    assert_eq!(visited, vec![p1, p2]);
+    let errors = &e.downcast_ref::<PartErrors>().expect("PartErrors").errors;
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].0, p1);
+}
+
+#[test]
+fn snapshot_write_failure() {
+    let tmp_dir = util::mk_temp_dir("repo_ss_write_fail");
+    let p1 = PartId::from_num(1);
+    let statesum = {
+        let io = make_parts(tmp_dir.as_ref(), "ss_write_fail");
+        let io = ShortWritePartIO { inner: io.make_part_io(p1).expect("make part io"), limit: 200 };
+        let mut part = Partition::<String>::open(Box::new(io)).expect("open");
+        part.load_latest(None, None).expect("load");
+        let mut state = part.tip().expect("tip").clone_mut();
+        for i in 0..50 {
+            state.insert(format!("element {}", i)).expect("insert");
+        }
+        part.push_state(state, None).expect("push state");
+        part.write_fast(None).expect("write log");
+        
+        // The snapshot is too large to be written:
+        assert!(part.write_snapshot(None).is_err());
+        part.tip().expect("tip").statesum().clone()
+    };
+    
+    // No partial snapshot remains:
+    for entry in fs::read_dir(&tmp_dir).expect("read dir") {
+        let name = entry.expect("dir entry").file_name().into_string().expect("file name");
+        assert!(!name.ends_with(".tmp") && !name.starts_with("pn1-ss1"), "found {}", name);
+    }
+    
+    // The previous snapshot and log are intact:
+    let io = discover::repo_from_path(tmp_dir.as_ref()).expect("discover");
+    let mut part = Partition::<String>::open(io.make_part_io(p1).expect("make part io"))
+            .expect("open");
+    part.load_latest(None, None).expect("load");
+    assert_eq!(*part.tip().expect("tip").statesum(), statesum);
+    assert_eq!(part.tip().expect("tip").num_avail(), 50);
+}
+
+#[test]
+fn discover_named() {
+    let tmp_dir = util::mk_temp_dir("repo_discover_named");
+    // Two repositories using the same partition numbers in one directory:
+    let mut io = RepoFileIO::new(tmp_dir.as_ref());
+    for &(name, prefix, num) in &[("alpha", "a", 1), ("beta", "b", 1), ("beta", "b", 2)] {
+        let part_id = PartId::from_num(num);
+        io.new_part(part_id, format!("{}-pn{}", prefix, num)).expect("new part");
+        let part_io = io.make_part_io(part_id).expect("make part io");
+        Partition::<String>::create(part_io, name, None, None).expect("create partition");
+        io = RepoFileIO::new(tmp_dir.as_ref());
+    }
+    
+    let io = discover::repo_named_from_path(tmp_dir.as_ref(), "beta").expect("discover");
+    assert_eq!(io.parts().into_iter().collect::<HashSet<_>>(),
+            [PartId::from_num(1), PartId::from_num(2)].iter().cloned().collect());
+    let part_io = io.make_part_io(PartId::from_num(1)).expect("make part io");
+    let mut part = Partition::<String>::open(part_io).expect("open");
+    assert_eq!(part.get_repo_name().expect("repo name"), "beta");
+    
+    let io = discover::repo_named_from_path(tmp_dir.as_ref(), "alpha").expect("discover");
+    assert_eq!(io.parts(), vec![PartId::from_num(1)]);
+    
+    let io = discover::repo_named_from_path(tmp_dir.as_ref(), "gamma").expect("discover");
+    assert_eq!(io.num_parts(), 0);
+}
+
+#[test]
+fn clone_shallow_files() {
+    let tmp_dir = util::mk_temp_dir("repo_clone_shallow");
+    let mut io = RepoFileIO::new(tmp_dir.as_ref());
+    let part_id = PartId::from_num(1);
+    io.new_part(part_id, "pn1".to_string()).expect("new part");
+    let part_io = io.make_part_io(part_id).expect("make part io");
+    let mut a = Partition::<String>::create(part_io, "clone_shallow", None, None)
+            .expect("create partition");
+    let mut b = a.clone_shallow().expect("clone_shallow");
+    // The loaded state is shared, not copied:
+    assert!(ptr::eq(a.tip().expect("tip"), b.tip().expect("tip")));
+    
+    // Logs written via one handle are found by the other:
+    let mut state = b.tip().expect("tip").clone_mut();
+    state.insert("apple".to_string()).expect("insert");
+    b.push_state(state, None).expect("push_state");
+    b.write_fast(None).expect("write_fast");
+    assert_eq!(a.catch_up(None, None).expect("catch_up"), 1);
+    assert_eq!(a.tip().expect("tip").num_avail(), 1);
+}
@@ -60,7 +60,7 @@ fn create() {
             state.insert_initial(rng.gen::<u32>() & 0xFF_FFFF, seq).expect("insert element");
         }
         repo.merge_in(state, Some(&meta_gen)).expect("merge");
-        repo.write_all(false).expect("write");
+        repo.write_full().expect("write");
     }
     
     // We do two types of check. Because our "random" number generator is
@@ -128,3 +128,20 @@ fn insert() {
             "1374C065686236F33C7A1B73B1CE7577678DDB81753C119E2B89063A961CF2A1");
     }
 }
+
+#[test]
+fn classifier_on_load() {
+    use pippin::{PartId, discover};
+    
+    let repo_dir = util::get_data_dir("seq_small");
+    let io = discover::repo_from_path(repo_dir.to_path_buf()).expect("discover")
+            .is_readonly(true);
+    let mut repo = Repository::open(SeqRepo::new(io)).expect("open");
+    repo.load_latest(None).expect("load");
+    
+    // Classification requires the classifier's lookup table to have been
+    // built from the partition info read while loading:
+    let mut state = repo.clone_state().expect("clone state");
+    let id = state.insert(Sequence::from(vec![1.0, 2.0, 3.0])).expect("insert element");
+    assert_eq!(id.part_id(), PartId::from_num(1));
+}
@@ -0,0 +1,211 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Minimal HTTP server exposing a repository for reading.
+//! 
+//! Usage: `server PATH [ADDRESS]`, where `PATH` is a directory containing a
+//! repository of string elements (as created by `pippincmd`) and `ADDRESS`
+//! defaults to `127.0.0.1:8000`. The repository is opened read-only and all
+//! partitions loaded on start-up. Requests supported (all `GET`):
+//! 
+//! *   `/partitions` lists partition identifiers, one per line
+//! *   `/elt/ID` returns the contents of element `ID` (or 404)
+//! *   `/stats` lists, for each partition, the number of elements and tips
+//! 
+//! Since a `Repository` is not `Send`, requests are served sequentially from a
+//! single thread. A server wishing to handle requests concurrently could give
+//! each worker a `RepoState` (see `Repository::clone_state()`).
+
+extern crate pippin;
+extern crate tiny_http;
+
+use std::env;
+use std::process::exit;
+use std::result;
+
+use tiny_http::{Server, Response, Method};
+use pippin::{Repository, RepoIO, RepoT, ClassifierT, UserFields, UserData};
+use pippin::{PartId, EltId, Partition, StateT, discover};
+use pippin::repo::RepoDivideError;
+use pippin::error::Result;
+
+/// Trivial classifier. The server does not insert elements, thus
+/// classification is never needed.
+#[derive(Clone)]
+struct ServerClassifier;
+impl ClassifierT for ServerClassifier {
+    type Element = String;
+    fn classify(&self, _: &String) -> Option<PartId> {
+        Some(PartId::from_num(1))
+    }
+}
+
+/// `RepoT` for the server: no user fields and no division.
+struct ServerRepo<IO: RepoIO> {
+    io: IO,
+}
+impl<IO: RepoIO> UserFields for ServerRepo<IO> {
+    fn write_user_fields(&mut self, _: PartId, _: bool) -> Vec<UserData> {
+        vec![]
+    }
+    fn read_user_fields(&mut self, _: Vec<UserData>, _: PartId, _: bool) {}
+}
+impl<IO: RepoIO> RepoT<ServerClassifier> for ServerRepo<IO> {
+    fn io(&mut self) -> &mut RepoIO {
+        &mut self.io
+    }
+    fn clone_classifier(&self) -> ServerClassifier {
+        ServerClassifier
+    }
+    fn init_first(&mut self) -> Result<PartId> {
+        Ok(PartId::from_num(1))
+    }
+    fn divide(&mut self, _: &Partition<String>) ->
+        result::Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>
+    {
+        Err(RepoDivideError::NotSubdivisible)
+    }
+}
+
+/// Handle a `GET` request for `url`, returning an HTTP status code and the
+/// response body.
+fn handle<R: RepoT<ServerClassifier>>(repo: &Repository<ServerClassifier, R>,
+        url: &str) -> (u16, String)
+{
+    let mut parts: Vec<&Partition<String>> = repo.partitions().collect();
+    parts.sort_by_key(|p| p.part_id());
+    
+    if url == "/partitions" {
+        let mut body = String::new();
+        for part in parts {
+            body.push_str(&format!("{}\n", part.part_id()));
+        }
+        (200, body)
+    } else if url == "/stats" {
+        let mut body = String::new();
+        let mut total = 0;
+        for part in parts {
+            match part.tip() {
+                Ok(tip) => {
+                    total += tip.num_avail();
+                    body.push_str(&format!("partition {}: {} elements, {} tips\n",
+                            part.part_id(), tip.num_avail(), part.tips_len()));
+                },
+                Err(e) => {
+                    body.push_str(&format!("partition {}: {}\n", part.part_id(), e));
+                },
+            }
+        }
+        body.push_str(&format!("total: {} elements\n", total));
+        (200, body)
+    } else if url.starts_with("/elt/") {
+        let id = match url[5..].parse::<u64>().ok().and_then(|n| EltId::try_from(n).ok()) {
+            Some(id) => id,
+            None => return (400, "invalid element identifier\n".to_string()),
+        };
+        let elt = parts.into_iter()
+                .find(|p| p.part_id() == id.part_id())
+                .and_then(|p| p.tip().ok())
+                .and_then(|tip| tip.get(id).ok());
+        match elt {
+            Some(elt) => (200, elt.clone()),
+            None => (404, format!("element {} not found\n", id)),
+        }
+    } else {
+        (404, "not found\n".to_string())
+    }
+}
+
+fn inner(path: &str, addr: &str) -> Result<()> {
+    let io = discover::repo_from_path(path)?.is_readonly(true);
+    let mut repo = Repository::open(ServerRepo { io: io })?;
+    repo.load_latest(None)?;
+    println!("Serving repository {} on http://{}/", repo.name(), addr);
+    
+    let server = match Server::http(addr) {
+        Ok(server) => server,
+        Err(e) => {
+            println!("Unable to start server: {}", e);
+            exit(1);
+        }
+    };
+    for request in server.incoming_requests() {
+        let (code, body) = if *request.method() == Method::Get {
+            handle(&repo, request.url())
+        } else {
+            (405, "only GET is supported\n".to_string())
+        };
+        let response = Response::from_string(body).with_status_code(code);
+        if let Err(e) = request.respond(response) {
+            println!("Error responding to request: {}", e);
+        }
+    }
+    Ok(())
+}
+
+fn main() {
+    let args: Vec<String> = env::args().collect();
+    if args.len() < 2 || args.len() > 3 {
+        println!("Usage: {} PATH [ADDRESS]", args[0]);
+        exit(1);
+    }
+    let addr = args.get(2).map_or("127.0.0.1:8000", |s| &s[..]);
+    match inner(&args[1], addr) {
+        Ok(()) => {},
+        Err(e) => {
+            println!("Error: {}", e);
+            exit(1);
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::any::Any;
+    use pippin::{PartIO, MutStateT};
+    use pippin::part::DummyPartIO;
+    use super::*;
+    
+    /// In-memory `RepoIO`. Partition data is held only by the `Partition`
+    /// objects (see `DummyPartIO`).
+    struct MemRepoIO {
+        parts: Vec<PartId>,
+    }
+    impl RepoIO for MemRepoIO {
+        fn as_any(&self) -> &Any { self }
+        fn num_parts(&self) -> usize { self.parts.len() }
+        fn parts(&self) -> Vec<PartId> { self.parts.clone() }
+        fn has_part(&self, pn: PartId) -> bool { self.parts.contains(&pn) }
+        fn new_part(&mut self, num: PartId, _: String) -> Result<()> {
+            self.parts.push(num);
+            Ok(())
+        }
+        fn make_part_io(&self, num: PartId) -> Result<Box<PartIO>> {
+            Ok(Box::new(DummyPartIO::new(num)))
+        }
+    }
+    
+    #[test]
+    fn endpoints() {
+        let rt = ServerRepo { io: MemRepoIO { parts: vec![] } };
+        let mut repo = Repository::create(rt, "server test", None).expect("create");
+        let mut state = repo.clone_state().expect("clone state");
+        let id = state.insert("hello".to_string()).expect("insert");
+        state.insert("world".to_string()).expect("insert");
+        repo.merge_in(state, None).expect("merge_in");
+        
+        assert_eq!(handle(&repo, "/partitions"), (200, "1\n".to_string()));
+        assert_eq!(handle(&repo, &format!("/elt/{}", id)), (200, "hello".to_string()));
+        let stats = handle(&repo, "/stats");
+        assert_eq!(stats.0, 200);
+        assert!(stats.1.contains("2 elements, 1 tips"));
+        
+        let missing: u64 = PartId::from_num(1).elt_id(12345).into();
+        assert_eq!(handle(&repo, &format!("/elt/{}", missing)).0, 404);
+        assert_eq!(handle(&repo, "/elt/x").0, 400);
+        assert_eq!(handle(&repo, "/elt/5").0, 400);
+        assert_eq!(handle(&repo, "/other").0, 404);
+    }
+}
@@ -0,0 +1,141 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Benchmark of write buffering: writes many tiny elements to an in-memory
+//! `PartIO`, with and without buffering, reporting the time taken and the
+//! number of write calls made on the `PartIO`'s writers. Snapshot writing is
+//! also timed with element checksums calculated on several threads.
+//! 
+//! Usage: `write_bench [NUM_ELEMENTS [SUM_THREADS]]` (defaults: 100000, 4)
+
+extern crate pippin;
+
+use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::cmp::min;
+use std::collections::BTreeMap;
+use std::env;
+use std::io::{self, Read, Write, Cursor};
+use std::rc::Rc;
+use std::time::Instant;
+
+use pippin::{PartId, PartIO, PartitionBuilder, Result, StateT, MutStateT};
+use pippin::part::DEFAULT_WRITE_BUF_SIZE;
+
+type Files = Rc<RefCell<BTreeMap<(usize, Option<usize>), Vec<u8>>>>;
+
+// In-memory `PartIO` counting the write calls made on its writers
+struct MemIO {
+    part_id: PartId,
+    files: Files,
+    writes: Rc<Cell<usize>>,
+}
+struct MemWriter {
+    files: Files,
+    key: (usize, Option<usize>),
+    writes: Rc<Cell<usize>>,
+}
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.writes.set(self.writes.get() + 1);
+        self.files.borrow_mut().get_mut(&self.key).expect("file").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+impl MemIO {
+    fn read<'a>(&self, key: (usize, Option<usize>)) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.files.borrow().get(&key)
+            .map(|data| Box::new(Cursor::new(data.clone())) as Box<Read+'a>))
+    }
+    fn write<'a>(&self, key: (usize, Option<usize>), new: bool) -> Result<Option<Box<Write+'a>>> {
+        let exists = self.files.borrow().contains_key(&key);
+        if exists == new {
+            return Ok(None);
+        }
+        self.files.borrow_mut().entry(key).or_insert_with(Vec::new);
+        Ok(Some(Box::new(MemWriter { files: self.files.clone(), key: key,
+                writes: self.writes.clone() })))
+    }
+}
+impl PartIO for MemIO {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.part_id }
+    fn ss_len(&self) -> usize {
+        self.files.borrow().keys().map(|&(ss, _)| ss + 1).max().unwrap_or(0)
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.files.borrow().keys()
+            .filter_map(|&(ss, cl)| if ss == ss_num { cl.map(|cl| cl + 1) } else { None })
+            .max().unwrap_or(0)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.files.borrow().contains_key(&(ss_num, None))
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read((ss_num, None))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read((ss_num, Some(cl_num)))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.write((ss_num, None), true)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.write((ss_num, Some(cl_num)), false)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.write((ss_num, Some(cl_num)), true)
+    }
+}
+
+// Write `num` elements, in commits of 100, to a log then a snapshot
+fn bench(num: usize, buf_size: usize, threads: usize) -> Result<()> {
+    let writes = Rc::new(Cell::new(0));
+    let io = MemIO {
+        part_id: PartId::from_num(1),
+        files: Rc::new(RefCell::new(BTreeMap::new())),
+        writes: writes.clone(),
+    };
+    let mut part = PartitionBuilder::new()
+            .write_buffer_size(buf_size)
+            .sum_threads(threads)
+            .create::<String>(Box::new(io), "write bench")?;
+    for i in 0..(num + 99) / 100 {
+        let mut state = part.tip()?.clone_mut();
+        for j in i * 100..min((i + 1) * 100, num) {
+            state.insert(format!("{}", j))?;
+        }
+        part.push_state(state, None)?;
+    }
+    
+    writes.set(0);
+    let start = Instant::now();
+    part.write_fast(None)?;
+    let log_time = start.elapsed();
+    let log_writes = writes.get();
+    
+    writes.set(0);
+    let start = Instant::now();
+    part.write_snapshot(None)?;
+    let ss_time = start.elapsed();
+    
+    println!("buffer {:>6} bytes, {} threads: log {:>4}.{:03}s, {:>7} writes; \
+        snapshot {:>4}.{:03}s, {:>7} writes",
+        buf_size, threads,
+        log_time.as_secs(), log_time.subsec_nanos() / 1_000_000, log_writes,
+        ss_time.as_secs(), ss_time.subsec_nanos() / 1_000_000, writes.get());
+    Ok(())
+}
+
+fn main() {
+    let num = env::args().nth(1).map_or(100_000, |n| n.parse().expect("NUM_ELEMENTS"));
+    let threads = env::args().nth(2).map_or(4, |n| n.parse().expect("SUM_THREADS"));
+    println!("Writing {} elements", num);
+    for &(size, threads) in &[(0, 1), (DEFAULT_WRITE_BUF_SIZE, 1), (DEFAULT_WRITE_BUF_SIZE, threads)] {
+        if let Err(e) = bench(num, size, threads) {
+            println!("Error: {}", e);
+        }
+    }
+}
@@ -0,0 +1,14 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Fuzz target for file header parsing: any input must give a header or an
+//! error, never a panic. Run with `cargo fuzz run parse_header`.
+
+#![no_main]
+#[macro_use] extern crate libfuzzer_sys;
+extern crate pippin;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = pippin::parse_header(data);
+});
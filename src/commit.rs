@@ -4,18 +4,20 @@
 
 //! Pippin: commit structs and functionality
 
-use std::collections::{HashMap, hash_map};
+use std::collections::{HashMap, BTreeMap, hash_map};
 use std::clone::Clone;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::u32;
 use std::cmp::max;
 use std::ops::BitOr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use chrono::{DateTime, NaiveDateTime, UTC};
+use byteorder::{ByteOrder, BigEndian};
 
 use {PartState, MutPartState, MutStateT};
 use {ElementT, EltId, Sum};
-use error::{Result, ElementOp, OtherError};
+use error::{Result, ElementOp, ArgError, OtherError};
 
 
 /// The type of the user-specified *extra* metadata field. This allows users
@@ -31,6 +33,15 @@ pub enum ExtraMeta {
     Text(String),
 }
 
+/// Maximum length of the extension data stored with commit metadata (this
+/// limits the size of commit tags; see `CommitMetaPartial::set_tag()`).
+pub const EXT_DATA_MAX: usize = 255 * 8;
+/// Maximum length of a branch name, in bytes (see `Partition::branch()`).
+pub const BRANCH_NAME_MAX: usize = 64;
+// Extension data record numbers
+const EXT_TAGS: u16 = 1;
+const EXT_BRANCH: u16 = 2;
+
 const FLAG_RECLASSIFY_BIT: u16 = 0b10;
 const FLAG_RECLASSIFY_MASK: u16 = 0b11;
 const FLAG_ESSENTIAL: u16 = 0b01010101_01010101;
@@ -92,7 +103,8 @@ impl BitOr<MetaFlags> for MetaFlags {
 ///     of ancestors leading back to the initial commit)
 /// *   A time-stamp (usually the UTC time of creation)
 /// 
-/// Additionally, users may attach information via the `ExtraMeta` struct.
+/// Additionally, users may attach information via the `ExtraMeta` struct
+/// and via key/value *tags* (see `CommitMetaPartial::set_tag()`).
 #[derive(Debug, PartialEq, Clone)]
 pub struct CommitMeta {
     /// Commit number. First (real) commit has number 1, each subsequent commit
@@ -111,12 +123,22 @@ pub struct CommitMeta {
     ext_flags: MetaFlags,
     /// User-provided extra metadata
     extra: ExtraMeta,
+    /// User-provided tags (stored in extension data; not inherited)
+    tags: BTreeMap<String, Vec<u8>>,
+    /// Name of the branch this commit was made on, if not the main line
+    /// (stored in extension data; not inherited)
+    branch: Option<String>,
+    /// Extension data records not understood by this version, as (record
+    /// number, data). These are preserved when the commit is rewritten.
+    ext_unknown: Vec<(u16, Vec<u8>)>,
 }
 /// Partial version of metadata (used by some functions on CommitMeta).
 #[derive(Debug, PartialEq, Clone)]
 pub struct CommitMetaPartial {
     number: u32,
     ext_flags: MetaFlags,
+    tags: BTreeMap<String, Vec<u8>>,
+    branch: Option<String>,
 }
 
 
@@ -131,17 +153,47 @@ impl CommitMeta {
             timestamp: timestamp,
             ext_flags: ext_flags,
             extra: mm.map_or(ExtraMeta::None, |mm| mm.make_extrameta(number, parents)),
+            tags: BTreeMap::new(),
+            branch: None,
+            ext_unknown: vec![],
         }
     }
     /// Create, explicitly providing all fields.
+    /// 
+    /// `ext_data` is the extension data as stored in files (see
+    /// `ext_data()`); this fails if it cannot be parsed.
     pub fn new_explicit(number: u32, timestamp: i64, ext_flags: MetaFlags,
-            _ext_data: Vec<u8>, extra: ExtraMeta) -> Result<Self, OtherError>
+            ext_data: Vec<u8>, extra: ExtraMeta) -> Result<Self, OtherError>
     {
         if (ext_flags.unknown_essential()) {
             return Err(OtherError::new("found essential unknown commit meta flag"));
         }
-        // ignore ext_data because we can and don't currently store anything there
-        Ok(CommitMeta { number: number, timestamp: timestamp, ext_flags: ext_flags, extra: extra })
+        let mut tags = BTreeMap::new();
+        let mut branch = None;
+        let mut ext_unknown = vec![];
+        let mut p = 0;
+        while p + 4 <= ext_data.len() {
+            let num = BigEndian::read_u16(&ext_data[p..p+2]);
+            let len = BigEndian::read_u16(&ext_data[p+2..p+4]) as usize;
+            if num == 0 && len == 0 {
+                break;  // padding
+            }
+            p += 4;
+            if p + len > ext_data.len() {
+                return Err(OtherError::new("commit meta extension record too long"));
+            }
+            if num == EXT_TAGS {
+                read_tags(&ext_data[p..p+len], &mut tags)?;
+            } else if num == EXT_BRANCH {
+                branch = Some(String::from_utf8(ext_data[p..p+len].to_vec())
+                        .map_err(|_| OtherError::new("invalid commit branch name"))?);
+            } else {
+                ext_unknown.push((num, ext_data[p..p+len].to_vec()));
+            }
+            p += len;
+        }
+        Ok(CommitMeta { number: number, timestamp: timestamp, ext_flags: ext_flags, extra: extra,
+                tags: tags, branch: branch, ext_unknown: ext_unknown })
     }
     /// Create a partial new version from a single parent.
     /// 
@@ -150,6 +202,8 @@ impl CommitMeta {
         CommitMetaPartial {
             number: par_meta.next_number(),
             ext_flags: par_meta.ext_flags,
+            tags: BTreeMap::new(),
+            branch: None,
         }
     }
     /// Create, from a partial version (assumes a single parent commit).
@@ -164,6 +218,9 @@ impl CommitMeta {
             timestamp: timestamp,
             ext_flags: partial.ext_flags,
             extra: mm.map_or(ExtraMeta::None, |mm| mm.make_extrameta(partial.number, parents)),
+            tags: partial.tags,
+            branch: partial.branch,
+            ext_unknown: vec![],
         }
     }
     
@@ -174,6 +231,18 @@ impl CommitMeta {
         UTC::now().timestamp()
     }
     
+    /// Convert a `SystemTime` to a timestamp as used by commits (see
+    /// `timestamp()`), rounding down to a whole second.
+    pub fn timestamp_of(time: SystemTime) -> i64 {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs() as i64,
+            Err(e) => {
+                let d = e.duration();
+                -(d.as_secs() as i64) - if d.subsec_nanos() > 0 { 1 } else { 0 }
+            },
+        }
+    }
+    
     /// Get the commit's timestamp
     pub fn timestamp(&self) -> i64 {
         self.timestamp
@@ -210,6 +279,36 @@ impl CommitMeta {
     pub fn extra(&self) -> &ExtraMeta {
         &self.extra
     }
+    
+    /// Get the commit's tags (see `CommitMetaPartial::set_tag()`).
+    pub fn tags(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.tags
+    }
+    
+    /// Get the name of the branch the commit was made on, or `None` for
+    /// the main line (see `Partition::branch()`).
+    pub fn branch(&self) -> Option<&str> {
+        self.branch.as_ref().map(|b| b.as_str())
+    }
+    
+    /// Get the extension data as stored in files: a sequence of records,
+    /// zero-padded to a multiple of eight bytes. This includes tags and any
+    /// records not understood by this version.
+    pub fn ext_data(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        if !self.tags.is_empty() {
+            push_record(&mut data, EXT_TAGS, &write_tags(&self.tags));
+        }
+        if let Some(ref branch) = self.branch {
+            push_record(&mut data, EXT_BRANCH, branch.as_bytes());
+        }
+        for &(num, ref record) in &self.ext_unknown {
+            push_record(&mut data, num, record);
+        }
+        let pad_len = 8 * ((data.len() + 7) / 8) - data.len();
+        data.extend_from_slice(&[0u8; 7][0..pad_len]);
+        data
+    }
 }
 
 impl CommitMetaPartial {
@@ -227,6 +326,99 @@ impl CommitMetaPartial {
     pub fn ext_flags_mut(&mut self) -> &mut MetaFlags {
         &mut self.ext_flags
     }
+    
+    /// Get the tags set on this commit
+    pub fn tags(&self) -> &BTreeMap<String, Vec<u8>> {
+        &self.tags
+    }
+    
+    /// Set a tag: a key/value pair stored with the commit (e.g. a request
+    /// identifier or the name of the source system). Tags are not inherited
+    /// by child commits.
+    /// 
+    /// Fails (without change) if the encoded tags would exceed
+    /// `EXT_DATA_MAX` bytes.
+    pub fn set_tag(&mut self, key: String, value: Vec<u8>) -> Result<()> {
+        let old = self.tags.insert(key.clone(), value);
+        let branch_len = self.branch.as_ref().map_or(0, |b| 4 + b.len());
+        if 4 + write_tags(&self.tags).len() + branch_len > EXT_DATA_MAX {
+            match old {
+                Some(old) => { self.tags.insert(key, old); },
+                None => { self.tags.remove(&key); },
+            }
+            return ArgError::err("commit tags too large");
+        }
+        Ok(())
+    }
+    
+    /// Remove a tag, returning its value if it was present
+    pub fn remove_tag(&mut self, key: &str) -> Option<Vec<u8>> {
+        self.tags.remove(key)
+    }
+    
+    /// Get the name of the branch the commit is to be made on
+    pub fn branch(&self) -> Option<&str> {
+        self.branch.as_ref().map(|b| b.as_str())
+    }
+    
+    /// Set the branch the commit is to be made on (`None` for the main
+    /// line). This is normally done by `Partition::push_state()` according
+    /// to the branch checked out.
+    /// 
+    /// Fails (without change) if the name is longer than `BRANCH_NAME_MAX`.
+    pub fn set_branch(&mut self, branch: Option<String>) -> Result<()> {
+        if branch.as_ref().map_or(false, |b| b.len() > BRANCH_NAME_MAX) {
+            return ArgError::err("branch name too long");
+        }
+        self.branch = branch;
+        Ok(())
+    }
+}
+
+// Append an extension data record
+fn push_record(data: &mut Vec<u8>, num: u16, record: &[u8]) {
+    assert!(record.len() <= 0xFFFF);
+    let mut buf = [0u8; 4];
+    BigEndian::write_u16(&mut buf[0..2], num);
+    BigEndian::write_u16(&mut buf[2..4], record.len() as u16);
+    data.extend_from_slice(&buf);
+    data.extend_from_slice(record);
+}
+
+// Encode tags: for each, a u16 key length, the key, a u32 value length and
+// the value
+fn write_tags(tags: &BTreeMap<String, Vec<u8>>) -> Vec<u8> {
+    let mut data = Vec::new();
+    let mut buf = [0u8; 4];
+    for (key, value) in tags {
+        BigEndian::write_u16(&mut buf[0..2], key.len() as u16);
+        data.extend_from_slice(&buf[0..2]);
+        data.extend_from_slice(key.as_bytes());
+        BigEndian::write_u32(&mut buf[0..4], value.len() as u32);
+        data.extend_from_slice(&buf[0..4]);
+        data.extend_from_slice(value);
+    }
+    data
+}
+
+// Decode tags (see `write_tags()`)
+fn read_tags(data: &[u8], tags: &mut BTreeMap<String, Vec<u8>>) -> Result<(), OtherError> {
+    let invalid = || OtherError::new("invalid commit tags");
+    let mut p = 0;
+    while p < data.len() {
+        if p + 2 > data.len() { return Err(invalid()); }
+        let key_len = BigEndian::read_u16(&data[p..p+2]) as usize;
+        p += 2;
+        if p + key_len + 4 > data.len() { return Err(invalid()); }
+        let key = String::from_utf8(data[p..p+key_len].to_vec()).map_err(|_| invalid())?;
+        p += key_len;
+        let value_len = BigEndian::read_u32(&data[p..p+4]) as usize;
+        p += 4;
+        if p + value_len > data.len() { return Err(invalid()); }
+        tags.insert(key, data[p..p+value_len].to_vec());
+        p += value_len;
+    }
+    Ok(())
 }
 
 /// Interface used to assign user-specific metadata.
@@ -256,6 +448,34 @@ pub trait MakeMeta {
     }
 }
 
+/// Source of commit timestamps, used where no `MakeMeta` is given (see
+/// `Partition::set_clock()`).
+/// 
+/// The default is `SystemClock`. Tests may supply a fixed or stepping clock
+/// to make timestamps reproducible; `Cell` can be used for interior
+/// mutability.
+pub trait Clock {
+    /// Get the time now, as a UNIX timestamp (see `CommitMeta::timestamp()`).
+    fn now(&self) -> i64;
+}
+
+/// A `Clock` using the system time (via `CommitMeta::timestamp_now()`).
+pub struct SystemClock;
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        CommitMeta::timestamp_now()
+    }
+}
+
+/// A `MakeMeta` taking timestamps from a `Clock`; extra metadata is left
+/// empty.
+pub struct ClockMeta<'a>(pub &'a Clock);
+impl<'a> MakeMeta for ClockMeta<'a> {
+    fn make_timestamp(&self) -> i64 {
+        self.0.now()
+    }
+}
+
 
 /// A commit: a set of changes.
 /// 
@@ -280,9 +500,9 @@ pub enum EltChange<E: ElementT> {
     /// Element was deleted
     Deletion,
     /// Element was added (full data)
-    Insertion(Rc<E>),
+    Insertion(Arc<E>),
     /// Element was replaced (full data)
-    Replacement(Rc<E>),
+    Replacement(Arc<E>),
     /// Element has been moved, must be removed from this partition; new identity mentioned
     MoveOut(EltId),
     /// Same as `MoveOut` except that the element has already been removed from the partition
@@ -290,11 +510,11 @@ pub enum EltChange<E: ElementT> {
 }
 impl<E: ElementT> EltChange<E> {
     /// Create an `Insertion`
-    pub fn insertion(elt: Rc<E>) -> EltChange<E> {
+    pub fn insertion(elt: Arc<E>) -> EltChange<E> {
         EltChange::Insertion(elt)
     }
     /// Create a `Replacement`
-    pub fn replacement(elt: Rc<E>) -> EltChange<E> {
+    pub fn replacement(elt: Arc<E>) -> EltChange<E> {
         EltChange::Replacement(elt)
     }
     /// Create a `Deletion`
@@ -312,7 +532,7 @@ impl<E: ElementT> EltChange<E> {
         }
     }
     /// Get `Some(elt)` if an element is contained, `None` otherwise
-    pub fn element(&self) -> Option<&Rc<E>> {
+    pub fn element(&self) -> Option<&Arc<E>> {
         match self {
             &EltChange::Deletion => None,
             &EltChange::Insertion(ref elt) => Some(elt),
@@ -408,6 +628,16 @@ impl<E: ElementT> Commit<E> {
         }
     }
     
+    /// Create the commit which yields `state` when applied to `parent`, which
+    /// must be the state's first parent. Unlike `from_diff()`, this keeps all
+    /// of the state's parents and always returns a commit (possibly without
+    /// changes, as for a merge).
+    pub fn from_state(parent: &PartState<E>, state: &PartState<E>) -> Commit<E> {
+        let changes = Commit::from_diff(parent, state).map_or_else(HashMap::new, |c| c.changes);
+        Commit::new_explicit(state.statesum().clone(), state.parents().to_vec(),
+                changes, state.meta().clone())
+    }
+    
     /// Apply this commit to a `MutPartState`. This does not verify the final
     /// statesum and does not use the metadata stored in this commit.
     /// 
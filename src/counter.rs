@@ -0,0 +1,177 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Counter element types
+//! 
+//! These are conflict-free: concurrent changes to a counter on two branches
+//! are combined by adding each branch's change relative to the common
+//! ancestor. Use `merge::EltMergeSolver2W` to merge states holding these.
+
+use std::io::Write;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use ElementT;
+use merge::EltMergeT;
+use error::{Result, ReadError};
+
+/// A grow-only counter.
+/// 
+/// Elements cannot be modified in place; use `incremented()` to make a new
+/// version and replace the old one with it.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct GCounter {
+    count: u64,
+}
+impl GCounter {
+    /// Create a new counter, with value zero
+    pub fn new() -> GCounter {
+        GCounter { count: 0 }
+    }
+    /// Get the counter's value
+    pub fn value(&self) -> u64 {
+        self.count
+    }
+    /// Get a copy of this counter with `n` added
+    pub fn incremented(&self, n: u64) -> GCounter {
+        GCounter { count: self.count.wrapping_add(n) }
+    }
+}
+impl ElementT for GCounter {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_u64::<BigEndian>(self.count)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        if buf.len() != 8 {
+            return ReadError::err("GCounter: expected 8 bytes", 0, (0, buf.len()));
+        }
+        Ok(GCounter { count: BigEndian::read_u64(buf) })
+    }
+}
+impl EltMergeT for GCounter {
+    fn merge3(a: &Self, b: &Self, c: Option<&Self>) -> Option<Self> {
+        let c = c.map_or(0, |c| c.count);
+        // Wrapping arithmetic gives the right result so long as the true
+        // value fits.
+        Some(GCounter { count: a.count.wrapping_add(b.count).wrapping_sub(c) })
+    }
+}
+
+/// A counter supporting both increments and decrements.
+/// 
+/// Increments and decrements are tracked separately, so that each is
+/// grow-only and merges as `GCounter` does.
+#[derive(PartialEq, Eq, Debug, Clone, Copy, Default)]
+pub struct PNCounter {
+    inc: u64,
+    dec: u64,
+}
+impl PNCounter {
+    /// Create a new counter, with value zero
+    pub fn new() -> PNCounter {
+        PNCounter { inc: 0, dec: 0 }
+    }
+    /// Get the counter's value
+    pub fn value(&self) -> i64 {
+        self.inc.wrapping_sub(self.dec) as i64
+    }
+    /// Get a copy of this counter with `n` added
+    pub fn incremented(&self, n: u64) -> PNCounter {
+        PNCounter { inc: self.inc.wrapping_add(n), dec: self.dec }
+    }
+    /// Get a copy of this counter with `n` subtracted
+    pub fn decremented(&self, n: u64) -> PNCounter {
+        PNCounter { inc: self.inc, dec: self.dec.wrapping_add(n) }
+    }
+}
+impl ElementT for PNCounter {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_u64::<BigEndian>(self.inc)?;
+        writer.write_u64::<BigEndian>(self.dec)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        if buf.len() != 16 {
+            return ReadError::err("PNCounter: expected 16 bytes", 0, (0, buf.len()));
+        }
+        Ok(PNCounter {
+            inc: BigEndian::read_u64(&buf[0..8]),
+            dec: BigEndian::read_u64(&buf[8..16]),
+        })
+    }
+}
+impl EltMergeT for PNCounter {
+    fn merge3(a: &Self, b: &Self, c: Option<&Self>) -> Option<Self> {
+        let (c_inc, c_dec) = c.map_or((0, 0), |c| (c.inc, c.dec));
+        Some(PNCounter {
+            inc: a.inc.wrapping_add(b.inc).wrapping_sub(c_inc),
+            dec: a.dec.wrapping_add(b.dec).wrapping_sub(c_dec),
+        })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use {PartId, Partition, StateT, MutStateT};
+    use part::DummyPartIO;
+    use merge::EltMergeSolver2W;
+    
+    #[test]
+    fn merge_branches() {
+        let p = PartId::from_num(1);
+        let (g_id, pn_id) = (p.elt_id(1), p.elt_id(2));
+        
+        let io = Box::new(DummyPartIO::new(p));
+        let mut part = Partition::<GCounter>::create(io, "counters", None, None).unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.insert_with_id(g_id, Arc::new(GCounter::new().incremented(5))).unwrap();
+        part.push_state(state, None).unwrap();
+        
+        // Two branches from the same parent:
+        let mut state_a = part.tip().unwrap().clone_mut();
+        let mut state_b = part.tip().unwrap().clone_mut();
+        let elt = state_a.get(g_id).unwrap().incremented(2);
+        state_a.replace(g_id, elt).unwrap();
+        let elt = state_b.get(g_id).unwrap().incremented(3);
+        state_b.replace(g_id, elt).unwrap();
+        part.push_state(state_a, None).unwrap();
+        part.push_state(state_b, None).unwrap();
+        assert!(part.merge_required());
+        
+        part.merge(&EltMergeSolver2W::new(), false, None).unwrap();
+        assert_eq!(part.tip().unwrap().get(g_id).unwrap().value(), 10);
+        
+        // Same again with a PNCounter:
+        let io = Box::new(DummyPartIO::new(p));
+        let mut part = Partition::<PNCounter>::create(io, "counters", None, None).unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.insert_with_id(pn_id, Arc::new(PNCounter::new().incremented(5))).unwrap();
+        part.push_state(state, None).unwrap();
+        
+        let mut state_a = part.tip().unwrap().clone_mut();
+        let mut state_b = part.tip().unwrap().clone_mut();
+        let elt = state_a.get(pn_id).unwrap().incremented(4);
+        state_a.replace(pn_id, elt).unwrap();
+        let elt = state_b.get(pn_id).unwrap().decremented(7);
+        state_b.replace(pn_id, elt).unwrap();
+        part.push_state(state_a, None).unwrap();
+        part.push_state(state_b, None).unwrap();
+        
+        part.merge(&EltMergeSolver2W::new(), false, None).unwrap();
+        assert_eq!(part.tip().unwrap().get(pn_id).unwrap().value(), 2);
+    }
+    
+    #[test]
+    fn read_write() {
+        let c = PNCounter::new().incremented(12).decremented(30);
+        let mut buf = Vec::new();
+        c.write_buf(&mut buf).unwrap();
+        assert_eq!(PNCounter::read_buf(&buf).unwrap(), c);
+        assert!(GCounter::read_buf(&buf).is_err());
+    }
+}
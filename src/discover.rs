@@ -6,15 +6,16 @@
 
 use std::path::Path;
 use std::fs::{read_dir, File};
+use std::collections::HashSet;
 use std::collections::hash_map::{HashMap, Entry};
 
 use regex::Regex;
 use walkdir::WalkDir;
 
-use {RepoIO, PartId};
-use fileio::{PartFileIO, RepoFileIO, PartPaths};
+use {RepoIO, PartIO, PartId};
+use fileio::{PartFileIO, RepoFileIO, PartPaths, valid_dataset_name};
 use readwrite::read_head;
-use error::{Result, PathError, OtherError};
+use error::{Result, PathError, OtherError, ArgError};
 
 
 /// Will attempt to discover files belonging to a single partition from a path.
@@ -144,6 +145,8 @@ pub fn part_from_path<P: AsRef<Path>>(path: P, opt_part_num: Option<PartId>) ->
             // PartFileIO does not expect '-' separator in prefix
             bname.pop();
         }
+        info!("Found {} snapshot(s) and {} log(s) for partition {}",
+            part_paths.num_ss_files(), part_paths.num_cl_files(), part_id);
         Ok(PartFileIO::new(part_id, dir.join(bname), part_paths))
     } else {
         Err(Box::new(if opt_part_num.is_some() {
@@ -164,11 +167,75 @@ pub fn part_from_path<P: AsRef<Path>>(path: P, opt_part_num: Option<PartId>) ->
 /// If it points to a file, this method will proceed as if
 /// it were run with the parent directory instead.
 /// 
+/// File name prefixes are not interpreted as datasets (they may come from
+/// `RepoT::suggest_part_prefix`); use `dataset_from_path` to open one of
+/// several datasets sharing a directory.
+/// 
 /// #0040: it would be nice to specify whether this should be recursive
 /// (max_depth) and whether it should follow links, but without adding extra
 /// required arguments (builder pattern like WalkDir?).
 pub fn repo_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
-    let path = path.as_ref();
+    repo_from_path_filtered(path.as_ref(), None, None)
+}
+
+/// Like `repo_from_path`, but only discovers partitions whose files belong
+/// to the repository called `name` (according to file headers). Files of
+/// other repositories sharing the directory are ignored.
+/// 
+/// The header of the first file found with each prefix is read; other files
+/// with the same prefix are assumed to belong to the same repository.
+pub fn repo_named_from_path<P: AsRef<Path>>(path: P, name: &str) -> Result<RepoFileIO> {
+    repo_from_path_filtered(path.as_ref(), None, Some(name))
+}
+
+/// Like `repo_from_path`, but discovers files in several directories (e.g.
+/// where partitions are spread over several devices; see also
+/// `RepoFileIO::set_cold_dir()`). Each directory is scanned as by
+/// `repo_from_path`, and the partitions found are combined.
+/// 
+/// The first directory is used as the top directory of the returned
+/// `RepoFileIO` (thus new partitions are created there). Fails if no
+/// directories are given or if files of one partition are found in more
+/// than one directory.
+pub fn repo_from_dirs(dirs: &[&Path]) -> Result<RepoFileIO> {
+    let (first, rest) = match dirs.split_first() {
+        Some(x) => x,
+        None => return ArgError::err("discover::repo_from_dirs: no directories given"),
+    };
+    let mut repo = repo_from_path_filtered(first, None, None)?;
+    for dir in rest {
+        let found = repo_from_path_filtered(dir, None, None)?;
+        for part in found.partitions() {
+            if repo.has_part(part.part_id()) {
+                return PathError::err("partition found in more than one directory", part.prefix());
+            }
+            repo.insert_part(part.clone());
+        }
+    }
+    info!("Found {} partitions in {} directories", repo.num_parts(), dirs.len());
+    Ok(repo)
+}
+
+/// Like `repo_from_path`, but only discovers files belonging to the given
+/// dataset, i.e. those whose file name starts with `DATASET-` (see
+/// `RepoFileIO::set_dataset()`). This allows multiple datasets to share a
+/// directory, even where partition numbers overlap.
+/// 
+/// The returned `RepoFileIO` has its dataset set, so that new partitions are
+/// created within the same dataset.
+pub fn dataset_from_path<P: AsRef<Path>>(path: P, dataset: &str) -> Result<RepoFileIO> {
+    if !valid_dataset_name(dataset) {
+        return ArgError::err("invalid dataset name");
+    }
+    let mut repo = repo_from_path_filtered(path.as_ref(), Some(dataset), None)?;
+    repo.set_dataset(Some(dataset.to_string()))?;
+    Ok(repo)
+}
+
+fn repo_from_path_filtered(path: &Path, dataset: Option<&str>, repo_name: Option<&str>)
+        -> Result<RepoFileIO>
+{
+    let dataset_prefix = dataset.map(|name| format!("{}-", name));
     let ss_pat = Regex::new("^((?:.*)-)?ss(0|[1-9][0-9]*)\\.pip$").expect("valid regex");
     let cl_pat = Regex::new("^((?:.*)-)?ss(0|[1-9][0-9]*)-cl(0|[1-9][0-9]*)\\.piplog$").expect("valid regex");
     enum Type { Snapshot(usize), Log(usize, usize) };
@@ -185,12 +252,21 @@ pub fn repo_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
     // #0039: do we need to store the prefix as a string?
     let mut prefixes = HashMap::<String, PartId>::new();
     let mut partitions = HashMap::<PartId, PartPaths>::new();
+    // prefixes of files belonging to other repositories (when filtering by name)
+    let mut foreign = HashSet::<String>::new();
+    let mut num_files = 0;
     
     for entry in WalkDir::new(dir) {
         let entry = entry?;
         // filter non-Pippin files
         match entry.file_name().to_str() {
-            Some(s) if s.ends_with(".pip") || s.ends_with(".piplog") => {}
+            Some(s) if s.ends_with(".pip") || s.ends_with(".piplog") => {
+                if let Some(ref prefix) = dataset_prefix {
+                    if !s.starts_with(prefix) {
+                        continue;   // different dataset (or none)
+                    }
+                }
+            }
             _ => { continue; }
         };
         
@@ -210,32 +286,42 @@ pub fn repo_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
             }
         };
         
-        let (pn, mut part_paths) = match prefixes.entry(prefix) {
+        if let Some(name) = repo_name {
+            if foreign.contains(&prefix) {
+                continue;
+            }
+            if !prefixes.contains_key(&prefix) {
+                let head = read_head(&mut File::open(&path)?)?;
+                if head.name != name {
+                    debug!("Ignoring files with prefix '{}' (repository: {})", prefix, head.name);
+                    foreign.insert(prefix);
+                    continue;
+                }
+            }
+        }
+        
+        let mut part_paths = match prefixes.entry(prefix) {
             Entry::Occupied(e) => {
-                let pn = e.get();
-                (*pn, partitions.get_mut(pn).expect("partitions has entry for pn"))
+                partitions.get_mut(e.get()).expect("partitions has entry for pn")
             },
             Entry::Vacant(e) => {
                 let fname = entry.file_name().to_str()
                     .ok_or_else(|| PathError::new("not valid UTF-8", path.to_path_buf()))?;
                 let pn = find_part_num(fname, &path)?;
                 e.insert(pn);
-                (pn, partitions.entry(pn).or_insert_with(|| PartPaths::new()))
+                partitions.entry(pn).or_insert_with(|| PartPaths::new())
             },
         };
         
-        match numbers {
-            Type::Snapshot(ss) => {
-                let has_prev = part_paths.insert_ss(ss, path);
-                assert!(!has_prev, "Multiple files for partition number {}, \
-                    snapshot {}", pn, ss);
-            },
-            Type::Log(ss, cl) => {
-                let has_prev = part_paths.insert_cl(ss, cl, path);
-                assert!(!has_prev, "Multiple files for partition number {}, \
-                    snapshot {}, log {}", pn, ss, cl);
-            }
+        let has_prev = match numbers {
+            Type::Snapshot(ss) => part_paths.insert_ss(ss, path.clone()),
+            Type::Log(ss, cl) => part_paths.insert_cl(ss, cl, path.clone()),
         };
+        if has_prev {
+            // E.g. several datasets in one directory (see dataset_from_path)
+            return PathError::err("multiple files for the same partition and number", path);
+        }
+        num_files += 1;
     }
     
     let mut repo = RepoFileIO::new(dir);
@@ -252,6 +338,8 @@ pub fn repo_from_path<P: AsRef<Path>>(path: P) -> Result<RepoFileIO> {
             assert!(repo.has_part(pn));
         }
     }
+    info!("Found {} files for {} partitions in: {}",
+        num_files, repo.num_parts(), dir.display());
     Ok(repo)
 }
 
@@ -283,6 +371,7 @@ pub fn find_part_num<P: AsRef<Path>>(name: &str, path: P) -> Result<PartId> {
     let head = read_head(&mut File::open(path)?)?;
     head.part_id.ok_or(Box::new(OtherError::new("file contains no part id")))
 }
+
 /// A helper to try matching a file name against standard Pippin file patterns,
 /// and if it fits return the "basename" part.
 pub fn discover_basename(fname: &str) -> Option<String> {
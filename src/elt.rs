@@ -6,8 +6,9 @@
 
 use std::fmt;
 use std::fmt::Debug;
+use std::mem::size_of;
 use std::io::{/*Read,*/ Write};
-use std::str::from_utf8;
+use std::str::{FromStr, from_utf8};
 // use vec_map::VecMap;
 
 use Sum;
@@ -72,15 +73,24 @@ impl fmt::Display for PartId {
 /// This encodes both a partition identifier (`PartId`) and a 24-bit element
 /// number (unique within the partition).
 /// 
-/// Supports `From` (`EltId::from(n)`) to convert from a `u64` (this panics if
-/// the value is not a valid identifier). Supports `Into` (`pn.into()`) to
+/// Supports `From` (`EltId::from(n)`) to convert from a `u64` (this does not
+/// validate the value; `try_from()` does). Supports `Into` (`pn.into()`) to
 /// convert to a `u64`.
+/// 
+/// Supports `fmt::Display` (displays the same value as `id.into()`) and
+/// `FromStr`, which accepts only this form of valid identifiers.
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash, PartialOrd, Ord)]
 pub struct EltId {
     // #0018: optimise usage as Option with NonZero?
     id: u64,
 }
 impl EltId {
+    /// Reconstructs from a value returned by `into()` (see `Into<u64>` impl).
+    /// Fails if the value does not include a valid partition identifier.
+    pub fn try_from(id: u64) -> Result<EltId, OtherError> {
+        if (id >> 24) == 0 { return Err(OtherError::new("invalid element id")); }
+        Ok(EltId { id: id })
+    }
     /// Extract the partition identifier
     pub fn part_id(self) -> PartId {
         PartId::try_from(self.id & 0xFFFF_FFFF_FF00_0000).unwrap()
@@ -117,6 +127,17 @@ impl fmt::Display for EltId {
         write!(f, "{}", self.id)
     }
 }
+impl FromStr for EltId {
+    type Err = OtherError;
+    fn from_str(s: &str) -> Result<EltId, OtherError> {
+        // u64 parsing accepts a leading '+', which is not canonical
+        if s.starts_with('+') {
+            return Err(OtherError::new("invalid element id"));
+        }
+        let id = s.parse::<u64>().map_err(|_| OtherError::new("invalid element id"))?;
+        EltId::try_from(id)
+    }
+}
 
 /// Whatever element type the user wishes to store must implement this trait.
 /// 
@@ -193,6 +214,9 @@ pub trait ElementT where Self: Sized+PartialEq+Debug {
     /// The given writer points to a dynamically allocated buffer so that
     /// length can be determined before the contents are finally written out
     /// (see `use_buf_io`).
+    /// 
+    /// Writing nothing is allowed; the element is then stored with empty data
+    /// and `read_buf` is passed an empty buffer when loading.
     fn write_buf(&self, writer: &mut Write) -> Result<()>;
     /// Deserialise the given data into a new element.
     fn read_buf(buf: &[u8]) -> Result<Self>;
@@ -206,6 +230,18 @@ pub trait ElementT where Self: Sized+PartialEq+Debug {
 //     /// Read from a data stream. The implementation *must* read `len` bytes!
 //     fn read<R: Read>(reader: R) -> Result<Self>;
     
+    /// Deserialise the given data into an existing element, replacing its
+    /// contents. On error, `target` may be left in any valid state.
+    /// 
+    /// This lets read loops reuse one element's heap allocations (see
+    /// `SnapshotEltIter::next_into()`). The default implementation calls
+    /// `read_buf` and does not reuse anything; types owning a buffer (e.g. a
+    /// `Vec`) should override it to clear and refill that buffer.
+    fn read_into(buf: &[u8], target: &mut Self) -> Result<()> {
+        *target = Self::read_buf(buf)?;
+        Ok(())
+    }
+    
     /// Create an instance from a buffer. This implementation wraps `read_buf`;
     /// write your own for more efficiency.
     fn from_vec(vec: Vec<u8>) -> Result<Self>{
@@ -232,6 +268,49 @@ pub trait ElementT where Self: Sized+PartialEq+Debug {
         self.write_buf(&mut &mut buf).expect("write_buf does not fail in get_sum");
         Sum::elt_sum(id, &buf)
     }
+    
+    /// Get the approximate amount of memory used by this element in bytes,
+    /// including any heap allocations it owns. This is used only for
+    /// estimates (see `PartState::approx_memory_usage()`).
+    /// 
+    /// The default implementation returns `size_of::<Self>()`, which does not
+    /// account for heap-allocated data.
+    fn size_hint(&self) -> usize {
+        size_of::<Self>()
+    }
+    
+    /// Get a fingerprint of the element's data, if this can be found more
+    /// cheaply than by serialising the element (e.g. a hash of a few key
+    /// fields). Elements with the same data must have the same fingerprint;
+    /// elements with different data may also share a fingerprint, thus this
+    /// is only a hint. Writing de-duplicated snapshots uses it to skip
+    /// checksumming the data of elements whose fingerprint is unique.
+    /// 
+    /// The default implementation returns `None` (no fingerprint).
+    fn fingerprint(&self) -> Option<u64> {
+        None
+    }
+    
+    /// Check whether two elements have the same content. Merging uses this to
+    /// decide whether two versions of an element actually differ (and thus
+    /// conflict). Override it for types where logically-equal values may be
+    /// represented differently (e.g. unordered collections).
+    /// 
+    /// The default implementation uses `PartialEq`.
+    fn eq_content(&self, other: &Self) -> bool {
+        self == other
+    }
+    
+    /// Check that the element is valid. This is called by
+    /// `MutStateT::insert()` and `replace()` (and variants, except the
+    /// lowest-level `insert_rc_initial()` and `replace_rc()`), which fail
+    /// with `ElementOp::Invalid` if this returns an error. It is not called
+    /// when loading elements from files or applying commits.
+    /// 
+    /// The default implementation accepts all elements.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
 impl ElementT for String {
@@ -243,7 +322,33 @@ impl ElementT for String {
         let s = from_utf8(buf)?;
         Ok(s.to_string())
     }
+    fn read_into(buf: &[u8], target: &mut Self) -> Result<()> {
+        let s = from_utf8(buf)?;
+        target.clear();
+        target.push_str(s);
+        Ok(())
+    }
     fn from_vec(vec: Vec<u8>) -> Result<Self>{
         Ok(String::from_utf8(vec)?)
     }
+    fn size_hint(&self) -> usize {
+        size_of::<String>() + self.capacity()
+    }
+}
+
+
+#[test]
+fn elt_id_parse() {
+    let id = PartId::from_num(3).elt_id(17);
+    let s = id.to_string();
+    assert_eq!(s, ((3u64 << 24) + 17).to_string());
+    assert_eq!(s.parse::<EltId>().unwrap(), id);
+    assert_eq!(EltId::try_from(id.into()).unwrap(), id);
+    
+    // No partition identifier:
+    assert!(EltId::try_from(17).is_err());
+    assert!("17".parse::<EltId>().is_err());
+    for s in &["", "-5", "+50331665", " 50331665", "0x3000011", "1e9", "18446744073709551616"] {
+        assert!(s.parse::<EltId>().is_err(), "parsed {:?}", s);
+    }
 }
@@ -9,6 +9,8 @@ use std::path::PathBuf;
 use std::cmp::{min, max};
 
 use util::HexFormatter;
+use elt::{EltId, PartId};
+use sum::Sum;
 
 /// Our custom result type
 pub type Result<T, E = Error> = result::Result<T, E>;
@@ -66,6 +68,16 @@ impl ReadError {
         let (o0, o1) = offset;
         ReadError { detail: Wrapped::ErrT(e), pos: pos, off_start: o0, off_end: o1 }
     }
+    /// Get the wrapped error, if created with `new_wrap()`.
+    /// 
+    /// This allows matching on the underlying cause, e.g.
+    /// `err.wrapped().and_then(|e| e.downcast_ref::<ClassifierError>())`.
+    pub fn wrapped(&self) -> Option<&(ErrorTrait + 'static)> {
+        match self.detail {
+            Wrapped::Msg(_) => None,
+            Wrapped::ErrT(ref e) => Some(&**e),
+        }
+    }
     /// Return an object which can be used in format expressions.
     /// 
     /// Usage: `println!("{}", err.display(&buf));`
@@ -131,6 +143,102 @@ impl<'a> fmt::Display for ReadErrorFormatter<'a> {
 }
 
 
+// —————  EltSumError  —————
+/// An element's stored checksum does not match its data (when reading a
+/// snapshot or commit log). This identifies the corrupt element.
+#[derive(PartialEq, Eq, Debug)]
+pub struct EltSumError {
+    /// Identifier of the corrupt element (as stored in the file)
+    pub elt_id: EltId,
+    /// Read position of the element's checksum
+    pub pos: usize,
+}
+impl EltSumError {
+    /// New instance, wrapped with `Err`
+    pub fn err<T>(elt_id: EltId, pos: usize) -> Result<T> {
+        Err(Box::new(EltSumError { elt_id: elt_id, pos: pos }))
+    }
+}
+impl ErrorTrait for EltSumError {
+    fn description(&self) -> &str { "element checksum mismatch" }
+}
+impl fmt::Display for EltSumError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "checksum mismatch for element {} (pos {})", self.elt_id, self.pos)
+    }
+}
+
+
+// —————  TipMismatch  —————
+/// A partition's tip is not that expected (see `Partition::verify_against()`).
+#[derive(PartialEq, Eq, Debug)]
+pub struct TipMismatch {
+    /// Partition checked
+    pub part_id: PartId,
+    /// State sum of the expected tip
+    pub expected: Sum,
+    /// State sum of the tip found
+    pub found: Sum,
+}
+impl TipMismatch {
+    /// New instance, wrapped with `Err`
+    pub fn err<T>(part_id: PartId, expected: Sum, found: Sum) -> Result<T> {
+        Err(Box::new(TipMismatch { part_id: part_id, expected: expected, found: found }))
+    }
+}
+impl ErrorTrait for TipMismatch {
+    fn description(&self) -> &str { "partition tip does not match" }
+}
+impl fmt::Display for TipMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "partition {} tip does not match: expected {}, found {}",
+                self.part_id, self.expected, self.found)
+    }
+}
+
+
+// —————  ClassifierError  —————
+/// Classifier data (as stored by a user's `RepoT` implementation, typically
+/// in user fields) is invalid. This is distinct from errors in element data,
+/// allowing a corrupt classifier to be handled separately.
+#[derive(PartialEq, Eq, Debug, Clone, Copy)]
+pub enum ClassifierError {
+    /// Data does not have the expected length
+    Format {
+        /// Length expected
+        expected: usize,
+        /// Length found
+        actual: usize,
+    },
+    /// Data does not start with the expected identifier
+    Identifier,
+}
+impl ClassifierError {
+    /// New `Format` instance
+    pub fn format(expected: usize, actual: usize) -> ClassifierError {
+        ClassifierError::Format { expected: expected, actual: actual }
+    }
+}
+impl ErrorTrait for ClassifierError {
+    fn description(&self) -> &str {
+        match *self {
+            ClassifierError::Format { .. } => "classifier data has wrong length",
+            ClassifierError::Identifier => "classifier data has unknown identifier",
+        }
+    }
+}
+impl fmt::Display for ClassifierError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        match *self {
+            ClassifierError::Format { expected, actual } =>
+                write!(f, "classifier data has wrong length (expected {}, found {})",
+                        expected, actual),
+            ClassifierError::Identifier => write!(f, "{}", self.description()),
+        }
+    }
+}
+
+
 // —————  ArgError  ————
 /// Any error where an invalid argument was supplied
 #[derive(PartialEq, Debug)]
@@ -176,6 +284,8 @@ pub enum ElementOp {
     ClassifyFailure,
     /// The relevant partition is not loaded within a repository
     NotLoaded,
+    /// The element was rejected by `ElementT::validate()`
+    Invalid,
 }
 impl ErrorTrait for ElementOp {
     fn description(&self) -> &'static str {
@@ -186,6 +296,7 @@ impl ErrorTrait for ElementOp {
             ElementOp::IdClash => "identifier already in use",
             ElementOp::ClassifyFailure => "classification of element failed",
             ElementOp::NotLoaded => "partition must be loaded",
+            ElementOp::Invalid => "element failed validation",
         }
     }
 }
@@ -373,6 +484,86 @@ impl fmt::Display for ReadOnly {
 }
 
 
+// —————  Cancelled  —————
+/// Operation was cancelled via a cancellation flag (see
+/// `Repository::set_cancel_flag()`).
+#[derive(PartialEq, Eq, Debug)]
+pub struct Cancelled {}
+impl Cancelled {
+    /// Create.
+    pub fn new() -> Cancelled { Cancelled{} }
+    /// Create, wrapped with `Err`
+    pub fn err<T>() -> Result<T> {
+        Err(Box::new(Cancelled::new()))
+    }
+}
+impl ErrorTrait for Cancelled {
+    fn description(&self) -> &str {
+        "operation cancelled"
+    }
+}
+impl fmt::Display for Cancelled {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "operation cancelled")
+    }
+}
+
+
+// —————  AlreadyExists  —————
+/// Data already exists where a new repository was to be created (see
+/// `Repository::create()`).
+#[derive(PartialEq, Eq, Debug)]
+pub struct AlreadyExists {}
+impl AlreadyExists {
+    /// Create.
+    pub fn new() -> AlreadyExists { AlreadyExists{} }
+    /// Create, wrapped with `Err`
+    pub fn err<T>() -> Result<T> {
+        Err(Box::new(AlreadyExists::new()))
+    }
+}
+impl ErrorTrait for AlreadyExists {
+    fn description(&self) -> &str {
+        "repository data already exists"
+    }
+}
+impl fmt::Display for AlreadyExists {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "repository data already exists")
+    }
+}
+
+
+// —————  PartErrors  —————
+/// An operation failed on some partitions (see
+/// `Repository::for_each_partition_mut()`). Partitions not listed succeeded.
+#[derive(Debug)]
+pub struct PartErrors {
+    /// Each partition for which the operation failed, with its error
+    pub errors: Vec<(PartId, Error)>,
+}
+impl PartErrors {
+    /// Create from a list of partitions and their errors
+    pub fn new(errors: Vec<(PartId, Error)>) -> PartErrors {
+        PartErrors { errors: errors }
+    }
+}
+impl ErrorTrait for PartErrors {
+    fn description(&self) -> &str {
+        "operation failed on some partitions"
+    }
+}
+impl fmt::Display for PartErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> result::Result<(), fmt::Error> {
+        write!(f, "operation failed on {} partition(s)", self.errors.len())?;
+        for &(part_id, ref e) in &self.errors {
+            write!(f, "; {}: {}", part_id, e)?;
+        }
+        Ok(())
+    }
+}
+
+
 // —————  OtherError  —————
 /// Unclassified, generally not recoverable errors
 #[derive(PartialEq, Eq, Debug)]
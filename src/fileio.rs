@@ -5,16 +5,19 @@
 //! Pippin: file access for repositories and partitions.
 
 use std::path::{Path, PathBuf};
-use std::io::{Read, Write};
-use std::fs::{File, OpenOptions};
+use std::io::{Read, Write, ErrorKind};
+use std::fs::{self, File, OpenOptions};
 use std::any::Any;
+use std::cell::{Ref, RefMut, RefCell};
 use std::ops::Add;
+use std::rc::Rc;
 use std::collections::hash_map::{HashMap, Values};
 
 use vec_map::{VecMap, Entry};
 
-use {PartIO, PartId, RepoIO};
-use error::{Result, ReadOnly, OtherError};
+use {PartIO, PartId, RepoIO, Sum};
+use repo::Tier;
+use error::{Result, ReadOnly, OtherError, ArgError};
 
 
 // —————  Partition  —————
@@ -93,14 +96,29 @@ impl PartPaths {
 
 /// Remembers a set of file names associated with a partition, opens read
 /// and write streams on these and creates new partition files.
-#[derive(Debug, Clone)]
+/// 
+/// Cloning copies the list of files; handles made by `PartIO::clone_io()`
+/// share it instead.
+#[derive(Debug)]
 pub struct PartFileIO {
     readonly: bool,
     // Partition identifier (required)
     part_id: PartId,
     // Appended with snapshot/log number and extension to get a file path
     prefix: PathBuf,
-    paths: PartPaths,
+    // Shared with handles made by `clone_io()`
+    paths: Rc<RefCell<PartPaths>>,
+}
+
+impl Clone for PartFileIO {
+    fn clone(&self) -> PartFileIO {
+        PartFileIO {
+            readonly: self.readonly,
+            part_id: self.part_id,
+            prefix: self.prefix.clone(),
+            paths: Rc::new(RefCell::new(self.paths.borrow().clone())),
+        }
+    }
 }
 
 impl PartFileIO {
@@ -136,7 +154,7 @@ impl PartFileIO {
             readonly: false,
             part_id: part_id,
             prefix: prefix,
-            paths: paths,
+            paths: Rc::new(RefCell::new(paths)),
         }
     }
     
@@ -164,13 +182,60 @@ impl PartFileIO {
     pub fn prefix(&self) -> &Path {
         &self.prefix
     }
-    /// Get a reference to the internal store of paths
-    pub fn paths(&self) -> &PartPaths {
-        &self.paths
+    /// Get a reference to the internal store of paths.
+    /// 
+    /// The store is shared with handles made by `clone_io()`, thus this is a
+    /// guard which must be dropped before files are created via this or such
+    /// a handle.
+    pub fn paths(&self) -> Ref<PartPaths> {
+        self.paths.borrow()
+    }
+    /// Get a mutable reference to the internal store of paths (see
+    /// `paths()`).
+    pub fn mut_paths(&mut self) -> RefMut<PartPaths> {
+        self.paths.borrow_mut()
+    }
+    
+    /// Get the path of the file storing the blob with the given key (see
+    /// `PartIO::write_blob()`): the prefix appended with `-blob-`, the key in
+    /// hexadecimal and `.pipblob`. Blob files are not listed in `paths()`.
+    pub fn blob_path(&self, key: &Sum) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push(format!("-blob-{}.pipblob", key.as_string(false)));
+        PathBuf::from(p)
+    }
+    
+    // Update the list of files from the directory: files named with the
+    // prefix are added and listed files which no longer exist are dropped.
+    fn refresh_paths(&mut self) -> Result<()> {
+        let mut paths = PartPaths::new();
+        for (ss, &(ref ss_path, ref logs)) in &self.paths.borrow().paths {
+            if let Some(ref path) = *ss_path {
+                if path.exists() { paths.insert_ss(ss, path.clone()); }
+            }
+            for (cl, path) in logs {
+                if path.exists() { paths.insert_cl(ss, cl, path.clone()); }
+            }
+        }
+        if self.prefix.parent().map_or(true, |dir| dir == Path::new("") || dir.is_dir()) {
+            for (path, file) in find_part_files(&self.prefix)? {
+                match file {
+                    PartFile::Snapshot(ss) => { paths.insert_ss(ss, path); },
+                    PartFile::Log(ss, cl) => { paths.insert_cl(ss, cl, path); },
+                    PartFile::Blob => {},
+                }
+            }
+        }
+        *self.paths.borrow_mut() = paths;
+        Ok(())
     }
-    /// Get a mutable reference to the internal store of paths
-    pub fn mut_paths(&mut self) -> &mut PartPaths {
-        &mut self.paths
+    
+    // Path of snapshot `ss_num`, or of the temporary file this is written to
+    // before completion
+    fn ss_path(&self, ss_num: usize, temp: bool) -> PathBuf {
+        let mut p = self.prefix.as_os_str().to_os_string();
+        p.push(format!("-ss{}.pip{}", ss_num, if temp { ".tmp" } else { "" }));
+        PathBuf::from(p)
     }
 }
 
@@ -180,19 +245,19 @@ impl PartIO for PartFileIO {
     fn part_id(&self) -> PartId { self.part_id }
     
     fn ss_len(&self) -> usize {
-        self.paths.ss_len()
+        self.paths.borrow().ss_len()
     }
     fn ss_cl_len(&self, ss_num: usize) -> usize {
-        self.paths.ss_cl_len(ss_num)
+        self.paths.borrow().ss_cl_len(ss_num)
     }
     
     fn has_ss(&self, ss_num: usize) -> bool {
-        self.paths.paths.get(ss_num).map(|&(ref p, _)| p.is_some()).unwrap_or(false)
+        self.paths.borrow().paths.get(ss_num).map(|&(ref p, _)| p.is_some()).unwrap_or(false)
     }
     
     fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
         // Cannot replace `match` with `map` since `try!()` cannot be used in a closure
-        Ok(match self.paths.paths.get(ss_num) {
+        Ok(match self.paths.borrow().paths.get(ss_num) {
             Some(&(ref p, _)) => {
                 if let &Some(ref path) = p {
                     trace!("Reading snapshot file: {}", path.display());
@@ -206,7 +271,7 @@ impl PartIO for PartFileIO {
     }
     
     fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
-        Ok(match self.paths.paths.get(ss_num).and_then(|&(_, ref logs)| logs.get(cl_num)) {
+        Ok(match self.paths.borrow().paths.get(ss_num).and_then(|&(_, ref logs)| logs.get(cl_num)) {
             Some(p) => {
                 trace!("Reading log file: {}", p.display());
                 Some(Box::new(File::open(p)?))
@@ -215,31 +280,68 @@ impl PartIO for PartFileIO {
         })
     }
     
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        Ok(match self.paths.borrow().get_ss(ss_num) {
+            Some(path) => Some(fs::metadata(path)?.len()),
+            None => None,
+        })
+    }
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        Ok(match self.paths.borrow().get_cl(ss_num, cl_num) {
+            Some(path) => Some(fs::metadata(path)?.len()),
+            None => None,
+        })
+    }
+    
     fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
         if self.readonly {
             return ReadOnly::err();
         }
-        let mut p = self.prefix.as_os_str().to_os_string();
-        p.push(format!("-ss{}.pip", ss_num));
-        let p = PathBuf::from(p);
-        if self.paths.paths.get(ss_num).map_or(false, |&(ref p, _)| p.is_some()) || p.exists() {
+        let p = self.ss_path(ss_num, false);
+        if self.paths.borrow().paths.get(ss_num).map_or(false, |&(ref p, _)| p.is_some()) || p.exists() {
             // File already exists in internal map or on filesystem
             return Ok(None);
         }
+        // Write to a temporary file; `finish_ss()` moves this into place.
+        // Any left-over temporary file is from an earlier failure.
+        let p = self.ss_path(ss_num, true);
         trace!("Creating snapshot file: {}", p.display());
         let stream = File::create(&p)?;
-        match self.paths.paths.entry(ss_num) {
+        Ok(Some(Box::new(stream)))
+    }
+    fn finish_ss(&mut self, ss_num: usize) -> Result<()> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let (temp, p) = (self.ss_path(ss_num, true), self.ss_path(ss_num, false));
+        // Make sure the data is on disk before the file becomes visible:
+        OpenOptions::new().write(true).open(&temp)?.sync_all()?;
+        trace!("Renaming snapshot file: {} -> {}", temp.display(), p.display());
+        fs::rename(&temp, &p)?;
+        match self.paths.borrow_mut().paths.entry(ss_num) {
             Entry::Occupied(mut entry) => { entry.get_mut().0 = Some(p); },
             Entry::Vacant(entry) => { entry.insert((Some(p), VecMap::new())); },
         };
-        Ok(Some(Box::new(stream)))
+        Ok(())
+    }
+    fn discard_ss(&mut self, ss_num: usize) -> Result<()> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let temp = self.ss_path(ss_num, true);
+        trace!("Removing partial snapshot file: {}", temp.display());
+        match fs::remove_file(&temp) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
     }
     
     fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
         if self.readonly {
             return ReadOnly::err();
         }
-        Ok(match self.paths.paths.get(ss_num).and_then(|&(_, ref logs)| logs.get(cl_num)) {
+        Ok(match self.paths.borrow().paths.get(ss_num).and_then(|&(_, ref logs)| logs.get(cl_num)) {
             Some(p) => {
                 trace!("Appending to log file: {}", p.display());
                 Some(Box::new(OpenOptions::new().write(true).append(true).open(p)?))
@@ -251,7 +353,8 @@ impl PartIO for PartFileIO {
         if self.readonly {
             return ReadOnly::err();
         }
-        let mut logs = &mut self.paths.paths.entry(ss_num).or_insert_with(|| (None, VecMap::new())).1;
+        let mut paths = self.paths.borrow_mut();
+        let logs = &mut paths.paths.entry(ss_num).or_insert_with(|| (None, VecMap::new())).1;
         let mut p = self.prefix.as_os_str().to_os_string();
         p.push(format!("-ss{}-cl{}.piplog", ss_num, cl_num));
         let p = PathBuf::from(p);
@@ -264,15 +367,136 @@ impl PartIO for PartFileIO {
         logs.insert(cl_num, p);
         Ok(Some(Box::new(stream)))
     }
+    
+    fn quarantine_ss(&mut self, ss_num: usize) -> Result<Option<String>> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let path = match self.paths.borrow().get_ss(ss_num) {
+            Some(p) => quarantine_file(p)?,
+            None => return Ok(None),
+        };
+        if let Some(entry) = self.paths.borrow_mut().paths.get_mut(ss_num) {
+            entry.0 = None;
+        }
+        Ok(Some(path.display().to_string()))
+    }
+    fn quarantine_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<Option<String>> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let path = match self.paths.borrow().get_cl(ss_num, cl_num) {
+            Some(p) => quarantine_file(p)?,
+            None => return Ok(None),
+        };
+        if let Some(entry) = self.paths.borrow_mut().paths.get_mut(ss_num) {
+            entry.1.remove(cl_num);
+        }
+        Ok(Some(path.display().to_string()))
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        match self.paths.borrow().get_ss(ss_num) {
+            Some(p) => {
+                trace!("Removing snapshot file: {}", p.display());
+                fs::remove_file(p)?;
+            },
+            None => return Ok(false),
+        }
+        if let Some(entry) = self.paths.borrow_mut().paths.get_mut(ss_num) {
+            entry.0 = None;
+        }
+        Ok(true)
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        match self.paths.borrow().get_cl(ss_num, cl_num) {
+            Some(p) => {
+                trace!("Removing log file: {}", p.display());
+                fs::remove_file(p)?;
+            },
+            None => return Ok(false),
+        }
+        if let Some(entry) = self.paths.borrow_mut().paths.get_mut(ss_num) {
+            entry.1.remove(cl_num);
+        }
+        Ok(true)
+    }
+    
+    fn read_blob(&self, key: &Sum) -> Result<Option<Vec<u8>>> {
+        let path = self.blob_path(key);
+        if !path.exists() {
+            return Ok(None);
+        }
+        trace!("Reading blob file: {}", path.display());
+        let mut data = Vec::new();
+        File::open(&path)?.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+    fn write_blob(&mut self, key: &Sum, data: &[u8]) -> Result<()> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let path = self.blob_path(key);
+        if path.exists() {
+            return Ok(());
+        }
+        // Write to a temporary file then rename, so that the blob is either
+        // complete or absent:
+        let mut tmp_path = path.as_os_str().to_os_string();
+        tmp_path.push(".tmp");
+        let tmp_path = PathBuf::from(tmp_path);
+        {
+            let mut stream = File::create(&tmp_path)?;
+            stream.write_all(data)?;
+            stream.sync_all()?;
+        }
+        trace!("Creating blob file: {}", path.display());
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+    
+    fn clone_io(&self) -> Option<Box<PartIO>> {
+        Some(Box::new(PartFileIO {
+            readonly: self.readonly,
+            part_id: self.part_id,
+            prefix: self.prefix.clone(),
+            paths: self.paths.clone(),
+        }))
+    }
+}
+
+// Rename a file by appending `.quarantine` (thus it is no longer discovered),
+// returning the new path
+fn quarantine_file(path: &Path) -> Result<PathBuf> {
+    let mut p = path.as_os_str().to_os_string();
+    p.push(".quarantine");
+    let p = PathBuf::from(p);
+    if p.exists() {
+        return OtherError::err("quarantine file already exists");
+    }
+    trace!("Quarantining file: {} → {}", path.display(), p.display());
+    fs::rename(path, &p)?;
+    Ok(p)
 }
 
 
 // —————  Repository  —————
 
+// File names of the transaction journal, relative to the repository directory
+const JOURNAL: &'static str = "journal.pipj";
+const JOURNAL_TMP: &'static str = "journal.pipj.tmp";
+// File names of the partition identifier allocation record
+const PART_ALLOC: &'static str = "partids.pipa";
+const PART_ALLOC_TMP: &'static str = "partids.pipa.tmp";
+
 /// Stores a set of `PartFileIO`s, each of which stores the paths of its files.
-/// This is not "live" and could get out-of-date if another process touches the
-/// files or if multiple `PartIO`s are requested for the same partition in this
-/// process.
+/// Partitions write via copies of these (see `make_part_io()`), so the stored
+/// lists are not "live"; each new copy lists the files currently on disk.
 pub struct RepoFileIO {
     readonly: bool,
     // Top directory of partition (which paths are relative to)
@@ -280,6 +504,10 @@ pub struct RepoFileIO {
     // PartFileIO for each partition. We duplicate PartId here, but accepting
     // the overhead seems the easiest approach.
     parts: HashMap<PartId, PartFileIO>,
+    // Dataset name, prefixed to file names of new partitions and the journal
+    dataset: Option<String>,
+    // Directory storing partitions of the cold tier, if any
+    cold_dir: Option<PathBuf>,
 }
 impl RepoFileIO {
     /// Create a new instance. This could be for a new repository or existing
@@ -290,7 +518,47 @@ impl RepoFileIO {
     pub fn new<P: Into<PathBuf>>(dir: P) -> RepoFileIO {
         let dir = dir.into();
         trace!("New RepoFileIO; dir: {}", dir.display());
-        RepoFileIO { readonly: false, dir: dir, parts: HashMap::new() }
+        RepoFileIO { readonly: false, dir: dir, parts: HashMap::new(), dataset: None,
+                cold_dir: None }
+    }
+    
+    /// Get the directory storing partitions of the cold tier, if set.
+    pub fn cold_dir(&self) -> Option<&Path> {
+        self.cold_dir.as_ref().map(|dir| dir.as_path())
+    }
+    /// Set the directory storing partitions of the cold tier (see
+    /// `RepoIO::set_tier()`); a relative path is taken relative to the top
+    /// directory. Partitions are in the cold tier when their files are in
+    /// this directory; others (including new partitions) are in the hot tier,
+    /// with files in the top directory.
+    /// 
+    /// To find partitions of both tiers when opening a repository, the cold
+    /// directory should be within the top directory (discovery is
+    /// recursive) and this must be set again.
+    pub fn set_cold_dir(&mut self, dir: Option<PathBuf>) {
+        self.cold_dir = dir.map(|dir| self.dir.join(dir));
+    }
+    
+    /// Get the dataset name, if any.
+    pub fn dataset(&self) -> Option<&str> {
+        self.dataset.as_ref().map(|s| s.as_str())
+    }
+    /// Set the dataset name. This allows multiple repositories to share a
+    /// directory: files of new partitions (and the journal) are named with
+    /// the prefix `DATASET-`, and `discover::dataset_from_path` only finds
+    /// files with this prefix.
+    /// 
+    /// Names must be non-empty and contain only ASCII letters, digits and
+    /// underscores (in particular, no `-`, so that no dataset's prefix is a
+    /// prefix of another's). Fails if the name is not valid.
+    pub fn set_dataset(&mut self, dataset: Option<String>) -> Result<()> {
+        if let Some(ref name) = dataset {
+            if !valid_dataset_name(name) {
+                return ArgError::err("invalid dataset name");
+            }
+        }
+        self.dataset = dataset;
+        Ok(())
     }
     
     /// Get property: is this readonly? If this is readonly, file creation and
@@ -333,6 +601,51 @@ impl RepoFileIO {
     pub fn partitions(&self) -> RepoPartIter {
         RepoPartIter { iter: self.parts.values() }
     }
+    
+    // Path of a repository-level file (journal etc.), given its name
+    fn repo_file_path(&self, name: &str) -> PathBuf {
+        match self.dataset {
+            Some(ref dataset) => self.dir.join(format!("{}-{}", dataset, name)),
+            None => self.dir.join(name),
+        }
+    }
+    
+    // Replace a repository-level file. Data is written to a temporary file
+    // then renamed, so that the file is either complete or absent.
+    fn write_repo_file(&self, name: &str, tmp_name: &str, data: &[u8]) -> Result<()> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let tmp_path = self.repo_file_path(tmp_name);
+        {
+            let mut stream = File::create(&tmp_path)?;
+            stream.write_all(data)?;
+            stream.sync_all()?;
+        }
+        trace!("Writing file: {}", self.repo_file_path(name).display());
+        fs::rename(&tmp_path, self.repo_file_path(name))?;
+        Ok(())
+    }
+    
+    // Read a repository-level file, if it exists
+    fn read_repo_file(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let path = self.repo_file_path(name);
+        if !path.exists() {
+            return Ok(None);
+        }
+        trace!("Reading file: {}", path.display());
+        let mut data = Vec::new();
+        File::open(&path)?.read_to_end(&mut data)?;
+        Ok(Some(data))
+    }
+}
+
+/// True if `name` is a valid dataset name (see `RepoFileIO::set_dataset()`).
+pub fn valid_dataset_name(name: &str) -> bool {
+    !name.is_empty() && name.chars().all(|c| match c {
+        'a'..='z' | 'A'..='Z' | '0'..='9' | '_' => true,
+        _ => false,
+    })
 }
 impl RepoIO for RepoFileIO {
     fn as_any(&self) -> &Any { self }
@@ -349,17 +662,170 @@ impl RepoIO for RepoFileIO {
         if self.readonly {
             return ReadOnly::err();
         }
-        let path = self.dir.join(prefix);
-        self.parts.insert(num, PartFileIO::new_empty(num, path));
+        let path = match self.dataset {
+            Some(ref dataset) => self.dir.join(format!("{}-{}", dataset, prefix)),
+            None => self.dir.join(prefix),
+        };
+        // List any files left over from a previous partition with this prefix:
+        let mut io = PartFileIO::new_empty(num, path);
+        io.refresh_paths()?;
+        self.parts.insert(num, io);
         Ok(())
     }
     fn make_part_io(&self, num: PartId) -> Result<Box<PartIO>> {
         if let Some(ref io) = self.parts.get(&num) {
-            Ok(Box::new((**io).clone()))
+            let mut io = (**io).clone();
+            io.refresh_paths()?;
+            Ok(Box::new(io))
         } else {
             OtherError::err("partition not found")
         }
     }
+    fn write_journal(&mut self, data: &[u8]) -> Result<()> {
+        self.write_repo_file(JOURNAL, JOURNAL_TMP, data)
+    }
+    fn read_journal(&self) -> Result<Option<Vec<u8>>> {
+        self.read_repo_file(JOURNAL)
+    }
+    fn clear_journal(&mut self) -> Result<()> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        match fs::remove_file(self.repo_file_path(JOURNAL)) {
+            Ok(()) => Ok(()),
+            Err(ref e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            Err(e) => Err(Box::new(e)),
+        }
+    }
+    fn write_part_alloc(&mut self, data: &[u8]) -> Result<()> {
+        self.write_repo_file(PART_ALLOC, PART_ALLOC_TMP, data)
+    }
+    fn read_part_alloc(&self) -> Result<Option<Vec<u8>>> {
+        self.read_repo_file(PART_ALLOC)
+    }
+    fn delete_part(&mut self, num: PartId) -> Result<()> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let prefix = match self.parts.get(&num) {
+            Some(part) => part.prefix().to_path_buf(),
+            None => return OtherError::err("partition not found"),
+        };
+        for (path, _) in find_part_files(&prefix)? {
+            trace!("Removing file: {}", path.display());
+            fs::remove_file(path)?;
+        }
+        self.parts.remove(&num);
+        Ok(())
+    }
+    fn readonly(&self) -> bool {
+        self.readonly
+    }
+    fn tier(&self, num: PartId) -> Tier {
+        let dir = self.parts.get(&num).and_then(|part| part.prefix().parent());
+        match (dir, self.cold_dir()) {
+            (Some(dir), Some(cold_dir)) if dir == cold_dir => Tier::Cold,
+            _ => Tier::Hot,
+        }
+    }
+    // Files are moved one at a time; should this fail, the partition may be
+    // left split between directories.
+    fn set_tier(&mut self, num: PartId, tier: Tier) -> Result<()> {
+        if self.readonly {
+            return ReadOnly::err();
+        }
+        let dir = match tier {
+            Tier::Hot => self.dir.clone(),
+            Tier::Cold => match self.cold_dir {
+                Some(ref dir) => dir.clone(),
+                None => return ArgError::err("no directory set for cold tier"),
+            },
+        };
+        let part = match self.parts.get_mut(&num) {
+            Some(part) => part,
+            None => return OtherError::err("partition not found"),
+        };
+        let name = match part.prefix.file_name() {
+            Some(name) => name.to_os_string(),
+            None => return OtherError::err("partition prefix has no file name"),
+        };
+        let old_dir = match part.prefix.parent() {
+            Some(dir) if dir != Path::new("") => dir.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        if old_dir == dir {
+            return Ok(());
+        }
+        fs::create_dir_all(&dir)?;
+        let move_file = |path: &Path| -> Result<PathBuf> {
+            let new_path = dir.join(path.file_name().expect("file name"));
+            trace!("Moving file: {} to {}", path.display(), new_path.display());
+            fs::rename(path, &new_path)?;
+            Ok(new_path)
+        };
+        
+        let mut paths = PartPaths::new();
+        for (path, file) in find_part_files(&part.prefix)? {
+            let new_path = move_file(&path)?;
+            match file {
+                PartFile::Snapshot(ss) => { paths.insert_ss(ss, new_path); },
+                PartFile::Log(ss, cl) => { paths.insert_cl(ss, cl, new_path); },
+                PartFile::Blob => {},
+            }
+        }
+        part.prefix = dir.join(name);
+        *part.paths.borrow_mut() = paths;
+        Ok(())
+    }
+}
+
+// Kind of a file found by `find_part_files()`
+enum PartFile {
+    Snapshot(usize),
+    Log(usize, usize),
+    Blob,
+}
+
+// Find the files of the partition with the given prefix by name: snapshots,
+// logs and blobs. Partitions write files via copies of the `PartFileIO` (see
+// `RepoIO::make_part_io()`), thus those listed by the repository may be
+// out of date.
+fn find_part_files(prefix: &Path) -> Result<Vec<(PathBuf, PartFile)>> {
+    let name = match prefix.file_name() {
+        Some(name) => format!("{}-", name.to_string_lossy()),
+        None => return OtherError::err("partition prefix has no file name"),
+    };
+    let dir = match prefix.parent() {
+        Some(dir) if dir != Path::new("") => dir,
+        _ => Path::new("."),
+    };
+    let num = |s: &str| s.parse::<usize>().ok();
+    let mut files = vec![];
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        let fname = entry.file_name().to_string_lossy().into_owned();
+        if !fname.starts_with(&name) {
+            continue;
+        }
+        let rest = &fname[name.len()..];
+        let file = if rest.starts_with("blob-") && rest.ends_with(".pipblob") {
+            Some(PartFile::Blob)
+        } else if rest.starts_with("ss") && rest.ends_with(".piplog") {
+            let mut nums = rest[2..rest.len() - 7].splitn(2, "-cl");
+            match (nums.next().and_then(&num), nums.next().and_then(&num)) {
+                (Some(ss), Some(cl)) => Some(PartFile::Log(ss, cl)),
+                _ => None,
+            }
+        } else if rest.starts_with("ss") && rest.ends_with(".pip") {
+            num(&rest[2..rest.len() - 4]).map(|ss| PartFile::Snapshot(ss))
+        } else {
+            None
+        };
+        if let Some(file) = file {
+            files.push((entry.path(), file));
+        }
+    }
+    Ok(files)
 }
 
 /// Iterator over the partitions in a `RepoFileIO`.
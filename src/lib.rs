@@ -84,26 +84,35 @@ extern crate log;
 // Redundantly re-export some of the main types here:
 pub use error::{Result};
 pub use elt::{EltId, PartId, ElementT};
-pub use part::{Partition, PartIO, UserFields};
-pub use repo::{Repository, RepoIO, RepoT, ClassifierT, RepoState};
-pub use state::{PartState, MutPartState, StateT, MutStateT};
+pub use part::{Partition, PartitionBuilder, PartInspection, PartStats, RepairReport, PartIO, UserFields};
+pub use part::{CompactOutcome, VerifyReport};
+pub use repo::{Repository, RepoIO, RepoT, ClassifierT, RepoState, RepoStats, Checkpoint};
+pub use repo::{CompactPolicy, CompactReport};
+pub use state::{PartState, PartStateRef, MutPartState, StateT, MutStateT, RedactionPolicy, compute_state_sum};
 pub use sum::Sum;
 // Export some items not otherwise available here:
-pub use readwrite::{FileType, read_head, UserData};
+pub use readwrite::{FileType, SumType, SumKey, read_head, read_head_keyed, UserData, Manifest, ManifestPart};
+pub use readwrite::{HeaderPolicy, PartConfig, read_head_with_policy};
+pub use readwrite::{SnapshotEltIter, parse_header, PartIdAlloc, BlobSource, NoBlobs, blob_key};
 
 pub mod commit;
+pub mod counter;
 pub mod discover;
 pub mod elt;
 pub mod error;
 pub mod fileio;
 pub mod merge;
+pub mod merkle;
 pub mod part;
 mod readwrite;
 pub mod repo;
+pub mod retry;
 mod repo_traits;
 pub mod state;
 pub mod sum;
+pub mod tagged;
 pub mod util;
+pub mod vecelt;
 
 /// Version. The low 16 bits are patch number, next 16 are the minor version
 /// number, the next are the major version number. The top 16 are zero.
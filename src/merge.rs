@@ -32,7 +32,7 @@
 
 use std::collections::HashMap;
 use std::marker::PhantomData;
-use std::rc::Rc;
+use std::sync::Arc;
 
 use commit::{Commit, CommitMeta, MakeMeta, EltChange};
 use {PartState, StateT};
@@ -72,7 +72,7 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
         for (id, elt1) in a.elts_iter() {
             if let Some(elt2) = map_b.remove(&id) {
                 // Have elt in states 1 and 2
-                if elt1 != elt2 {
+                if !elt1.eq_content(elt2) {
                     v.push((id, EltMerge::NoResult));
                 }
             } else {
@@ -99,6 +99,28 @@ impl<'a, E: ElementT> TwoWayMerge<'a, E> {
         }
     }
     
+    /// Resolve all still-ambiguous cases with a closure. This is an
+    /// alternative to implementing `TwoWaySolver` for one-off merge logic.
+    /// 
+    /// Operation is `O(X)`.
+    pub fn solve_with<F>(&mut self, mut f: F) where F: FnMut(&Conflict<E>) -> Resolution<E> {
+        for &mut (id, ref mut result) in self.v.iter_mut() {
+            if *result == EltMerge::NoResult {
+                let conflict = Conflict {
+                    id: id,
+                    a: self.a.get_rc(id).ok(),
+                    b: self.b.get_rc(id).ok(),
+                    c: self.c.get_rc(id).ok(),
+                };
+                *result = match f(&conflict) {
+                    Resolution::TakeA => EltMerge::A,
+                    Resolution::TakeB => EltMerge::B,
+                    Resolution::Custom(elt) => EltMerge::Elt(Arc::new(elt)),
+                };
+            }
+        }
+    }
+    
     /// Run a solver. Same as `solve()` but consumes and returns self to allow
     /// chaining.
     pub fn solve_inline<S>(mut self, s: &S) -> Self where S: TwoWaySolver<E> {
@@ -394,7 +416,7 @@ pub enum EltMerge<E: ElementT> {
     /// Use the value from the second state
     B,
     /// Use a custom value (specified in full)
-    Elt(Rc<E>),
+    Elt(Arc<E>),
     /// Remove the element
     NoElt,
     /// Rename one element and include both; where only one element is present
@@ -404,13 +426,38 @@ pub enum EltMerge<E: ElementT> {
     NoResult,
 }
 
+/// Details of one conflict, passed to a closure by `TwoWayMerge::solve_with()`.
+pub struct Conflict<'a, E: ElementT+'a> {
+    /// Identifier of the conflicting element
+    pub id: EltId,
+    /// The element in the first state, if present
+    pub a: Option<&'a Arc<E>>,
+    /// The element in the second state, if present
+    pub b: Option<&'a Arc<E>>,
+    /// The element in the common ancestor state, if present
+    pub c: Option<&'a Arc<E>>,
+}
+
+/// Resolution of a conflict, returned by closures passed to
+/// `TwoWayMerge::solve_with()`.
+pub enum Resolution<E: ElementT> {
+    /// Use the value from the first state (if it is not present there, the
+    /// element is deleted)
+    TakeA,
+    /// Use the value from the second state (if it is not present there, the
+    /// element is deleted)
+    TakeB,
+    /// Use a custom value
+    Custom(E),
+}
+
 /// Implementations solve two-way merges on an element-by-element basis.
 pub trait TwoWaySolver<E: ElementT> {
     /// This function should take possibly-present elements from states A, B
     /// and common ancestor state C, which all have the same identifier, and
     /// return an `EltMerge` object.
-    fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
-        c: Option<&'a Rc<E>>) -> EltMerge<E>;
+    fn solve<'a>(&self, a: Option<&'a Arc<E>>, b: Option<&'a Arc<E>>,
+        c: Option<&'a Arc<E>>) -> EltMerge<E>;
 }
 
 /// Implementation of TwoWaySolver which always selects state A.
@@ -424,8 +471,8 @@ impl<E: ElementT> TwoWaySolveUseA<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveUseA<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::A
     }
@@ -441,8 +488,8 @@ impl<E: ElementT> TwoWaySolveUseB<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveUseB<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::B
     }
@@ -458,8 +505,8 @@ impl<E: ElementT> TwoWaySolveUseC<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveUseC<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         match c {
             Some(ref elt) => EltMerge::Elt((*elt).clone()),
@@ -478,8 +525,8 @@ impl<E: ElementT> TwoWaySolveNoResult<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for TwoWaySolveNoResult<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        _: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        _: Option<&Arc<E>>) -> EltMerge<E>
     {
         EltMerge::NoResult
     }
@@ -504,8 +551,8 @@ impl<'a, E: ElementT, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a>
 impl<'a, E: ElementT, S: TwoWaySolver<E>+'a, T: TwoWaySolver<E>+'a> TwoWaySolver<E>
     for TwoWaySolverChain<'a, E, S, T>
 {
-    fn solve(&self, a: Option<&Rc<E>>, b: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, a: Option<&Arc<E>>, b: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         let result = self.s.solve(a, b, c);
         if result != EltMerge::NoResult {
@@ -535,20 +582,29 @@ impl<E: ElementT> AncestorSolver2W<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for AncestorSolver2W<E> {
-    fn solve<'a>(&self, a: Option<&'a Rc<E>>, b: Option<&'a Rc<E>>,
-        c: Option<&'a Rc<E>>) -> EltMerge<E>
+    fn solve<'a>(&self, a: Option<&'a Arc<E>>, b: Option<&'a Arc<E>>,
+        c: Option<&'a Arc<E>>) -> EltMerge<E>
     {
         // Assumption: a != b
-        if a == c {
+        if eq_content(a, c) {
             return EltMerge::B;
         }
-        if b == c {
+        if eq_content(b, c) {
             return EltMerge::A;
         }
         EltMerge::NoResult
     }
 }
 
+// Compare optional elements with `ElementT::eq_content()`
+fn eq_content<E: ElementT>(a: Option<&Arc<E>>, b: Option<&Arc<E>>) -> bool {
+    match (a, b) {
+        (Some(a), Some(b)) => (**a).eq_content(&**b),
+        (None, None) => true,
+        _ => false,
+    }
+}
+
 /// Solver which handles the case where there is no common ancestor element by
 /// renaming (or in the case that either `a` or `b` is `None`, choosing the
 /// other).
@@ -562,8 +618,8 @@ impl<E: ElementT> RenamingSolver2W<E> {
     }
 }
 impl<E: ElementT> TwoWaySolver<E> for RenamingSolver2W<E> {
-    fn solve(&self, _: Option<&Rc<E>>, _: Option<&Rc<E>>,
-        c: Option<&Rc<E>>) -> EltMerge<E>
+    fn solve(&self, _: Option<&Arc<E>>, _: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
     {
         if c == None {
             EltMerge::Rename
@@ -572,3 +628,98 @@ impl<E: ElementT> TwoWaySolver<E> for RenamingSolver2W<E> {
         }
     }
 }
+
+/// Element types which know how to merge two divergent versions of themselves.
+/// 
+/// This is the element-level merge hook: types implementing it can be merged
+/// with `EltMergeSolver2W`, without writing a custom `TwoWaySolver`.
+pub trait EltMergeT: ElementT {
+    /// Merge two versions of an element, `a` and `b`, given the version from
+    /// the common ancestor state `c`, if present there.
+    /// 
+    /// Return `None` if no automatic merge is possible.
+    fn merge3(a: &Self, b: &Self, c: Option<&Self>) -> Option<Self>;
+}
+
+/// Solver using `EltMergeT::merge3` where both states have the element. In
+/// other cases (element deleted from one state) this returns
+/// `EltMerge::NoResult`; chain with `AncestorSolver2W` or similar to handle
+/// those.
+pub struct EltMergeSolver2W<E: EltMergeT>{
+    p: PhantomData<E>
+}
+impl<E: EltMergeT> EltMergeSolver2W<E> {
+    /// Create an instance (requires no parameters)
+    pub fn new() -> Self {
+        EltMergeSolver2W { p: PhantomData }
+    }
+}
+impl<E: EltMergeT> TwoWaySolver<E> for EltMergeSolver2W<E> {
+    fn solve(&self, a: Option<&Arc<E>>, b: Option<&Arc<E>>,
+        c: Option<&Arc<E>>) -> EltMerge<E>
+    {
+        if let (Some(a), Some(b)) = (a, b) {
+            if let Some(elt) = E::merge3(&**a, &**b, c.map(|c| &**c)) {
+                return EltMerge::Elt(Arc::new(elt));
+            }
+        }
+        EltMerge::NoResult
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use super::*;
+    use {PartId, MutStateT};
+    use error::Result;
+    
+    // Unordered set of bytes: content is equal regardless of order, though
+    // the serialisation (and `PartialEq`) is not
+    #[derive(PartialEq, Debug)]
+    struct ByteSet(Vec<u8>);
+    impl ElementT for ByteSet {
+        fn write_buf(&self, writer: &mut Write) -> Result<()> {
+            writer.write_all(&self.0)?;
+            Ok(())
+        }
+        fn read_buf(buf: &[u8]) -> Result<Self> {
+            Ok(ByteSet(buf.to_vec()))
+        }
+        fn eq_content(&self, other: &Self) -> bool {
+            let (mut a, mut b) = (self.0.clone(), other.0.clone());
+            a.sort();
+            b.sort();
+            a == b
+        }
+    }
+    
+    #[test]
+    fn content_equal_no_conflict() {
+        let part_id = PartId::from_num(1);
+        let (id1, id2) = (part_id.elt_id(1), part_id.elt_id(2));
+        let mut state = PartState::<ByteSet>::new(part_id, None).clone_mut();
+        state.insert_with_id(id1, Arc::new(ByteSet(vec![1]))).unwrap();
+        state.insert_with_id(id2, Arc::new(ByteSet(vec![1, 2]))).unwrap();
+        let c = PartState::from_mut(state, None);
+        
+        // Both sides make content-equal changes to id1. For id2, a only
+        // re-orders the bytes while b changes the content.
+        let mut state = c.clone_mut();
+        state.replace(id1, ByteSet(vec![3, 4])).unwrap();
+        state.replace(id2, ByteSet(vec![2, 1])).unwrap();
+        let a = PartState::from_mut(state, None);
+        let mut state = c.clone_mut();
+        state.replace(id1, ByteSet(vec![4, 3])).unwrap();
+        state.replace(id2, ByteSet(vec![5])).unwrap();
+        let b = PartState::from_mut(state, None);
+        
+        let mut merge = TwoWayMerge::new(&a, &b, &c);
+        assert_eq!(merge.len(), 1);
+        assert_eq!(merge.status(0).0, id2);
+        merge.solve(&AncestorSolver2W::new());
+        assert!(merge.is_solved());
+        assert!(merge.status(0).1 == EltMerge::B);
+    }
+}
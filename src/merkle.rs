@@ -0,0 +1,174 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: Merkle trees over element checksums, for comparing states.
+//! 
+//! Comparing two states element-by-element takes time linear in the number
+//! of elements; where two replicas of a large partition differ in only a few
+//! elements, a `MerkleTree` allows finding these more quickly, by comparing
+//! roots then descending only into differing subtrees.
+//! 
+//! Elements are assigned to `2^depth` leaves ("buckets") by identifier
+//! (see `MerkleTree::leaf_index()`), thus inserting or removing an element
+//! only affects the path to one leaf. Each leaf's sum is the combination of
+//! the checksums (`Sum::elt_sum()`) of its elements, as with state sums, and
+//! each internal node's sum is the checksum of its two children's sums.
+//! Trees can only be compared when they have the same depth.
+
+use {ElementT, EltId, Sum, PartState};
+use sum::BYTES;
+use error::{Result, ArgError};
+
+/// Depth used by `PartState::merkle_root()`: 256 leaves.
+pub const DEFAULT_DEPTH: u32 = 8;
+/// Maximum depth supported.
+pub const MAX_DEPTH: u32 = 20;
+
+/// A Merkle tree over the element checksums of some state.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct MerkleTree {
+    // levels[0] holds the leaves; each subsequent level has half as many
+    // nodes; the last holds only the root
+    levels: Vec<Vec<Sum>>,
+}
+
+impl MerkleTree {
+    /// Build a tree of the given depth from element identifiers and their
+    /// checksums.
+    /// 
+    /// Fails if `depth` exceeds `MAX_DEPTH`.
+    pub fn new<I>(depth: u32, elts: I) -> Result<MerkleTree>
+        where I: Iterator<Item = (EltId, Sum)>
+    {
+        if depth > MAX_DEPTH {
+            return ArgError::err("Merkle tree depth too large");
+        }
+        let mut leaves = vec![Sum::zero(); 1 << depth];
+        for (id, sum) in elts {
+            leaves[leaf_index(depth, id)].permute(&sum);
+        }
+        
+        let mut levels = vec![leaves];
+        let mut buf = [0u8; 2 * BYTES];
+        while levels[levels.len() - 1].len() > 1 {
+            let next = levels[levels.len() - 1].chunks(2).map(|pair| {
+                pair[0].write(&mut &mut buf[0..BYTES]).expect("write to buf");
+                pair[1].write(&mut &mut buf[BYTES..]).expect("write to buf");
+                Sum::calculate(&buf)
+            }).collect();
+            levels.push(next);
+        }
+        Ok(MerkleTree { levels: levels })
+    }
+    
+    /// Build a tree over the elements of `state`.
+    /// 
+    /// This calculates the checksum of every element (see
+    /// `PartState::element_checksum()`).
+    pub fn from_state<E: ElementT>(state: &PartState<E>, depth: u32) -> Result<MerkleTree> {
+        MerkleTree::new(depth, state.elts_iter().map(|(id, elt)| (id, elt.sum(id))))
+    }
+    
+    /// Get the depth: the tree has `2^depth` leaves.
+    pub fn depth(&self) -> u32 {
+        (self.levels.len() - 1) as u32
+    }
+    
+    /// Get the root sum.
+    pub fn root(&self) -> &Sum {
+        &self.levels[self.levels.len() - 1][0]
+    }
+    
+    /// Get a node's sum: `level` 0 are the leaves, `depth()` the root, and
+    /// `index` is less than `2^(depth() - level)`. Returns `None` if out of
+    /// range.
+    pub fn node(&self, level: u32, index: usize) -> Option<&Sum> {
+        self.levels.get(level as usize).and_then(|nodes| nodes.get(index))
+    }
+    
+    /// Get the index of the leaf which element `id` belongs to.
+    pub fn leaf_index(&self, id: EltId) -> usize {
+        leaf_index(self.depth(), id)
+    }
+    
+    /// List the indices of leaves which differ from those of `other`, in
+    /// increasing order. Only subtrees whose roots differ are examined.
+    /// 
+    /// Elements in these leaves (see `leaf_index()`) may differ between the
+    /// two states; elements in other leaves are the same.
+    /// 
+    /// Fails if the trees do not have the same depth.
+    pub fn diff(&self, other: &MerkleTree) -> Result<Vec<usize>> {
+        if self.depth() != other.depth() {
+            return ArgError::err("Merkle trees have different depths");
+        }
+        let mut result = Vec::new();
+        self.diff_node(other, self.depth(), 0, &mut result);
+        Ok(result)
+    }
+    
+    fn diff_node(&self, other: &MerkleTree, level: u32, index: usize, result: &mut Vec<usize>) {
+        if self.levels[level as usize][index] == other.levels[level as usize][index] {
+            return;
+        }
+        if level == 0 {
+            result.push(index);
+        } else {
+            self.diff_node(other, level - 1, 2 * index, result);
+            self.diff_node(other, level - 1, 2 * index + 1, result);
+        }
+    }
+}
+
+fn leaf_index(depth: u32, id: EltId) -> usize {
+    let id: u64 = id.into();
+    (id & ((1 << depth) - 1)) as usize
+}
+
+
+#[test]
+fn merkle_diff() {
+    use std::sync::Arc;
+    use {PartId, MutStateT};
+    
+    let part_id = PartId::from_num(1);
+    let mut state = PartState::<String>::new(part_id, None).clone_mut();
+    let mut ids = vec![];
+    for i in 0..100 {
+        ids.push(state.insert(format!("element {}", i)).unwrap());
+    }
+    let state1 = PartState::from_mut(state, None);
+    let mut state = state1.clone_mut();
+    state.replace(ids[42], "changed".to_string()).unwrap();
+    let state2 = PartState::from_mut(state, None);
+    
+    let t1 = MerkleTree::from_state(&state1, DEFAULT_DEPTH).unwrap();
+    let t2 = MerkleTree::from_state(&state2, DEFAULT_DEPTH).unwrap();
+    assert_eq!(t1.depth(), DEFAULT_DEPTH);
+    assert!(t1.root() != t2.root());
+    assert_eq!(*t1.root(), state1.merkle_root());
+    assert_eq!(t1, MerkleTree::from_state(&state1.clone_exact(), DEFAULT_DEPTH).unwrap());
+    
+    // Only nodes on the path to the changed element's leaf differ:
+    let leaf = t1.leaf_index(ids[42]);
+    assert_eq!(t1.diff(&t2).unwrap(), vec![leaf]);
+    for level in 0..(DEFAULT_DEPTH + 1) {
+        for index in 0..(1 << (DEFAULT_DEPTH - level)) {
+            let same = t1.node(level, index) == t2.node(level, index);
+            assert_eq!(same, index != leaf >> level);
+        }
+    }
+    assert!(t1.node(DEFAULT_DEPTH, 1).is_none());
+    assert!(t1.diff(&t1).unwrap().is_empty());
+    
+    // Insertion affects only one leaf:
+    let mut state = state1.clone_mut();
+    let id = state.insert_with_id(part_id.elt_id(1000), Arc::new("new".to_string())).unwrap();
+    let t3 = MerkleTree::from_state(&PartState::from_mut(state, None), DEFAULT_DEPTH).unwrap();
+    assert_eq!(t1.diff(&t3).unwrap(), vec![t1.leaf_index(id)]);
+    
+    assert!(t1.diff(&MerkleTree::from_state(&state1, 4).unwrap()).is_err());
+    assert!(MerkleTree::from_state(&state1, MAX_DEPTH + 1).is_err());
+    assert_eq!(MerkleTree::new(0, None.into_iter()).unwrap().root(), &Sum::zero());
+}
@@ -4,24 +4,33 @@
 
 //! Pippin: partition
 
-use std::io::{Read, Write, ErrorKind};
-use std::collections::{HashSet, VecDeque};
+use std::io::{self, Read, Write, BufReader, BufWriter, ErrorKind};
+use std::collections::{HashMap, HashSet, BTreeSet, VecDeque};
+use std::collections::vec_deque;
 use std::collections::hash_set as hs;
 use std::result;
 use std::any::Any;
 use std::ops::Deref;
 use std::usize;
-use std::cmp::min;
+use std::cmp::{min, max};
+use std::mem::replace;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::time::SystemTime;
 use hashindexed::{HashIndexed, Iter};
 
-use readwrite::{FileHeader, UserData, FileType, read_head, write_head, validate_repo_name};
-use readwrite::{read_snapshot, write_snapshot};
-use readwrite::{read_log, start_log, write_commit};
-use state::{PartState, MutPartState, PartStateSumComparator};
-use commit::{Commit, MakeMeta};
-use merge::{TwoWayMerge, TwoWaySolver};
-use {ElementT, Sum, PartId};
-use error::{Result, TipError, PatchOp, MatchError, MergeError, OtherError, make_io_err};
+use readwrite::{FileHeader, UserData, FileType, SumType, HeaderPolicy, PartConfig, read_head,
+        read_head_with_policy, write_head, validate_repo_name};
+use readwrite::{read_snapshot, read_snapshot_checked, write_snapshot, write_snapshot_dedup, SnapshotEltIter};
+use readwrite::{read_log, read_log_checked, read_log_tail, start_log, write_commit, SumCheck};
+use readwrite::{BlobSource, blob_key, HEAD_VERSIONS};
+use state::{PartState, PartStateRef, MutPartState, PartStateSumComparator, StateT};
+use commit::{Commit, CommitMeta, MakeMeta, Clock, SystemClock, ClockMeta, BRANCH_NAME_MAX};
+use merge::{TwoWayMerge, TwoWaySolver, Conflict, Resolution};
+use retry::{RetryPolicy, RetryPartIO};
+use {ElementT, Sum, SumKey, PartId};
+use sum::BYTES as SUM_BYTES;
+use error::{Result, TipError, PatchOp, MatchError, MergeError, ArgError, OtherError, TipMismatch, make_io_err};
 
 /// An interface providing read and/or write access to a suitable location.
 /// 
@@ -96,6 +105,28 @@ pub trait PartIO {
     /// This can fail due to IO operations failing.
     fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>>;
     
+    /// Get the size in bytes of a snapshot, or `None` if not present.
+    /// 
+    /// The default implementation reads the whole stream; implementations
+    /// should override this where the size is cheaply available.
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        match self.read_ss(ss_num)? {
+            Some(mut r) => Ok(Some(io::copy(&mut r, &mut io::sink())?)),
+            None => Ok(None),
+        }
+    }
+    
+    /// Get the size in bytes of a commit log, or `None` if not present.
+    /// 
+    /// The default implementation reads the whole stream; implementations
+    /// should override this where the size is cheaply available.
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        match self.read_ss_cl(ss_num, cl_num)? {
+            Some(mut r) => Ok(Some(io::copy(&mut r, &mut io::sink())?)),
+            None => Ok(None),
+        }
+    }
+    
     /// Open a write stream on a new snapshot file, numbered ss_num.
     /// This will increase the number returned by ss_len().
     /// 
@@ -104,9 +135,32 @@ pub trait PartIO {
     /// Returns a heap-allocated write stream, either to some external resource
     /// (such as a file) or to an internal data-structure.
     /// 
+    /// Once the snapshot has been completely written and flushed,
+    /// `finish_ss(ss_num)` is called; if writing fails, `discard_ss(ss_num)`
+    /// is called instead. Implementations may thus write to a temporary
+    /// location and only make the snapshot available when finished.
+    /// 
     /// This can fail due to IO operations failing.
     fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>>;
     
+    /// Called after a snapshot opened with `new_ss(ss_num)` has been
+    /// completely written and flushed. Implementations writing to a
+    /// temporary location should now move the snapshot into place.
+    /// 
+    /// The default implementation does nothing.
+    fn finish_ss(&mut self, _ss_num: usize) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Called instead of `finish_ss()` when writing a snapshot opened with
+    /// `new_ss(ss_num)` fails. Implementations should remove any partially
+    /// written data.
+    /// 
+    /// The default implementation does nothing.
+    fn discard_ss(&mut self, _ss_num: usize) -> Result<()> {
+        Ok(())
+    }
+    
     /// Open an append-write stream on an existing commit file. Writes may be
     /// atomic. Each commit should be written via a single write operation.
     /// 
@@ -133,6 +187,166 @@ pub trait PartIO {
     /// This can fail due to IO operations failing.
     // #0012: verify atomicity of writes
     fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>>;
+    
+    /// Move a snapshot file out of the way: afterwards it is no longer listed
+    /// (`has_ss(ss_num)` returns false) but its data should be kept for
+    /// inspection. Used by `Partition::repair()`.
+    /// 
+    /// Returns a description of the new location (e.g. a file path), or
+    /// `None` if no such snapshot exists.
+    /// 
+    /// The default implementation fails; data is never moved unless the
+    /// implementation supports this.
+    fn quarantine_ss(&mut self, _ss_num: usize) -> Result<Option<String>> {
+        OtherError::err("quarantine not supported")
+    }
+    
+    /// Move a commit log out of the way, as `quarantine_ss()`.
+    fn quarantine_ss_cl(&mut self, _ss_num: usize, _cl_num: usize) -> Result<Option<String>> {
+        OtherError::err("quarantine not supported")
+    }
+    
+    /// Delete a snapshot file. Used by `Partition::compact()` to remove files
+    /// superseded by a newer snapshot.
+    /// 
+    /// Returns false if no such snapshot exists.
+    /// 
+    /// The default implementation fails; data is never deleted unless the
+    /// implementation supports this.
+    fn remove_ss(&mut self, _ss_num: usize) -> Result<bool> {
+        OtherError::err("file removal not supported")
+    }
+    
+    /// Delete a commit log, as `remove_ss()`.
+    fn remove_ss_cl(&mut self, _ss_num: usize, _cl_num: usize) -> Result<bool> {
+        OtherError::err("file removal not supported")
+    }
+    
+    /// Get the data of a blob (element data stored outside snapshots and
+    /// logs; see `Partition::set_blob_threshold()`) by its key. Returns
+    /// `None` if not found.
+    /// 
+    /// The default implementation always returns `None`.
+    fn read_blob(&self, _key: &Sum) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    
+    /// Store a blob. `key` is the checksum of `data` (see `blob_key()`), thus
+    /// if a blob with this key is already stored there is no need to write it
+    /// again. Blobs may be referred to by any snapshot or log and should not
+    /// be removed.
+    /// 
+    /// The default implementation fails; blobs cannot be used unless the
+    /// implementation supports these.
+    fn write_blob(&mut self, _key: &Sum, _data: &[u8]) -> Result<()> {
+        OtherError::err("blob store not supported")
+    }
+    
+    /// Get another handle on the same partition data (used by
+    /// `Partition::clone_shallow()`), or `None` if not supported. Files
+    /// written via either handle must be listed by and readable via the
+    /// other.
+    /// 
+    /// The default implementation returns `None`.
+    fn clone_io(&self) -> Option<Box<PartIO>> {
+        None
+    }
+}
+impl<'a> BlobSource for PartIO + 'a {
+    fn get_blob(&self, key: &Sum) -> Result<Option<Vec<u8>>> {
+        self.read_blob(key)
+    }
+}
+
+/// Summary of a partition's files, as returned by `Partition::inspect()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PartInspection {
+    /// Partition identifier
+    pub part_id: PartId,
+    /// Repository name, as found in file headers (empty if there are no files)
+    pub repo_name: String,
+    /// Number of snapshot files
+    pub num_ss: usize,
+    /// Number of commit log files
+    pub num_cl: usize,
+    /// Total size of snapshot files, in bytes
+    pub ss_bytes: u64,
+    /// Total size of commit log files, in bytes
+    pub cl_bytes: u64,
+    /// Number of the latest snapshot file, if any
+    pub latest_ss: Option<usize>,
+}
+impl PartInspection {
+    /// Total size of all files, in bytes
+    pub fn total_bytes(&self) -> u64 {
+        self.ss_bytes + self.cl_bytes
+    }
+}
+
+/// Outcome of `Partition::compact()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompactOutcome {
+    /// Number of the new snapshot file
+    pub snapshot: usize,
+    /// Number of files removed
+    pub files_removed: usize,
+    /// Total size of files removed, in bytes
+    pub bytes_reclaimed: u64,
+}
+
+/// Statistics on a partition, as returned by `Partition::stats()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct PartStats {
+    /// Partition identifier
+    pub part_id: PartId,
+    /// Number of elements in the tip, or `None` unless ready (see
+    /// `Partition::is_ready()`)
+    pub num_elts: Option<usize>,
+    /// Number of tips (more than one when a merge is required)
+    pub num_tips: usize,
+    /// True if a snapshot is wanted (see `Partition::want_snapshot()`)
+    pub want_snapshot: bool,
+    /// Number of commits not yet written
+    pub num_unsaved: usize,
+    /// Summary of the partition's files
+    pub files: PartInspection,
+}
+impl PartStats {
+    /// True if a merge is required (see `Partition::merge_required()`)
+    pub fn merge_required(&self) -> bool {
+        self.num_tips > 1
+    }
+}
+
+/// Outcome of `Partition::repair()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RepairReport {
+    /// Number of commits read from logs and applied
+    pub commits_recovered: usize,
+    /// Number of commits read but dropped since their parent was not found
+    pub commits_dropped: usize,
+    /// Number of the new snapshot file
+    pub snapshot: usize,
+    /// Files quarantined, as described by `PartIO::quarantine_ss()`
+    pub quarantined: Vec<String>,
+}
+
+/// Outcome of `Partition::verify()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct VerifyReport {
+    /// Partition identifier
+    pub part_id: PartId,
+    /// Number of snapshot and log files read without error
+    pub files_ok: usize,
+    /// Damaged files, as a snapshot number and (for a commit log) a log
+    /// number, each with a description of the error
+    pub damaged: Vec<(usize, Option<usize>, String)>,
+}
+impl VerifyReport {
+    /// True if no damaged files were found
+    pub fn is_ok(&self) -> bool {
+        self.damaged.is_empty()
+    }
 }
 
 /// Provide access to user fields of header
@@ -198,6 +412,9 @@ impl PartIO for DummyPartIO {
         self.buf.clear();
         Ok(Some(Box::new(&mut self.buf)))
     }
+    fn clone_io(&self) -> Option<Box<PartIO>> {
+        Some(Box::new(DummyPartIO::new(self.part_id)))
+    }
 }
 
 /// A *partition* is a sub-set of the entire set such that (a) each element is
@@ -223,6 +440,8 @@ pub struct Partition<E: ElementT> {
     repo_name: String,
     // Partition identifier
     part_id: PartId,
+    // Checksum algorithm used when writing files
+    sum_type: SumType,
     // Number of first snapshot file loaded (equal to ss1 if nothing is loaded)
     ss0: usize,
     // Number of latest snapshot file loaded + 1; 0 if nothing loaded and never less than ss0
@@ -230,16 +449,69 @@ pub struct Partition<E: ElementT> {
     // Determines when to write new snapshots
     ss_commits: usize,
     ss_edits: usize,
-    // Known committed states indexed by statesum 
-    states: HashIndexed<PartState<E>, Sum, PartStateSumComparator>,
+    // Known committed states indexed by statesum (immutable; shared with
+    // handles made by `clone_shallow()` and with `PartStateRef`s)
+    states: HashIndexed<Arc<PartState<E>>, Sum, PartStateSumComparator>,
     // All states not in `states` which are known to be superceded
     ancestors: HashSet<Sum>,
     // All states without a known successor
     tips: HashSet<Sum>,
     // Commits created but not yet saved to disk. First in at front; use as queue.
     unsaved: VecDeque<Commit<E>>,
+    // How far each loaded commit log was read, by snapshot and log number
+    logs_read: HashMap<(usize, usize), LogPos>,
+    // Logs written by this partition may be appended to until this size
+    max_log_size: usize,
+    // Snapshot number, log number, size and checksum type of the log last
+    // written (cleared if writing fails)
+    cur_log: Option<(usize, usize, usize, SumType)>,
+    // Number of commits after which `write()` also writes a snapshot (0: never)
+    snapshot_interval: usize,
+    // Set by `push_state()` when the snapshot interval is reached
+    snapshot_due: bool,
+    // Whether snapshots store identical element data only once
+    snapshot_dedup: bool,
+    // Size of the buffer used when loading files (0: unbuffered)
+    read_buf_size: usize,
+    // Size of the buffer used when writing files (0: unbuffered)
+    write_buf_size: usize,
+    // Number of threads calculating element checksums when writing snapshots
+    sum_threads: usize,
+    // Minimum size of element data stored as a blob (None: blobs not used)
+    blob_min: Option<usize>,
+    // Whether `snapshot_dedup` and `blob_min` were set explicitly, thus are
+    // not replaced by settings recorded in files when loading
+    dedup_set: bool,
+    blob_min_set: bool,
+    // Whether loading fails when a commit is read twice (else it is skipped)
+    reject_duplicates: bool,
+    // Whether loading continues despite checksum mismatches
+    ignore_checksum: bool,
+    // Handling of unknown header content when loading
+    header_policy: HeaderPolicy,
+    // Set when data was loaded despite a checksum mismatch
+    unverified: bool,
+    // Oldest format version of the files loaded (None: nothing read yet)
+    format_ver: Option<u32>,
+    // Whether loading files of an old format version makes a snapshot due
+    upgrade_on_write: bool,
+    // Source of timestamps for commits made without a `MakeMeta`
+    clock: Rc<Clock>,
+    // Heads of named branches (see `branch()`); these are in `states` but
+    // not in `tips`
+    branches: HashMap<String, Sum>,
+    // Branch checked out, if not the main line
+    cur_branch: Option<String>,
 }
 
+/// Default size of the buffer used when reading snapshots and logs (see
+/// `Partition::set_read_buffer_size()`).
+pub const DEFAULT_READ_BUF_SIZE: usize = 8 * 1024;
+
+/// Default size of the buffer used when writing snapshots and logs (see
+/// `Partition::set_write_buffer_size()`).
+pub const DEFAULT_WRITE_BUF_SIZE: usize = 64 * 1024;
+
 // Methods creating a partition, loading its data or checking status
 impl<E: ElementT> Partition<E> {
     /// Create a partition, assigning an IO provider (this can only be done at
@@ -257,26 +529,54 @@ impl<E: ElementT> Partition<E> {
     /// let partition = Partition::<String>::create(io, "example repo", None, None);
     /// ```
     // #0040: are we passing too many optional parameters: user & make_meta?
-    pub fn create<'a>(mut io: Box<PartIO>, name: &str,
+    pub fn create<'a>(io: Box<PartIO>, name: &str,
             user: Option<&mut UserFields>, make_meta: Option<&MakeMeta>)
             -> Result<Partition<E>>
+    {
+        let state = PartState::new(io.part_id(), make_meta);
+        Partition::create_with(io, name, user, state, SumType::default(), PartConfig::default())
+    }
+    
+    /// Create a partition as `create()`, but with `state` as its initial
+    /// state instead of a blank one. This is used to recreate a partition
+    /// from a copy of its state, e.g. when replicating (see
+    /// `Repository::import_stream()`).
+    /// 
+    /// Fails if the state belongs to a different partition than `io`.
+    pub fn create_from_state(io: Box<PartIO>, name: &str,
+            user: Option<&mut UserFields>, state: PartState<E>)
+            -> Result<Partition<E>>
+    {
+        if state.part_id() != io.part_id() {
+            return ArgError::err("create_from_state: state has wrong partition identifier");
+        }
+        Partition::create_with(io, name, user, state, SumType::default(), PartConfig::default())
+    }
+    
+    // Implementation of `create()`, also used by `PartitionBuilder`
+    fn create_with(mut io: Box<PartIO>, name: &str,
+            user: Option<&mut UserFields>, state: PartState<E>,
+            sum_type: SumType, config: PartConfig) -> Result<Partition<E>>
     {
         validate_repo_name(name)?;
         let ss = 0;
         let part_id = io.part_id();
         info!("Creating partiton {}; writing snapshot {}", part_id, ss);
         
-        let state = PartState::new(part_id, make_meta);
         let header = FileHeader {
             ftype: FileType::Snapshot(0),
             name: name.to_string(),
             part_id: Some(part_id),
             user: user.map_or(vec![], |u| u.write_user_fields(part_id, false)),
+            config: Some(config),
+            renamed_from: None,
+            sum_type: sum_type,
         };
-        if let Some(mut writer) = io.new_ss(ss)? {
-            write_head(&header, &mut writer)?;
-            write_snapshot(&state, &mut writer)?;
-        } else {
+        let written = Self::write_ss_file(&mut *io, ss, 0, |writer| {
+            write_head(&header, writer)?;
+            write_snapshot(&state, writer, header.sum_type)
+        })?;
+        if written.is_none() {
             return make_io_err(ErrorKind::AlreadyExists, "snapshot already exists");
         }
         
@@ -284,6 +584,7 @@ impl<E: ElementT> Partition<E> {
             io: io,
             repo_name: header.name,
             part_id: part_id,
+            sum_type: header.sum_type,
             ss0: ss,
             ss1: ss + 1,
             ss_commits: 0,
@@ -292,9 +593,30 @@ impl<E: ElementT> Partition<E> {
             ancestors: HashSet::new(),
             tips: HashSet::new(),
             unsaved: VecDeque::new(),
+            logs_read: HashMap::new(),
+            max_log_size: 0,
+            cur_log: None,
+            snapshot_interval: 0,
+            snapshot_due: false,
+            snapshot_dedup: config.snapshot_dedup,
+            read_buf_size: DEFAULT_READ_BUF_SIZE,
+            write_buf_size: DEFAULT_WRITE_BUF_SIZE,
+            sum_threads: 1,
+            blob_min: config.blob_min,
+            dedup_set: false,
+            blob_min_set: false,
+            reject_duplicates: false,
+            ignore_checksum: false,
+            header_policy: HeaderPolicy::default(),
+            unverified: false,
+            format_ver: None,
+            upgrade_on_write: false,
+            clock: Rc::new(SystemClock),
+            branches: HashMap::new(),
+            cur_branch: None,
         };
         part.tips.insert(state.statesum().clone());
-        part.states.insert(state);
+        part.states.insert(Arc::new(state));
         
         Ok(part)
     }
@@ -327,6 +649,7 @@ impl<E: ElementT> Partition<E> {
             io: io,
             repo_name: "".to_string() /*temporary value; checked before usage elsewhere*/,
             part_id: part_id,
+            sum_type: SumType::default(),
             ss0: 0,
             ss1: 0,
             ss_commits: 0,
@@ -335,9 +658,129 @@ impl<E: ElementT> Partition<E> {
             ancestors: HashSet::new(),
             tips: HashSet::new(),
             unsaved: VecDeque::new(),
+            logs_read: HashMap::new(),
+            max_log_size: 0,
+            cur_log: None,
+            snapshot_interval: 0,
+            snapshot_due: false,
+            snapshot_dedup: false,
+            read_buf_size: DEFAULT_READ_BUF_SIZE,
+            write_buf_size: DEFAULT_WRITE_BUF_SIZE,
+            sum_threads: 1,
+            blob_min: None,
+            dedup_set: false,
+            blob_min_set: false,
+            reject_duplicates: false,
+            ignore_checksum: false,
+            header_policy: HeaderPolicy::default(),
+            unverified: false,
+            format_ver: None,
+            upgrade_on_write: false,
+            clock: Rc::new(SystemClock),
+            branches: HashMap::new(),
+            cur_branch: None,
         })
     }
     
+    /// Summarise the files of a partition without loading it.
+    /// 
+    /// This reads only file headers (checking that the repository name and
+    /// partition identifier are consistent) and file sizes (see
+    /// `PartIO::ss_size()`); element data is not read.
+    /// 
+    /// Fails on files written with a keyed checksum; use
+    /// `inspect_with_policy()` for these.
+    pub fn inspect(io: &PartIO) -> Result<PartInspection> {
+        Self::inspect_with_policy(io, None, HeaderPolicy::default())
+    }
+    
+    /// Summarise the files of a partition as `inspect()` does, using `key`
+    /// to verify keyed checksums (see `read_head_keyed()`) and handling
+    /// unknown header content according to `policy`.
+    pub fn inspect_with_policy(io: &PartIO, key: Option<SumKey>, policy: HeaderPolicy)
+            -> Result<PartInspection>
+    {
+        let part_id = io.part_id();
+        let mut result = PartInspection {
+            part_id: part_id,
+            repo_name: String::new(),
+            num_ss: 0,
+            num_cl: 0,
+            ss_bytes: 0,
+            cl_bytes: 0,
+            latest_ss: None,
+        };
+        let mut renames = Self::find_renames(io, key, policy, 0, &mut result.repo_name)?;
+        for ss in 0..io.ss_len() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                let head = read_head_with_policy(&mut r, key, policy)?;
+                Self::verify_head(&head, Self::name_at(&mut renames, ss, &mut result.repo_name),
+                        part_id)?;
+                result.num_ss += 1;
+                result.ss_bytes += io.ss_size(ss)?.unwrap_or(0);
+                result.latest_ss = Some(ss);
+            }
+            for cl in 0..io.ss_cl_len(ss) {
+                if let Some(mut r) = io.read_ss_cl(ss, cl)? {
+                    let head = read_head_with_policy(&mut r, key, policy)?;
+                    Self::verify_head(&head, Self::name_at(&mut renames, ss, &mut result.repo_name),
+                            part_id)?;
+                    result.num_cl += 1;
+                    result.cl_bytes += io.ss_cl_size(ss, cl)?.unwrap_or(0);
+                }
+            }
+        }
+        Ok(result)
+    }
+    
+    /// Iterate over the elements of the latest snapshot without loading the
+    /// partition.
+    /// 
+    /// Elements are read from the snapshot file one at a time and not
+    /// retained, thus this is suitable for one-pass processing of partitions
+    /// too large to hold in memory. Commit logs are not read. Checksums are
+    /// verified as reading progresses; a mismatch in the file or state
+    /// checksum is reported as an error after the last element.
+    /// 
+    /// Fails if no snapshot is found.
+    pub fn stream_snapshot_elements<'a>(io: &'a PartIO) ->
+            Result<SnapshotEltIter<Box<Read + 'a>, E, &'a PartIO>>
+    {
+        let mut repo_name = String::new();
+        for ss in (0..io.ss_len()).rev() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                let head = read_head(&mut r)?;
+                Self::verify_head(&head, &mut repo_name, io.part_id())?;
+                return SnapshotEltIter::with_blobs(r, io.part_id(), head.ftype.ver(),
+                        head.sum_type, io);
+            }
+        }
+        OtherError::err("no snapshot found")
+    }
+    
+    /// Open a partition and load its latest state if the IO provider has any
+    /// snapshot files, otherwise create it as with `create()`.
+    /// 
+    /// When opening, `name` is checked against the name stored in files (see
+    /// `set_repo_name()`). `user` and `make_meta` are used as in `create()` or
+    /// `load_latest()`.
+    /// 
+    /// On success returns the partition, ready for use, and `true` if it was
+    /// newly created or `false` if it was opened.
+    pub fn open_or_create(io: Box<PartIO>, name: &str,
+            user: Option<&mut UserFields>, make_meta: Option<&MakeMeta>)
+            -> Result<(Partition<E>, bool)>
+    {
+        if io.ss_len() == 0 {
+            Ok((Partition::create(io, name, user, make_meta)?, true))
+        } else {
+            let mut part = Partition::open(io)?;
+            part.set_repo_name(name)?;
+            part.load_latest(user, make_meta)?;
+            Ok((part, false))
+        }
+    }
+    
     /// Set the repo name. This is not set by `open()`, but is used to verify
     /// loaded files belong to the correct partition. Once set, load operations
     /// will fail if the name stored in the file does not match.
@@ -356,6 +799,189 @@ impl<E: ElementT> Partition<E> {
         Ok(())
     }
     
+    /// Set the checksum algorithm used for files written from now on (the
+    /// default is `SumType::Blake2b`).
+    /// 
+    /// The algorithm used by each file is detected from its header when
+    /// reading, thus a partition may contain files using different
+    /// algorithms. The exception is `SumType::Blake2bKeyed`: its key is not
+    /// stored, so files written with it can only be read while the same
+    /// keyed type is set here (set it before loading).
+    pub fn set_sum_type(&mut self, sum_type: SumType) {
+        self.sum_type = sum_type;
+    }
+    
+    /// Set the maximum size of commit logs, in bytes.
+    /// 
+    /// When writing commits, they are appended to the log file last written
+    /// by this `Partition` (for the same snapshot) while its size is less
+    /// than this; otherwise a new log file is created. The default, zero,
+    /// means that each write creates a new log file.
+    /// 
+    /// Logs written by other processes are never appended to.
+    pub fn set_max_log_size(&mut self, size: usize) {
+        self.max_log_size = size;
+    }
+    
+    /// Set the number of commits after which a snapshot is written
+    /// automatically.
+    /// 
+    /// When `push_state()` brings the number of commits since the last
+    /// snapshot to this number, the next call to `write()` also writes a
+    /// snapshot. The default, zero, disables this (`write_full()` still
+    /// consults `PartIO::want_snapshot()`).
+    pub fn set_snapshot_interval(&mut self, commits: usize) {
+        self.snapshot_interval = commits;
+    }
+    
+    /// Set whether snapshots written from now on de-duplicate element data:
+    /// if true, data shared by several elements is stored once and referred
+    /// to by the others. Default: false.
+    /// 
+    /// Reading such snapshots requires a version of this library supporting
+    /// the feature; references are resolved transparently. This setting is
+    /// recorded in file headers and restored when loading unless set
+    /// explicitly (see `config()`).
+    pub fn set_snapshot_dedup(&mut self, dedup: bool) {
+        self.snapshot_dedup = dedup;
+        self.dedup_set = true;
+    }
+    
+    /// Set whether element data is stored separately from snapshots and logs:
+    /// if `Some(min_size)`, the data of each element at least `min_size` bytes
+    /// long is stored as a *blob* via `PartIO::write_blob()` and files refer
+    /// to it by checksum. Thus data is stored once, no matter how many commits
+    /// and snapshots include it. Default: `None` (all data stored inline).
+    /// 
+    /// This requires a `PartIO` supporting blobs. Files referring to blobs
+    /// can only be read by versions of this library supporting the feature.
+    /// This setting is recorded in file headers and restored when loading
+    /// unless set explicitly (see `config()`).
+    pub fn set_blob_threshold(&mut self, min_size: Option<usize>) {
+        self.blob_min = min_size;
+        self.blob_min_set = true;
+    }
+    
+    /// Get the settings recorded in the headers of files written.
+    /// 
+    /// When loading, the settings recorded by the latest file read (if it
+    /// records any) are applied, thus a partition writes files as the
+    /// partition which last wrote it did without the caller knowing its
+    /// settings. Settings set explicitly (via `set_snapshot_dedup()`,
+    /// `set_blob_threshold()` or `PartitionBuilder`) take precedence and are
+    /// not replaced.
+    pub fn config(&self) -> PartConfig {
+        PartConfig {
+            snapshot_dedup: self.snapshot_dedup,
+            blob_min: self.blob_min,
+        }
+    }
+    
+    // Apply settings recorded by a file at the tip (see `config()`)
+    fn apply_config(&mut self, config: PartConfig) {
+        trace!("Partition {}: applying recorded settings {:?}", self.part_id, config);
+        if !self.dedup_set {
+            self.snapshot_dedup = config.snapshot_dedup;
+        }
+        if !self.blob_min_set {
+            self.blob_min = config.blob_min;
+        }
+    }
+    
+    /// Set the size of the buffer placed between each reader returned by
+    /// the `PartIO` and the parser when loading snapshots and logs. Parsing
+    /// mostly requests small pieces (e.g. 16 bytes); the buffer replaces
+    /// these with fewer, larger reads, which matters for high-latency
+    /// (e.g. network) I/O. Zero disables buffering. Default:
+    /// `DEFAULT_READ_BUF_SIZE`.
+    pub fn set_read_buffer_size(&mut self, size: usize) {
+        self.read_buf_size = size;
+    }
+    
+    /// Set the size of the buffer used when writing snapshots and logs.
+    /// Serialisation writes many small pieces (e.g. each element's data);
+    /// these are collected in memory and passed to the `PartIO`'s writer in
+    /// chunks of about this size. Commits are never split between chunks and
+    /// are only considered saved once their chunk was written. Zero writes
+    /// each commit separately and snapshots unbuffered. Default:
+    /// `DEFAULT_WRITE_BUF_SIZE`.
+    pub fn set_write_buffer_size(&mut self, size: usize) {
+        self.write_buf_size = size;
+    }
+    
+    /// Set the number of threads used to calculate element checksums when
+    /// writing snapshots. With more than one, elements are serialised on
+    /// the calling thread and their checksums calculated by worker threads
+    /// meanwhile; output is identical. This may help with large snapshots;
+    /// it ignores checksums cached by the element type (see
+    /// `ElementT::sum()`). Default: 1.
+    pub fn set_sum_threads(&mut self, threads: usize) {
+        self.sum_threads = max(threads, 1);
+    }
+    
+    /// Set how commits found more than once while loading (e.g. because a
+    /// log file was copied) are handled. Either way a warning is logged; if
+    /// `reject` is true loading then fails, otherwise the copies are skipped.
+    /// Default: false.
+    pub fn set_reject_duplicates(&mut self, reject: bool) {
+        self.reject_duplicates = reject;
+    }
+    
+    /// Set whether snapshots and logs are loaded despite checksum mismatches
+    /// ("best effort" reading, for salvaging data from damaged files). If
+    /// true, each mismatch is logged as a warning, the data is used anyway
+    /// and `is_unverified()` returns true. The structure of files must still
+    /// be intact, as must file headers. Default: false (mismatches are
+    /// errors).
+    pub fn set_read_ignoring_checksum(&mut self, ignore: bool) {
+        self.ignore_checksum = ignore;
+    }
+    
+    /// Set how file headers containing sections or blocks unknown to this
+    /// version are handled when loading. `HeaderPolicy::Strict` rejects such
+    /// files, for users needing to be sure every file read was fully
+    /// understood. Default: `HeaderPolicy::Lenient`.
+    pub fn set_header_policy(&mut self, policy: HeaderPolicy) {
+        self.header_policy = policy;
+    }
+    
+    /// Set whether files in an old format are upgraded: if true, loading a
+    /// snapshot or log written in a format older than the current one makes
+    /// a snapshot due, thus the next call to `write()` writes the loaded
+    /// state in the current format. Default: false.
+    /// 
+    /// Either way old files can still be read and a warning is logged. Old
+    /// files are not modified or removed; the new snapshot is written as any
+    /// other, thus the upgrade can be reverted by deleting it.
+    pub fn set_upgrade_on_write(&mut self, upgrade: bool) {
+        self.upgrade_on_write = upgrade;
+    }
+    
+    /// Set the clock used to timestamp commits made by `push_state()` and
+    /// merges when no `MakeMeta` is passed (where one is, it assigns the
+    /// timestamp). Default: `SystemClock`.
+    /// 
+    /// A clock may be shared by several partitions.
+    pub fn set_clock(&mut self, clock: Rc<Clock>) {
+        self.clock = clock;
+    }
+    
+    /// Get the oldest file format version (e.g. `2016_08_15`) of the
+    /// snapshots and logs loaded, or `None` if nothing has been read yet.
+    /// 
+    /// Files written are always in the latest format; see
+    /// `set_upgrade_on_write()`.
+    pub fn format_version(&self) -> Option<u32> {
+        self.format_ver
+    }
+    
+    /// True if data was loaded despite checksum mismatches (only possible
+    /// after `set_read_ignoring_checksum(true)`); this data is not verified
+    /// and may be damaged.
+    pub fn is_unverified(&self) -> bool {
+        self.unverified
+    }
+    
     /// Get the repo name.
     /// 
     /// If this partition was created with `create()`, not `new()`, and no
@@ -369,7 +995,7 @@ impl<E: ElementT> Partition<E> {
         }
         for ss in (0 .. self.io.ss_len()).rev() {
             if let Some(mut ssf) = self.io.read_ss(ss)? {
-                let header = read_head(&mut *ssf)?;
+                let header = read_head_with_policy(&mut *ssf, self.sum_type.key(), self.header_policy)?;
                 Self::verify_head(&header, &mut self.repo_name, self.part_id)?;
                 return Ok(&self.repo_name);
             }
@@ -377,6 +1003,56 @@ impl<E: ElementT> Partition<E> {
         return OtherError::err("no snapshot found for first partition");
     }
     
+    /// Get the former repo name recorded by the latest snapshot if this was
+    /// written by `rename()`, otherwise `None`. This reads a snapshot file
+    /// header.
+    /// 
+    /// This is used by `Repository::open()` to recognise an interrupted
+    /// rename.
+    pub fn get_former_repo_name(&self) -> Result<Option<String>> {
+        for ss in (0 .. self.io.ss_len()).rev() {
+            if let Some(mut ssf) = self.io.read_ss(ss)? {
+                let header = read_head_with_policy(&mut *ssf, self.sum_type.key(), self.header_policy)?;
+                return Ok(header.renamed_from);
+            }
+        }
+        Ok(None)
+    }
+    
+    /// Change the repo name. The new name is written to the headers of all
+    /// files written from now on; a snapshot is written immediately so that
+    /// the latest state can be loaded under the new name.
+    /// 
+    /// If the partition is not loaded, the latest state is loaded first
+    /// without checking the name it was saved under. This allows an
+    /// interrupted rename to be completed by calling `rename` again (before
+    /// loading). Historical files keep the old name; the new snapshot records
+    /// the old name, which is then accepted for files older than this
+    /// snapshot (thus history may still be loaded and verified).
+    /// 
+    /// The name is validated as in `create()`. Fails if a merge is required.
+    pub fn rename(&mut self, new_name: &str, mut user: Option<&mut UserFields>)
+            -> Result<()>
+    {
+        validate_repo_name(new_name)?;
+        if !self.is_loaded() {
+            self.repo_name.clear();
+            self.load_latest(user.as_mut().map_or(None, |p| Some(*p)), None)?;
+        }
+        if self.repo_name == new_name {
+            return Ok(());
+        }
+        info!("Partition {}: renaming repository from {} to {}",
+            self.part_id, self.repo_name, new_name);
+        self.write_fast(user.as_mut().map_or(None, |p| Some(*p)))?;
+        let old_name = replace(&mut self.repo_name, new_name.to_string());
+        if let Err(e) = self.write_snapshot_renamed(user, Some(old_name.clone())) {
+            self.repo_name = old_name;
+            return Err(e);
+        }
+        Ok(())
+    }
+    
     /// Load all history. Shortcut for `load_range(0, usize::MAX, user)`.
     // #0040: are we passing too many optional parameters: user & make_meta?
     pub fn load_all(&mut self, user: Option<&mut UserFields>,
@@ -403,12 +1079,131 @@ impl<E: ElementT> Partition<E> {
     /// does not overlap with this range, all snapshots in between will be
     /// loaded.
     /// 
+    /// If the latest snapshot is already loaded and is within the range, only
+    /// commits added to its logs since these were last read are applied; thus
+    /// calling `load_latest()` repeatedly is an efficient way to catch up with
+    /// changes made by another process. Should a log file previously read
+    /// have been rewritten (not only appended to) or removed, all loaded data
+    /// is discarded and the range is loaded from scratch; this fails if there
+    /// are unsaved changes.
+    /// 
     /// The `user` parameter allows any headers read to be examined. If `None`
     /// is passed, these fields are simply ignored.
     // #0040: are we passing too many optional parameters: user & make_meta?
     pub fn load_range(&mut self, ss0: usize, ss1: usize,
-            mut user: Option<&mut UserFields>, make_meta: Option<&MakeMeta>)
+            user: Option<&mut UserFields>, make_meta: Option<&MakeMeta>)
             -> Result<()>
+    {
+        self.load_range_count(ss0, ss1, user, make_meta).map(|_| ())
+    }
+    
+    /// Load the latest state, as `load_latest()`, returning the number of
+    /// commits applied (i.e. commits not previously known).
+    /// 
+    /// When already loaded, this only reads commits added since the last
+    /// load (see `load_range()`), thus is suitable for polling.
+    pub fn catch_up(&mut self, user: Option<&mut UserFields>,
+            make_meta: Option<&MakeMeta>) -> Result<usize>
+    {
+        self.load_range_count(usize::MAX, usize::MAX, user, make_meta)
+    }
+    
+    /// Load the state as of `time`, e.g. for point-in-time queries.
+    /// 
+    /// All history is loaded, then states committed after `time` are
+    /// discarded, leaving the tip at the last commit made at or before `time`.
+    /// Commit timestamps have a resolution of one second (see
+    /// `CommitMeta::timestamp_of()`).
+    /// If no commit is that old, the tip is a new empty state. Should several
+    /// branches exist at this time, a merge is required (`merge_required()`).
+    /// 
+    /// Named branches (see `branch()`) are rewound likewise; those created
+    /// after `time` are not present.
+    /// 
+    /// Loaded data is discarded first; this fails if there are unsaved
+    /// changes. Since later history is discarded, call `unload()` before
+    /// loading the latest state again; new commits made on this tip would
+    /// branch off from history.
+    pub fn load_asof(&mut self, time: SystemTime, user: Option<&mut UserFields>,
+            make_meta: Option<&MakeMeta>) -> Result<()>
+    {
+        let time = CommitMeta::timestamp_of(time);
+        if !self.unload(false) {
+            return OtherError::err("cannot reload partition with unsaved changes");
+        }
+        self.load_all(user, make_meta)?;
+        
+        let newer: Vec<Sum> = self.states.iter()
+                .filter(|state| state.meta().timestamp() > time)
+                .map(|state| state.statesum().clone())
+                .collect();
+        for sum in &newer {
+            self.states.remove(sum);
+        }
+        if self.states.is_empty() {
+            self.states.insert(Arc::new(PartState::new(self.part_id, make_meta)));
+        }
+        
+        // Recalculate tips and ancestors of what remains. Branch states are
+        // not tips, nor do they hide their main-line parents from being tips:
+        let mut parents = HashSet::new();
+        let mut main_parents = HashSet::new();
+        for state in self.states.iter() {
+            parents.extend(state.parents().iter().cloned());
+            if state.meta().branch().is_none() {
+                main_parents.extend(state.parents().iter().cloned());
+            }
+        }
+        let tips = self.states.iter()
+                .filter(|state| state.meta().branch().is_none())
+                .map(|state| state.statesum())
+                .filter(|sum| !main_parents.contains(*sum))
+                .cloned().collect();
+        let ancestors = parents.iter().filter(|sum| !self.states.contains(*sum)).cloned().collect();
+        self.tips = tips;
+        self.ancestors = ancestors;
+        
+        // Rewind each branch to its last remaining commit; drop branches
+        // created after `time`:
+        let mut branch_parents = HashSet::new();
+        for state in self.states.iter() {
+            if let Some(name) = state.meta().branch() {
+                for parent in state.parents() {
+                    branch_parents.insert((name, parent));
+                }
+            }
+        }
+        let mut branches = HashMap::new();
+        for state in self.states.iter() {
+            let name = match state.meta().branch() {
+                Some(name) => name,
+                None => continue,
+            };
+            if branch_parents.contains(&(name, state.statesum())) {
+                continue;   // not the head
+            }
+            let newer = match branches.get(name) {
+                Some(head) => {
+                    let head: &PartState<E> = self.states.get(head).unwrap();
+                    (state.meta().timestamp(), state.meta().number()) >
+                        (head.meta().timestamp(), head.meta().number())
+                },
+                None => true,
+            };
+            if newer {
+                branches.insert(name.to_string(), state.statesum().clone());
+            }
+        }
+        self.branches = branches;
+        info!("Partition {}: loaded state as of {}; {} newer states discarded",
+                self.part_id, time, newer.len());
+        Ok(())
+    }
+    
+    // Implementation of `load_range()`; returns the number of commits applied
+    fn load_range_count(&mut self, ss0: usize, ss1: usize,
+            mut user: Option<&mut UserFields>, make_meta: Option<&MakeMeta>)
+            -> Result<usize>
     {
         // We have to consider several cases: nothing previously loaded, that
         // we're loading data older than what was previously loaded, or newer,
@@ -425,6 +1220,23 @@ impl<E: ElementT> Partition<E> {
         let ss_len = self.io.ss_len();
         let mut ss0 = min(ss0, if ss_len > 0 { ss_len - 1 } else { ss_len });
         let mut ss1 = min(ss1, ss_len);
+        
+        // Sums of commits read, to detect duplicates:
+        let mut seen = HashSet::new();
+        
+        // If the latest snapshot is loaded, apply commits added since:
+        let mut num_commits = 0;
+        if self.ss1 > self.ss0 && ss1 >= self.ss1 {
+            if let Some(queue) = self.read_new_logs(&mut user)? {
+                num_commits += self.apply_commits(queue, &mut seen)?;
+            } else {
+                if !self.unsaved.is_empty() {
+                    return OtherError::err("partition files were rewritten while changes are unsaved");
+                }
+                info!("Partition {}: files were rewritten; reloading", self.part_id);
+                self.unload(true);
+            }
+        }
         // If data is already loaded, we must load snapshots between it and the new range too:
         if self.ss1 > self.ss0 {
             if ss0 > self.ss1 { ss0 = self.ss1; }
@@ -439,24 +1251,38 @@ impl<E: ElementT> Partition<E> {
             // No initial snapshot; assume a blank state
             let state = PartState::new(self.part_id, make_meta);
             self.tips.insert(state.statesum().clone());
-            self.states.insert(state);
+            self.states.insert(Arc::new(state));
         }
         
         let mut require_ss = false;
+        let mut check = self.sum_check();
+        // Settings recorded by the latest file read at the tip
+        let mut config = None;
+        let mut renames = Self::find_renames(&*self.io, self.sum_type.key(), self.header_policy,
+                ss0, &mut self.repo_name)?;
         for ss in ss0..ss1 {
             // If already loaded, skip this snapshot:
             if self.ss0 <= ss && ss < self.ss1 { continue; }
             let at_tip = ss >= self.ss1;
+            // Format versions of files read; noted once readers are dropped
+            let mut vers = vec![];
             
-            if let Some(mut r) = self.io.read_ss(ss)? {
-                let head = read_head(&mut r)?;
-                Self::verify_head(&head, &mut self.repo_name, self.part_id)?;
+            if let Some(r) = self.io.read_ss(ss)? {
+                let mut r = buffered(r, self.read_buf_size);
+                let head = read_head_with_policy(&mut r, self.sum_type.key(), self.header_policy)?;
+                Self::verify_head(&head, Self::name_at(&mut renames, ss, &mut self.repo_name),
+                        self.part_id)?;
                 let file_ver = head.ftype.ver();
+                vers.push(file_ver);
+                if let (true, Some(c)) = (at_tip, head.config) {
+                    config = Some(c);
+                }
                 if let Some(ref mut u) = user {
                     u.read_user_fields(head.user, self.part_id, false);
                 }
                 
-                let state = read_snapshot(&mut r, self.part_id, file_ver)?;
+                let state = read_snapshot_checked(&mut r, self.part_id, file_ver, head.sum_type,
+                        &*self.io, &mut check)?;
                 
                 if !self.ancestors.contains(state.statesum()) {
                     self.tips.insert(state.statesum().clone());
@@ -466,7 +1292,7 @@ impl<E: ElementT> Partition<E> {
                         self.ancestors.insert(parent.clone());
                     }
                 }
-                self.states.insert(state);
+                self.states.insert(Arc::new(state));
                 require_ss = false;
                 if at_tip {
                     // reset snapshot policy
@@ -480,18 +1306,28 @@ impl<E: ElementT> Partition<E> {
             
             let mut queue = vec![];
             for cl in 0..self.io.ss_cl_len(ss) {
-                if let Some(mut r) = self.io.read_ss_cl(ss, cl)? {
-                    let head = read_head(&mut r)?;
-                    Self::verify_head(&head, &mut self.repo_name, self.part_id)?;
+                if let Some(r) = self.io.read_ss_cl(ss, cl)? {
+                    let mut r = TrackingReader::new(buffered(r, self.read_buf_size));
+                    let head = read_head_with_policy(&mut r, self.sum_type.key(), self.header_policy)?;
+                    Self::verify_head(&head, Self::name_at(&mut renames, ss, &mut self.repo_name),
+                            self.part_id)?;
+                    if let (true, Some(c)) = (at_tip, head.config) {
+                        config = Some(c);
+                    }
                     if let Some(ref mut u) = user {
                         u.read_user_fields(head.user, self.part_id, true);
                     }
-                    read_log(&mut r, &mut queue, head.ftype.ver())?;
+                    let (ver, sum_type) = (head.ftype.ver(), head.sum_type);
+                    vers.push(ver);
+                    read_log_checked(&mut r, &mut queue, ver, sum_type, &*self.io, &mut check)?;
+                    self.logs_read.insert((ss, cl), r.log_pos(ver, sum_type));
                 }
             }
-            for commit in queue {
-                self.add_commit(commit)?;
+            for ver in vers {
+                self.note_format_ver(ver);
             }
+            self.unverified |= check.mismatches() > 0;
+            num_commits += self.apply_commits(queue, &mut seen)?;
             if at_tip {
                 self.ss1 = ss + 1;
             }
@@ -505,10 +1341,159 @@ impl<E: ElementT> Partition<E> {
         }
         assert!(self.ss0 <= ss1 && ss1 <= self.ss1);
         
+        if let Some(config) = config {
+            self.apply_config(config);
+        }
         if require_ss {
             self.require_snapshot();
         }
-        Ok(())
+        info!("Partition {}: applied {} commits", self.part_id, num_commits);
+        Ok(num_commits)
+    }
+    
+    // Read commits added to the logs of the latest loaded snapshot since
+    // these were last read. New log files are read in full.
+    // 
+    // Returns `None` if a log previously read has been removed or changed
+    // other than by appending data.
+    fn read_new_logs(&mut self, user: &mut Option<&mut UserFields>)
+            -> Result<Option<Vec<Commit<E>>>>
+    {
+        let ss = self.ss1 - 1;
+        let cl_len = self.io.ss_cl_len(ss);
+        if self.logs_read.keys().any(|&(s, cl)| s == ss && cl >= cl_len) {
+            return Ok(None);
+        }
+        
+        let mut queue = vec![];
+        let mut vers = vec![];
+        let mut config = None;
+        let mut check = self.sum_check();
+        for cl in 0..cl_len {
+            let prev = self.logs_read.get(&(ss, cl)).cloned();
+            let r = match self.io.read_ss_cl(ss, cl)? {
+                Some(r) => r,
+                None => {
+                    if prev.is_some() { return Ok(None); }
+                    continue;
+                },
+            };
+            let mut r = TrackingReader::new(buffered(r, self.read_buf_size));
+            let (ver, sum_type) = if let Some(prev) = prev {
+                // Skip data already read, checking that it is unchanged:
+                io::copy(&mut (&mut r).take(prev.len as u64), &mut io::sink())?;
+                if r.count != prev.len || r.tail != prev.tail {
+                    return Ok(None);
+                }
+                read_log_tail(&mut r, &mut queue, prev.ver, prev.sum_type,
+                        &*self.io, &mut check)?;
+                (prev.ver, prev.sum_type)
+            } else {
+                let head = read_head_with_policy(&mut r, self.sum_type.key(), self.header_policy)?;
+                Self::verify_head(&head, &mut self.repo_name, self.part_id)?;
+                if let Some(c) = head.config {
+                    config = Some(c);
+                }
+                if let Some(ref mut u) = *user {
+                    u.read_user_fields(head.user, self.part_id, true);
+                }
+                let (ver, sum_type) = (head.ftype.ver(), head.sum_type);
+                vers.push(ver);
+                read_log_checked(&mut r, &mut queue, ver, sum_type, &*self.io, &mut check)?;
+                (ver, sum_type)
+            };
+            self.logs_read.insert((ss, cl), r.log_pos(ver, sum_type));
+        }
+        for ver in vers {
+            self.note_format_ver(ver);
+        }
+        if let Some(config) = config {
+            self.apply_config(config);
+        }
+        self.unverified |= check.mismatches() > 0;
+        Ok(Some(queue))
+    }
+    
+    // Add commits (as `add_commit()`), returning the number not already known.
+    // Commits whose sums are in `seen` are duplicates (see
+    // `set_reject_duplicates()`); sums of others are added.
+    // 
+    // Commits are applied in dependency order (see `order_commits()`), not
+    // necessarily the order read. Fails if a commit's parent is not found.
+    fn apply_commits(&mut self, queue: Vec<Commit<E>>, seen: &mut HashSet<Sum>)
+            -> Result<usize>
+    {
+        let mut unique = Vec::with_capacity(queue.len());
+        for commit in queue {
+            if !seen.insert(commit.statesum().clone()) {
+                warn!("Partition {}: commit {} read more than once", self.part_id, commit.statesum());
+                if self.reject_duplicates {
+                    return OtherError::err("duplicate commit found while loading");
+                }
+                continue;
+            }
+            unique.push(commit);
+        }
+        
+        let mut num = 0;
+        for commit in Self::order_commits(unique)? {
+            if !self.states.contains(commit.statesum()) {
+                num += 1;
+            }
+            for parent in commit.parents() {
+                if let Some(p) = self.states.get(parent) {
+                    if commit.meta().timestamp() < p.meta().timestamp() {
+                        warn!("Partition {}: commit {} has a timestamp earlier than its parent {} \
+                            (clock skew?)", self.part_id, commit.statesum(), parent);
+                    }
+                }
+            }
+            if let Err(e) = self.add_commit(commit) {
+                warn!("Partition {}: cannot apply commit while loading: {}", self.part_id, e);
+                return Err(Box::new(e));
+            }
+        }
+        Ok(num)
+    }
+    
+    // Order commits such that each follows those of its parents which are
+    // also in `queue`, thus log files need not be read in the order they
+    // were written. Otherwise the order is unchanged. Sums must be unique.
+    // 
+    // Fails if parent links form a cycle.
+    fn order_commits(queue: Vec<Commit<E>>) -> Result<Vec<Commit<E>>> {
+        let index: HashMap<Sum, usize> = queue.iter().enumerate()
+                .map(|(i, commit)| (commit.statesum().clone(), i))
+                .collect();
+        // For each commit, the number of parents not yet ordered, and children
+        let mut num_deps = vec![0; queue.len()];
+        let mut children = vec![vec![]; queue.len()];
+        for (i, commit) in queue.iter().enumerate() {
+            for parent in commit.parents() {
+                if let Some(&j) = index.get(parent) {
+                    num_deps[i] += 1;
+                    children[j].push(i);
+                }
+            }
+        }
+        
+        let mut ready: BTreeSet<usize> = (0..queue.len()).filter(|&i| num_deps[i] == 0).collect();
+        let mut commits: Vec<_> = queue.into_iter().map(Some).collect();
+        let mut result = Vec::with_capacity(commits.len());
+        while let Some(i) = ready.iter().next().cloned() {
+            ready.remove(&i);
+            for &j in &children[i] {
+                num_deps[j] -= 1;
+                if num_deps[j] == 0 {
+                    ready.insert(j);
+                }
+            }
+            result.push(commits[i].take().expect("commit ordered once"));
+        }
+        if result.len() < commits.len() {
+            return OtherError::err("cycle in commit parents");
+        }
+        Ok(result)
     }
     
     /// Returns true when elements have been loaded (i.e. there is at least one
@@ -517,6 +1502,17 @@ impl<E: ElementT> Partition<E> {
         self.tips.len() > 0
     }
     
+    /// Get an approximation of the memory used by loaded states, in bytes:
+    /// the sum of `PartState::approx_memory_usage()` over tips. Other states
+    /// mostly share elements with these and are not counted. Zero when not
+    /// loaded.
+    pub fn approx_memory_usage(&self) -> usize {
+        self.tips.iter()
+            .filter_map(|sum| self.states.get(sum))
+            .map(|state| state.approx_memory_usage())
+            .sum()
+    }
+    
     /// Returns true when ready for use (this is equivalent to
     /// `self.is_loaded() && !self.merge_required()`, i.e. there is exactly
     /// one tip).
@@ -533,6 +1529,28 @@ impl<E: ElementT> Partition<E> {
         self.tips.len() > 1
     }
     
+    /// Returns true if ready and a snapshot is due, according to either the
+    /// `PartIO`'s snapshot policy (see `write_full()`) or the snapshot
+    /// interval (see `write()`).
+    pub fn want_snapshot(&self) -> bool {
+        self.is_ready() &&
+            (self.snapshot_due || self.io.want_snapshot(self.ss_commits, self.ss_edits))
+    }
+    
+    /// Get statistics on the partition. This summarises files as
+    /// `inspect_with_policy()` does (with the configured checksum key and
+    /// header policy), thus reads file headers.
+    pub fn stats(&self) -> Result<PartStats> {
+        Ok(PartStats {
+            part_id: self.part_id,
+            num_elts: self.tip().ok().map(|tip| tip.num_avail()),
+            num_tips: self.tips.len(),
+            want_snapshot: self.want_snapshot(),
+            num_unsaved: self.unsaved.len(),
+            files: Self::inspect_with_policy(&*self.io, self.sum_type.key(), self.header_policy)?,
+        })
+    }
+    
     /// Verify values in a header match those we expect.
     /// 
     /// This function is called for every file loaded. It does not take self as
@@ -553,32 +1571,162 @@ impl<E: ElementT> Partition<E> {
         Ok(())
     }
     
-    /// Unload data from memory. Note that unless `force == true` the operation
-    /// will fail if any changes have not yet been saved to disk.
-    /// 
-    /// Returns true if data was unloaded, false if not (implies `!force` and 
-    /// that unsaved changes exist).
-    pub fn unload(&mut self, force: bool) -> bool {
+    // Find renames recorded by snapshots after `ss0` (see `rename()`), as
+    // pairs of snapshot number and former name, in order. If `self_name` is
+    // empty it is set to the name given by the last rename.
+    // 
+    // Snapshots whose header cannot be read are skipped; reading them fails
+    // later anyway.
+    fn find_renames(io: &PartIO, sum_key: Option<SumKey>, policy: HeaderPolicy, ss0: usize,
+            self_name: &mut String) -> Result<Vec<(usize, String)>>
+    {
+        let mut renames = vec![];
+        let mut last_name = None;
+        for ss in ss0 + 1 .. io.ss_len() {
+            if let Some(mut r) = io.read_ss(ss)? {
+                if let Ok(head) = read_head_with_policy(&mut r, sum_key, policy) {
+                    if let Some(old_name) = head.renamed_from {
+                        renames.push((ss, old_name));
+                        last_name = Some(head.name);
+                    }
+                }
+            }
+        }
+        if let (true, Some(name)) = (self_name.is_empty(), last_name) {
+            *self_name = name;
+        }
+        Ok(renames)
+    }
+    
+    // Name expected in headers of files of snapshot `ss` (and its logs): the
+    // former name recorded by the first later rename if any, otherwise
+    // `self_name`.
+    fn name_at<'a>(renames: &'a mut [(usize, String)], ss: usize,
+            self_name: &'a mut String) -> &'a mut String
+    {
+        match renames.iter_mut().find(|r| r.0 > ss) {
+            Some(r) => &mut r.1,
+            None => self_name,
+        }
+    }
+    
+    /// Unload data from memory. Note that unless `force == true` the operation
+    /// will fail if any changes have not yet been saved to disk.
+    /// 
+    /// Returns true if data was unloaded, false if not (implies `!force` and 
+    /// that unsaved changes exist).
+    pub fn unload(&mut self, force: bool) -> bool {
         trace!("Unloading partition {} data", self.part_id);
         if force || self.unsaved.is_empty() {
             self.states.clear();
             self.ancestors.clear();
             self.tips.clear();
+            self.unsaved.clear();
+            self.logs_read.clear();
+            self.ss0 = 0;
+            self.ss1 = 0;
+            self.format_ver = None;
+            self.unverified = false;
+            self.branches.clear();
+            self.cur_branch = None;
             true
         } else {
             false
         }
     }
     
+    /// Replace the held `PartIO` with `io`, returning the old one.
+    /// 
+    /// This is for when the partition's files have been moved (e.g. see
+    /// `RepoIO::set_tier()`): `io` must give access to the same files. Loaded
+    /// states and options set on the partition are kept.
+    /// 
+    /// Fails if `io` is for a different partition.
+    pub fn replace_io(&mut self, io: Box<PartIO>) -> Result<Box<PartIO>> {
+        if io.part_id() != self.part_id {
+            return ArgError::err("replace_io: wrong partition identifier");
+        }
+        Ok(replace(&mut self.io, io))
+    }
+    
     /// Consume the `Partition` and return the held `PartIO`.
     /// 
     /// This destroys all states held internally, but states may be cloned
     /// before unwrapping. Since `Element`s are copy-on-write, cloning
     /// shouldn't be too expensive.
+    /// 
+    /// Unsaved commits are discarded; see `into_io()`.
     pub fn unwrap_io(self) -> Box<PartIO> {
         self.io
     }
     
+    /// Write any unsaved commits (as `write_fast()`), then consume the
+    /// `Partition` and return the held `PartIO`.
+    /// 
+    /// On failure the partition and its unsaved commits are lost; to recover
+    /// from write errors, call `write_fast()` before this.
+    pub fn into_io(mut self, user: Option<&mut UserFields>) -> Result<Box<PartIO>> {
+        self.write_fast(user)?;
+        Ok(self.io)
+    }
+    
+    /// Make a second, independent handle on this partition without loading
+    /// its data again: loaded states (which are immutable) are shared between
+    /// the handles and settings are copied.
+    /// 
+    /// Each handle has its own unsaved commits (the clone starts with none)
+    /// and writes its own logs. Commits made via one handle are therefore
+    /// not seen by the other until read from its logs (e.g. by
+    /// `catch_up()`), after which a merge may be required; thus two code
+    /// paths may build divergent states and merge these later.
+    /// 
+    /// Fails if the `PartIO` does not support this (see `PartIO::clone_io()`).
+    pub fn clone_shallow(&self) -> Result<Partition<E>> {
+        let io = match self.io.clone_io() {
+            Some(io) => io,
+            None => return OtherError::err("PartIO does not support clone_io()"),
+        };
+        let mut states = HashIndexed::new();
+        for state in self.states.iter() {
+            states.insert(state.clone());
+        }
+        Ok(Partition {
+            io: io,
+            repo_name: self.repo_name.clone(),
+            part_id: self.part_id,
+            sum_type: self.sum_type,
+            ss0: self.ss0,
+            ss1: self.ss1,
+            ss_commits: self.ss_commits,
+            ss_edits: self.ss_edits,
+            states: states,
+            ancestors: self.ancestors.clone(),
+            tips: self.tips.clone(),
+            unsaved: VecDeque::new(),
+            logs_read: self.logs_read.clone(),
+            max_log_size: self.max_log_size,
+            cur_log: None,
+            snapshot_interval: self.snapshot_interval,
+            snapshot_due: self.snapshot_due,
+            snapshot_dedup: self.snapshot_dedup,
+            read_buf_size: self.read_buf_size,
+            write_buf_size: self.write_buf_size,
+            sum_threads: self.sum_threads,
+            blob_min: self.blob_min,
+            dedup_set: self.dedup_set,
+            blob_min_set: self.blob_min_set,
+            reject_duplicates: self.reject_duplicates,
+            ignore_checksum: self.ignore_checksum,
+            header_policy: self.header_policy,
+            unverified: self.unverified,
+            format_ver: self.format_ver,
+            upgrade_on_write: self.upgrade_on_write,
+            clock: self.clock.clone(),
+            branches: self.branches.clone(),
+            cur_branch: self.cur_branch.clone(),
+        })
+    }
+    
     /// Get the partition's number
     pub fn part_id(&self) -> PartId {
         self.part_id
@@ -596,6 +1744,17 @@ impl<E: ElementT> Partition<E> {
         Ok(&self.states.get(self.tip_key()?).unwrap())
     }
     
+    /// Get a shared handle on the tip state.
+    /// 
+    /// Unlike `tip()`, the result does not borrow the partition: it remains
+    /// valid and unchanged while new commits are pushed and written, so a
+    /// reader may hold it across modifications (see `PartStateRef`).
+    /// 
+    /// Fails when `tip()` fails.
+    pub fn tip_ref(&self) -> result::Result<PartStateRef<E>, TipError> {
+        Ok(self.states.get(self.tip_key()?).unwrap().clone())
+    }
+    
     /// Get the state-sum (key) of the tip. Fails when `tip()` fails.
     pub fn tip_key(&self) -> result::Result<&Sum, TipError> {
         if self.tips.len() == 1 {
@@ -607,6 +1766,36 @@ impl<E: ElementT> Partition<E> {
         }
     }
     
+    /// Check that the latest state on disk is `expected_tip`, for example the
+    /// tip recorded in a manifest (see `Repository::checkpoint()`). The
+    /// latest state is loaded first (as by `load_latest()`; if already
+    /// loaded, only new commits are read).
+    /// 
+    /// Fails with a `TipMismatch` error, giving both sums, if the tip is
+    /// different. Also fails if there are unsaved changes (the tip would not
+    /// be that on disk) or a merge is required.
+    pub fn verify_against(&mut self, expected_tip: &Sum) -> Result<()> {
+        if !self.unsaved.is_empty() {
+            return OtherError::err("cannot verify partition with unsaved changes");
+        }
+        self.load_latest(None, None)?;
+        let found = self.tip_key()?;
+        if found != expected_tip {
+            return TipMismatch::err(self.part_id, expected_tip.clone(), found.clone());
+        }
+        Ok(())
+    }
+    
+    /// Get the number of elements available in the tip (as
+    /// `tip()?.num_avail()`).
+    /// 
+    /// This fails when `tip()` fails, i.e. if no data has been loaded yet or
+    /// if a merge is required, rather than reporting a count which may be
+    /// stale.
+    pub fn num_avail(&self) -> Result<usize> {
+        Ok(self.tip()?.num_avail())
+    }
+    
     /// Get the number of tips.
     pub fn tips_len(&self) -> usize {
         self.tips.len()
@@ -643,7 +1832,92 @@ impl<E: ElementT> Partition<E> {
     /// 
     /// If you want to keep a copy, clone it.
     pub fn state(&self, key: &Sum) -> Option<&PartState<E>> {
-        self.states.get(key)
+        self.states.get(key).map(|s| &**s)
+    }
+    
+    /// Get a shared handle on a state by its statesum, if found.
+    /// 
+    /// See `tip_ref()` and `PartStateRef`.
+    pub fn state_ref(&self, key: &Sum) -> Option<PartStateRef<E>> {
+        self.states.get(key).cloned()
+    }
+    
+    /// Get the state `back` commits before the tip, following first parents
+    /// (thus `state_at_index(0)` is the tip).
+    /// 
+    /// Fails when `tip()` fails or if `back` exceeds the length of loaded
+    /// history.
+    pub fn state_at_index(&self, back: usize) -> Result<&PartState<E>> {
+        let mut state = self.tip()?;
+        for _ in 0..back {
+            state = match state.parents().first().and_then(|p| self.states.get(p)) {
+                Some(parent) => parent,
+                None => return ArgError::err("index exceeds length of loaded history"),
+            };
+        }
+        Ok(state)
+    }
+    
+    /// List pairs of loaded states `(parent, child)` where the child's
+    /// timestamp is earlier than its parent's (see `CommitMeta::timestamp()`),
+    /// in order of parent then child sum.
+    /// 
+    /// Commit timestamps should not decrease along history; such pairs
+    /// usually indicate clock skew between writers, and break the assumptions
+    /// of `load_asof()`. These are also reported as warnings when loading.
+    pub fn timestamp_anomalies(&self) -> Vec<(Sum, Sum)> {
+        let mut result = vec![];
+        for state in self.states.iter() {
+            for parent in state.parents() {
+                if let Some(p) = self.states.get(parent) {
+                    if state.meta().timestamp() < p.meta().timestamp() {
+                        result.push((parent.clone(), state.statesum().clone()));
+                    }
+                }
+            }
+        }
+        result.sort();
+        result
+    }
+    
+    /// Get commits recreating all loaded states which are not `base` or one
+    /// of its ancestors, ordered such that each commit follows its parents.
+    /// 
+    /// Applying these (e.g. via `push_commit()`) to a copy of the partition
+    /// which has state `base` brings that copy up to date with this one.
+    /// Fails if `base` is not loaded or the first parent of some state to
+    /// recreate is not loaded.
+    pub fn commits_since(&self, base: &Sum) -> Result<Vec<Commit<E>>> {
+        if !self.states.contains(base) {
+            return ArgError::err("commits_since: base state not loaded");
+        }
+        let mut known = HashSet::new();
+        let mut next = vec![base];
+        while let Some(key) = next.pop() {
+            if !known.insert(key) { continue; }
+            if let Some(state) = self.states.get(key) {
+                next.extend(state.parents());
+            }
+        }
+        
+        // Commit numbers exceed those of parents; sort by number, then sum
+        // (for reproducible output):
+        let mut states: Vec<&PartState<E>> = self.states.iter()
+                .filter(|state| !known.contains(state.statesum()))
+                .map(|state| &**state)
+                .collect();
+        states.sort_by(|a, b| (a.meta().number(), a.statesum())
+                .cmp(&(b.meta().number(), b.statesum())));
+        
+        let mut commits = Vec::with_capacity(states.len());
+        for state in states {
+            let parent = match self.states.get(&state.parents()[0]) {
+                Some(parent) => parent,
+                None => return OtherError::err("commits_since: parent state not loaded"),
+            };
+            commits.push(Commit::from_state(parent, state));
+        }
+        Ok(commits)
     }
     
     /// Try to find a state given a string representation of the key (as a byte array).
@@ -680,6 +1954,27 @@ impl<E: ElementT> Partition<E> {
     /// to find a common ancestor.
     pub fn merge<S: TwoWaySolver<E>>(&mut self, solver: &S, auto_load: bool,
         make_meta: Option<&MakeMeta>) -> Result<()>
+    {
+        self.merge_impl(|merger| merger.solve(solver), auto_load, make_meta)
+    }
+    
+    /// Merge all latest states into a single tip, resolving conflicts with
+    /// a closure instead of a `TwoWaySolver`.
+    /// 
+    /// This is like `merge()`, except that each conflict is passed to `f`,
+    /// which must return `Resolution::TakeA`, `Resolution::TakeB` or a custom
+    /// element (see `TwoWayMerge::solve_with()`). Which tip is "A" and which
+    /// is "B" is determined by sorting the tips' state-sums.
+    pub fn merge_with<F>(&mut self, mut f: F, auto_load: bool,
+        make_meta: Option<&MakeMeta>) -> Result<()>
+        where F: FnMut(&Conflict<E>) -> Resolution<E>
+    {
+        self.merge_impl(|merger| merger.solve_with(&mut f), auto_load, make_meta)
+    }
+    
+    fn merge_impl<F>(&mut self, mut solve: F, auto_load: bool,
+        make_meta: Option<&MakeMeta>) -> Result<()>
+        where F: FnMut(&mut TwoWayMerge<E>)
     {
         while self.tips.len() > 1 {
             let (tip1, tip2): (Sum, Sum) = {
@@ -689,13 +1984,22 @@ impl<E: ElementT> Partition<E> {
                 (tips[0].clone(), tips[1].clone())
             };
             trace!("Partition {}: attempting merge of tips {} and {}", self.part_id, &tip1, &tip2);
-            let c = self.merge_two(&tip1, &tip2, auto_load)?
-                    .solve_inline(solver).make_commit(make_meta);
+            let part_id = self.part_id;
+            let clock = self.clock.clone();
+            let c = {
+                let clock_meta = ClockMeta(&*clock);
+                let mut merger = self.merge_two(&tip1, &tip2, auto_load)?;
+                info!("Partition {}: merging tips with {} conflicting element(s)",
+                        part_id, merger.len());
+                solve(&mut merger);
+                merger.make_commit(Some(make_meta.unwrap_or(&clock_meta)))
+            };
             if let Some(commit) = c {
                 trace!("Pushing merge commit: {} ({} changes)",
                         commit.statesum(), commit.num_changes());
                 self.push_commit(commit)?;
             } else {
+                warn!("Partition {}: merge solver did not resolve all conflicts", part_id);
                 return Err(Box::new(MergeError::NotSolved));
             }
         }
@@ -782,13 +2086,21 @@ impl<E: ElementT> Partition<E> {
     /// Mutates the commit in the (very unlikely) case that its statesum
     /// clashes with another commit whose data is different.
     /// 
+    /// If a branch is checked out (see `checkout()`), the commit is made on
+    /// that branch: its head is updated instead of the tip.
+    /// 
     /// Returns `Ok(true)` on success, or `Ok(false)` if the state matches its
     /// parent (i.e. hasn't been changed) or another already known state.
-    pub fn push_state(&mut self, state: MutPartState<E>,
+    pub fn push_state(&mut self, mut state: MutPartState<E>,
             make_meta: Option<&MakeMeta>) -> Result<bool, PatchOp>
     {
+        // Name length was checked by branch(), thus this cannot fail:
+        state.meta_mut().set_branch(self.cur_branch.clone()).expect("valid branch name");
         let parent_sum = state.parent().clone();
-        let new_state = PartState::from_mut(state, make_meta);
+        let new_state = {
+            let clock_meta = ClockMeta(&*self.clock);
+            PartState::from_mut(state, Some(make_meta.unwrap_or(&clock_meta)))
+        };
         
         // #0019: Commit::from_diff compares old and new states and code be slow.
         // #0019: Instead, we could record each alteration as it happens.
@@ -797,19 +2109,210 @@ impl<E: ElementT> Partition<E> {
                     self.states.get(&parent_sum).ok_or(PatchOp::NoParent)?,
                     &new_state)
             {
-                self.add_pair(commit, new_state)
+                let added = self.add_pair(commit, new_state);
+                if self.snapshot_interval > 0 && self.ss_commits >= self.snapshot_interval {
+                    self.snapshot_due = true;
+                }
+                added
             } else {
                 false
             }
         )
     }
     
+    /// Get the state at the head of the branch checked out, or the tip if on
+    /// the main line (see `checkout()`). Fails when `tip()` fails on the
+    /// main line or if no data is loaded.
+    pub fn head(&self) -> result::Result<&PartState<E>, TipError> {
+        Ok(&self.states.get(self.head_key()?).unwrap())
+    }
+    
+    /// Get the state-sum (key) of `head()`.
+    pub fn head_key(&self) -> result::Result<&Sum, TipError> {
+        match self.cur_branch {
+            Some(ref name) => self.branches.get(name).ok_or(TipError::NotReady),
+            None => self.tip_key(),
+        }
+    }
+    
+    /// Get the heads of named branches, by name. Branch heads are states but
+    /// not tips.
+    pub fn branches(&self) -> &HashMap<String, Sum> {
+        &self.branches
+    }
+    
+    /// Get the name of the branch checked out, or `None` for the main line.
+    pub fn current_branch(&self) -> Option<&str> {
+        self.cur_branch.as_ref().map(|b| b.as_str())
+    }
+    
+    /// Create a branch called `name`: an alternate line of history starting
+    /// from `head()`. This does not check the branch out (see `checkout()`).
+    /// 
+    /// Commits on a branch are stored in the commit logs like others, marked
+    /// with the branch name (see `CommitMeta::branch()`); creating a branch
+    /// adds an empty commit. Branches thus persist, but a snapshot only holds
+    /// the tip; when one is written, each branch head is carried over to the
+    /// new log as a commit on top of the snapshotted tip.
+    /// 
+    /// Fails if no head is available, the name is empty or longer than
+    /// `BRANCH_NAME_MAX` bytes or a branch of this name exists.
+    pub fn branch(&mut self, name: &str) -> Result<()> {
+        if name.is_empty() || name.len() > BRANCH_NAME_MAX {
+            return ArgError::err("invalid branch name");
+        }
+        if self.branches.contains_key(name) {
+            return ArgError::err("branch already exists");
+        }
+        let base = self.head_key()?.clone();
+        let mut state = self.states.get(&base).unwrap().clone_mut();
+        state.meta_mut().set_branch(Some(name.to_string()))?;
+        let state = {
+            let clock_meta = ClockMeta(&*self.clock);
+            PartState::from_mut(state, Some(&clock_meta))
+        };
+        self.add_branch_state(base, state);
+        info!("Partition {}: created branch {}", self.part_id, name);
+        Ok(())
+    }
+    
+    /// Check out a branch, or the main line if `name` is `None`. Subsequent
+    /// calls to `push_state()` commit to this branch.
+    /// 
+    /// Fails if no branch of this name exists.
+    pub fn checkout(&mut self, name: Option<&str>) -> Result<()> {
+        if let Some(name) = name {
+            if !self.branches.contains_key(name) {
+                return ArgError::err("no such branch");
+            }
+        }
+        self.cur_branch = name.map(|n| n.to_string());
+        Ok(())
+    }
+    
+    /// Merge the head of branch `name` into the tip of the main line. The
+    /// branch is left unchanged (further commits may be made on it).
+    /// 
+    /// Fails if the branch does not exist, the main line requires a merge or
+    /// the solver does not resolve all conflicts.
+    pub fn merge_branch<S: TwoWaySolver<E>>(&mut self, name: &str, solver: &S,
+        make_meta: Option<&MakeMeta>) -> Result<()>
+    {
+        let head = match self.branches.get(name) {
+            Some(sum) => sum.clone(),
+            None => return ArgError::err("no such branch"),
+        };
+        let tip = self.tip_key()?.clone();
+        let clock = self.clock.clone();
+        let c = {
+            let clock_meta = ClockMeta(&*clock);
+            let mut merger = self.merge_two(&tip, &head, true)?;
+            merger.solve(solver);
+            merger.make_commit(Some(make_meta.unwrap_or(&clock_meta)))
+        };
+        if let Some(commit) = c {
+            self.push_commit(commit)?;
+            Ok(())
+        } else {
+            Err(Box::new(MergeError::NotSolved))
+        }
+    }
+    
+    /// Replace the commits from `from` to `to` (inclusive) with a single
+    /// commit showing only the net effect. The new state has the elements
+    /// and metadata of `to` and the parent of `from`; intermediate states are
+    /// discarded. Returns the new state's sum (this differs from `to`'s since
+    /// the parent differs).
+    /// 
+    /// Fails unless the range is a linear chain: each state from `to` back to
+    /// `from` must have a single parent, `to` must be a tip and other states
+    /// in the range must have no other successors.
+    /// 
+    /// Only unsaved commits may be squashed (see `unsaved_len()`); this fails
+    /// if any commit in the range has been written. Commits cannot be removed
+    /// from logs once written, and loading history would restore them beside
+    /// the squashed commit. To discard written history use `compact()`.
+    pub fn squash(&mut self, from: Sum, to: Sum) -> Result<Sum> {
+        // Collect the range, newest first, and find the parent of `from`:
+        let mut chain = vec![];
+        let mut key = to;
+        let base = loop {
+            let parent = {
+                let state = self.states.get(&key)
+                        .ok_or(ArgError::new("squash: state not found"))?;
+                if state.parents().len() != 1 {
+                    return ArgError::err("squash: range is not a linear chain");
+                }
+                state.parents()[0].clone()
+            };
+            let done = key == from;
+            chain.push(key);
+            if done { break parent; }
+            key = parent;
+        };
+        
+        if !self.tips.contains(&chain[0]) {
+            return ArgError::err("squash: last state is not a tip");
+        }
+        if !self.states.contains(&base) {
+            return ArgError::err("squash: parent of first state not loaded");
+        }
+        for state in self.states.iter() {
+            for parent in state.parents() {
+                if chain[1..].contains(parent) && !chain.contains(state.statesum()) {
+                    return ArgError::err("squash: range is not a linear chain");
+                }
+            }
+        }
+        {
+            let unsaved: HashSet<&Sum> = self.unsaved.iter().map(|c| c.statesum()).collect();
+            if chain.iter().any(|key| !unsaved.contains(key)) {
+                return ArgError::err("squash: range includes commits already written");
+            }
+        }
+        if chain.len() == 1 {
+            // Nothing to squash
+            return Ok(chain.pop().unwrap());
+        }
+        
+        let new_state = {
+            let last = self.states.get(&chain[0]).unwrap();
+            PartState::new_explicit(self.part_id, vec![base.clone()],
+                    last.elts_iter().map(|(id, elt)| (id, elt.clone())).collect(),
+                    last.moved_iter().collect(),
+                    last.meta().clone(), last.statesum() ^ &last.metasum())
+        };
+        let commit = Commit::from_diff(self.states.get(&base).unwrap(), &new_state)
+            .unwrap_or_else(|| Commit::new_explicit(new_state.statesum().clone(),
+                    vec![base.clone()], HashMap::new(), new_state.meta().clone()));
+        
+        for key in &chain {
+            self.states.remove(key);
+            self.tips.remove(key);
+        }
+        self.unsaved.retain(|commit| !chain.contains(commit.statesum()));
+        
+        let mut key = new_state.statesum().clone();
+        if self.add_pair(commit, new_state) {
+            // add_pair may have mutated the sum
+            key = self.unsaved.back().expect("unsaved commit").statesum().clone();
+        }
+        info!("Partition {}: squashed {} commits into {}", self.part_id, chain.len(), key);
+        Ok(key)
+    }
+    
     /// The number of commits waiting to be written to permanent storage by
     /// the `write(...)` function.
     pub fn unsaved_len(&self) -> usize {
         self.unsaved.len()
     }
     
+    /// Iterate over the commits waiting to be written to permanent storage,
+    /// oldest first.
+    pub fn unsaved_iter(&self) -> vec_deque::Iter<Commit<E>> {
+        self.unsaved.iter()
+    }
+    
     /// Require that a snapshot be written the next time `write_full` is called.
     /// (This property is not persisted across save/load.)
     pub fn require_snapshot(&mut self) {
@@ -835,31 +2338,58 @@ impl<E: ElementT> Partition<E> {
         }
         
         let part_id = self.part_id;
+        let num_commits = self.unsaved.len();
         trace!("Partition {}: writing {} commits to log",
-            part_id, self.unsaved.len());
+            part_id, num_commits);
         
-        // #0012: extend existing logs instead of always writing a new log file.
-        let mut cl_num = self.io.ss_cl_len(self.ss1 - 1);
+        // Blobs must be stored before commits referring to them:
+        Self::write_blobs(&mut *self.io, self.blob_min, self.unsaved.iter()
+                .flat_map(|commit| commit.changes_iter().filter_map(|(_, c)| c.element())))?;
+        
+        // Append to the log last written if not too large and its header
+        // declares the current checksum type. If the last write failed the
+        // log may be damaged, so `take()` ensures we start a new one.
+        let ss_num = self.ss1 - 1;
+        if let Some((ss, cl_num, len, sum_type)) = self.cur_log.take() {
+            if ss == ss_num && len < self.max_log_size && sum_type == self.sum_type {
+                if let Some(writer) = self.io.append_ss_cl(ss_num, cl_num)? {
+                    let mut writer = CountingWriter::new(writer);
+                    Self::write_commits(&mut self.unsaved, &mut writer, self.sum_type,
+                            self.blob_min, self.write_buf_size)?;
+                    
+                    info!("Partition {}: appended {} commits to log {} ({} bytes)",
+                        part_id, num_commits, cl_num, writer.count());
+                    self.cur_log = Some((ss, cl_num, len + writer.count(), sum_type));
+                    return Ok(true);
+                }
+            }
+        }
+        
+        let config = self.config();
+        let mut cl_num = self.io.ss_cl_len(ss_num);
         loop {
-            if let Some(mut writer) = self.io.new_ss_cl(self.ss1 - 1, cl_num)? {
+            if let Some(writer) = self.io.new_ss_cl(ss_num, cl_num)? {
+                let mut writer = CountingWriter::new(writer);
                 // Write a header since this is a new file:
                 let header = FileHeader {
                     ftype: FileType::CommitLog(0),
                     name: self.repo_name.clone(),
                     part_id: Some(part_id),
                     user: user.as_mut().map_or(vec![], |u| u.write_user_fields(part_id, true)),
+                    config: Some(config),
+                    renamed_from: None,
+                    sum_type: self.sum_type,
                 };
                 write_head(&header, &mut writer)?;
                 start_log(&mut writer)?;
                 
                 // Now write commits:
-                while !self.unsaved.is_empty() {
-                    // We try to write the commit, then when successful remove it
-                    // from the list of 'unsaved' commits.
-                    write_commit(&self.unsaved.front().unwrap(), &mut writer)?;
-                    self.unsaved.pop_front().expect("pop_front");
-                }
+                Self::write_commits(&mut self.unsaved, &mut writer, self.sum_type,
+                        self.blob_min, self.write_buf_size)?;
                 
+                info!("Partition {}: wrote {} commits to log {} ({} bytes)",
+                    part_id, num_commits, cl_num, writer.count());
+                self.cur_log = Some((ss_num, cl_num, writer.count(), self.sum_type));
                 return Ok(true);
             } else {
                 // Log file already exists! So try another number.
@@ -872,6 +2402,22 @@ impl<E: ElementT> Partition<E> {
         }
     }
     
+    /// Write all unsaved commits to a log on the disk, then write a snapshot
+    /// if one is due according to the snapshot interval (see
+    /// `set_snapshot_interval()`).
+    /// 
+    /// `user` allows extra data to be written to file headers.
+    /// 
+    /// Returns true if a snapshot was written.
+    pub fn write(&mut self, mut user: Option<&mut UserFields>) -> Result<bool> {
+        self.write_fast(user.as_mut().map_or(None, |p| Some(*p)))?;
+        if self.snapshot_due && self.is_ready() {
+            self.write_snapshot(user)?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+    
     /// This will write all unsaved commits to a log on the disk, then write a
     /// snapshot if needed.
     /// 
@@ -903,44 +2449,407 @@ impl<E: ElementT> Partition<E> {
     /// 
     /// `user` allows extra data to be written to file headers.
     pub fn write_snapshot(&mut self, user: Option<&mut UserFields>) -> Result<()> {
+        self.write_snapshot_renamed(user, None)
+    }
+    
+    // Implementation of `write_snapshot()`; `renamed_from` is recorded in the
+    // header (see `rename()`)
+    fn write_snapshot_renamed(&mut self, mut user: Option<&mut UserFields>,
+            renamed_from: Option<String>) -> Result<()>
+    {
         // fail early if not ready:
         let tip_key = self.tip_key()?.clone();
         let part_id = self.part_id;
+        Self::write_blobs(&mut *self.io, self.blob_min,
+                self.states.get(&tip_key).unwrap().elts_iter().map(|(_, elt)| elt))?;
         
-        let mut ss_num = self.ss1;
-        loop {
-            // Try to get a writer for this snapshot number:
-            if let Some(mut writer) = self.io.new_ss(ss_num)? {
-                info!("Partition {}: writing snapshot {}: {}",
-                    part_id, ss_num, tip_key);
-                
-                let header = FileHeader {
-                    ftype: FileType::Snapshot(0),
-                    name: self.repo_name.clone(),
-                    part_id: Some(part_id),
-                    user: user.map_or(vec![], |u| u.write_user_fields(part_id, false)),
+        let header = FileHeader {
+            ftype: FileType::Snapshot(0),
+            name: self.repo_name.clone(),
+            part_id: Some(part_id),
+            user: user.as_mut().map_or(vec![], |u| u.write_user_fields(part_id, false)),
+            config: Some(self.config()),
+            renamed_from: renamed_from,
+            sum_type: self.sum_type,
+        };
+        {
+            let state = self.states.get(&tip_key).unwrap();
+            let (dedup, blob_min, threads) = (self.snapshot_dedup, self.blob_min, self.sum_threads);
+            let buf_size = self.write_buf_size;
+            let mut ss_num = self.ss1;
+            loop {
+                // Try to write a snapshot with this number:
+                let written = Self::write_ss_file(&mut *self.io, ss_num, buf_size, |writer| {
+                    write_head(&header, writer)?;
+                    write_snapshot_dedup(state, writer, header.sum_type, dedup, blob_min, threads)
+                })?;
+                if let Some(count) = written {
+                    info!("Partition {}: wrote snapshot {}: {} ({} bytes)",
+                        part_id, ss_num, tip_key, count);
+                    self.ss1 = ss_num + 1;
+                    // reset snapshot policy:
+                    self.ss_commits = 0;
+                    self.ss_edits = 0;
+                    self.snapshot_due = false;
+                    break;
+                } else {
+                    // Snapshot file already exists! So try another number.
+                    if ss_num > 1000_000 {
+                        // We should give up eventually. When is arbitrary.
+                        return Err(Box::new(OtherError::new("Snapshot number too high")));
+                    }
+                    ss_num += 1;
+                }
+            }
+        }
+        
+        if !self.branches.is_empty() {
+            self.carry_branches(&tip_key);
+            self.write_fast(user)?;
+        }
+        Ok(())
+    }
+    
+    /// Total size of the commit logs of the latest snapshot, in bytes: the
+    /// log growth since the last snapshot was written.
+    pub fn log_bytes(&self) -> Result<u64> {
+        let ss_len = self.io.ss_len();
+        if ss_len == 0 {
+            return Ok(0);
+        }
+        let mut bytes = 0;
+        for cl in 0..self.io.ss_cl_len(ss_len - 1) {
+            bytes += self.io.ss_cl_size(ss_len - 1, cl)?.unwrap_or(0);
+        }
+        Ok(bytes)
+    }
+    
+    /// Write a snapshot of the tip, then delete all older snapshots and
+    /// commit logs (see `PartIO::remove_ss()`). History before the new
+    /// snapshot can no longer be loaded afterwards, though states already
+    /// loaded remain available.
+    /// 
+    /// Fails if there are unsaved changes or `tip()` fails; nothing is
+    /// deleted unless the snapshot is written successfully.
+    /// 
+    /// `user` allows extra data to be written to file headers.
+    pub fn compact(&mut self, user: Option<&mut UserFields>) -> Result<CompactOutcome> {
+        if !self.unsaved.is_empty() {
+            return OtherError::err("cannot compact partition with unsaved changes");
+        }
+        self.tip_key()?;
+        self.write_snapshot(user)?;
+        let snapshot = self.ss1 - 1;
+        
+        let mut outcome = CompactOutcome {
+            snapshot: snapshot,
+            files_removed: 0,
+            bytes_reclaimed: 0,
+        };
+        for ss in 0..snapshot {
+            for cl in 0..self.io.ss_cl_len(ss) {
+                let size = self.io.ss_cl_size(ss, cl)?.unwrap_or(0);
+                if self.io.remove_ss_cl(ss, cl)? {
+                    outcome.files_removed += 1;
+                    outcome.bytes_reclaimed += size;
+                }
+            }
+            let size = self.io.ss_size(ss)?.unwrap_or(0);
+            if self.io.remove_ss(ss)? {
+                outcome.files_removed += 1;
+                outcome.bytes_reclaimed += size;
+            }
+        }
+        self.ss0 = snapshot;
+        self.logs_read.retain(|&(ss, _), _| ss >= snapshot);
+        info!("Partition {}: compacted to snapshot {}; removed {} files ({} bytes)",
+                self.part_id, snapshot, outcome.files_removed, outcome.bytes_reclaimed);
+        Ok(outcome)
+    }
+    
+    /// Read every snapshot and commit log file of the partition, checking
+    /// headers and checksums, and report any which are damaged.
+    /// 
+    /// Loaded data is not touched (files are read independently of it), thus
+    /// this may be called at any time. Failure to access files is returned
+    /// as an error; damage is reported. See `repair()`.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let (sum_key, policy) = (self.sum_type.key(), self.header_policy);
+        let mut repo_name = self.repo_name.clone();
+        let mut renames = Self::find_renames(&*self.io, sum_key, policy, 0, &mut repo_name)?;
+        let mut report = VerifyReport {
+            part_id: self.part_id,
+            files_ok: 0,
+            damaged: vec![],
+        };
+        for ss in 0..self.io.ss_len() {
+            if let Some(r) = self.io.read_ss(ss)? {
+                match Self::read_ss_file(&mut buffered(r, self.read_buf_size), &*self.io,
+                        sum_key, policy, Self::name_at(&mut renames, ss, &mut repo_name), self.part_id) {
+                    Ok(_) => report.files_ok += 1,
+                    Err(e) => report.damaged.push((ss, None, e.to_string())),
+                }
+            }
+            for cl in 0..self.io.ss_cl_len(ss) {
+                if let Some(r) = self.io.read_ss_cl(ss, cl)? {
+                    let mut queue = vec![];
+                    match Self::read_cl_file(&mut buffered(r, self.read_buf_size), &*self.io,
+                            &mut queue, sum_key, policy, Self::name_at(&mut renames, ss, &mut repo_name),
+                            self.part_id) {
+                        Ok(_) => report.files_ok += 1,
+                        Err(e) => report.damaged.push((ss, Some(cl), e.to_string())),
+                    }
+                }
+            }
+        }
+        if !report.is_ok() {
+            warn!("Partition {}: found {} damaged files", self.part_id, report.damaged.len());
+        }
+        Ok(report)
+    }
+    
+    /// Recover whatever can be read from the partition's files, write a new
+    /// snapshot, then quarantine unreadable files (see
+    /// `PartIO::quarantine_ss()`). Afterwards, the partition's latest state
+    /// loads without error.
+    /// 
+    /// Loaded data is discarded first; this fails if there are unsaved
+    /// changes. The latest readable snapshot is loaded, then every commit
+    /// which can be read from its logs and those of later snapshots is
+    /// applied (reading a damaged log stops at the first bad commit).
+    /// 
+    /// The recovered data must have a single tip. If not, an error is
+    /// returned without writing or quarantining anything; the partition is
+    /// left loaded, thus one may merge, write, then repair again.
+    /// 
+    /// `user` allows header user fields to be read and written.
+    pub fn repair(&mut self, mut user: Option<&mut UserFields>) -> Result<RepairReport> {
+        if !self.unsaved.is_empty() {
+            return OtherError::err("cannot repair partition with unsaved changes");
+        }
+        self.unload(true);
+        let (sum_key, policy) = (self.sum_type.key(), self.header_policy);
+        let mut damaged = vec![];
+        let mut renames = Self::find_renames(&*self.io, sum_key, policy, 0, &mut self.repo_name)?;
+        
+        // Load the latest readable snapshot:
+        let ss_len = self.io.ss_len();
+        let mut ss0 = None;
+        for ss in (0..ss_len).rev() {
+            let result = match self.io.read_ss(ss)? {
+                Some(r) => Self::read_ss_file(&mut buffered(r, self.read_buf_size), &*self.io, sum_key,
+                        policy, Self::name_at(&mut renames, ss, &mut self.repo_name), self.part_id),
+                None => continue,
+            };
+            match result {
+                Ok((head_user, state)) => {
+                    if let Some(ref mut u) = user {
+                        u.read_user_fields(head_user, self.part_id, false);
+                    }
+                    self.tips.insert(state.statesum().clone());
+                    for parent in state.parents() {
+                        self.ancestors.insert(parent.clone());
+                    }
+                    self.states.insert(Arc::new(state));
+                    ss0 = Some(ss);
+                    break;
+                },
+                Err(e) => {
+                    warn!("Partition {}: snapshot {} is unreadable: {}", self.part_id, ss, e);
+                    damaged.push((ss, None));
+                },
+            }
+        }
+        let ss0 = match ss0 {
+            Some(ss) => ss,
+            None => return OtherError::err("no readable snapshot"),
+        };
+        
+        // Apply all commits which can be read:
+        let mut recovered = 0;
+        let mut dropped = 0;
+        for ss in ss0..ss_len {
+            for cl in 0..self.io.ss_cl_len(ss) {
+                let mut queue = vec![];
+                let result = match self.io.read_ss_cl(ss, cl)? {
+                    Some(r) => Self::read_cl_file(&mut buffered(r, self.read_buf_size),
+                            &*self.io, &mut queue, sum_key, policy,
+                            Self::name_at(&mut renames, ss, &mut self.repo_name), self.part_id),
+                    None => continue,
                 };
-                write_head(&header, &mut writer)?;
-                write_snapshot(self.states.get(&tip_key).unwrap(), &mut writer)?;
-                self.ss1 = ss_num + 1;
-                // reset snapshot policy:
-                self.ss_commits = 0;
-                self.ss_edits = 0;
-                return Ok(())
-            } else {
-                // Snapshot file already exists! So try another number.
-                if ss_num > 1000_000 {
-                    // We should give up eventually. When is arbitrary.
-                    return Err(Box::new(OtherError::new("Snapshot number too high")));
+                match result {
+                    Ok(head_user) => {
+                        if let Some(ref mut u) = user {
+                            u.read_user_fields(head_user, self.part_id, true);
+                        }
+                    },
+                    Err(e) => {
+                        warn!("Partition {}: log {} of snapshot {} is damaged ({} commits readable): {}",
+                                self.part_id, cl, ss, queue.len(), e);
+                        damaged.push((ss, Some(cl)));
+                    },
+                }
+                for commit in queue {
+                    let is_new = !self.states.contains(commit.statesum());
+                    match self.add_commit(commit) {
+                        Ok(()) => if is_new { recovered += 1; },
+                        Err(e) => {
+                            warn!("Partition {}: dropping commit: {}", self.part_id, e);
+                            dropped += 1;
+                        },
+                    }
                 }
-                ss_num += 1;
             }
         }
+        self.ss0 = ss0;
+        self.ss1 = ss_len;
+        
+        // Fail before writing anything if there is not a single tip:
+        self.tip_key()?;
+        self.write_snapshot(user)?;
+        let mut quarantined = vec![];
+        for (ss, cl) in damaged {
+            let name = match cl {
+                None => self.io.quarantine_ss(ss)?,
+                Some(cl) => self.io.quarantine_ss_cl(ss, cl)?,
+            };
+            if let Some(name) = name {
+                quarantined.push(name);
+            }
+        }
+        info!("Partition {}: repaired; recovered {} commits, quarantined {} files",
+                self.part_id, recovered, quarantined.len());
+        Ok(RepairReport {
+            commits_recovered: recovered,
+            commits_dropped: dropped,
+            snapshot: self.ss1 - 1,
+            quarantined: quarantined,
+        })
     }
 }
 
 // Internal support functions
 impl<E: ElementT> Partition<E> {
+    // Record the format version of a file being loaded, warning if old (see
+    // `set_upgrade_on_write()`)
+    fn note_format_ver(&mut self, ver: u32) {
+        self.format_ver = Some(self.format_ver.map_or(ver, |v| min(v, ver)));
+        if ver < HEAD_VERSIONS[HEAD_VERSIONS.len() - 1] {
+            warn!("Partition {}: reading file of old format version {}", self.part_id, ver);
+            if self.upgrade_on_write {
+                self.snapshot_due = true;
+            }
+        }
+    }
+    
+    // Checksum verification used when loading (see `set_read_ignoring_checksum()`)
+    fn sum_check(&self) -> SumCheck {
+        if self.ignore_checksum { SumCheck::best_effort() } else { SumCheck::strict() }
+    }
+    
+    // Read a snapshot file, returning header user fields and the state
+    fn read_ss_file(r: &mut Read, blobs: &PartIO, sum_key: Option<SumKey>, policy: HeaderPolicy,
+            self_name: &mut String, self_partid: PartId) -> Result<(Vec<UserData>, PartState<E>)>
+    {
+        let head = read_head_with_policy(r, sum_key, policy)?;
+        Self::verify_head(&head, self_name, self_partid)?;
+        let state = read_snapshot(r, self_partid, head.ftype.ver(), head.sum_type, blobs)?;
+        Ok((head.user, state))
+    }
+    
+    // Read a log file, returning header user fields. Commits are pushed to
+    // `queue` as read, thus on failure those read before the error remain.
+    fn read_cl_file(r: &mut Read, blobs: &PartIO, queue: &mut Vec<Commit<E>>,
+            sum_key: Option<SumKey>, policy: HeaderPolicy, self_name: &mut String,
+            self_partid: PartId) -> Result<Vec<UserData>>
+    {
+        let head = read_head_with_policy(r, sum_key, policy)?;
+        Self::verify_head(&head, self_name, self_partid)?;
+        read_log(r, queue, head.ftype.ver(), head.sum_type, blobs)?;
+        Ok(head.user)
+    }
+    
+    // Write `unsaved` commits to `writer`, serialising these to a buffer
+    // which is written whenever it reaches `buf_size` bytes. Commits are
+    // removed from `unsaved` once written, thus on failure those remaining
+    // can be retried.
+    fn write_commits(unsaved: &mut VecDeque<Commit<E>>, writer: &mut Write,
+            sum_type: SumType, blob_min: Option<usize>, buf_size: usize) -> Result<()>
+    {
+        let mut buf = Vec::with_capacity(buf_size);
+        let mut pending = 0;
+        while pending < unsaved.len() {
+            write_commit(&unsaved[pending], &mut buf, sum_type, blob_min)?;
+            pending += 1;
+            if buf.len() >= buf_size || pending == unsaved.len() {
+                writer.write_all(&buf)?;
+                buf.clear();
+                unsaved.drain(..pending);
+                pending = 0;
+            }
+        }
+        Ok(())
+    }
+    
+    // Store the data of those elements at least `blob_min` bytes in size as
+    // blobs (see `set_blob_threshold()`)
+    fn write_blobs<'b, I>(io: &mut PartIO, blob_min: Option<usize>, elts: I) -> Result<()>
+        where I: Iterator<Item = &'b Arc<E>>, E: 'b
+    {
+        let blob_min = match blob_min {
+            Some(min) => min,
+            None => return Ok(()),
+        };
+        let mut buf = Vec::new();
+        for elt in elts {
+            buf.clear();
+            elt.write_buf(&mut &mut buf)?;
+            if buf.len() >= blob_min {
+                io.write_blob(&blob_key(&buf), &buf)?;
+            }
+        }
+        Ok(())
+    }
+    
+    // Write snapshot number `ss_num` via `io`, using `write` to write the
+    // contents through a buffer of `buf_size` bytes. Returns the number of
+    // bytes written, or `None` if this snapshot already exists.
+    // 
+    // The snapshot is only finished (see `PartIO::finish_ss()`) once
+    // completely written and flushed; on failure it is discarded, so that no
+    // partial snapshot is left behind.
+    fn write_ss_file<F>(io: &mut PartIO, ss_num: usize, buf_size: usize, write: F)
+            -> Result<Option<usize>>
+        where F: FnOnce(&mut Write) -> Result<()>
+    {
+        let result = match io.new_ss(ss_num)? {
+            Some(writer) => {
+                let mut writer = CountingWriter::new(buffered_writer(writer, buf_size));
+                let write_all = move || -> Result<usize> {
+                    write(&mut writer)?;
+                    // Dropping the buffer would ignore errors; flush explicitly:
+                    writer.flush()?;
+                    Ok(writer.count())
+                };
+                write_all()
+            },
+            None => return Ok(None),
+        };
+        match result {
+            Ok(count) => {
+                io.finish_ss(ss_num)?;
+                Ok(Some(count))
+            },
+            Err(e) => {
+                if let Err(e2) = io.discard_ss(ss_num) {
+                    warn!("Partition {}: failed to discard partial snapshot {}: {}",
+                        io.part_id(), ss_num, e2);
+                }
+                Err(e)
+            },
+        }
+    }
+    
     // Take self and two sums. Return a copy of a key to avoid lifetime issues.
     fn latest_common_ancestor(&self, k1: &Sum, k2: &Sum) -> Result<Sum, MergeError> {
         // #0019: there are multiple strategies here; we just find all
@@ -1007,6 +2916,18 @@ impl<E: ElementT> Partition<E> {
             return;
         }
         
+        if let Some(name) = state.meta().branch().map(|b| b.to_string()) {
+            // A commit on a branch moves the branch head; tips are unaffected
+            for parent in state.parents() {
+                if !self.states.contains(parent) {
+                    self.ancestors.insert(parent.clone());
+                }
+            }
+            self.branches.insert(name, state.statesum().clone());
+            self.states.insert(Arc::new(state));
+            return;
+        }
+        
         for parent in state.parents() {
             // Remove from 'tips' if it happened to be there:
             self.tips.remove(parent);
@@ -1022,7 +2943,7 @@ impl<E: ElementT> Partition<E> {
             self.ss_edits += n_edits;
             self.tips.insert(state.statesum().clone());
         }
-        self.states.insert(state);
+        self.states.insert(Arc::new(state));
     }
     
     /// Creates a state from the commit and adds to self. Updates tip if this
@@ -1054,7 +2975,7 @@ impl<E: ElementT> Partition<E> {
         assert_eq!(commit.statesum(), state.statesum());
         assert!(self.states.contains(commit.first_parent()));
         
-        while let Some(ref old_state) = self.states.get(state.statesum()) {
+        while let Some(old_state) = self.states.get(state.statesum()) {
             if state == **old_state {
                 trace!("Partition {} already contains commit {}", self.part_id, commit.statesum());
                 return false;
@@ -1068,6 +2989,123 @@ impl<E: ElementT> Partition<E> {
         self.unsaved.push_back(commit);
         true
     }
+    
+    // Add a state on a branch, derived from `parent`, with its commit. Unlike
+    // `push_state()`, an empty commit is made if the elements are unchanged.
+    fn add_branch_state(&mut self, parent: Sum, state: PartState<E>) -> bool {
+        let commit = match Commit::from_diff(self.states.get(&parent).unwrap(), &state) {
+            Some(commit) => commit,
+            None => Commit::new_explicit(state.statesum().clone(), vec![parent],
+                    HashMap::new(), state.meta().clone()),
+        };
+        self.add_pair(commit, state)
+    }
+    
+    // Re-base each branch head onto the tip, so that branches remain
+    // loadable from the latest snapshot (see `branch()`)
+    fn carry_branches(&mut self, tip_key: &Sum) {
+        let mut names: Vec<String> = self.branches.keys().cloned().collect();
+        names.sort();
+        for name in names {
+            let state = {
+                let head = self.states.get(&self.branches[&name]).unwrap();
+                let mut state = self.states.get(tip_key).unwrap().clone_mut();
+                state.clear();
+                for (id, elt) in head.elts_iter() {
+                    state.insert_with_id(id, elt.clone()).expect("insert into cleared state");
+                }
+                for (id, new_id) in head.moved_iter() {
+                    state.set_move(id, new_id);
+                }
+                state.meta_mut().set_branch(Some(name.clone())).expect("valid branch name");
+                let clock_meta = ClockMeta(&*self.clock);
+                PartState::from_mut(state, Some(&clock_meta))
+            };
+            self.add_branch_state(tip_key.clone(), state);
+        }
+    }
+}
+
+// Wrapper around a writer, counting the number of bytes written.
+struct CountingWriter<W: Write> {
+    inner: W,
+    count: usize,
+}
+impl<W: Write> CountingWriter<W> {
+    fn new(inner: W) -> CountingWriter<W> {
+        CountingWriter { inner: inner, count: 0 }
+    }
+    fn count(&self) -> usize { self.count }
+}
+impl<W: Write> Write for CountingWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count += n;
+        Ok(n)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+// Position reached in a commit log, with the file's version and checksum type
+#[derive(Clone)]
+struct LogPos {
+    // Number of bytes read
+    len: usize,
+    // The last bytes read (up to SUM_BYTES), used to detect rewritten files
+    tail: Vec<u8>,
+    ver: u32,
+    sum_type: SumType,
+}
+
+// Wrap `r` in a buffer of `size` bytes, unless `size` is zero. The buffer
+// only consumes data from `r` in larger pieces; everything read from `r` is
+// still passed on exactly once, thus checksums are unaffected.
+fn buffered<'a>(r: Box<Read+'a>, size: usize) -> Box<Read+'a> {
+    if size > 0 {
+        Box::new(BufReader::with_capacity(size, r))
+    } else {
+        r
+    }
+}
+
+// Wrap `w` in a buffer of `size` bytes, unless `size` is zero. As with
+// `buffered()`, data passes through unchanged; the caller must `flush()`.
+fn buffered_writer<'a>(w: Box<Write+'a>, size: usize) -> Box<Write+'a> {
+    if size > 0 {
+        Box::new(BufWriter::with_capacity(size, w))
+    } else {
+        w
+    }
+}
+
+// Wrapper around a reader, counting the number of bytes read and remembering
+// the last few of these.
+struct TrackingReader<R: Read> {
+    inner: R,
+    count: usize,
+    tail: Vec<u8>,
+}
+impl<R: Read> TrackingReader<R> {
+    fn new(inner: R) -> TrackingReader<R> {
+        TrackingReader { inner: inner, count: 0, tail: Vec::with_capacity(2 * SUM_BYTES) }
+    }
+    fn log_pos(&self, ver: u32, sum_type: SumType) -> LogPos {
+        LogPos { len: self.count, tail: self.tail.clone(), ver: ver, sum_type: sum_type }
+    }
+}
+impl<R: Read> Read for TrackingReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count += n;
+        self.tail.extend_from_slice(&buf[..n]);
+        if self.tail.len() > SUM_BYTES {
+            let excess = self.tail.len() - SUM_BYTES;
+            self.tail.drain(..excess);
+        }
+        Ok(n)
+    }
 }
 
 /// Wrapper around underlying iterator structure
@@ -1115,7 +3153,7 @@ impl<'a, E: ElementT+'a> Deref for StateItem<'a, E> {
 
 /// Iterator over a partition's (historical or current) states
 pub struct StateIter<'a, E: ElementT+'a> {
-    iter: Iter<'a, PartState<E>, Sum, PartStateSumComparator>,
+    iter: Iter<'a, Arc<PartState<E>>, Sum, PartStateSumComparator>,
     tips: &'a HashSet<Sum>,
 }
 impl<'a, E: ElementT+'a> Iterator for StateIter<'a, E> {
@@ -1123,7 +3161,7 @@ impl<'a, E: ElementT+'a> Iterator for StateIter<'a, E> {
     fn next(&mut self) -> Option<Self::Item> {
         self.iter.next().map(|item|
             StateItem {
-                state: item,
+                state: &**item,
                 tips: self.tips,
             }
         )
@@ -1132,13 +3170,255 @@ impl<'a, E: ElementT+'a> Iterator for StateIter<'a, E> {
 }
 
 
+/// Builder for creating or opening a `Partition` with options.
+/// 
+/// Example:
+/// 
+/// ```
+/// use pippin::{Partition, PartitionBuilder, PartId, SumType};
+/// use pippin::part::DummyPartIO;
+/// 
+/// let io = Box::new(DummyPartIO::new(PartId::from_num(1)));
+/// let partition: Partition<String> = PartitionBuilder::new()
+///         .sum_type(SumType::Sha256)
+///         .create(io, "example repo").unwrap();
+/// ```
+pub struct PartitionBuilder<'a> {
+    repo_name: Option<String>,
+    sum_type: SumType,
+    retry: Option<RetryPolicy>,
+    max_log_size: usize,
+    snapshot_interval: usize,
+    snapshot_dedup: Option<bool>,
+    read_buf_size: usize,
+    write_buf_size: usize,
+    sum_threads: usize,
+    blob_min: Option<Option<usize>>,
+    reject_duplicates: bool,
+    ignore_checksum: bool,
+    header_policy: HeaderPolicy,
+    upgrade_on_write: bool,
+    clock: Option<Rc<Clock>>,
+    load: bool,
+    user: Option<&'a mut UserFields>,
+    make_meta: Option<&'a MakeMeta>,
+}
+impl<'a> PartitionBuilder<'a> {
+    /// Create a builder with default options.
+    pub fn new() -> PartitionBuilder<'a> {
+        PartitionBuilder {
+            repo_name: None,
+            sum_type: SumType::default(),
+            retry: None,
+            max_log_size: 0,
+            snapshot_interval: 0,
+            snapshot_dedup: None,
+            read_buf_size: DEFAULT_READ_BUF_SIZE,
+            write_buf_size: DEFAULT_WRITE_BUF_SIZE,
+            sum_threads: 1,
+            blob_min: None,
+            reject_duplicates: false,
+            ignore_checksum: false,
+            header_policy: HeaderPolicy::default(),
+            upgrade_on_write: false,
+            clock: None,
+            load: false,
+            user: None,
+            make_meta: None,
+        }
+    }
+    
+    /// Set the repository name expected when opening (see
+    /// `Partition::set_repo_name()`). Ignored by `create()`.
+    pub fn repo_name(mut self, name: &str) -> Self {
+        self.repo_name = Some(name.to_string());
+        self
+    }
+    /// Set the checksum algorithm used for files written (see
+    /// `Partition::set_sum_type()`). This is also how the key for reading
+    /// files with keyed checksums is supplied.
+    pub fn sum_type(mut self, sum_type: SumType) -> Self {
+        self.sum_type = sum_type;
+        self
+    }
+    /// Retry reads failing with transient errors, by wrapping the `PartIO`
+    /// in a `RetryPartIO`.
+    pub fn retry(mut self, policy: RetryPolicy) -> Self {
+        self.retry = Some(policy);
+        self
+    }
+    /// Set the maximum size of commit logs to append to (see
+    /// `Partition::set_max_log_size()`).
+    pub fn max_log_size(mut self, size: usize) -> Self {
+        self.max_log_size = size;
+        self
+    }
+    /// Set the number of commits after which `write()` also writes a
+    /// snapshot (see `Partition::set_snapshot_interval()`).
+    pub fn snapshot_interval(mut self, commits: usize) -> Self {
+        self.snapshot_interval = commits;
+        self
+    }
+    /// Set whether snapshots de-duplicate element data (see
+    /// `Partition::set_snapshot_dedup()`). If not set, `open()` uses the
+    /// setting recorded in the partition's files.
+    pub fn snapshot_dedup(mut self, dedup: bool) -> Self {
+        self.snapshot_dedup = Some(dedup);
+        self
+    }
+    /// Set the size of the buffer used when reading files (see
+    /// `Partition::set_read_buffer_size()`).
+    pub fn read_buffer_size(mut self, size: usize) -> Self {
+        self.read_buf_size = size;
+        self
+    }
+    /// Set the size of the buffer used when writing files (see
+    /// `Partition::set_write_buffer_size()`).
+    pub fn write_buffer_size(mut self, size: usize) -> Self {
+        self.write_buf_size = size;
+        self
+    }
+    /// Set the number of threads calculating element checksums when writing
+    /// snapshots (see `Partition::set_sum_threads()`).
+    pub fn sum_threads(mut self, threads: usize) -> Self {
+        self.sum_threads = threads;
+        self
+    }
+    /// Set the minimum size of element data stored as a blob (see
+    /// `Partition::set_blob_threshold()`). If not set, `open()` uses the
+    /// setting recorded in the partition's files.
+    pub fn blob_threshold(mut self, min_size: Option<usize>) -> Self {
+        self.blob_min = Some(min_size);
+        self
+    }
+    /// Set whether loading fails on finding duplicate commits (see
+    /// `Partition::set_reject_duplicates()`).
+    pub fn reject_duplicates(mut self, reject: bool) -> Self {
+        self.reject_duplicates = reject;
+        self
+    }
+    /// Set whether loading continues despite checksum mismatches (see
+    /// `Partition::set_read_ignoring_checksum()`).
+    pub fn read_ignoring_checksum(mut self, ignore: bool) -> Self {
+        self.ignore_checksum = ignore;
+        self
+    }
+    /// Set how unknown header content is handled when loading (see
+    /// `Partition::set_header_policy()`).
+    pub fn header_policy(mut self, policy: HeaderPolicy) -> Self {
+        self.header_policy = policy;
+        self
+    }
+    /// Set whether files in an old format are upgraded on write (see
+    /// `Partition::set_upgrade_on_write()`).
+    pub fn upgrade_on_write(mut self, upgrade: bool) -> Self {
+        self.upgrade_on_write = upgrade;
+        self
+    }
+    /// Set the clock used to timestamp commits (see
+    /// `Partition::set_clock()`). On `create()` this also timestamps the
+    /// initial state unless a metadata generator is set.
+    pub fn clock(mut self, clock: Rc<Clock>) -> Self {
+        self.clock = Some(clock);
+        self
+    }
+    /// If true, `open()` loads the latest state (see
+    /// `Partition::load_latest()`). Default: false.
+    pub fn load_latest(mut self, load: bool) -> Self {
+        self.load = load;
+        self
+    }
+    /// Set user fields, used when writing the initial snapshot on `create()`
+    /// and when loading on `open()`.
+    pub fn user_fields(mut self, user: &'a mut UserFields) -> Self {
+        self.user = Some(user);
+        self
+    }
+    /// Set the metadata generator used for the initial state on `create()`
+    /// and when loading on `open()`.
+    pub fn make_meta(mut self, make_meta: &'a MakeMeta) -> Self {
+        self.make_meta = Some(make_meta);
+        self
+    }
+    
+    /// Create a new partition with these options (see `Partition::create()`).
+    pub fn create<E: ElementT>(self, io: Box<PartIO>, name: &str) -> Result<Partition<E>> {
+        let io = self.wrap_io(io);
+        let state = {
+            let clock_meta = self.clock.as_ref().map(|clock| ClockMeta(&**clock));
+            let make_meta = self.make_meta.or(clock_meta.as_ref().map(|mm| mm as &MakeMeta));
+            PartState::new(io.part_id(), make_meta)
+        };
+        let config = PartConfig {
+            snapshot_dedup: self.snapshot_dedup.unwrap_or(false),
+            blob_min: self.blob_min.unwrap_or(None),
+        };
+        let mut part = Partition::create_with(io, name, self.user, state, self.sum_type, config)?;
+        part.set_max_log_size(self.max_log_size);
+        part.set_snapshot_interval(self.snapshot_interval);
+        part.set_read_buffer_size(self.read_buf_size);
+        part.set_write_buffer_size(self.write_buf_size);
+        part.set_sum_threads(self.sum_threads);
+        part.set_reject_duplicates(self.reject_duplicates);
+        part.set_read_ignoring_checksum(self.ignore_checksum);
+        part.set_header_policy(self.header_policy);
+        part.set_upgrade_on_write(self.upgrade_on_write);
+        if let Some(clock) = self.clock {
+            part.set_clock(clock);
+        }
+        Ok(part)
+    }
+    
+    /// Open an existing partition with these options (see `Partition::open()`).
+    pub fn open<E: ElementT>(self, io: Box<PartIO>) -> Result<Partition<E>> {
+        let io = self.wrap_io(io);
+        let mut part = Partition::open(io)?;
+        part.set_sum_type(self.sum_type);
+        part.set_max_log_size(self.max_log_size);
+        part.set_snapshot_interval(self.snapshot_interval);
+        if let Some(dedup) = self.snapshot_dedup {
+            part.set_snapshot_dedup(dedup);
+        }
+        part.set_read_buffer_size(self.read_buf_size);
+        part.set_write_buffer_size(self.write_buf_size);
+        part.set_sum_threads(self.sum_threads);
+        if let Some(min_size) = self.blob_min {
+            part.set_blob_threshold(min_size);
+        }
+        part.set_reject_duplicates(self.reject_duplicates);
+        part.set_read_ignoring_checksum(self.ignore_checksum);
+        part.set_header_policy(self.header_policy);
+        part.set_upgrade_on_write(self.upgrade_on_write);
+        if let Some(clock) = self.clock {
+            part.set_clock(clock);
+        }
+        if let Some(ref name) = self.repo_name {
+            part.set_repo_name(name)?;
+        }
+        if self.load {
+            part.load_latest(self.user, self.make_meta)?;
+        }
+        Ok(part)
+    }
+    
+    fn wrap_io(&self, io: Box<PartIO>) -> Box<PartIO> {
+        match self.retry {
+            Some(policy) => Box::new(RetryPartIO::new(io, policy)),
+            None => io,
+        }
+    }
+}
+
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use commit::{Commit};
+    use merge::Resolution;
     use PartId;
     use state::*;
     use std::rc::Rc;
+    use std::sync::Arc;
     
     #[test]
     fn commit_creation_and_replay(){
@@ -1146,7 +3426,7 @@ mod tests {
         let mut queue = vec![];
         
         let insert = |state: &mut MutPartState<_>, num, string: &str| -> Result<_, _> {
-            state.insert_with_id(p.elt_id(num), Rc::new(string.to_string()))
+            state.insert_with_id(p.elt_id(num), Arc::new(string.to_string()))
         };
         
         let mut state = PartState::new(p, None).clone_mut();
@@ -1190,6 +3470,52 @@ mod tests {
         assert_eq!(*replayed_state, state_d);
     }
     
+    #[test]
+    fn state_ref_isolation() {
+        use std::sync::mpsc::channel;
+        use std::thread;
+        
+        let io = Box::new(DummyPartIO::new(PartId::from_num(3)));
+        let mut part = Partition::<String>::create(io, "state_ref", None, None)
+                .expect("partition creation");
+        
+        let mut state = part.tip().unwrap().clone_mut();
+        let id = state.insert("first".to_string()).unwrap();
+        part.push_state(state, None).unwrap();
+        
+        // A reader on another thread reads its state before and after the
+        // writer pushes and writes a new commit:
+        let reader = part.tip_ref().unwrap();
+        let key = reader.statesum().clone();
+        let (read_tx, read_rx) = channel();
+        let (write_tx, write_rx) = channel();
+        let handle = thread::spawn(move || {
+            let read = |state: &PartState<String>| (state.statesum().clone(),
+                    state.num_avail(), state.get(id).ok().cloned());
+            let before = read(&reader);
+            read_tx.send(()).unwrap();
+            write_rx.recv().unwrap();
+            (before, read(&reader), reader)
+        });
+        
+        read_rx.recv().unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.replace(id, "second".to_string()).unwrap();
+        state.insert("third".to_string()).unwrap();
+        part.push_state(state, None).unwrap();
+        part.write_fast(None).unwrap();
+        write_tx.send(()).unwrap();
+        
+        let (before, after, reader) = handle.join().unwrap();
+        assert_eq!(before, (key.clone(), 1, Some("first".to_string())));
+        assert_eq!(after, before);
+        assert_eq!(part.tip().unwrap().get(id), Ok(&"second".to_string()));
+        assert!(Arc::ptr_eq(&reader, &part.state_ref(&key).unwrap()));
+        
+        assert!(part.unload(true));
+        assert_eq!(reader.get(id), Ok(&"first".to_string()));
+    }
+    
     #[test]
     fn on_new_partition() {
         let io = Box::new(DummyPartIO::new(PartId::from_num(7)));
@@ -1223,4 +3549,183 @@ mod tests {
         
         assert_eq!(part.push_state(state, None).expect("committing"), false);
     }
+    
+    #[test]
+    fn state_at_index() {
+        let p = PartId::from_num(4);
+        let io = Box::new(DummyPartIO::new(p));
+        let mut part = Partition::create(io, "state_at_index", None, None).unwrap();
+        let mut sums = vec![part.tip_key().unwrap().clone()];
+        for i in 1..6 {
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert_with_id(p.elt_id(i), Arc::new(format!("elt {}", i))).unwrap();
+            part.push_state(state, None).unwrap();
+            sums.push(part.tip_key().unwrap().clone());
+        }
+        
+        for back in 0..6 {
+            let state = part.state_at_index(back).unwrap();
+            assert_eq!(state.statesum(), &sums[5 - back]);
+            assert_eq!(state.elts_len(), 5 - back);
+        }
+        assert!(part.state_at_index(6).is_err());
+    }
+    
+    #[test]
+    fn squash() {
+        let p = PartId::from_num(2);
+        let io = Box::new(DummyPartIO::new(p));
+        let mut part = Partition::create(io, "squash", None, None).unwrap();
+        let base = part.tip_key().unwrap().clone();
+        
+        let mut sums = vec![];
+        for i in 1..4 {
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert_with_id(p.elt_id(i), Arc::new(format!("elt {}", i))).unwrap();
+            if i == 3 {
+                state.remove(p.elt_id(1)).unwrap();
+            }
+            assert!(part.push_state(state, None).unwrap());
+            sums.push(part.tip_key().unwrap().clone());
+        }
+        assert_eq!(part.states_len(), 4);
+        assert_eq!(part.unsaved_len(), 3);
+        
+        // Not a chain (reversed):
+        assert!(part.squash(sums[2].clone(), sums[0].clone()).is_err());
+        // Last is not a tip:
+        assert!(part.squash(sums[0].clone(), sums[1].clone()).is_err());
+        
+        let elts: HashMap<_, _> = part.tip().unwrap().elts_iter()
+                .map(|(id, elt)| (id, elt.clone())).collect();
+        let key = part.squash(sums[0].clone(), sums[2].clone()).unwrap();
+        assert_eq!(part.states_len(), 2);
+        assert_eq!(part.unsaved_len(), 1);
+        assert_eq!(part.tip_key().unwrap(), &key);
+        let tip = part.tip().unwrap();
+        assert_eq!(tip.parents(), &[base]);
+        let elts2: HashMap<_, _> = tip.elts_iter().map(|(id, elt)| (id, elt.clone())).collect();
+        assert_eq!(elts2, elts);
+        assert!(sums.iter().all(|sum| part.state(sum).is_none()));
+        
+        // The commit reproduces the state:
+        let commit = part.unsaved_iter().next().unwrap();
+        let state = PartState::from_state_commit(part.state(&tip.parents()[0]).unwrap(), commit);
+        assert_eq!(state.as_ref(), Ok(tip));
+    }
+    
+    #[test]
+    fn merge_with_closure() {
+        let p = PartId::from_num(3);
+        let id = p.elt_id(1);
+        let io = Box::new(DummyPartIO::new(p));
+        let mut part = Partition::create(io, "merge_with", None, None).unwrap();
+        let mut state = part.tip().unwrap().clone_mut();
+        state.insert_with_id(id, Arc::new("original".to_string())).unwrap();
+        part.push_state(state, None).unwrap();
+        
+        let mut state_a = part.tip().unwrap().clone_mut();
+        let mut state_b = part.tip().unwrap().clone_mut();
+        state_a.replace(id, "first edit".to_string()).unwrap();
+        state_b.replace(id, "second edit".to_string()).unwrap();
+        part.push_state(state_a, None).unwrap();
+        part.push_state(state_b, None).unwrap();
+        assert!(part.merge_required());
+        
+        // Tips are sorted to choose which is A and which B:
+        let expected = {
+            let mut tips: Vec<_> = part.tips().iter().collect();
+            tips.sort();
+            part.state(tips[1]).unwrap().get(id).unwrap().clone()
+        };
+        
+        let mut num_conflicts = 0;
+        part.merge_with(|conflict| {
+            assert_eq!(conflict.id, id);
+            assert!(conflict.a.is_some() && conflict.b.is_some());
+            num_conflicts += 1;
+            Resolution::TakeB
+        }, false, None).unwrap();
+        assert_eq!(num_conflicts, 1);
+        assert!(part.is_ready());
+        assert_eq!(part.tip().unwrap().get(id), Ok(&expected));
+    }
+    
+    #[test]
+    fn num_avail() {
+        let p = PartId::from_num(5);
+        let part = Partition::<String>::open(Box::new(DummyPartIO::new(p))).unwrap();
+        let e = part.num_avail().unwrap_err();
+        assert_eq!(e.downcast_ref::<TipError>(), Some(&TipError::NotReady));
+        
+        let io = Box::new(DummyPartIO::new(p));
+        let mut part = Partition::<String>::create(io, "num_avail", None, None).unwrap();
+        assert_eq!(part.num_avail().unwrap(), 0);
+        let mut state_a = part.tip().unwrap().clone_mut();
+        let mut state_b = part.tip().unwrap().clone_mut();
+        state_a.insert("a".to_string()).unwrap();
+        state_b.insert("b".to_string()).unwrap();
+        part.push_state(state_a, None).unwrap();
+        assert_eq!(part.num_avail().unwrap(), 1);
+        part.push_state(state_b, None).unwrap();
+        let e = part.num_avail().unwrap_err();
+        assert_eq!(e.downcast_ref::<TipError>(), Some(&TipError::MergeRequired));
+    }
+    
+    #[test]
+    fn fixed_clock() {
+        use std::cell::Cell;
+        
+        // A clock advancing ten seconds each time it is read:
+        struct StepClock(Cell<i64>);
+        impl Clock for StepClock {
+            fn now(&self) -> i64 {
+                let t = self.0.get();
+                self.0.set(t + 10);
+                t
+            }
+        }
+        
+        let io = Box::new(DummyPartIO::new(PartId::from_num(6)));
+        let mut part = PartitionBuilder::new()
+                .clock(Rc::new(StepClock(Cell::new(1_000_000))))
+                .create::<String>(io, "fixed_clock").unwrap();
+        assert_eq!(part.tip().unwrap().meta().timestamp(), 1_000_000);
+        for elt in &["one", "two"] {
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert(elt.to_string()).unwrap();
+            part.push_state(state, None).unwrap();
+        }
+        let tip = part.tip().unwrap();
+        assert_eq!(tip.meta().timestamp(), 1_000_020);
+        let parent = part.state(&tip.parents()[0]).unwrap();
+        assert_eq!(parent.meta().timestamp(), 1_000_010);
+    }
+    
+    #[test]
+    fn timestamp_anomalies() {
+        use std::cell::RefCell;
+        
+        // A clock which goes backwards once:
+        struct ListClock(RefCell<Vec<i64>>);
+        impl Clock for ListClock {
+            fn now(&self) -> i64 {
+                self.0.borrow_mut().remove(0)
+            }
+        }
+        
+        let io = Box::new(DummyPartIO::new(PartId::from_num(7)));
+        let clock = ListClock(RefCell::new(vec![1_000_000, 1_000_010, 999_990, 1_000_020]));
+        let mut part = PartitionBuilder::new()
+                .clock(Rc::new(clock))
+                .create::<String>(io, "anomalies").unwrap();
+        let mut sums = vec![part.tip_key().unwrap().clone()];
+        for elt in &["one", "two", "three"] {
+            let mut state = part.tip().unwrap().clone_mut();
+            state.insert(elt.to_string()).unwrap();
+            part.push_state(state, None).unwrap();
+            sums.push(part.tip_key().unwrap().clone());
+        }
+        assert_eq!(part.timestamp_anomalies(), vec![(sums[1].clone(), sums[2].clone())]);
+    }
 }
@@ -0,0 +1,67 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Support for element data stored outside snapshots and logs ("blobs").
+//! 
+//! Where enabled (see `Partition::set_blob_threshold()`), the data of large
+//! elements is not written inline. Snapshots and commit logs instead refer to
+//! the data by its checksum (the *blob key*), and the data itself is stored
+//! via `PartIO::write_blob()`. Data referred to by several commits or
+//! snapshots is thus stored only once.
+
+use std::collections::HashMap;
+
+use Sum;
+use error::{Result, ReadError};
+
+/// Provides blob data when reading snapshots and logs. Implemented for all
+/// `PartIO`s (via `PartIO::read_blob()`).
+pub trait BlobSource {
+    /// Get the data stored under `key`, or `None` if not found.
+    fn get_blob(&self, key: &Sum) -> Result<Option<Vec<u8>>>;
+}
+
+/// A `BlobSource` without any data; references to blobs cannot be resolved.
+pub struct NoBlobs;
+impl BlobSource for NoBlobs {
+    fn get_blob(&self, _key: &Sum) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+}
+impl BlobSource for HashMap<Sum, Vec<u8>> {
+    fn get_blob(&self, key: &Sum) -> Result<Option<Vec<u8>>> {
+        Ok(self.get(key).cloned())
+    }
+}
+impl<'a, B: BlobSource + ?Sized> BlobSource for &'a B {
+    fn get_blob(&self, key: &Sum) -> Result<Option<Vec<u8>>> {
+        (**self).get_blob(key)
+    }
+}
+
+/// Get the key under which some data is stored as a blob
+pub fn blob_key(data: &[u8]) -> Sum {
+    Sum::calculate(data)
+}
+
+/// True if data of length `len` is stored as a blob, given the threshold
+/// `blob_min` (see `Partition::set_blob_threshold()`).
+pub fn use_blob(len: usize, blob_min: Option<usize>) -> bool {
+    blob_min.map_or(false, |min| len >= min)
+}
+
+// Get the data of a blob referred to at `pos`, checking its length. The
+// caller should verify the element checksum.
+pub fn read_blob_ref<B: BlobSource + ?Sized>(blobs: &B, key: &Sum, len: usize,
+        pos: usize) -> Result<Vec<u8>>
+{
+    let data = match blobs.get_blob(key)? {
+        Some(data) => data,
+        None => return ReadError::err("blob referred to not found", pos, (0, 8)),
+    };
+    if data.len() != len {
+        return ReadError::err("blob length differs from that referred to", pos, (8, 16));
+    }
+    Ok(data)
+}
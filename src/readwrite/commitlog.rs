@@ -8,16 +8,19 @@
 
 use std::io::{Read, Write};
 use std::collections::HashMap;
-use std::rc::Rc;
+use std::sync::Arc;
 use std::u32;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
 use readwrite::{sum, read_meta, write_meta};
+use readwrite::sum::{SumType, SumCheck};
+use readwrite::blob::{BlobSource, blob_key, use_blob, read_blob_ref};
+use readwrite::eltdata::{DataLen, write_data};
 use commit::{Commit, EltChange};
 use {ElementT, Sum};
 use sum::BYTES as SUM_BYTES;
-use error::{Result, ReadError};
+use error::{Result, ReadError, EltSumError};
 
 /// Implement this to use read_log().
 /// 
@@ -39,26 +42,55 @@ impl<E: ElementT> CommitReceiver<E> for Vec<Commit<E>> {
 
 /// Read a commit log from a stream
 /// 
-/// `format_ver` is the decimalised file format version
-pub fn read_log<E: ElementT>(mut reader: &mut Read,
-        receiver: &mut CommitReceiver<E>, format_ver: u32) -> Result<()>
+/// `format_ver` is the decimalised file format version and `sum_type` the
+/// checksum algorithm; both should be taken from the file header. Element
+/// data stored as blobs is retrieved from `blobs`.
+pub fn read_log<E: ElementT, B: BlobSource + ?Sized>(reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32, sum_type: SumType,
+        blobs: &B) -> Result<()>
 {
-    let mut pos: usize = 0;
-    let mut buf = vec![0; 32];
-    
-    reader.read_exact(&mut buf[0..16])?;
-    if buf[0..16] != *b"COMMIT LOG\x00\x00\x00\x00\x00\x00" {
+    read_log_checked(reader, receiver, format_ver, sum_type, blobs, &mut SumCheck::strict())
+}
+
+/// Read a commit log, as `read_log()`, handling checksum mismatches (element
+/// and commit checksums) via `check`.
+pub fn read_log_checked<E: ElementT, B: BlobSource + ?Sized>(reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32, sum_type: SumType,
+        blobs: &B, check: &mut SumCheck) -> Result<()>
+{
+    let mut buf = [0u8; 16];
+    reader.read_exact(&mut buf)?;
+    if buf != *b"COMMIT LOG\x00\x00\x00\x00\x00\x00" {
         return ReadError::err("unexpected contents (expected \
-            COMMIT LOG\\x00\\x00\\x00\\x00\\x00\\x00)", pos, (0, 16));
+            COMMIT LOG\\x00\\x00\\x00\\x00\\x00\\x00)", 0, (0, 16));
     }
-    pos += 16;
+    read_commits(reader, receiver, format_ver, sum_type, blobs, 16, check)
+}
+
+/// Continue reading a commit log from a stream positioned at the start of a
+/// commit (i.e. immediately after some previously read commit), reading
+/// commits until the end of the stream.
+/// 
+/// Parameters are as for `read_log_checked()`.
+pub fn read_log_tail<E: ElementT, B: BlobSource + ?Sized>(reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32, sum_type: SumType,
+        blobs: &B, check: &mut SumCheck) -> Result<()>
+{
+    read_commits(reader, receiver, format_ver, sum_type, blobs, 0, check)
+}
+
+fn read_commits<E: ElementT, B: BlobSource + ?Sized>(mut reader: &mut Read,
+        receiver: &mut CommitReceiver<E>, format_ver: u32, sum_type: SumType,
+        blobs: &B, mut pos: usize, check: &mut SumCheck) -> Result<()>
+{
+    let mut buf = vec![0; 32];
     
     // We now read commits. Since new commits can simply be appended to the
     // file, we only know we're at the end if we hit EOF. This is the only
     // condition where encountering EOF is not an error.
     loop {
         // A reader which calculates the checksum of what was read:
-        let mut r = sum::HashReader::new(reader);
+        let mut r = sum::HashReader::new(reader, sum_type);
         
         let l = r.read(&mut buf[0..16])?;
         if l == 0 { break; /*end of file (EOF)*/ }
@@ -117,30 +149,32 @@ pub fn read_log<E: ElementT>(mut reader: &mut Read,
                 Change::Delete => EltChange::deletion(),
                 Change::Insert | Change::Replace => {
                     r.read_exact(&mut buf[0..16])?;
-                    if buf[0..8] != *b"ELT DATA" {
-                        return ReadError::err("unexpected contents (expected ELT DATA)", pos, (0, 8));
+                    let is_blob = buf[0..8] == *b"ELT BLOB";
+                    if !is_blob && buf[0..8] != *b"ELT DATA" {
+                        return ReadError::err("unexpected contents (expected ELT DATA or ELT BLOB)", pos, (0, 8));
                     }
                     let data_len = BigEndian::read_u64(&buf[8..16]) as usize;   // #0015
-                    pos += 16;
-                    
-                    let mut data = vec![0; data_len];
-                    r.read_exact(&mut data)?;
-                    pos += data_len;
                     
-                    let pad_len = 16 * ((data_len + 15) / 16) - data_len;
-                    if pad_len > 0 {
-                        r.read_exact(&mut buf[0..pad_len])?;
-                        pos += pad_len;
-                    }
+                    let data = if is_blob {
+                        let ref_pos = pos;
+                        pos += 16;
+                        r.read_exact(&mut buf[0..SUM_BYTES])?;
+                        pos += SUM_BYTES;
+                        read_blob_ref(blobs, &Sum::load(&buf[0..SUM_BYTES]), data_len, ref_pos)?
+                    } else {
+                        let data = DataLen::load(&buf[8..16], pos + 8)?.read_data(&mut r, pos + 8)?;
+                        pos += 16 + 16 * ((data_len + 15) / 16);
+                        data
+                    };
                     
                     let elt_sum = Sum::elt_sum(elt_id, &data);
                     r.read_exact(&mut buf[0..SUM_BYTES])?;
-                    if !elt_sum.eq(&buf[0..SUM_BYTES]) {
-                        return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
-                    }
+                    let elt_pos = pos;
+                    check.check(elt_sum.eq(&buf[0..SUM_BYTES]),
+                            || EltSumError::err(elt_id, elt_pos))?;
                     pos += SUM_BYTES;
                     
-                    let elt = Rc::new(E::from_vec_sum(data, elt_sum)?);
+                    let elt = Arc::new(E::from_vec_sum(data, elt_sum)?);
                     match change_t {
                         Change::Insert => EltChange::insertion(elt),
                         Change::Replace => EltChange::replacement(elt),
@@ -166,9 +200,9 @@ pub fn read_log<E: ElementT>(mut reader: &mut Read,
         let sum = r.sum();
         reader = r.into_inner();
         reader.read_exact(&mut buf[0..SUM_BYTES])?;
-        if !sum.eq(&buf[0..SUM_BYTES]) {
-            return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
-        }
+        let sum_pos = pos;
+        check.check(sum.eq(&buf[0..SUM_BYTES]),
+                || ReadError::err("checksum invalid", sum_pos, (0, SUM_BYTES)))?;
         
         trace!("Read commit ({} changes): {}; first parent: {}", changes.len(), commit_sum, parents[0]);
         let cont = receiver.receive(Commit::new_explicit(commit_sum, parents, changes, meta));
@@ -191,12 +225,20 @@ pub fn start_log(writer: &mut Write) -> Result<()> {
 }
 
 /// Write a single commit to a stream
-pub fn write_commit<E: ElementT>(commit: &Commit<E>, writer: &mut Write) -> Result<()> {
+/// 
+/// `sum_type` must match that specified in the file header.
+/// 
+/// Element data of at least `blob_min` bytes (if not `None`) is not written
+/// but referred to by its blob key (see `blob_key()`); the caller must store
+/// this data separately (see `PartIO::write_blob()`).
+pub fn write_commit<E: ElementT>(commit: &Commit<E>, writer: &mut Write,
+        sum_type: SumType, blob_min: Option<usize>) -> Result<()>
+{
     trace!("Writing commit ({} changes): {}",
         commit.num_changes(), commit.statesum());
     
     // A writer which calculates the checksum of what was written:
-    let mut w = sum::HashWriter::new(writer);
+    let mut w = sum::HashWriter::new(writer, sum_type);
     
     if commit.parents().len() == 1 {
         w.write(b"COMMIT\x00U")?;
@@ -234,16 +276,15 @@ pub fn write_commit<E: ElementT>(commit: &Commit<E>, writer: &mut Write) -> Resu
         w.write(marker)?;
         w.write_u64::<BigEndian>((*elt_id).into())?;
         if let Some(elt) = change.element() {
-            w.write(b"ELT DATA")?;
             elt_buf.clear();
             elt.write_buf(&mut &mut elt_buf)?;
-            w.write_u64::<BigEndian>(elt_buf.len() as u64)?;      // #0015
-            
-            w.write(&elt_buf)?;
-            let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
-            if pad_len > 0 {
-                let padding = [0u8; 15];
-                w.write(&padding[0..pad_len])?;
+            if use_blob(elt_buf.len(), blob_min) {
+                w.write(b"ELT BLOB")?;
+                w.write_u64::<BigEndian>(elt_buf.len() as u64)?;      // #0015
+                blob_key(&elt_buf).write(&mut w)?;
+            } else {
+                w.write(b"ELT DATA")?;
+                write_data(&elt_buf, &mut w)?;
             }
             
             elt.sum(*elt_id).write(&mut w)?;
@@ -266,6 +307,7 @@ pub fn write_commit<E: ElementT>(commit: &Commit<E>, writer: &mut Write) -> Resu
 fn commit_write_read(){
     use readwrite::header::HEAD_VERSIONS;
     use commit::{CommitMeta, ExtraMeta, MetaFlags};
+    use readwrite::blob::NoBlobs;
     use PartId;
     
     // Note that we can make up completely nonsense commits here. Element
@@ -282,26 +324,26 @@ fn commit_write_read(){
     
     let p = PartId::from_num(1681);
     let mut changes = HashMap::new();
-    changes.insert(p.elt_id(3), EltChange::insertion(Rc::new("three".to_string())));
-    changes.insert(p.elt_id(4), EltChange::insertion(Rc::new("four".to_string())));
-    changes.insert(p.elt_id(5), EltChange::insertion(Rc::new("five".to_string())));
+    changes.insert(p.elt_id(3), EltChange::insertion(Arc::new("three".to_string())));
+    changes.insert(p.elt_id(4), EltChange::insertion(Arc::new("four".to_string())));
+    changes.insert(p.elt_id(5), EltChange::insertion(Arc::new("five".to_string())));
     let meta1 = CommitMeta::new_explicit(1, 123456, MetaFlags::zero(), vec![], ExtraMeta::None).expect("new meta");
     let commit_1 = Commit::new_explicit(seq, vec![squares], changes, meta1);
     
     changes = HashMap::new();
     changes.insert(p.elt_id(1), EltChange::deletion());
-    changes.insert(p.elt_id(9), EltChange::replacement(Rc::new("NINE!".to_string())));
-    changes.insert(p.elt_id(5), EltChange::insertion(Rc::new("five again?".to_string())));
+    changes.insert(p.elt_id(9), EltChange::replacement(Arc::new("NINE!".to_string())));
+    changes.insert(p.elt_id(5), EltChange::insertion(Arc::new("five again?".to_string())));
     let meta2 = CommitMeta::new_explicit(1, 321654, MetaFlags::zero(), vec![], ExtraMeta::Text("123".to_string())).expect("new meta");
     let commit_2 = Commit::new_explicit(nonsense, vec![quadr], changes, meta2);
     
     let mut obj = Vec::new();
     assert!(start_log(&mut obj).is_ok());
-    assert!(write_commit(&commit_1, &mut obj).is_ok());
-    assert!(write_commit(&commit_2, &mut obj).is_ok());
+    assert!(write_commit(&commit_1, &mut obj, SumType::Blake2b, None).is_ok());
+    assert!(write_commit(&commit_2, &mut obj, SumType::Blake2b, None).is_ok());
     
     let mut commits = Vec::new();
-    match read_log(&mut &obj[..], &mut commits, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]) {
+    match read_log(&mut &obj[..], &mut commits, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1], SumType::Blake2b, &NoBlobs) {
         Ok(()) => {},
         Err(e) => {
 //             // specialisation for a ReadError:
@@ -314,3 +356,73 @@ fn commit_write_read(){
     assert_eq!(commits[0], commit_1);
     assert_eq!(commits[1], commit_2);
 }
+
+#[test]
+fn commit_blobs() {
+    use readwrite::header::HEAD_VERSIONS;
+    use commit::{CommitMeta, ExtraMeta, MetaFlags};
+    use PartId;
+    
+    let p = PartId::from_num(1);
+    let big = "a long element stored as a blob".to_string();
+    let mut changes = HashMap::new();
+    changes.insert(p.elt_id(1), EltChange::insertion(Arc::new(big.clone())));
+    changes.insert(p.elt_id(2), EltChange::insertion(Arc::new("small".to_string())));
+    let meta = CommitMeta::new_explicit(1, 123456, MetaFlags::zero(), vec![], ExtraMeta::None).expect("new meta");
+    let commit = Commit::new_explicit(Sum::zero(), vec![Sum::zero()], changes, meta);
+    
+    let mut obj = Vec::new();
+    start_log(&mut obj).unwrap();
+    write_commit(&commit, &mut obj, SumType::Blake2b, Some(16)).unwrap();
+    assert!(!obj.windows(big.len()).any(|w| w == big.as_bytes()));
+    assert!(obj.windows(5).any(|w| w == b"small"));
+    
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let mut blobs = HashMap::new();
+    let mut commits: Vec<Commit<String>> = Vec::new();
+    assert!(read_log(&mut &obj[..], &mut commits, ver, SumType::Blake2b, &blobs).is_err());
+    
+    blobs.insert(blob_key(big.as_bytes()), big.as_bytes().to_vec());
+    let mut commits = Vec::new();
+    read_log(&mut &obj[..], &mut commits, ver, SumType::Blake2b, &blobs).unwrap();
+    assert_eq!(commits, vec![commit]);
+}
+
+#[test]
+fn commit_tags() {
+    use std::collections::BTreeMap;
+    use readwrite::header::HEAD_VERSIONS;
+    use commit::{CommitMeta, ExtraMeta, MetaFlags, EXT_DATA_MAX};
+    use readwrite::blob::NoBlobs;
+    use PartId;
+    
+    // A tags record and a record unknown to this version, then padding:
+    let mut ext_data = Vec::from(&b"\x00\x01\x00\x0B\x00\x03req\x00\x00\x00\x02xy"[..]);
+    ext_data.extend_from_slice(b"\x00\x09\x00\x03abc\x00\x00");
+    let meta = CommitMeta::new_explicit(1, 123456, MetaFlags::zero(), ext_data.clone(),
+            ExtraMeta::None).expect("new meta");
+    let mut tags = BTreeMap::new();
+    tags.insert("req".to_string(), b"xy".to_vec());
+    assert_eq!(meta.tags(), &tags);
+    assert_eq!(meta.ext_data(), ext_data);
+    
+    let mut partial = CommitMeta::new_partial(&meta);
+    assert!(partial.tags().is_empty());
+    partial.set_tag("source".to_string(), b"test".to_vec()).unwrap();
+    assert!(partial.set_tag("big".to_string(), vec![0; EXT_DATA_MAX]).is_err());
+    assert_eq!(partial.tags().len(), 1);
+    
+    let p = PartId::from_num(1);
+    let mut changes = HashMap::new();
+    changes.insert(p.elt_id(1), EltChange::insertion(Arc::new("one".to_string())));
+    let commit = Commit::new_explicit(Sum::zero(), vec![Sum::zero()], changes, meta);
+    
+    let mut obj = Vec::new();
+    start_log(&mut obj).unwrap();
+    write_commit(&commit, &mut obj, SumType::Blake2b, None).unwrap();
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let mut commits = Vec::new();
+    read_log(&mut &obj[..], &mut commits, ver, SumType::Blake2b, &NoBlobs).unwrap();
+    assert_eq!(commits, vec![commit]);
+    assert_eq!(commits[0].meta().ext_data(), ext_data);
+}
@@ -0,0 +1,118 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Framing of element data stored within snapshots and commit logs.
+//! 
+//! Element data written inline (i.e. not as a blob or reference) follows a
+//! marker (`BYTES\x00\x00\x00`, `BYTESSHR` or `ELT DATA`; see
+//! `doc/file-format.md`) and is framed as:
+//! 
+//! *   the length of the data, as a big-endian `u64`
+//! *   the data, padded with zeros to the next 16-byte boundary
+//! *   the element checksum (written and verified by the caller)
+//! 
+//! `ElementT::read_buf()` is thus given exactly the bytes written by
+//! `ElementT::write_buf()`. Since the length is read before the data, a
+//! damaged length may claim more data than remains; data is therefore read
+//! incrementally and such a length is reported as an error instead of
+//! causing a huge allocation or reading past the element.
+
+use std::io::{Read, Write};
+use std::cmp::min;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use error::{Result, ReadError};
+
+// Largest amount of memory reserved for data before it is read
+const MAX_RESERVE: usize = 64 * 1024;
+
+/// Length of inline element data, as written before the data.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct DataLen(usize);
+
+impl DataLen {
+    /// Get the length of `data`
+    pub fn of(data: &[u8]) -> DataLen {
+        DataLen(data.len())
+    }
+    
+    /// Load from the 8 bytes at the start of `buf`. `pos` is the position of
+    /// these bytes, for error reporting.
+    pub fn load(buf: &[u8], pos: usize) -> Result<DataLen> {
+        let len = BigEndian::read_u64(&buf[0..8]);
+        if len > usize::max_value() as u64 {
+            return ReadError::err("element data too large for this platform", pos, (0, 8));
+        }
+        Ok(DataLen(len as usize))
+    }
+    
+    /// Get the length in bytes
+    pub fn len(self) -> usize {
+        self.0
+    }
+    
+    // Number of zero bytes following the data
+    fn pad_len(self) -> usize {
+        (16 - self.0 % 16) % 16
+    }
+    
+    /// Write the length
+    pub fn write(self, w: &mut Write) -> Result<()> {
+        w.write_u64::<BigEndian>(self.0 as u64)?;
+        Ok(())
+    }
+    
+    /// Read data of this length, followed by padding. `pos` is the position
+    /// of the length itself, for error reporting.
+    /// 
+    /// Fails if the stream ends first; no more than the data and padding are
+    /// read.
+    pub fn read_data(self, r: &mut Read, pos: usize) -> Result<Vec<u8>> {
+        let mut data = Vec::with_capacity(min(self.0, MAX_RESERVE));
+        r.take(self.0 as u64).read_to_end(&mut data)?;
+        if data.len() < self.0 {
+            return ReadError::err("element data truncated (length exceeds remaining data)",
+                    pos, (0, 8));
+        }
+        let mut padding = [0u8; 15];
+        r.read_exact(&mut padding[0..self.pad_len()])?;
+        Ok(data)
+    }
+}
+
+/// Write the length of `data`, `data` itself, then padding.
+pub fn write_data(data: &[u8], w: &mut Write) -> Result<()> {
+    let len = DataLen::of(data);
+    len.write(w)?;
+    w.write_all(data)?;
+    let padding = [0u8; 15];
+    w.write_all(&padding[0..len.pad_len()])?;
+    Ok(())
+}
+
+#[test]
+fn data_framing() {
+    for n in &[0, 1, 15, 16, 17, 100] {
+        let data: Vec<u8> = (0..*n).map(|i| i as u8).collect();
+        let mut buf = Vec::new();
+        write_data(&data, &mut buf).unwrap();
+        assert_eq!(buf.len() % 16, 8);
+        
+        let len = DataLen::load(&buf, 0).unwrap();
+        assert_eq!(len.len(), data.len());
+        let mut r = &buf[8..];
+        assert_eq!(len.read_data(&mut r, 0).unwrap(), data);
+        assert!(r.is_empty());
+    }
+    
+    // A length exceeding the data available is detected:
+    let mut buf = Vec::new();
+    write_data(b"some data", &mut buf).unwrap();
+    buf[0..8].copy_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0]);
+    let len = DataLen::load(&buf, 0).unwrap();
+    assert_eq!(len.len(), 1 << 32);
+    let e = len.read_data(&mut &buf[8..], 16).unwrap_err();
+    assert!(e.to_string().contains("truncated"));
+}
@@ -11,7 +11,7 @@ use std::result::Result as stdResult;
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
 use PartId;
-use readwrite::sum;
+use readwrite::sum::{self, SumType, SumKey};
 use error::{Result, ArgError, ReadError, make_io_err};
 use sum::BYTES as SUM_BYTES;
 use util::rtrim;
@@ -42,7 +42,15 @@ pub const HEAD_VERSIONS : [u32; 3] = [
 ];
 const SUM_SHA256 : [u8; 16] = *b"HSUM SHA-2 256\x00\x00";
 const SUM_BLAKE2_16 : [u8; 16] = *b"HSUM BLAKE2 16\x00\x00";
+const SUM_BLAKE2_16_KEYED : [u8; 16] = *b"HSUM BLAKE2 16 K";
 const PARTID : [u8; 8] = *b"HPARTID ";
+const CONFIG : [u8; 10] = *b"Q2config\x00\x00";
+// Flags of the `config` block
+const CONFIG_DEDUP : u64 = 1;
+const CONFIG_BLOBS : u64 = 2;
+const RENAMED : [u8; 16] = *b"Q2renamed\x00\x00\x00\x00\x00\x00\x00";
+// Maximum length of a `Qx` section in multiples of 16 bytes (x = 'Z')
+const MAX_QX : usize = 35;
 
 /// File type and version.
 /// 
@@ -79,6 +87,40 @@ pub enum UserData {
     Text(String),
 }
 
+/// How header sections and blocks not known to this version are handled when
+/// reading.
+/// 
+/// The format marks some unknown content as safe to ignore: sections with a
+/// lower-case type and blocks whose name starts with a lower-case letter.
+/// Unknown content not so marked is always rejected.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum HeaderPolicy {
+    /// Skip unknown content marked as safe to ignore (default)
+    Lenient,
+    /// Reject any unknown content, thus a header read successfully has been
+    /// fully interpreted
+    Strict,
+}
+impl Default for HeaderPolicy {
+    fn default() -> Self {
+        HeaderPolicy::Lenient
+    }
+}
+
+/// Partition settings recorded in file headers, so that a partition opened
+/// later writes files as the partition which wrote them did without these
+/// being specified again (see `Partition::set_snapshot_dedup()` and
+/// `Partition::set_blob_threshold()`).
+/// 
+/// Files can be read regardless of these settings.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct PartConfig {
+    /// Whether snapshots de-duplicate element data
+    pub snapshot_dedup: bool,
+    /// Minimum size of element data stored as a blob, if blobs are used
+    pub blob_min: Option<usize>,
+}
+
 /// Information stored in a file header
 pub struct FileHeader {
     /// File type: snapshot or log file.
@@ -89,6 +131,15 @@ pub struct FileHeader {
     pub part_id: Option<PartId>,
     /// User data fields, remarks, etc.
     pub user: Vec<UserData>,
+    /// Partition settings, if recorded.
+    pub config: Option<PartConfig>,
+    /// Former repo name, recorded in the snapshot written by a rename (see
+    /// `Partition::rename()`). Files older than this snapshot may use this
+    /// name.
+    pub renamed_from: Option<String>,
+    /// Checksum algorithm used by the file. When reading this is detected
+    /// from the `HSUM` header block.
+    pub sum_type: SumType,
 }
 
 // Decodes from a string to the format used in HEAD_VERSIONS. Returns zero on
@@ -102,6 +153,22 @@ fn read_head_version(s: &[u8]) -> u32 {
     v
 }
 
+// Read the remainder of a header section of `len` bytes (at least 16) into
+// `buf`, where the first 16 bytes have already been read. The buffer grows as
+// data arrives instead of being sized up front, so that a crafted length does
+// not cause a large allocation unless the data is actually present.
+fn read_section<R: Read>(r: R, buf: &mut Vec<u8>, len: usize, pos: usize) -> Result<()> {
+    buf.truncate(16);
+    r.take((len - 16) as u64).read_to_end(buf)?;
+    if buf.len() < len {
+        return ReadError::err("header section truncated", pos, (0, 4));
+    }
+    if buf.len() < 32 {
+        buf.resize(32, 0);
+    }
+    Ok(())
+}
+
 /// Performs basic validation of a repository name. This same function is used
 /// on the name given to a new partition or repository on creation.
 pub fn validate_repo_name(name: &str) -> stdResult<(), ArgError> {
@@ -115,9 +182,38 @@ pub fn validate_repo_name(name: &str) -> stdResult<(), ArgError> {
 }
 
 /// Read a file header.
+/// 
+/// Fails on files written with a keyed checksum; use `read_head_keyed` for
+/// these.
 pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
-    // A reader which also calculates a checksum:
-    let mut r = sum::HashReader::new(reader);
+    read_head_keyed(reader, None)
+}
+
+/// Parse a file header from a byte slice.
+/// 
+/// This is equivalent to `read_head` on a slice, and is intended as an entry
+/// point for fuzzing: no input should cause a panic, only an error. Any bytes
+/// following the header are ignored.
+pub fn parse_header(bytes: &[u8]) -> Result<FileHeader> {
+    read_head(&mut &bytes[..])
+}
+
+/// Read a file header, using `key` to verify keyed checksums.
+/// 
+/// Files not using a keyed checksum are read as by `read_head`. Files using
+/// a keyed checksum fail to verify unless the same key is given.
+pub fn read_head_keyed(reader: &mut Read, key: Option<SumKey>) -> Result<FileHeader> {
+    read_head_with_policy(reader, key, HeaderPolicy::Lenient)
+}
+
+/// Read a file header as by `read_head_keyed`, handling unknown sections
+/// and blocks according to `policy`.
+pub fn read_head_with_policy(reader: &mut Read, key: Option<SumKey>, policy: HeaderPolicy)
+        -> Result<FileHeader>
+{
+    // A reader which also calculates a checksum. We don't know which algorithm
+    // is used until we reach the "HSUM" block, so calculate all.
+    let mut r = sum::HashReader::new_detect_keyed(reader, key);
     
     let mut pos: usize = 0;
     let mut buf = vec![0; 32];
@@ -144,53 +240,111 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
     pos += 16;
     
     let mut part_id = None;
+    let mut config = None;
+    let mut renamed_from = None;
     let mut user_fields = Vec::new();
-    loop {
+    let sum_type = loop {
         r.read_exact(&mut buf[0..16])?;
         let (block, off): (&[u8], usize) = if buf[0] == b'H' {
             pos += 1;
             (&buf[1..16], 1)
         } else if buf[0] == b'Q' {
             let x: usize = match buf[1] {
-                b'1' ... b'9' => buf[1] - b'0',
-                b'A' ... b'Z' => buf[1] + 10 - b'A',
+                b'1' ..= b'9' => buf[1] - b'0',
+                b'A' ..= b'Z' => buf[1] + 10 - b'A',
                 _ => return ReadError::err("header section Qx... has invalid length specification 'x'", pos, (0, 2))
             } as usize;
+            if x > MAX_QX {
+                return ReadError::err("header section Qx... is too long", pos, (0, 2));
+            }
             let len = x * 16;
-            if buf.len() < len { buf.resize(len, 0); }
-            r.read_exact(&mut buf[16..len])?;
+            read_section(&mut r, &mut buf, len, pos)?;
             pos += 2;
             (&buf[2..len], 2)
         } else if buf[0] == b'B' {
             let len: usize = ((buf[1] as usize) << 16)
                            + ((buf[2] as usize) << 8)
                            +  (buf[3] as usize);
+            if len <= 4 {
+                return ReadError::err("header section has invalid length", pos, (0, 4));
+            }
             let padded = ((len + 15) / 16) * 16; // round up
-            if buf.len() < padded { buf.resize(padded, 0); }
-            r.read_exact(&mut buf[16..padded])?;
+            read_section(&mut r, &mut buf, padded, pos)?;
             pos += 4;
             (&buf[4..len], 4)
+        } else if buf[0] >= b'a' && buf[0] <= b'z' {
+            // Section type unknown to this version, with the same length
+            // specification as `Bbbb`. Skip for forward compatibility.
+            let len: usize = ((buf[1] as usize) << 16)
+                           + ((buf[2] as usize) << 8)
+                           +  (buf[3] as usize);
+            if len < 4 {
+                return ReadError::err("header section has invalid length", pos, (0, 4));
+            }
+            if policy == HeaderPolicy::Strict {
+                return ReadError::err("unknown header section (strict policy)", pos, (0, 1));
+            }
+            let padded = ((len + 15) / 16) * 16; // round up
+            read_section(&mut r, &mut buf, padded, pos)?;
+            trace!("Skipping unknown header section type: {}", buf[0] as char);
+            pos += padded;
+            continue;
         } else {
             return ReadError::err("unexpected header contents", pos, (0, 1));
         };
         
-        if block[0..3] == *b"SUM" {
-            if rtrim(&block[3..], 0) == &SUM_BLAKE2_16[4..14] {
-                /* we don't support any other checksum at run-time, so don't need
-                 * to configure anything here */
+        if block.starts_with(b"SUM") {
+            let sum_type = if rtrim(&block[3..], 0) == &SUM_BLAKE2_16[4..14] {
+                SumType::Blake2b
             } else if rtrim(&block[3..], 0) == &SUM_SHA256[4..14] {
-                return ReadError::err("file uses SHA256 checksum; program not configured for this",
-                    pos, (3+off, 13+off))
-            }else {
+                SumType::Sha256
+            } else if block[3..] == SUM_BLAKE2_16_KEYED[4..] {
+                match key {
+                    Some(key) => SumType::Blake2bKeyed(key),
+                    None => return ReadError::err("checksum key required", pos, (3+off, 15+off)),
+                }
+            } else {
                 return ReadError::err("unknown checksum format", pos, (3+off, 13+off))
             };
-            break;      // "HSUM" must be last item of header before final checksum
-        } else if block[0..7] == PARTID[1..] {
+            r.select(sum_type);
+            break sum_type;     // "HSUM" must be last item of header before final checksum
+        } else if block.starts_with(&PARTID[1..]) {
             if part_id != None {
                 return ReadError::err("repeat of PARTID", pos, (off, off+7));
             }
+            if block.len() < 15 {
+                return ReadError::err("PARTID block too short", pos, (off, off+block.len()));
+            }
             let id = BigEndian::read_u64(&block[7..15]);
             part_id = Some(PartId::try_from(id)?);
+        } else if block.starts_with(&CONFIG[2..8]) {
+            if config != None {
+                return ReadError::err("repeat of config", pos, (off, off+6));
+            }
+            if block.len() < 24 {
+                return ReadError::err("config block too short", pos, (off, off+block.len()));
+            }
+            // Unknown flags are ignored: these settings are inessential
+            let flags = BigEndian::read_u64(&block[8..16]);
+            let blob_min = BigEndian::read_u64(&block[16..24]);
+            if blob_min > usize::max_value() as u64 {
+                return ReadError::err("blob threshold too large for this platform", pos, (off+16, off+24));
+            }
+            config = Some(PartConfig {
+                snapshot_dedup: flags & CONFIG_DEDUP != 0,
+                blob_min: if flags & CONFIG_BLOBS != 0 { Some(blob_min as usize) } else { None },
+            });
+        } else if block.starts_with(&RENAMED[2..9]) {
+            if renamed_from != None {
+                return ReadError::err("repeat of renamed", pos, (off, off+7));
+            }
+            if block.len() < 30 {
+                return ReadError::err("renamed block too short", pos, (off, off+block.len()));
+            }
+            renamed_from = match String::from_utf8(rtrim(&block[14..30], 0).to_vec()) {
+                Ok(name) => Some(name),
+                Err(_) => return ReadError::err("former repo name not valid UTF-8", pos, (off+14, off+30)),
+            };
         } else if block[0] == b'R' {
             user_fields.push(UserData::Text(String::from_utf8(rtrim(&block[1..], 0).to_vec())?));
         } else if block[0] == b'U' {
@@ -203,6 +357,9 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
             return ReadError::err("unknown essential header block", pos, (off, off+block.len()));
         } else if block[0] >= b'a' && block[0] <= b'z' {
             // Match unknown inessential extensions here
+            if policy == HeaderPolicy::Strict {
+                return ReadError::err("unknown header block (strict policy)", pos, (off, off+block.len()));
+            }
             trace!("Ignoring unknown inessential header block: {}", String::from_utf8_lossy(block));
         } else {
             // Match any other block rules here.
@@ -210,7 +367,7 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
             return ReadError::err("invalid header block", pos, (off, off+block.len()));
         }
         pos += block.len();
-    }
+    };
     
     // Read checksum:
     let sum = r.sum();
@@ -225,13 +382,16 @@ pub fn read_head(reader: &mut Read) -> Result<FileHeader> {
         name: repo_name,
         part_id: part_id,
         user: user_fields,
+        config: config,
+        renamed_from: renamed_from,
+        sum_type: sum_type,
     })
 }
 
 /// Write a file header.
 pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
     // A writer which calculates the checksum of what was written:
-    let mut w = sum::HashWriter::new(writer);
+    let mut w = sum::HashWriter::new(writer, header.sum_type);
     
     match header.ftype {
         // Note: we always write in the latest version, even if we read from an old one
@@ -251,6 +411,23 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
         w.write_u64::<BigEndian>(part_id.into())?;
     }
     
+    if let Some(config) = header.config {
+        let mut flags = 0;
+        if config.snapshot_dedup { flags |= CONFIG_DEDUP; }
+        if config.blob_min.is_some() { flags |= CONFIG_BLOBS; }
+        w.write(&CONFIG)?;
+        w.write_u64::<BigEndian>(flags)?;
+        w.write_u64::<BigEndian>(config.blob_min.unwrap_or(0) as u64)?;
+        pad(&mut w, 6)?;
+    }
+    
+    if let Some(ref old_name) = header.renamed_from {
+        validate_repo_name(old_name)?;
+        w.write(&RENAMED)?;
+        let len = w.write(old_name.as_bytes())?;
+        pad(&mut w, 16 - len)?;
+    }
+    
     for u in &header.user {
         // We allow padding in text mode:
         let (t, uf, is_text) = match u {
@@ -262,7 +439,7 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
             w.write(&l[3..5])?;
             w.write(&uf)?;
             pad(&mut w, 14 - uf.len())?;
-        } else if uf.len() + 3 <= 16 * 36 && 
+        } else if uf.len() + 3 <= 16 * MAX_QX && 
             (is_text || (uf.len() + 3) % 16 == 0)
         {
             let n = (uf.len() + 3 /* QxU */ + 15 /* round up */) / 16;
@@ -283,7 +460,11 @@ pub fn write_head(header: &FileHeader, writer: &mut Write) -> Result<()> {
         }
     }
     
-    w.write(&SUM_BLAKE2_16)?;
+    w.write(match header.sum_type {
+        SumType::Sha256 => &SUM_SHA256,
+        SumType::Blake2b => &SUM_BLAKE2_16,
+        SumType::Blake2bKeyed(_) => &SUM_BLAKE2_16_KEYED,
+    })?;
     
     // Write the checksum of everything above:
     let sum = w.sum();
@@ -331,6 +512,7 @@ fn read_header() {
     assert_eq!(header.user[1], UserData::Data(b"user rule".to_vec()));
     assert_eq!(header.user[2], UserData::Data(b"user rule\x00\x00\x00\x00\x00".to_vec()));
     assert_eq!(header.user[3], UserData::Text("EM  completely pointless text".to_string()));
+    assert_eq!(header.sum_type, SumType::Blake2b);
 }
 
 #[test]
@@ -345,6 +527,9 @@ fn write_header() {
             UserData::Data(b"0123456789abcdefghijklmnopqrs".to_vec()),
             UserData::Data(b" rsei noasr auyv 10()% xovn".to_vec()),
         ],
+        config: None,
+        renamed_from: None,
+        sum_type: SumType::Blake2b,
     };
     let mut buf = Vec::new();
     write_head(&header, &mut buf).unwrap();
@@ -368,3 +553,212 @@ fn write_header() {
         assert!(false);
     }
 }
+
+#[test]
+fn write_header_long_text() {
+    // Too long for a Qx section (x = 36 cannot be encoded); uses Bbbb instead
+    let text = "x".repeat(16 * 36 - 3);
+    let header = FileHeader {
+        ftype: FileType::Snapshot(0),
+        name: "long text".to_string(),
+        part_id: None,
+        user: vec![UserData::Text(text.clone())],
+        config: None,
+        renamed_from: None,
+        sum_type: SumType::Blake2b,
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    assert_eq!(buf[32], b'B');
+    let header = read_head(&mut &buf[..]).unwrap();
+    assert_eq!(header.user, vec![UserData::Text(text)]);
+}
+
+#[test]
+fn header_sum_types() {
+    for sum_type in &[SumType::Sha256, SumType::Blake2b] {
+        let header = FileHeader {
+            ftype: FileType::CommitLog(0),
+            name: "sum types".to_string(),
+            part_id: None,
+            user: vec![UserData::Text("Remark".to_string())],
+            config: None,
+            renamed_from: None,
+            sum_type: *sum_type,
+        };
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf).unwrap();
+        let header2 = read_head(&mut &buf[..]).unwrap();
+        assert_eq!(header2.sum_type, *sum_type);
+        assert_eq!(header2.user, header.user);
+        
+        // Corruption must still be detected:
+        buf[20] ^= 1;
+        assert!(read_head(&mut &buf[..]).is_err());
+    }
+}
+
+#[test]
+fn header_keyed_sum() {
+    let key = SumKey::new([0x5A; 32]);
+    let header = FileHeader {
+        ftype: FileType::Snapshot(0),
+        name: "keyed".to_string(),
+        part_id: None,
+        user: vec![],
+        config: None,
+        renamed_from: None,
+        sum_type: SumType::Blake2bKeyed(key),
+    };
+    let mut buf = Vec::new();
+    write_head(&header, &mut buf).unwrap();
+    
+    assert!(read_head(&mut &buf[..]).is_err());
+    let wrong_key = SumKey::new([0x5B; 32]);
+    assert!(read_head_keyed(&mut &buf[..], Some(wrong_key)).is_err());
+    let header2 = read_head_keyed(&mut &buf[..], Some(key)).unwrap();
+    assert_eq!(header2.sum_type, SumType::Blake2bKeyed(key));
+}
+
+#[test]
+fn header_config() {
+    let configs = [
+        PartConfig { snapshot_dedup: false, blob_min: None },
+        PartConfig { snapshot_dedup: true, blob_min: Some(0) },
+        PartConfig { snapshot_dedup: false, blob_min: Some(4096) },
+    ];
+    for config in &configs {
+        let header = FileHeader {
+            ftype: FileType::Snapshot(0),
+            name: "config".to_string(),
+            part_id: Some(PartId::from_num(2)),
+            user: vec![UserData::Text("Remark".to_string())],
+            config: Some(*config),
+            renamed_from: None,
+            sum_type: SumType::Blake2b,
+        };
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf).unwrap();
+        assert_eq!(buf.len() % 16, 0);
+        for policy in &[HeaderPolicy::Lenient, HeaderPolicy::Strict] {
+            let header2 = read_head_with_policy(&mut &buf[..], None, *policy).unwrap();
+            assert_eq!(header2.config, Some(*config));
+            assert_eq!(header2.part_id, header.part_id);
+            assert_eq!(header2.user, header.user);
+        }
+    }
+}
+
+#[test]
+fn header_renamed() {
+    for old_name in &[None, Some("old name"), Some("sixteen byte nam")] {
+        let header = FileHeader {
+            ftype: FileType::Snapshot(0),
+            name: "new name".to_string(),
+            part_id: Some(PartId::from_num(3)),
+            user: vec![],
+            config: None,
+            renamed_from: old_name.map(|n| n.to_string()),
+            sum_type: SumType::Blake2b,
+        };
+        let mut buf = Vec::new();
+        write_head(&header, &mut buf).unwrap();
+        assert_eq!(buf.len() % 16, 0);
+        for policy in &[HeaderPolicy::Lenient, HeaderPolicy::Strict] {
+            let header2 = read_head_with_policy(&mut &buf[..], None, *policy).unwrap();
+            assert_eq!(header2.name, header.name);
+            assert_eq!(header2.renamed_from, header.renamed_from);
+        }
+    }
+}
+
+#[test]
+fn read_header_unknown_section() {
+    fn with_sum(data: &[u8]) -> Vec<u8> {
+        use ::Sum;
+        let mut v = data.to_vec();
+        Sum::calculate(data).write(&mut v).unwrap();
+        v
+    }
+    
+    // A section type not known to this version, with a length prefix
+    let head = with_sum(b"PIPPINCL20160815\
+                forward compat\x00\x00\
+                x\x00\x00\x14new section \
+                data\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                HRemark\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+                HSUM BLAKE2 16\x00\x00");
+    let header = read_head(&mut &head[..]).expect("read header");
+    assert_eq!(header.name, "forward compat");
+    assert_eq!(header.user, vec![UserData::Text("emark".to_string())]);
+    // ... which the strict policy rejects
+    assert!(read_head_with_policy(&mut &head[..], None, HeaderPolicy::Lenient).is_ok());
+    assert!(read_head_with_policy(&mut &head[..], None, HeaderPolicy::Strict).is_err());
+    
+    // Likewise an inessential block
+    let head = with_sum(b"PIPPINCL20160815\
+                forward compat\x00\x00\
+                Hxnew block\x00\x00\x00\x00\x00\
+                HSUM BLAKE2 16\x00\x00");
+    assert!(read_head_with_policy(&mut &head[..], None, HeaderPolicy::Lenient).is_ok());
+    assert!(read_head_with_policy(&mut &head[..], None, HeaderPolicy::Strict).is_err());
+    
+    // Invalid length
+    let head = with_sum(b"PIPPINCL20160815\
+                forward compat\x00\x00\
+                x\x00\x00\x02section data\
+                HSUM BLAKE2 16\x00\x00");
+    assert!(read_head(&mut &head[..]).is_err());
+    
+    // Garbage
+    let head = with_sum(b"PIPPINCL20160815\
+                forward compat\x00\x00\
+                \x01\xff\x8e\x14#garbage!!\x00\x00\
+                HSUM BLAKE2 16\x00\x00");
+    assert!(read_head(&mut &head[..]).is_err());
+}
+
+#[test]
+fn parse_header_malformed() {
+    // Inputs which previously caused a panic; each must fail cleanly
+    let inputs: [&[u8]; 4] = [
+        // PARTID block in a section too short to hold the identifier
+        b"PIPPINSS20160815malformed\x00\x00\x00\x00\x00\x00\x00\
+            Q1PARTID 1234567",
+        // Bbbb section with length less than its prefix
+        b"PIPPINSS20160815malformed\x00\x00\x00\x00\x00\x00\x00\
+            B\x00\x00\x02\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+        // Empty Bbbb section
+        b"PIPPINSS20160815malformed\x00\x00\x00\x00\x00\x00\x00\
+            B\x00\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+        // Bbbb section shorter than the PARTID marker
+        b"PIPPINSS20160815malformed\x00\x00\x00\x00\x00\x00\x00\
+            B\x00\x00\x08PART\x00\x00\x00\x00\x00\x00\x00\x00",
+    ];
+    for input in &inputs {
+        assert!(parse_header(input).is_err());
+    }
+    // Qx section count beyond the maximum (x = 'Z'):
+    assert!(parse_header(b"PIPPINSS20160815malformed\x00\x00\x00\x00\x00\x00\x00\
+            Q[USER\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00").is_err());
+    assert!(parse_header(b"").is_err());
+    assert!(parse_header(b"PIPPINSS2016081").is_err());
+}
+
+#[test]
+fn read_section_huge() {
+    // Claim the maximum Bbbb section length but provide no data:
+    let head = b"PIPPINSS20160815huge\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\
+            B\xff\xff\xff\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+    assert!(parse_header(head).is_err());
+    
+    let mut buf = vec![0; 32];
+    assert!(read_section(&mut &b""[..], &mut buf, 0xFF_FFF0, 0).is_err());
+    assert!(buf.capacity() < 0x1_0000);
+    
+    // With data present, the section is read in full:
+    let data = vec![7u8; 100];
+    assert!(read_section(&mut &data[..], &mut buf, 64, 0).is_ok());
+    assert_eq!(buf.len(), 64);
+    assert_eq!(buf[63], 7);
+}
@@ -0,0 +1,148 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Read and write support for repository transaction journals.
+//! 
+//! A journal lists commits about to be written to several partitions, so
+//! that an interrupted write can be completed later. The format is:
+//! 
+//! *   `PIPPIN JOURNAL\x00\x00`
+//! *   for each partition, `PARTID  ` followed by a `u64` partition
+//!     identifier, `LOG LEN ` followed by a `u64` length, then a commit log
+//!     body of this length (as in a commit log file, without the header)
+//! *   `JOURNAL END\x00\x00\x00\x00\x00`
+//! *   a checksum of everything above
+
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use readwrite::sum::{self, SumType};
+use readwrite::header::HEAD_VERSIONS;
+use readwrite::commitlog::{read_log, start_log, write_commit};
+use readwrite::blob::NoBlobs;
+use commit::Commit;
+use {ElementT, PartId};
+use sum::BYTES as SUM_BYTES;
+use error::{Result, ReadError};
+
+const JOURNAL_START: [u8; 16] = *b"PIPPIN JOURNAL\x00\x00";
+const JOURNAL_END: [u8; 16] = *b"JOURNAL END\x00\x00\x00\x00\x00";
+
+/// Read a journal, returning the list of commits for each partition.
+/// 
+/// Fails if the journal is incomplete or its checksum does not match.
+pub fn read_journal<E: ElementT>(reader: &mut Read) -> Result<Vec<(PartId, Vec<Commit<E>>)>> {
+    // A reader which calculates the checksum of what was read:
+    let mut r = sum::HashReader::new(reader, SumType::Blake2b);
+    
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    assert!(buf.len() >= SUM_BYTES);
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..16] != JOURNAL_START {
+        return ReadError::err("not a Pippin journal", pos, (0, 16));
+    }
+    pos += 16;
+    
+    let mut parts = Vec::new();
+    loop {
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..16] == JOURNAL_END {
+            break;
+        }
+        if buf[0..8] != *b"PARTID  " {
+            return ReadError::err("unexpected contents (expected PARTID)", pos, (0, 8));
+        }
+        let part_id = PartId::try_from(BigEndian::read_u64(&buf[8..16]))?;
+        pos += 16;
+        
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] != *b"LOG LEN " {
+            return ReadError::err("unexpected contents (expected LOG LEN)", pos, (0, 8));
+        }
+        let len = BigEndian::read_u64(&buf[8..16]) as usize;     // #0015
+        pos += 16;
+        
+        let mut data = vec![0; len];
+        r.read_exact(&mut data)?;
+        let mut commits = Vec::new();
+        read_log(&mut &data[..], &mut commits, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1],
+                SumType::Blake2b, &NoBlobs)?;
+        pos += len;
+        parts.push((part_id, commits));
+    }
+    pos += 16;
+    
+    let sum = r.sum();
+    let mut r = r.into_inner();
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    if !sum.eq(&buf[0..SUM_BYTES]) {
+        return ReadError::err("journal checksum invalid", pos, (0, SUM_BYTES));
+    }
+    Ok(parts)
+}
+
+/// Write a journal listing the commits for each partition given.
+pub fn write_journal<E: ElementT>(parts: &[(PartId, Vec<&Commit<E>>)],
+        writer: &mut Write) -> Result<()>
+{
+    // A writer which calculates the checksum of what was written:
+    let mut w = sum::HashWriter::new(writer, SumType::Blake2b);
+    
+    w.write(&JOURNAL_START)?;
+    let mut data = Vec::new();
+    for &(part_id, ref commits) in parts {
+        data.clear();
+        start_log(&mut data)?;
+        for commit in commits {
+            write_commit(*commit, &mut data, SumType::Blake2b, None)?;
+        }
+        
+        w.write(b"PARTID  ")?;
+        w.write_u64::<BigEndian>(part_id.into())?;
+        w.write(b"LOG LEN ")?;
+        w.write_u64::<BigEndian>(data.len() as u64)?;
+        w.write(&data)?;
+    }
+    w.write(&JOURNAL_END)?;
+    
+    // Write the checksum of everything above:
+    let sum = w.sum();
+    sum.write(&mut w.into_inner())?;
+    Ok(())
+}
+
+#[test]
+fn journal_write_read() {
+    use std::sync::Arc;
+    use std::collections::HashMap;
+    use commit::{CommitMeta, ExtraMeta, MetaFlags, EltChange};
+    use Sum;
+    
+    let make_commit = |p: PartId, n: u32, text: &str| {
+        let mut changes = HashMap::new();
+        changes.insert(p.elt_id(n), EltChange::insertion(Arc::new(text.to_string())));
+        let meta = CommitMeta::new_explicit(n, 123456, MetaFlags::zero(), vec![],
+                ExtraMeta::None).expect("new meta");
+        let sum = Sum::calculate(text.as_bytes());
+        Commit::new_explicit(sum, vec![Sum::calculate(b"parent")], changes, meta)
+    };
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let c1 = make_commit(p1, 1, "one");
+    let c2 = make_commit(p1, 2, "two");
+    let c3 = make_commit(p2, 3, "three");
+    
+    let mut buf = Vec::new();
+    write_journal(&[(p1, vec![&c1, &c2]), (p2, vec![&c3])], &mut buf).unwrap();
+    
+    let parts = read_journal::<String>(&mut &buf[..]).unwrap();
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0], (p1, vec![c1, c2]));
+    assert_eq!(parts[1], (p2, vec![c3]));
+    
+    // An incomplete journal must not be accepted:
+    assert!(read_journal::<String>(&mut &buf[0..buf.len() - 40]).is_err());
+}
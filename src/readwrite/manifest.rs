@@ -0,0 +1,223 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Read and write support for repository manifests.
+//! 
+//! A manifest describes the structure of a repository (its partitions, their
+//! tips and the user fields describing classification) without any element
+//! data. The format is:
+//! 
+//! *   `PIPPIN MANIFEST\x00`
+//! *   16 bytes: repository name, right-padded with zeros
+//! *   for each partition:
+//! 
+//!     1.  `PARTID  ` followed by a `u64` partition identifier
+//!     2.  `TIPS    ` followed by a `u64` number of tips, then each tip's
+//!         state sum
+//!     3.  `USER    ` followed by a `u64` number of user fields, then for
+//!         each `UDATA   ` (binary data) or `UTEXT   ` (UTF-8 text) followed
+//!         by a `u64` length and the data, zero-padded to a 16-byte boundary
+//! *   `MANIFEST END\x00\x00\x00\x00`
+//! *   a checksum of everything above
+
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use readwrite::sum::{self, SumType};
+use readwrite::header::UserData;
+use {PartId, Sum};
+use sum::BYTES as SUM_BYTES;
+use error::{Result, ReadError, ArgError};
+
+const MANIFEST_START: [u8; 16] = *b"PIPPIN MANIFEST\x00";
+const MANIFEST_END: [u8; 16] = *b"MANIFEST END\x00\x00\x00\x00";
+
+/// Description of a repository's structure (see
+/// `Repository::export_manifest()`).
+#[derive(Clone, PartialEq, Debug)]
+pub struct Manifest {
+    /// Repository name
+    pub name: String,
+    /// Details of each partition
+    pub parts: Vec<ManifestPart>,
+}
+
+/// Description of a partition within a `Manifest`.
+#[derive(Clone, PartialEq, Debug)]
+pub struct ManifestPart {
+    /// Partition identifier
+    pub part_id: PartId,
+    /// State sums of the partition's tips (empty if not loaded)
+    pub tips: Vec<Sum>,
+    /// User fields for the partition, as given by `UserFields`
+    pub user: Vec<UserData>,
+}
+
+/// Read a manifest.
+/// 
+/// Fails if the manifest is incomplete or its checksum does not match.
+pub fn read_manifest(reader: &mut Read) -> Result<Manifest> {
+    // A reader which calculates the checksum of what was read:
+    let mut r = sum::HashReader::new(reader, SumType::Blake2b);
+    
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    assert!(buf.len() >= SUM_BYTES);
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..16] != MANIFEST_START {
+        return ReadError::err("not a Pippin manifest", pos, (0, 16));
+    }
+    pos += 16;
+    
+    r.read_exact(&mut buf[0..16])?;
+    let name_len = buf[0..16].iter().position(|b| *b == 0).unwrap_or(16);
+    let name = match String::from_utf8(buf[0..name_len].to_vec()) {
+        Ok(name) => name,
+        Err(_) => return ReadError::err("repo name not valid UTF-8", pos, (0, 16)),
+    };
+    pos += 16;
+    
+    let mut parts = Vec::new();
+    loop {
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..16] == MANIFEST_END {
+            break;
+        }
+        if buf[0..8] != *b"PARTID  " {
+            return ReadError::err("unexpected contents (expected PARTID)", pos, (0, 8));
+        }
+        let part_id = PartId::try_from(BigEndian::read_u64(&buf[8..16]))?;
+        pos += 16;
+        
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] != *b"TIPS    " {
+            return ReadError::err("unexpected contents (expected TIPS)", pos, (0, 8));
+        }
+        let num_tips = BigEndian::read_u64(&buf[8..16]) as usize;     // #0015
+        pos += 16;
+        let mut tips = Vec::with_capacity(num_tips);
+        for _ in 0..num_tips {
+            r.read_exact(&mut buf[0..SUM_BYTES])?;
+            tips.push(Sum::load(&buf[0..SUM_BYTES]));
+            pos += SUM_BYTES;
+        }
+        
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] != *b"USER    " {
+            return ReadError::err("unexpected contents (expected USER)", pos, (0, 8));
+        }
+        let num_user = BigEndian::read_u64(&buf[8..16]) as usize;     // #0015
+        pos += 16;
+        let mut user = Vec::with_capacity(num_user);
+        for _ in 0..num_user {
+            r.read_exact(&mut buf[0..16])?;
+            let is_text = if buf[0..8] == *b"UTEXT   " {
+                true
+            } else if buf[0..8] == *b"UDATA   " {
+                false
+            } else {
+                return ReadError::err("unexpected contents (expected UTEXT or UDATA)", pos, (0, 8));
+            };
+            let len = BigEndian::read_u64(&buf[8..16]) as usize;     // #0015
+            pos += 16;
+            let mut data = vec![0; ((len + 15) / 16) * 16];
+            r.read_exact(&mut data)?;
+            data.truncate(len);
+            user.push(if is_text {
+                match String::from_utf8(data) {
+                    Ok(text) => UserData::Text(text),
+                    Err(_) => return ReadError::err("user text not valid UTF-8", pos, (0, len)),
+                }
+            } else {
+                UserData::Data(data)
+            });
+            pos += ((len + 15) / 16) * 16;
+        }
+        
+        parts.push(ManifestPart { part_id: part_id, tips: tips, user: user });
+    }
+    pos += 16;
+    
+    let sum = r.sum();
+    let mut r = r.into_inner();
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    if !sum.eq(&buf[0..SUM_BYTES]) {
+        return ReadError::err("manifest checksum invalid", pos, (0, SUM_BYTES));
+    }
+    Ok(Manifest { name: name, parts: parts })
+}
+
+/// Write a manifest.
+pub fn write_manifest(manifest: &Manifest, writer: &mut Write) -> Result<()> {
+    if manifest.name.len() > 16 {
+        return ArgError::err("repo name too long");
+    }
+    
+    // A writer which calculates the checksum of what was written:
+    let mut w = sum::HashWriter::new(writer, SumType::Blake2b);
+    let zeros = [0u8; 16];
+    
+    w.write(&MANIFEST_START)?;
+    w.write(manifest.name.as_bytes())?;
+    w.write(&zeros[manifest.name.len()..])?;
+    for part in &manifest.parts {
+        w.write(b"PARTID  ")?;
+        w.write_u64::<BigEndian>(part.part_id.into())?;
+        
+        w.write(b"TIPS    ")?;
+        w.write_u64::<BigEndian>(part.tips.len() as u64)?;
+        for tip in &part.tips {
+            tip.write(&mut w)?;
+        }
+        
+        w.write(b"USER    ")?;
+        w.write_u64::<BigEndian>(part.user.len() as u64)?;
+        for u in &part.user {
+            let (marker, data) = match u {
+                &UserData::Data(ref d) => (b"UDATA   ", &d[..]),
+                &UserData::Text(ref t) => (b"UTEXT   ", t.as_bytes()),
+            };
+            w.write(marker)?;
+            w.write_u64::<BigEndian>(data.len() as u64)?;
+            w.write(data)?;
+            let pad_len = 16 * ((data.len() + 15) / 16) - data.len();
+            w.write(&zeros[0..pad_len])?;
+        }
+    }
+    w.write(&MANIFEST_END)?;
+    
+    // Write the checksum of everything above:
+    let sum = w.sum();
+    sum.write(&mut w.into_inner())?;
+    Ok(())
+}
+
+#[test]
+fn manifest_write_read() {
+    let manifest = Manifest {
+        name: "manifest test".to_string(),
+        parts: vec![
+            ManifestPart {
+                part_id: PartId::from_num(1),
+                tips: vec![Sum::calculate(b"one"), Sum::calculate(b"two")],
+                user: vec![UserData::Text("αβγ".to_string()), UserData::Data(vec![1, 2, 3])],
+            },
+            ManifestPart {
+                part_id: PartId::from_num(12),
+                tips: vec![],
+                user: vec![],
+            },
+        ],
+    };
+    
+    let mut buf = Vec::new();
+    write_manifest(&manifest, &mut buf).unwrap();
+    assert_eq!(read_manifest(&mut &buf[..]).unwrap(), manifest);
+    
+    // Corruption must be detected:
+    buf[40] ^= 4;
+    assert!(read_manifest(&mut &buf[..]).is_err());
+}
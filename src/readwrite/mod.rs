@@ -14,10 +14,26 @@ mod sum;
 mod header;
 mod snapshot;
 mod commitlog;
+mod journal;
+mod replicate;
+mod manifest;
+mod partalloc;
+mod blob;
+mod eltdata;
 
-pub use self::header::{UserData, FileHeader, FileType, read_head, write_head, validate_repo_name};
-pub use self::snapshot::{read_snapshot, write_snapshot};
-pub use self::commitlog::{CommitReceiver, read_log, start_log, write_commit};
+pub use self::header::{UserData, FileHeader, FileType, HeaderPolicy, PartConfig, read_head,
+        read_head_keyed, read_head_with_policy, parse_header, write_head, validate_repo_name,
+        HEAD_VERSIONS};
+pub use self::snapshot::{read_snapshot, read_snapshot_checked, write_snapshot,
+        write_snapshot_dedup, SnapshotEltIter};
+pub use self::commitlog::{CommitReceiver, read_log, read_log_checked, read_log_tail,
+        start_log, write_commit};
+pub use self::sum::{SumType, SumKey, SumCheck};
+pub use self::journal::{read_journal, write_journal};
+pub use self::replicate::{read_replication, write_replication};
+pub use self::manifest::{Manifest, ManifestPart, read_manifest, write_manifest};
+pub use self::partalloc::{PartIdAlloc, read_part_alloc, write_part_alloc};
+pub use self::blob::{BlobSource, NoBlobs, blob_key};
 
 use std::io::{Read, Write};
 use std::u32;
@@ -25,8 +41,8 @@ use std::iter::repeat;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
-use commit::{CommitMeta, ExtraMeta, MetaFlags};
-use error::{Result, ReadError};
+use commit::{CommitMeta, ExtraMeta, MetaFlags, EXT_DATA_MAX};
+use error::{Result, ReadError, OtherError};
 
 // —————  private utility functions  —————
 
@@ -41,7 +57,7 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
     let secs = BigEndian::read_i64(&buf[8..16]);
     (*pos) += 16;
     
-    r.read_exact(&mut buf[0..16])?;
+    r.read_exact(&mut buf[0..8])?;
     let (ext_len, ext_flags) = if format_ver < 2016_08_15 {
         if buf[0..4] != *b"CNUM" {
             return ReadError::err("unexpected contents (expected CNUM)", *pos, (0, 4));
@@ -56,15 +72,18 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
         (len, flags)
     };
     let cnum = BigEndian::read_u32(&buf[4..8]);
+    // Extension data sits between the commit number and XM:
     let mut ext_data: Vec<u8> = repeat(0).take(ext_len).collect();
     r.read_exact(&mut ext_data)?;
+    (*pos) += 8 + ext_len;
     
-    if buf[8..10] != *b"XM" {
-        return ReadError::err("unexpected contents (expected XM)", *pos, (8, 10));
+    r.read_exact(&mut buf[0..8])?;
+    if buf[0..2] != *b"XM" {
+        return ReadError::err("unexpected contents (expected XM)", *pos, (0, 2));
     }
-    let xm_type_txt = buf[10..12] == *b"TT";
-    let xm_len = BigEndian::read_u32(&buf[12..16]) as usize;
-    (*pos) += 16;
+    let xm_type_txt = buf[2..4] == *b"TT";
+    let xm_len = BigEndian::read_u32(&buf[4..8]) as usize;
+    (*pos) += 8;
     
     let mut xm_data = vec![0; xm_len];
     r.read_exact(&mut xm_data)?;
@@ -89,13 +108,17 @@ fn read_meta(mut r: &mut Read, mut buf: &mut [u8], mut pos: &mut usize, format_v
 
 /// Write commit metadata
 fn write_meta(w: &mut Write, meta: &CommitMeta) -> Result<()> {
+    let ext_data = meta.ext_data();
+    if ext_data.len() > EXT_DATA_MAX {
+        return OtherError::err("commit meta extension data too long");
+    }
     w.write_i64::<BigEndian>(meta.timestamp())?;
     
     w.write(b"F")?;
-    w.write(&[0u8; 1])?; // 0 extension data: we don't use this currently
+    w.write(&[(ext_data.len() / 8) as u8])?;
     w.write_u16::<BigEndian>(meta.ext_flags().raw())?;
     w.write_u32::<BigEndian>(meta.number())?;
-    // extension data would go here, but we don't currently have any
+    w.write(&ext_data)?;
     
     match meta.extra() {
         &ExtraMeta::None => {
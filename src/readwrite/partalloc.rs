@@ -0,0 +1,237 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Partition identifier allocation and its read and write support.
+//! 
+//! A repository records which partition identifiers are allocated, so that
+//! identifiers are never reused by accident (e.g. by two divisions, or after
+//! a partition is deleted while another process still refers to it). The
+//! record is stored via `RepoIO::write_part_alloc()` in the format:
+//! 
+//! *   `PIPPIN PARTIDS\x00\x00`
+//! *   `RANGES  ` followed by a `u64` number of ranges
+//! *   for each range of allocated identifiers, the first and last numbers
+//!     (inclusive), each a `u64`; ranges are ascending and not adjacent
+//! *   a checksum of everything above
+
+use std::io::{Read, Write};
+use std::result;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use readwrite::sum::{self, SumType};
+use PartId;
+use sum::BYTES as SUM_BYTES;
+use error::{Result, ReadError};
+
+const PARTIDS_START: [u8; 16] = *b"PIPPIN PARTIDS\x00\x00";
+
+/// Set of allocated partition identifiers (see `Repository::part_alloc()`).
+/// 
+/// This is stored as a list of ranges, thus is compact where identifiers are
+/// allocated contiguously.
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct PartIdAlloc {
+    // Ranges of allocated numbers: (first, last), inclusive. Sorted, not
+    // overlapping and not adjacent.
+    ranges: Vec<(u64, u64)>,
+}
+
+impl PartIdAlloc {
+    /// Create, with no identifiers allocated
+    pub fn new() -> PartIdAlloc {
+        PartIdAlloc { ranges: Vec::new() }
+    }
+    
+    /// Create, with the given identifiers allocated
+    pub fn from_ids<I: IntoIterator<Item = PartId>>(ids: I) -> PartIdAlloc {
+        let mut alloc = PartIdAlloc::new();
+        for id in ids {
+            alloc.allocate(id);
+        }
+        alloc
+    }
+    
+    // Index of the range containing `n`, or the index at which a range
+    // starting at `n` would be inserted
+    fn find(&self, n: u64) -> result::Result<usize, usize> {
+        match self.ranges.binary_search_by(|r| r.0.cmp(&n)) {
+            Ok(i) => Ok(i),
+            Err(i) if i > 0 && self.ranges[i - 1].1 >= n => Ok(i - 1),
+            Err(i) => Err(i),
+        }
+    }
+    
+    /// True if `id` is allocated
+    pub fn is_allocated(&self, id: PartId) -> bool {
+        self.find(id.into_num()).is_ok()
+    }
+    
+    /// Mark `id` as allocated. Returns false if it already was.
+    pub fn allocate(&mut self, id: PartId) -> bool {
+        let n = id.into_num();
+        let i = match self.find(n) {
+            Ok(_) => return false,
+            Err(i) => i,
+        };
+        // n < max_num(), thus n + 1 cannot overflow
+        let join_prev = i > 0 && self.ranges[i - 1].1 + 1 == n;
+        let join_next = i < self.ranges.len() && self.ranges[i].0 == n + 1;
+        match (join_prev, join_next) {
+            (true, true) => {
+                self.ranges[i - 1].1 = self.ranges[i].1;
+                self.ranges.remove(i);
+            },
+            (true, false) => { self.ranges[i - 1].1 = n; },
+            (false, true) => { self.ranges[i].0 = n; },
+            (false, false) => { self.ranges.insert(i, (n, n)); },
+        }
+        true
+    }
+    
+    /// Mark `id` as free. Returns false if it was not allocated.
+    pub fn free(&mut self, id: PartId) -> bool {
+        let n = id.into_num();
+        let i = match self.find(n) {
+            Ok(i) => i,
+            Err(_) => return false,
+        };
+        let (first, last) = self.ranges[i];
+        if first == last {
+            self.ranges.remove(i);
+        } else if n == first {
+            self.ranges[i].0 = n + 1;
+        } else if n == last {
+            self.ranges[i].1 = n - 1;
+        } else {
+            self.ranges[i].1 = n - 1;
+            self.ranges.insert(i + 1, (n + 1, last));
+        }
+        true
+    }
+    
+    /// Get the first free identifier not less than `from`, if any.
+    pub fn next_free(&self, from: PartId) -> Option<PartId> {
+        let n = match self.find(from.into_num()) {
+            Ok(i) => self.ranges[i].1 + 1,  // ranges are not adjacent
+            Err(_) => from.into_num(),
+        };
+        if n <= PartId::max_num() {
+            Some(PartId::from_num(n))
+        } else {
+            None
+        }
+    }
+    
+    /// Get the number of identifiers allocated
+    pub fn num_allocated(&self) -> u64 {
+        self.ranges.iter().map(|&(first, last)| last - first + 1).fold(0, |a, b| a + b)
+    }
+    
+    /// Get ranges of allocated identifiers, as `(first, last)` (inclusive),
+    /// in ascending order.
+    pub fn ranges(&self) -> Vec<(PartId, PartId)> {
+        self.ranges.iter()
+            .map(|&(first, last)| (PartId::from_num(first), PartId::from_num(last)))
+            .collect()
+    }
+}
+
+/// Read an allocation record.
+/// 
+/// Fails if the record is incomplete, invalid or its checksum does not match.
+pub fn read_part_alloc(reader: &mut Read) -> Result<PartIdAlloc> {
+    // A reader which calculates the checksum of what was read:
+    let mut r = sum::HashReader::new(reader, SumType::Blake2b);
+    
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    assert!(buf.len() >= SUM_BYTES);
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..16] != PARTIDS_START {
+        return ReadError::err("not a Pippin partition identifier record", pos, (0, 16));
+    }
+    pos += 16;
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..8] != *b"RANGES  " {
+        return ReadError::err("unexpected contents (expected RANGES)", pos, (0, 8));
+    }
+    let num = BigEndian::read_u64(&buf[8..16]);
+    pos += 16;
+    
+    let mut ranges = Vec::new();
+    for _ in 0..num {
+        r.read_exact(&mut buf[0..16])?;
+        let first = BigEndian::read_u64(&buf[0..8]);
+        let last = BigEndian::read_u64(&buf[8..16]);
+        if first == 0 || last < first || last > PartId::max_num() {
+            return ReadError::err("invalid range", pos, (0, 16));
+        }
+        if ranges.last().map_or(false, |&(_, prev): &(u64, u64)| first <= prev + 1) {
+            return ReadError::err("ranges out of order or adjacent", pos, (0, 8));
+        }
+        ranges.push((first, last));
+        pos += 16;
+    }
+    
+    let sum = r.sum();
+    let mut r = r.into_inner();
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    if !sum.eq(&buf[0..SUM_BYTES]) {
+        return ReadError::err("partition identifier record checksum invalid", pos, (0, SUM_BYTES));
+    }
+    Ok(PartIdAlloc { ranges: ranges })
+}
+
+/// Write an allocation record.
+pub fn write_part_alloc(alloc: &PartIdAlloc, writer: &mut Write) -> Result<()> {
+    // A writer which calculates the checksum of what was written:
+    let mut w = sum::HashWriter::new(writer, SumType::Blake2b);
+    
+    w.write(&PARTIDS_START)?;
+    w.write(b"RANGES  ")?;
+    w.write_u64::<BigEndian>(alloc.ranges.len() as u64)?;
+    for &(first, last) in &alloc.ranges {
+        w.write_u64::<BigEndian>(first)?;
+        w.write_u64::<BigEndian>(last)?;
+    }
+    
+    // Write the checksum of everything above:
+    let sum = w.sum();
+    sum.write(&mut w.into_inner())?;
+    Ok(())
+}
+
+#[test]
+fn part_alloc() {
+    let p = |n| PartId::from_num(n);
+    let mut alloc = PartIdAlloc::from_ids(vec![p(1), p(2), p(3), p(7)]);
+    assert_eq!(alloc.ranges(), vec![(p(1), p(3)), (p(7), p(7))]);
+    assert!(!alloc.allocate(p(2)));
+    assert_eq!(alloc.next_free(p(1)), Some(p(4)));
+    assert_eq!(alloc.next_free(p(5)), Some(p(5)));
+    
+    assert!(alloc.free(p(2)));
+    assert!(!alloc.free(p(2)));
+    assert!(!alloc.is_allocated(p(2)));
+    assert_eq!(alloc.next_free(p(1)), Some(p(2)));
+    assert!(alloc.allocate(p(6)));
+    assert_eq!(alloc.ranges(), vec![(p(1), p(1)), (p(3), p(3)), (p(6), p(7))]);
+    assert_eq!(alloc.num_allocated(), 4);
+    
+    assert!(alloc.allocate(p(2)));
+    assert_eq!(alloc.ranges(), vec![(p(1), p(3)), (p(6), p(7))]);
+    let max = p(PartId::max_num());
+    assert!(alloc.allocate(max));
+    assert_eq!(alloc.next_free(max), None);
+    
+    let mut buf = Vec::new();
+    write_part_alloc(&alloc, &mut buf).unwrap();
+    assert_eq!(read_part_alloc(&mut &buf[..]).unwrap(), alloc);
+    // Damaged data must not be accepted:
+    buf[20] ^= 1;
+    assert!(read_part_alloc(&mut &buf[..]).is_err());
+}
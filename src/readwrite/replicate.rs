@@ -0,0 +1,225 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Read and write support for replication streams.
+//! 
+//! A replication stream carries the commits made to some partitions of one
+//! repository since given states, allowing another copy of the repository to
+//! catch up (see `Repository::export_since()`). Where the receiver is not
+//! assumed to have any state of a partition, a snapshot is sent instead. The
+//! format is:
+//! 
+//! *   `PIPPIN REPLICATE`
+//! *   the repository name, zero-padded to 16 bytes
+//! *   for each partition, `PARTID  ` followed by a `u64` partition
+//!     identifier; `SNAPSHOT` followed by a `u64` length then a snapshot body
+//!     of this length (as in a snapshot file, without the header), or
+//!     `NO SNAP ` followed by a zero `u64`; then `LOG LEN ` followed by a
+//!     `u64` length, then a commit log body of this length
+//! *   `REPLICATE END\x00\x00\x00`
+//! *   a checksum of everything above
+
+use std::io::{Read, Write};
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use readwrite::sum::{self, SumType};
+use readwrite::header::HEAD_VERSIONS;
+use readwrite::snapshot::{read_snapshot, write_snapshot};
+use readwrite::commitlog::{read_log, start_log, write_commit};
+use readwrite::blob::NoBlobs;
+use commit::Commit;
+use {ElementT, PartId, PartState};
+use sum::BYTES as SUM_BYTES;
+use error::{Result, ReadError, ArgError};
+
+const REPLICATE_START: [u8; 16] = *b"PIPPIN REPLICATE";
+const REPLICATE_END: [u8; 16] = *b"REPLICATE END\x00\x00\x00";
+
+/// Data for one partition within a replication stream: the identifier, a
+/// snapshot if sent, and commits (to be applied after the snapshot, if any).
+pub type ReplicatedPart<E> = (PartId, Option<PartState<E>>, Vec<Commit<E>>);
+
+/// Read a replication stream, returning the repository name and data for
+/// each partition.
+/// 
+/// Fails if the stream is incomplete or its checksum does not match.
+pub fn read_replication<E: ElementT>(reader: &mut Read)
+        -> Result<(String, Vec<ReplicatedPart<E>>)>
+{
+    // A reader which calculates the checksum of what was read:
+    let mut r = sum::HashReader::new(reader, SumType::Blake2b);
+    let format_ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    
+    let mut pos: usize = 0;
+    let mut buf = vec![0; 32];
+    assert!(buf.len() >= SUM_BYTES);
+    
+    r.read_exact(&mut buf[0..16])?;
+    if buf[0..16] != REPLICATE_START {
+        return ReadError::err("not a Pippin replication stream", pos, (0, 16));
+    }
+    pos += 16;
+    
+    r.read_exact(&mut buf[0..16])?;
+    let name = {
+        let len = buf[0..16].iter().position(|b| *b == 0).unwrap_or(16);
+        String::from_utf8(buf[0..len].to_vec())?
+    };
+    pos += 16;
+    
+    let mut parts = Vec::new();
+    loop {
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..16] == REPLICATE_END {
+            break;
+        }
+        if buf[0..8] != *b"PARTID  " {
+            return ReadError::err("unexpected contents (expected PARTID)", pos, (0, 8));
+        }
+        let part_id = PartId::try_from(BigEndian::read_u64(&buf[8..16]))?;
+        pos += 16;
+        
+        r.read_exact(&mut buf[0..16])?;
+        let has_snapshot = if buf[0..8] == *b"SNAPSHOT" {
+            true
+        } else if buf[0..8] == *b"NO SNAP " {
+            false
+        } else {
+            return ReadError::err("unexpected contents (expected SNAPSHOT)", pos, (0, 8));
+        };
+        let len = BigEndian::read_u64(&buf[8..16]);
+        pos += 16;
+        let state = if has_snapshot {
+            let data = read_section_data(&mut r, len, pos)?;
+            pos += data.len();
+            Some(read_snapshot(&mut &data[..], part_id, format_ver, SumType::Blake2b, &NoBlobs)?)
+        } else {
+            None
+        };
+        
+        r.read_exact(&mut buf[0..16])?;
+        if buf[0..8] != *b"LOG LEN " {
+            return ReadError::err("unexpected contents (expected LOG LEN)", pos, (0, 8));
+        }
+        let len = BigEndian::read_u64(&buf[8..16]);
+        pos += 16;
+        
+        let data = read_section_data(&mut r, len, pos)?;
+        let mut commits = Vec::new();
+        read_log(&mut &data[..], &mut commits, format_ver, SumType::Blake2b, &NoBlobs)?;
+        pos += data.len();
+        parts.push((part_id, state, commits));
+    }
+    pos += 16;
+    
+    let sum = r.sum();
+    let mut r = r.into_inner();
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    if !sum.eq(&buf[0..SUM_BYTES]) {
+        return ReadError::err("replication stream checksum invalid", pos, (0, SUM_BYTES));
+    }
+    Ok((name, parts))
+}
+
+// Read `len` bytes starting at `pos`, just after the length field. The length
+// comes from the stream, so memory is only allocated as data is read.
+fn read_section_data(r: &mut Read, len: u64, pos: usize) -> Result<Vec<u8>> {
+    let mut data = Vec::new();
+    r.take(len).read_to_end(&mut data)?;
+    if (data.len() as u64) < len {
+        return ReadError::err("replication stream truncated", pos - 16, (8, 16));
+    }
+    Ok(data)
+}
+
+/// Write a replication stream for repository `name`, with an optional
+/// snapshot and a list of commits for each partition given.
+pub fn write_replication<E: ElementT>(name: &str,
+        parts: &[(PartId, Option<&PartState<E>>, Vec<&Commit<E>>)],
+        writer: &mut Write) -> Result<()>
+{
+    if name.len() > 16 {
+        return ArgError::err("repository name too long");
+    }
+    
+    // A writer which calculates the checksum of what was written:
+    let mut w = sum::HashWriter::new(writer, SumType::Blake2b);
+    
+    w.write(&REPLICATE_START)?;
+    let mut name_buf = [0u8; 16];
+    name_buf[0..name.len()].copy_from_slice(name.as_bytes());
+    w.write(&name_buf)?;
+    
+    let mut data = Vec::new();
+    for &(part_id, state, ref commits) in parts {
+        w.write(b"PARTID  ")?;
+        w.write_u64::<BigEndian>(part_id.into())?;
+        
+        data.clear();
+        if let Some(state) = state {
+            write_snapshot(state, &mut data, SumType::Blake2b)?;
+            w.write(b"SNAPSHOT")?;
+        } else {
+            w.write(b"NO SNAP ")?;
+        }
+        w.write_u64::<BigEndian>(data.len() as u64)?;
+        w.write(&data)?;
+        
+        data.clear();
+        start_log(&mut data)?;
+        for commit in commits {
+            write_commit(*commit, &mut data, SumType::Blake2b, None)?;
+        }
+        w.write(b"LOG LEN ")?;
+        w.write_u64::<BigEndian>(data.len() as u64)?;
+        w.write(&data)?;
+    }
+    w.write(&REPLICATE_END)?;
+    
+    // Write the checksum of everything above:
+    let sum = w.sum();
+    sum.write(&mut w.into_inner())?;
+    Ok(())
+}
+
+#[test]
+fn replication_write_read() {
+    use std::sync::Arc;
+    use std::collections::HashMap;
+    use commit::{CommitMeta, ExtraMeta, MetaFlags, EltChange};
+    use Sum;
+    
+    let (p1, p2) = (PartId::from_num(1), PartId::from_num(2));
+    let state = PartState::<String>::new(p2, None);
+    let mut changes = HashMap::new();
+    changes.insert(p1.elt_id(1), EltChange::insertion(Arc::new("one".to_string())));
+    let meta = CommitMeta::new_explicit(1, 123456, MetaFlags::zero(), vec![],
+            ExtraMeta::None).expect("new meta");
+    let commit = Commit::new_explicit(Sum::calculate(b"one"),
+            vec![Sum::calculate(b"parent")], changes, meta);
+    
+    let mut buf = Vec::new();
+    write_replication("replicate", &[(p1, None, vec![&commit]), (p2, Some(&state), vec![])],
+            &mut buf).unwrap();
+    
+    let (name, parts) = read_replication::<String>(&mut &buf[..]).unwrap();
+    assert_eq!(name, "replicate");
+    assert_eq!(parts.len(), 2);
+    assert_eq!(parts[0].0, p1);
+    assert!(parts[0].1.is_none());
+    assert_eq!(parts[0].2, vec![commit]);
+    assert_eq!(parts[1].0, p2);
+    assert_eq!(parts[1].1.as_ref().map(|s| s.statesum()), Some(state.statesum()));
+    assert!(parts[1].2.is_empty());
+    
+    // A bogus length must fail, not allocate:
+    let mut bad = buf.clone();
+    BigEndian::write_u64(&mut bad[56..64], u64::max_value());
+    assert!(read_replication::<String>(&mut &bad[..]).is_err());
+    
+    // Damaged data must not be accepted:
+    buf[40] ^= 1;
+    assert!(read_replication::<String>(&mut &buf[..]).is_err());
+}
@@ -5,17 +5,24 @@
 //! Support for reading and writing Rust snapshots
 
 use std::io::{Read, Write};
-use std::rc::Rc;
+use std::sync::Arc;
 use std::{u8, u32};
 use std::collections::hash_map::{HashMap, Entry};
+use std::marker::PhantomData;
+use std::sync::mpsc;
+use std::thread;
 
 use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
 
 use readwrite::{sum, read_meta, write_meta};
+use readwrite::sum::{SumType, SumCheck};
+use readwrite::blob::{BlobSource, NoBlobs, blob_key, use_blob, read_blob_ref};
+use readwrite::eltdata::{DataLen, write_data};
 use {PartState, StateT};
-use {ElementT, PartId, Sum};
+use {ElementT, EltId, PartId, Sum};
+use commit::CommitMeta;
 use sum::BYTES as SUM_BYTES;
-use error::{Result, ReadError, ElementOp};
+use error::{Result, ReadError, EltSumError, ElementOp, OtherError};
 
 /// Read a snapshot of a set of elements from a stream.
 /// 
@@ -26,23 +33,213 @@ use error::{Result, ReadError, ElementOp};
 /// The `part_id` parameter is assigned to the `PartState` returned.
 /// 
 /// The file version affects how data is read. Get it from a header with
-/// `header.ftype.ver()`.
-pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
-        format_ver: u32) -> Result<PartState<T>>
+/// `header.ftype.ver()`. The checksum algorithm should likewise be taken from
+/// the header (`header.sum_type`). Element data stored as blobs is retrieved
+/// from `blobs`.
+pub fn read_snapshot<T: ElementT, B: BlobSource + ?Sized>(reader: &mut Read,
+        part_id: PartId, format_ver: u32, sum_type: SumType, blobs: &B)
+        -> Result<PartState<T>>
+{
+    read_snapshot_checked(reader, part_id, format_ver, sum_type, blobs, &mut SumCheck::strict())
+}
+
+/// Read a snapshot, as `read_snapshot()`, handling checksum mismatches
+/// (element, state and file checksums) via `check`.
+pub fn read_snapshot_checked<T: ElementT, B: BlobSource + ?Sized>(reader: &mut Read,
+        part_id: PartId, format_ver: u32, sum_type: SumType, blobs: &B, check: &mut SumCheck)
+        -> Result<PartState<T>>
 {
     // A reader which calculates the checksum of what was read:
-    let mut r = sum::HashReader::new(reader);
+    let mut r = sum::HashReader::new(reader, sum_type);
     
     let mut pos: usize = 0;
     let mut buf = vec![0; 32];
     assert!(buf.len() >= SUM_BYTES);
     
+    let (meta, parents, num_elts) = read_start(&mut r, &mut buf, &mut pos, format_ver)?;
+    
+    let mut elts = HashMap::new();
+    let mut shared = HashMap::new();
+    let mut combined_elt_sum = Sum::zero();
+    for _ in 0..num_elts {
+        let (ident, data, elt_sum) = read_elt_data(&mut r, &mut buf, &mut pos,
+                &mut shared, blobs, check)?;
+        combined_elt_sum.permute(&elt_sum);
+        
+        let elt = T::from_vec_sum(data, elt_sum)?;
+        if ident.part_id() != part_id { return Err(Box::new(ElementOp::WrongPartition)); }
+        match elts.entry(ident) {
+            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
+            Entry::Vacant(e) => e.insert(Arc::new(elt)),
+        };
+    }
+    
+    let moves = read_moves(&mut r, &mut buf, &mut pos, num_elts)?;
+    let state = PartState::new_explicit(part_id, parents,
+            elts, moves, meta, combined_elt_sum);
+    
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    check.check(state.statesum().eq(&buf[0..SUM_BYTES]),
+            || ReadError::err("state checksum mismatch", pos, (0, SUM_BYTES)))?;
+    pos += SUM_BYTES;
+    
+    read_file_sum(r, &mut buf, pos, check)?;
+    
+    trace!("Read snapshot (partition {} with {} elements): {}",
+        part_id, num_elts, state.statesum());
+    Ok(state)
+}
+
+/// Iterator over the elements of a snapshot, reading these from a stream one
+/// at a time (see `Partition::stream_snapshot_elements()`).
+/// 
+/// Elements are deserialised when reached and not retained by the iterator.
+/// After the last element, the rest of the snapshot is read and the state and
+/// file checksums are verified; if either does not match, the last item is
+/// an error. Iteration ends after the first error.
+/// 
+/// Element data stored as blobs is retrieved from `B` (see `with_blobs()`);
+/// by default there is no blob source and such elements cause an error.
+pub struct SnapshotEltIter<R: Read, T: ElementT, B: BlobSource = NoBlobs> {
+    // `None` once finished
+    r: Option<sum::HashReader<R>>,
+    part_id: PartId,
+    parents: Vec<Sum>,
+    meta: CommitMeta,
+    num_elts: usize,
+    remaining: usize,
+    pos: usize,
+    buf: Vec<u8>,
+    // Data of elements referred to by later elements
+    shared: HashMap<EltId, Vec<u8>>,
+    elt_sum: Sum,
+    blobs: B,
+    p: PhantomData<T>,
+}
+impl<R: Read, T: ElementT> SnapshotEltIter<R, T> {
+    /// Start reading a snapshot from `reader`. This reads the part of the
+    /// snapshot before the elements.
+    /// 
+    /// Parameters are as for `read_snapshot()`, except that there is no blob
+    /// source.
+    pub fn new(reader: R, part_id: PartId, format_ver: u32, sum_type: SumType)
+            -> Result<SnapshotEltIter<R, T>>
+    {
+        SnapshotEltIter::with_blobs(reader, part_id, format_ver, sum_type, NoBlobs)
+    }
+}
+impl<R: Read, T: ElementT, B: BlobSource> SnapshotEltIter<R, T, B> {
+    /// Start reading a snapshot from `reader`, as `new()`, retrieving element
+    /// data stored as blobs from `blobs`.
+    pub fn with_blobs(reader: R, part_id: PartId, format_ver: u32, sum_type: SumType,
+            blobs: B) -> Result<SnapshotEltIter<R, T, B>>
+    {
+        let mut r = sum::HashReader::new(reader, sum_type);
+        let mut pos: usize = 0;
+        let mut buf = vec![0; 32];
+        let (meta, parents, num_elts) = read_start(&mut r, &mut buf, &mut pos, format_ver)?;
+        Ok(SnapshotEltIter {
+            r: Some(r),
+            part_id: part_id,
+            parents: parents,
+            meta: meta,
+            num_elts: num_elts,
+            remaining: num_elts,
+            pos: pos,
+            buf: buf,
+            shared: HashMap::new(),
+            elt_sum: Sum::zero(),
+            blobs: blobs,
+            p: PhantomData,
+        })
+    }
+    
+    /// Number of elements in the snapshot
+    pub fn num_elts(&self) -> usize {
+        self.num_elts
+    }
+    
+    /// Read the next element into `target` (see `ElementT::read_into()`),
+    /// returning its identifier.
+    /// 
+    /// This behaves like `next()` (including checksum verification after the
+    /// last element, and returning `None` at the end), but allows a loop to
+    /// reuse one element's allocations instead of creating a new element
+    /// each time. `target` is unspecified after an error.
+    pub fn next_into(&mut self, target: &mut T) -> Option<Result<EltId>> {
+        self.advance(|iter| {
+            let (ident, data, _) = iter.read_elt_data()?;
+            T::read_into(&data, target)?;
+            Ok(ident)
+        })
+    }
+    
+    fn read_elt_data(&mut self) -> Result<(EltId, Vec<u8>, Sum)> {
+        let r = self.r.as_mut().expect("not finished");
+        let (ident, data, elt_sum) = read_elt_data(r, &mut self.buf, &mut self.pos,
+                &mut self.shared, &self.blobs, &mut SumCheck::strict())?;
+        if ident.part_id() != self.part_id { return Err(Box::new(ElementOp::WrongPartition)); }
+        self.elt_sum.permute(&elt_sum);
+        Ok((ident, data, elt_sum))
+    }
+    
+    fn read_elt(&mut self) -> Result<(EltId, T)> {
+        let (ident, data, elt_sum) = self.read_elt_data()?;
+        Ok((ident, T::from_vec_sum(data, elt_sum)?))
+    }
+    
+    // Read the next element via `read`, or finish after the last
+    fn advance<X, F>(&mut self, read: F) -> Option<Result<X>>
+        where F: FnOnce(&mut Self) -> Result<X>
+    {
+        if self.r.is_none() {
+            return None;
+        }
+        if self.remaining > 0 {
+            self.remaining -= 1;
+            let result = read(self);
+            if result.is_err() {
+                self.r = None;
+            }
+            Some(result)
+        } else {
+            match self.finish() {
+                Ok(()) => None,
+                Err(e) => Some(Err(e)),
+            }
+        }
+    }
+    
+    fn finish(&mut self) -> Result<()> {
+        let mut r = self.r.take().expect("not finished");
+        read_moves(&mut r, &mut self.buf, &mut self.pos, self.num_elts)?;
+        let metasum = Sum::state_meta_sum(self.part_id, &self.parents, &self.meta);
+        let statesum = &metasum ^ &self.elt_sum;
+        r.read_exact(&mut self.buf[0..SUM_BYTES])?;
+        if !statesum.eq(&self.buf[0..SUM_BYTES]) {
+            return ReadError::err("state checksum mismatch", self.pos, (0, SUM_BYTES));
+        }
+        self.pos += SUM_BYTES;
+        read_file_sum(r, &mut self.buf, self.pos, &mut SumCheck::strict())
+    }
+}
+impl<R: Read, T: ElementT, B: BlobSource> Iterator for SnapshotEltIter<R, T, B> {
+    type Item = Result<(EltId, T)>;
+    fn next(&mut self) -> Option<Self::Item> {
+        self.advance(|iter| iter.read_elt())
+    }
+}
+
+// Read the start of a snapshot, up to the number of elements.
+fn read_start(r: &mut Read, buf: &mut [u8], pos: &mut usize, format_ver: u32)
+        -> Result<(CommitMeta, Vec<Sum>, usize)>
+{
     r.read_exact(&mut buf[0..16])?;
     if buf[0..6] != *b"SNAPSH" || buf[7] != b'U' {
-        return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", pos, (0, 8));
+        return ReadError::err("unexpected contents (expected SNAPSH_U where _ is any)", *pos, (0, 8));
     }
     let num_parents = buf[6] as usize;
-    let meta = read_meta(&mut r, &mut buf, &mut pos, format_ver)?;
+    let meta = read_meta(r, buf, pos, format_ver)?;
     
     let mut parents = Vec::with_capacity(num_parents);
     for _ in 0..num_parents {
@@ -52,55 +249,72 @@ pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
     
     r.read_exact(&mut buf[0..16])?;
     if buf[0..8] != *b"ELEMENTS" {
-        return ReadError::err("unexpected contents (expected ELEMENTS)", pos, (0, 8));
+        return ReadError::err("unexpected contents (expected ELEMENTS)", *pos, (0, 8));
     }
     let num_elts = BigEndian::read_u64(&buf[8..16]) as usize;    // #0015
-    pos += 16;
+    *pos += 16;
+    Ok((meta, parents, num_elts))
+}
+
+// Read one element, verifying its checksum (via `check`). Returns the
+// identifier, data and element sum.
+// 
+// Data of elements marked as shared is copied into `shared`, from where it is
+// retrieved for elements referring to these (see `write_snapshot_dedup()`).
+// Data of elements stored as blobs is retrieved from `blobs`.
+fn read_elt_data<B: BlobSource + ?Sized>(r: &mut Read, buf: &mut [u8], pos: &mut usize,
+        shared: &mut HashMap<EltId, Vec<u8>>, blobs: &B, check: &mut SumCheck)
+        -> Result<(EltId, Vec<u8>, Sum)>
+{
+    r.read_exact(&mut buf[0..32])?;
+    if buf[0..8] != *b"ELEMENT\x00" {
+        println!("buf: \"{}\", {:?}", String::from_utf8_lossy(&buf[0..8]), &buf[0..8]);
+        return ReadError::err("unexpected contents (expected ELEMENT\\x00)", *pos, (0, 8));
+    }
+    let ident = BigEndian::read_u64(&buf[8..16]).into();
+    *pos += 16;
     
-    let mut elts = HashMap::new();
-    let mut combined_elt_sum = Sum::zero();
-    for _ in 0..num_elts {
-        r.read_exact(&mut buf[0..32])?;
-        if buf[0..8] != *b"ELEMENT\x00" {
-            println!("buf: \"{}\", {:?}", String::from_utf8_lossy(&buf[0..8]), &buf[0..8]);
-            return ReadError::err("unexpected contents (expected ELEMENT\\x00)", pos, (0, 8));
-        }
-        let ident = BigEndian::read_u64(&buf[8..16]).into();
-        pos += 16;
-        
-        if buf[16..24] != *b"BYTES\x00\x00\x00" {
-            return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", pos, (16, 24));
-        }
+    let data = if buf[16..24] == *b"SAMEAS\x00\x00" {
+        let source: EltId = BigEndian::read_u64(&buf[24..32]).into();
+        let data = match shared.get(&source) {
+            Some(data) => data.clone(),
+            None => return ReadError::err("reference to unknown shared element", *pos, (24, 32)),
+        };
+        *pos += 16;
+        data
+    } else if buf[16..24] == *b"BLOBREF\x00" {
         let data_len = BigEndian::read_u64(&buf[24..32]) as usize;   // #0015
-        pos += 16;
-        
-        let mut data = vec![0; data_len];
-        r.read_exact(&mut data)?;
-        pos += data_len;
-        
-        let pad_len = 16 * ((data_len + 15) / 16) - data_len;
-        if pad_len > 0 {
-            r.read_exact(&mut buf[0..pad_len])?;
-            pos += pad_len;
-        }
-        
-        let elt_sum = Sum::elt_sum(ident, &data);
         r.read_exact(&mut buf[0..SUM_BYTES])?;
-        if !elt_sum.eq(&buf[0..SUM_BYTES]) {
-            return ReadError::err("element checksum mismatch", pos, (0, SUM_BYTES));
+        let data = read_blob_ref(blobs, &Sum::load(&buf[0..SUM_BYTES]), data_len, *pos)?;
+        *pos += 16 + SUM_BYTES;
+        data
+    } else {
+        let is_shared = buf[16..24] == *b"BYTESSHR";
+        if !is_shared && buf[16..24] != *b"BYTES\x00\x00\x00" {
+            return ReadError::err("unexpected contents (expected BYTES\\x00\\x00\\x00)", *pos, (16, 24));
         }
-        pos += SUM_BYTES;
-        
-        combined_elt_sum.permute(&elt_sum);
-        
-        let elt = T::from_vec_sum(data, elt_sum)?;
-        if ident.part_id() != part_id { return Err(Box::new(ElementOp::WrongPartition)); }
-        match elts.entry(ident) {
-            Entry::Occupied(_) => { return Err(Box::new(ElementOp::IdClash)); },
-            Entry::Vacant(e) => e.insert(Rc::new(elt)),
-        };
-    }
+        let data_len = DataLen::load(&buf[24..32], *pos + 8)?;
+        let data = data_len.read_data(r, *pos + 8)?;
+        *pos += 16 + 16 * ((data_len.len() + 15) / 16);
+        if is_shared {
+            shared.insert(ident, data.clone());
+        }
+        data
+    };
     
+    let elt_sum = Sum::elt_sum(ident, &data);
+    r.read_exact(&mut buf[0..SUM_BYTES])?;
+    let elt_pos = *pos;
+    check.check(elt_sum.eq(&buf[0..SUM_BYTES]), || EltSumError::err(ident, elt_pos))?;
+    *pos += SUM_BYTES;
+    Ok((ident, data, elt_sum))
+}
+
+// Read element moves (if present) and the start of the state-sum section,
+// checking the number of elements. The state sum itself is not read.
+fn read_moves(r: &mut Read, buf: &mut [u8], pos: &mut usize, num_elts: usize)
+        -> Result<HashMap<EltId, EltId>>
+{
     let mut moves = HashMap::new();
     r.read_exact(&mut buf[0..16])?;
     if buf[0..8] == *b"ELTMOVES" /*versions from 20160201, optional*/ {
@@ -115,49 +329,68 @@ pub fn read_snapshot<T: ElementT>(reader: &mut Read, part_id: PartId,
         r.read_exact(&mut buf[0..16])?;
     }
     
-    let state = PartState::new_explicit(part_id, parents,
-            elts, moves, meta, combined_elt_sum);
-    
     if buf[0..8] != *b"STATESUM" {
-        return ReadError::err("unexpected contents (expected STATESUM or ELTMOVES)", pos, (0, 8));
+        return ReadError::err("unexpected contents (expected STATESUM or ELTMOVES)", *pos, (0, 8));
     }
-    pos += 8;
+    *pos += 8;
     if (BigEndian::read_u64(&buf[8..16]) as usize) != num_elts {
         return ReadError::err("unexpected contents (number of elements \
-            differs from that previously stated)", pos, (8, 16));
+            differs from that previously stated)", *pos, (8, 16));
     }
-    pos += 8;
-    
-    r.read_exact(&mut buf[0..SUM_BYTES])?;
-    if !state.statesum().eq(&buf[0..SUM_BYTES]) {
-        return ReadError::err("state checksum mismatch", pos, (0, SUM_BYTES));
-    }
-    pos += SUM_BYTES;
-    
+    *pos += 8;
+    Ok(moves)
+}
+
+// Read and verify the file checksum, which follows everything read via `r`.
+fn read_file_sum<R: Read>(mut r: sum::HashReader<R>, buf: &mut [u8], pos: usize,
+        check: &mut SumCheck) -> Result<()>
+{
     let sum = r.sum();
     let mut r = r.into_inner();
     r.read_exact(&mut buf[0..SUM_BYTES])?;
-    if !sum.eq(&buf[0..SUM_BYTES]) {
-        return ReadError::err("checksum invalid", pos, (0, SUM_BYTES));
-    }
-    
-    trace!("Read snapshot (partition {} with {} elements): {}",
-        part_id, num_elts, state.statesum());
-    Ok(state)
+    check.check(sum.eq(&buf[0..SUM_BYTES]),
+            || ReadError::err("checksum invalid", pos, (0, SUM_BYTES)))
 }
 
 /// Write a snapshot of a set of elements to a stream
 /// 
 /// The snapshot is derived from a partition state, but also includes a
 /// partition identifier range.
+/// 
+/// Elements and element moves are written in order of identifier, so that
+/// output is byte-for-byte reproducible for any given state.
+/// 
+/// `sum_type` must match that specified in the file header.
 pub fn write_snapshot<T: ElementT>(state: &PartState<T>,
-    writer: &mut Write) -> Result<()>
+    writer: &mut Write, sum_type: SumType) -> Result<()>
+{
+    write_snapshot_dedup(state, writer, sum_type, false, None, 1)
+}
+
+/// Write a snapshot, as `write_snapshot()`, optionally de-duplicating
+/// element data.
+/// 
+/// If `dedup` is true, the data of elements whose serialisation is identical
+/// (as determined by checksum) is stored only once; other elements with the
+/// same data refer to the first. Such snapshots cannot be read by versions
+/// of this library without support for references.
+/// 
+/// Element data of at least `blob_min` bytes (if not `None`) is not written
+/// but referred to by its blob key, as in `write_commit()`; the caller must
+/// store this data separately.
+/// 
+/// If `threads` is greater than one, element checksums are calculated on
+/// this many threads, from the serialised data (thus ignoring any cached
+/// `ElementT::sum()`). Output is the same either way.
+pub fn write_snapshot_dedup<T: ElementT>(state: &PartState<T>,
+    writer: &mut Write, sum_type: SumType, dedup: bool, blob_min: Option<usize>,
+    threads: usize) -> Result<()>
 {
     trace!("Writing snapshot (partition {} with {} elements): {}",
         state.part_id(), state.num_avail(), state.statesum());
     
     // A writer which calculates the checksum of what was written:
-    let mut w = sum::HashWriter::new(writer);
+    let mut w = sum::HashWriter::new(writer, sum_type);
     
     let mut snapsh_u: [u8; 8] = *b"SNAPSH_U";
     assert!(state.parents().len() <= (u8::MAX as usize));
@@ -177,30 +410,56 @@ pub fn write_snapshot<T: ElementT>(state: &PartState<T>,
     
     let mut keys: Vec<_> = state.elts_iter().map(|(k,_)| k).collect();
     keys.sort();
-    for ident in keys {
-        w.write(b"ELEMENT\x00")?;
-        w.write_u64::<BigEndian>(ident.into())?;
-        
-        let elt = state.get_rc(ident).expect("get elt by key");
-        w.write(b"BYTES\x00\x00\x00")?;
-        elt_buf.clear();
-        elt.write_buf(&mut &mut elt_buf)?;
-        w.write_u64::<BigEndian>(elt_buf.len() as u64 /* #0015 */)?;
-        
-        w.write(&elt_buf)?;
-        let pad_len = 16 * ((elt_buf.len() + 15) / 16) - elt_buf.len();
-        if pad_len > 0 {
-            let padding = [0u8; 15];
-            w.write(&padding[0..pad_len])?;
+    
+    // With dedup, find the checksum of each element's data and how many
+    // elements share it. The first element with shared data is marked as
+    // such; later elements refer to it. Elements with a unique fingerprint
+    // (see `ElementT::fingerprint()`) cannot share data and are skipped.
+    let mut data_sums = HashMap::new();
+    let mut sum_counts = HashMap::new();
+    if dedup {
+        let fingerprints: Vec<_> = keys.iter()
+                .map(|ident| state.get_rc(*ident).expect("get elt by key").fingerprint())
+                .collect();
+        let mut fp_counts = HashMap::new();
+        for fp in fingerprints.iter().filter_map(|fp| *fp) {
+            *fp_counts.entry(fp).or_insert(0) += 1;
+        }
+        for (ident, fp) in keys.iter().zip(fingerprints) {
+            if fp.map_or(false, |fp| fp_counts[&fp] == 1) {
+                continue;
+            }
+            elt_buf.clear();
+            state.get_rc(*ident).expect("get elt by key").write_buf(&mut &mut elt_buf)?;
+            let data_sum = Sum::calculate(&elt_buf);
+            *sum_counts.entry(data_sum.clone()).or_insert(0) += 1;
+            data_sums.insert(*ident, data_sum);
+        }
+    }
+    
+    let mut elts = EltWriter {
+        blob_min: blob_min,
+        data_sums: data_sums,
+        sum_counts: sum_counts,
+        shared_ids: HashMap::new(),
+    };
+    if threads > 1 {
+        write_elts_parallel(state, &keys, &mut elts, &mut w, threads)?;
+    } else {
+        for ident in keys {
+            let elt = state.get_rc(ident).expect("get elt by key");
+            elt_buf.clear();
+            elt.write_buf(&mut &mut elt_buf)?;
+            elts.write(&mut w, ident, &elt_buf, &elt.sum(ident))?;
         }
-        
-        elt.sum(ident).write(&mut w)?;
     }
     
     if state.moved_len() > 0 {
         w.write(b"ELTMOVES")?;
         w.write_u64::<BigEndian>(state.moved_len() as u64 /* #0015 */)?;
-        for (ident, new_ident) in state.moved_iter() {
+        let mut moves: Vec<_> = state.moved_iter().collect();
+        moves.sort();
+        for (ident, new_ident) in moves {
             w.write_u64::<BigEndian>(ident.into())?;
             w.write_u64::<BigEndian>(new_ident.into())?;
         }
@@ -219,6 +478,108 @@ pub fn write_snapshot<T: ElementT>(state: &PartState<T>,
     Ok(())
 }
 
+// Writes elements of a snapshot (see `write_snapshot_dedup()`)
+struct EltWriter {
+    blob_min: Option<usize>,
+    // Checksum of the data of each element which may share data, and the
+    // number of elements with each checksum
+    data_sums: HashMap<EltId, Sum>,
+    sum_counts: HashMap<Sum, usize>,
+    // First element written with each checksum shared by several elements
+    shared_ids: HashMap<Sum, EltId>,
+}
+impl EltWriter {
+    fn write(&mut self, w: &mut Write, ident: EltId, elt_buf: &[u8], elt_sum: &Sum)
+            -> Result<()>
+    {
+        w.write(b"ELEMENT\x00")?;
+        w.write_u64::<BigEndian>(ident.into())?;
+        
+        if use_blob(elt_buf.len(), self.blob_min) {
+            // Blobs are stored once regardless of `dedup`
+            w.write(b"BLOBREF\x00")?;
+            w.write_u64::<BigEndian>(elt_buf.len() as u64 /* #0015 */)?;
+            blob_key(elt_buf).write(w)?;
+            elt_sum.write(w)?;
+            return Ok(());
+        }
+        
+        let mut marker = b"BYTES\x00\x00\x00";
+        if let Some(data_sum) = self.data_sums.get(&ident) {
+            if let Some(source) = self.shared_ids.get(data_sum) {
+                w.write(b"SAMEAS\x00\x00")?;
+                w.write_u64::<BigEndian>((*source).into())?;
+                elt_sum.write(w)?;
+                return Ok(());
+            }
+            if self.sum_counts[data_sum] > 1 {
+                marker = b"BYTESSHR";
+                self.shared_ids.insert(data_sum.clone(), ident);
+            }
+        }
+        w.write(marker)?;
+        write_data(elt_buf, w)?;
+        elt_sum.write(w)?;
+        Ok(())
+    }
+}
+
+// Number of elements per thread which `write_elts_parallel()` may have
+// serialised but not yet written
+const SUM_QUEUE_LEN: usize = 64;
+
+// Write elements `keys` of `state`, calculating element checksums from the
+// serialised data on `threads` worker threads. Elements are dealt to workers
+// in turn and results collected in the same order, so output is in key
+// order; buffers are reused once written.
+fn write_elts_parallel<T: ElementT>(state: &PartState<T>, keys: &[EltId],
+        elts: &mut EltWriter, w: &mut Write, threads: usize) -> Result<()>
+{
+    let mut jobs = Vec::with_capacity(threads);
+    let mut results = Vec::with_capacity(threads);
+    for _ in 0..threads {
+        let (job_tx, job_rx) = mpsc::channel::<(EltId, Vec<u8>)>();
+        let (result_tx, result_rx) = mpsc::channel();
+        thread::spawn(move || {
+            for (ident, data) in job_rx {
+                let sum = Sum::elt_sum(ident, &data);
+                if result_tx.send((ident, data, sum)).is_err() {
+                    break;
+                }
+            }
+        });
+        jobs.push(job_tx);
+        results.push(result_rx);
+    }
+    
+    let mut free_bufs = Vec::new();
+    let mut write_next = |n: usize, free_bufs: &mut Vec<Vec<u8>>| -> Result<()> {
+        let (ident, data, sum) = results[n % threads].recv()
+                .map_err(|_| OtherError::new("element checksum thread failed"))?;
+        elts.write(w, ident, &data, &sum)?;
+        free_bufs.push(data);
+        Ok(())
+    };
+    let max_queued = threads * SUM_QUEUE_LEN;
+    let mut written = 0;
+    for (n, ident) in keys.iter().enumerate() {
+        if n - written == max_queued {
+            write_next(written, &mut free_bufs)?;
+            written += 1;
+        }
+        let mut data = free_bufs.pop().unwrap_or_else(Vec::new);
+        data.clear();
+        state.get_rc(*ident).expect("get elt by key").write_buf(&mut &mut data)?;
+        jobs[n % threads].send((*ident, data))
+                .map_err(|_| OtherError::new("element checksum thread failed"))?;
+    }
+    while written < keys.len() {
+        write_next(written, &mut free_bufs)?;
+        written += 1;
+    }
+    Ok(())
+}
+
 #[test]
 fn snapshot_writing() {
     use ::MutStateT;
@@ -257,8 +618,165 @@ fn snapshot_writing() {
     let state = PartState::from_mut(state, Some(&MyMM {}));
     
     let mut result = Vec::new();
-    assert!(write_snapshot(&state, &mut result).is_ok());
+    assert!(write_snapshot(&state, &mut result, SumType::Blake2b).is_ok());
+    
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let state2 = read_snapshot(&mut &result[..], part_id, ver, SumType::Blake2b, &NoBlobs).unwrap();
+    assert_eq!(state, state2);
+    
+    let mut result = Vec::new();
+    assert!(write_snapshot(&state, &mut result, SumType::Sha256).is_ok());
+    let state3 = read_snapshot(&mut &result[..], part_id, ver, SumType::Sha256, &NoBlobs).unwrap();
+    assert_eq!(state, state3);
+    assert!(read_snapshot::<String, _>(&mut &result[..], part_id, ver, SumType::Blake2b, &NoBlobs).is_err());
+}
+
+#[test]
+fn snapshot_deterministic() {
+    use std::collections::HashMap;
+    use ::MutStateT;
+    
+    let part_id = PartId::from_num(1);
+    let mut state = PartState::<String>::new(part_id, None).clone_mut();
+    for i in 0..50 {
+        state.insert(format!("element {}", i)).unwrap();
+    }
+    for i in 0..10 {
+        state.set_move(part_id.elt_id(1000 + i), PartId::from_num(2).elt_id(i));
+    }
+    let state = PartState::from_mut(state, None);
     
-    let state2 = read_snapshot(&mut &result[..], part_id, HEAD_VERSIONS[HEAD_VERSIONS.len() - 1]).unwrap();
+    // Rebuild the maps; iteration order of the new maps will (very likely)
+    // differ from that of the originals.
+    let elts: HashMap<_, _> = state.elts_iter().map(|(id, elt)| (id, elt.clone())).collect();
+    let moves: HashMap<_, _> = state.moved_iter().collect();
+    let elt_sum = state.statesum() ^ &state.metasum();
+    let state2 = PartState::new_explicit(part_id, state.parents().to_vec(),
+            elts, moves, state.meta().clone(), elt_sum);
     assert_eq!(state, state2);
+    
+    let mut buf1 = Vec::new();
+    write_snapshot(&state, &mut buf1, SumType::Blake2b).unwrap();
+    let mut buf2 = Vec::new();
+    write_snapshot(&state, &mut buf2, SumType::Blake2b).unwrap();
+    let mut buf3 = Vec::new();
+    write_snapshot(&state2, &mut buf3, SumType::Blake2b).unwrap();
+    assert_eq!(buf1, buf2);
+    assert_eq!(buf1, buf3);
+}
+
+#[test]
+fn snapshot_sum_threads() {
+    use ::MutStateT;
+    use readwrite::header::HEAD_VERSIONS;
+    
+    // Enough elements to fill the queue of each thread several times:
+    let part_id = PartId::from_num(1);
+    let mut state = PartState::<String>::new(part_id, None).clone_mut();
+    for i in 0..(20 * SUM_QUEUE_LEN + 10) {
+        state.insert(format!("element {}", i % 100)).unwrap();
+    }
+    let state = PartState::from_mut(state, None);
+    
+    let mut buf1 = Vec::new();
+    write_snapshot(&state, &mut buf1, SumType::Blake2b).unwrap();
+    for &(dedup, threads) in &[(false, 4), (false, 7), (true, 3)] {
+        let mut buf2 = Vec::new();
+        write_snapshot_dedup(&state, &mut buf2, SumType::Blake2b, dedup, None, threads).unwrap();
+        if !dedup {
+            assert_eq!(buf1, buf2);
+        }
+        let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+        let state2 = read_snapshot(&mut &buf2[..], part_id, ver, SumType::Blake2b, &NoBlobs).unwrap();
+        assert_eq!(state, state2);
+    }
+}
+
+#[test]
+fn snapshot_corrupt_element() {
+    use ::MutStateT;
+    use readwrite::header::HEAD_VERSIONS;
+    
+    let part_id = PartId::from_num(1);
+    let mut state = PartState::<String>::new(part_id, None).clone_mut();
+    state.insert("first element".to_string()).unwrap();
+    let id = state.insert("second element".to_string()).unwrap();
+    state.insert("third element".to_string()).unwrap();
+    let state = PartState::from_mut(state, None);
+    
+    let mut result = Vec::new();
+    write_snapshot(&state, &mut result, SumType::Blake2b).unwrap();
+    let pos = result.windows(6).position(|w| w == b"second").expect("find element data");
+    result[pos] = b'S';
+    
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let e = read_snapshot::<String, _>(&mut &result[..], part_id, ver, SumType::Blake2b, &NoBlobs).unwrap_err();
+    let e = e.downcast_ref::<EltSumError>().expect("EltSumError");
+    assert_eq!(e.elt_id, id);
+}
+
+#[test]
+fn snapshot_dedup() {
+    use ::MutStateT;
+    use readwrite::header::HEAD_VERSIONS;
+    
+    let part_id = PartId::from_num(1);
+    let data = "a sequence stored under two identifiers";
+    let mut state = PartState::<String>::new(part_id, None).clone_mut();
+    let id1 = state.insert(data.to_string()).unwrap();
+    let id2 = state.insert(data.to_string()).unwrap();
+    state.insert("something else".to_string()).unwrap();
+    let state = PartState::from_mut(state, None);
+    let count = |buf: &Vec<u8>| buf.windows(data.len()).filter(|w| *w == data.as_bytes()).count();
+    
+    let mut plain = Vec::new();
+    write_snapshot(&state, &mut plain, SumType::Blake2b).unwrap();
+    assert_eq!(count(&plain), 2);
+    let mut dedup = Vec::new();
+    write_snapshot_dedup(&state, &mut dedup, SumType::Blake2b, true, None, 1).unwrap();
+    assert_eq!(count(&dedup), 1);
+    assert!(dedup.len() < plain.len());
+    
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    let state2 = read_snapshot::<String, _>(&mut &dedup[..], part_id, ver, SumType::Blake2b, &NoBlobs).unwrap();
+    assert_eq!(state, state2);
+    assert_eq!(state2.get(id1).unwrap(), data);
+    assert_eq!(state2.get(id2).unwrap(), data);
+    
+    let iter = SnapshotEltIter::<_, String>::new(&dedup[..], part_id, ver, SumType::Blake2b).unwrap();
+    let elts: HashMap<_, _> = iter.map(|r| r.unwrap()).collect();
+    assert_eq!(elts.len(), 3);
+    assert_eq!(elts[&id1], data);
+    assert_eq!(elts[&id2], data);
+}
+
+#[test]
+fn snapshot_blobs() {
+    use ::MutStateT;
+    use readwrite::header::HEAD_VERSIONS;
+    
+    let part_id = PartId::from_num(1);
+    let big = "an element large enough to be stored as a blob";
+    let mut state = PartState::<String>::new(part_id, None).clone_mut();
+    let id1 = state.insert(big.to_string()).unwrap();
+    let id2 = state.insert(big.to_string()).unwrap();
+    state.insert("small".to_string()).unwrap();
+    let state = PartState::from_mut(state, None);
+    
+    let mut result = Vec::new();
+    write_snapshot_dedup(&state, &mut result, SumType::Blake2b, true, Some(16), 1).unwrap();
+    assert!(!result.windows(big.len()).any(|w| w == big.as_bytes()));
+    
+    let ver = HEAD_VERSIONS[HEAD_VERSIONS.len() - 1];
+    assert!(read_snapshot::<String, _>(&mut &result[..], part_id, ver, SumType::Blake2b, &NoBlobs).is_err());
+    let mut blobs = HashMap::new();
+    blobs.insert(blob_key(big.as_bytes()), big.as_bytes().to_vec());
+    let state2 = read_snapshot::<String, _>(&mut &result[..], part_id, ver, SumType::Blake2b, &blobs).unwrap();
+    assert_eq!(state, state2);
+    
+    let iter = SnapshotEltIter::<_, String, _>::with_blobs(&result[..], part_id, ver,
+            SumType::Blake2b, &blobs).unwrap();
+    let elts: HashMap<_, _> = iter.map(|r| r.unwrap()).collect();
+    assert_eq!(elts[&id1], big);
+    assert_eq!(elts[&id2], big);
 }
@@ -5,25 +5,93 @@
 //! For calculating checksums
 
 use std::io::{Read, Write, Result};
+use std::fmt;
 
 use crypto::digest::Digest;
-// use crypto::sha2::Sha256;
+use crypto::sha2::Sha256;
 use crypto::blake2b::Blake2b;
 use byteorder::{ByteOrder, BigEndian};
 
 use {EltId, PartId};
 use commit::{CommitMeta, ExtraMeta};
 use sum::{Sum, BYTES};
+use error;
 
 
 // Internal type / constructor for easy configuration.
-// type Hasher = Sha256;
+// Note: this is used for element and state sums, which must be consistent
+// across all files of a repository; only file checksums are configurable.
 type Hasher = Blake2b;
 fn mk_hasher() -> Hasher {
-//     Hasher::new()
     Hasher::new(BYTES)
 }
 
+// Input an item of metadata to a hasher: an 8-byte label, the data length
+// (big-endian u64), then the data
+fn hash_item(hasher: &mut Hasher, label: &[u8; 8], data: &[u8]) {
+    let mut buf = [0u8; 8];
+    BigEndian::write_u64(&mut buf, data.len() as u64);
+    hasher.input(label);
+    hasher.input(&buf);
+    hasher.input(data);
+}
+
+/// Algorithm used for file checksums (the checksum of each file header, of a
+/// snapshot's contents, and of each commit in a log).
+/// 
+/// The algorithm is recorded in the `HSUM` header block, thus files written
+/// with different algorithms can be mixed within a partition. Element and
+/// state sums are not affected by this setting.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SumType {
+    /// SHA-2 256
+    Sha256,
+    /// BLAKE2b with a 32-byte output
+    Blake2b,
+    /// BLAKE2b in keyed mode with a 32-byte output. The key is not stored;
+    /// files written with this type can only be read when the same key is
+    /// supplied.
+    Blake2bKeyed(SumKey),
+}
+impl SumType {
+    fn hasher(self) -> Box<Digest> {
+        match self {
+            SumType::Sha256 => Box::new(Sha256::new()),
+            SumType::Blake2b => Box::new(Blake2b::new(BYTES)),
+            SumType::Blake2bKeyed(key) => Box::new(Blake2b::new_keyed(BYTES, &key.0)),
+        }
+    }
+    /// Get the key, if this is a keyed type
+    pub fn key(&self) -> Option<SumKey> {
+        match *self {
+            SumType::Blake2bKeyed(key) => Some(key),
+            _ => None,
+        }
+    }
+}
+
+/// Secret key used by `SumType::Blake2bKeyed`.
+/// 
+/// Keys are never written to files and are hidden from `Debug` output.
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct SumKey([u8; 32]);
+impl SumKey {
+    /// Create from 32 bytes of key material
+    pub fn new(key: [u8; 32]) -> SumKey {
+        SumKey(key)
+    }
+}
+impl fmt::Debug for SumKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SumKey(..)")
+    }
+}
+impl Default for SumType {
+    fn default() -> SumType {
+        SumType::Blake2b
+    }
+}
+
 impl Sum {
     /// Calculate an element sum
     pub fn elt_sum(elt_id: EltId, data: &[u8]) -> Sum {
@@ -58,6 +126,15 @@ impl Sum {
                 hasher.input(text.as_bytes());
             },
         }
+        // Extension data is included only when present, thus sums of
+        // commits without it are unchanged
+        for (key, value) in meta.tags() {
+            hash_item(&mut hasher, b"TAGKEY\x00\x00", key.as_bytes());
+            hash_item(&mut hasher, b"TAGVALUE", value);
+        }
+        if let Some(branch) = meta.branch() {
+            hash_item(&mut hasher, b"BRANCH\x00\x00", branch.as_bytes());
+        }
         Sum::load_hasher(hasher)
     }
     /// Calculate a standard checksum
@@ -76,29 +153,96 @@ impl Sum {
 }
 
 
+// —————  checksum verification  —————
+
+/// How checksum mismatches are handled when reading snapshots and logs.
+/// 
+/// When strict (the default), any mismatch is an error. Otherwise
+/// ("best effort"), mismatches are logged as warnings and counted and the
+/// data is returned regardless; such data is not verified and should only be
+/// used to salvage what is possible from damaged files (see
+/// `Partition::set_read_ignoring_checksum()`).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SumCheck {
+    strict: bool,
+    mismatches: usize,
+}
+impl SumCheck {
+    /// Fail on any mismatch
+    pub fn strict() -> SumCheck {
+        SumCheck { strict: true, mismatches: 0 }
+    }
+    /// Log and count mismatches, but do not fail
+    pub fn best_effort() -> SumCheck {
+        SumCheck { strict: false, mismatches: 0 }
+    }
+    /// Number of mismatches ignored so far
+    pub fn mismatches(&self) -> usize {
+        self.mismatches
+    }
+    /// Handle a comparison of checksums. If these do not match, when strict
+    /// return the error made by `fail`, otherwise log it and continue.
+    pub fn check<F>(&mut self, matches: bool, fail: F) -> error::Result<()>
+        where F: FnOnce() -> error::Result<()>
+    {
+        if matches {
+            return Ok(());
+        }
+        if self.strict {
+            return fail();
+        }
+        if let Err(e) = fail() {
+            warn!("Ignoring checksum mismatch; data is not verified: {}", e);
+        }
+        self.mismatches += 1;
+        Ok(())
+    }
+}
+
+
 // —————  hash calculators  —————
 
 pub struct HashReader<R> {
-    hasher: Hasher,
+    // Normally a single hasher; while detecting the algorithm, one per type
+    hashers: Vec<(SumType, Box<Digest>)>,
     inner: R
 }
 
 impl<R: Read> HashReader<R> {
-    /// Create
-    pub fn new(r: R) -> HashReader<R> {
-        HashReader { hasher: mk_hasher(), inner: r }
+    /// Create, using the given checksum algorithm
+    pub fn new(r: R, sum_type: SumType) -> HashReader<R> {
+        HashReader { hashers: vec![(sum_type, sum_type.hasher())], inner: r }
+    }
+    /// Create, calculating checksums with all supported algorithms until
+    /// `select()` is called; a keyed checksum is included if a key is given
+    pub fn new_detect_keyed(r: R, key: Option<SumKey>) -> HashReader<R> {
+        let mut types = vec![SumType::Sha256, SumType::Blake2b];
+        if let Some(key) = key {
+            types.push(SumType::Blake2bKeyed(key));
+        }
+        let hashers = types.into_iter().map(|t| (t, t.hasher())).collect();
+        HashReader { hashers: hashers, inner: r }
+    }
+    /// Select which algorithm to use, discarding other hashers
+    pub fn select(&mut self, sum_type: SumType) {
+        self.hashers.retain(|&(t, _)| t == sum_type);
+        assert_eq!(self.hashers.len(), 1);
     }
 }
 
 #[allow(dead_code)]
 impl<R: Read> HashReader<R> {
     /// Get the hasher's Digest interface
-    pub fn digest(&mut self) -> &mut Digest { &mut self.hasher }
+    pub fn digest(&mut self) -> &mut Digest {
+        assert_eq!(self.hashers.len(), 1, "checksum algorithm not selected");
+        &mut *self.hashers[0].1
+    }
     /// Make a Sum from the digest
     pub fn sum(&mut self) -> Sum {
+        let hasher = self.digest();
         let mut buf = [0u8; BYTES];
-        assert_eq!(self.hasher.output_bytes(), buf.len());
-        self.hasher.result(&mut buf);
+        assert_eq!(hasher.output_bytes(), buf.len());
+        hasher.result(&mut buf);
         Sum::load(&buf)
     }
     
@@ -111,28 +255,30 @@ impl<R: Read> HashReader<R> {
 impl<R: Read> Read for HashReader<R> {
     fn read(&mut self, buf: &mut [u8]) -> Result<usize> {
         let len = self.inner.read(buf)?;
-        self.hasher.input(&buf[..len]);
+        for &mut (_, ref mut hasher) in &mut self.hashers {
+            hasher.input(&buf[..len]);
+        }
         Ok(len)
     }
 }
 
 
 pub struct HashWriter<W> {
-    hasher: Hasher,
+    hasher: Box<Digest>,
     inner: W
 }
 
 impl<W: Write> HashWriter<W> {
-    /// Create
-    pub fn new(w: W) -> HashWriter<W> {
-        HashWriter { hasher: mk_hasher(), inner: w }
+    /// Create, using the given checksum algorithm
+    pub fn new(w: W, sum_type: SumType) -> HashWriter<W> {
+        HashWriter { hasher: sum_type.hasher(), inner: w }
     }
 }
 
 #[allow(dead_code)]
 impl<W: Write> HashWriter<W> {
     /// Get the hasher's Digest interface
-    pub fn digest(&mut self) -> &mut Digest { &mut self.hasher }
+    pub fn digest(&mut self) -> &mut Digest { &mut *self.hasher }
     /// Make a Sum from the digest
     pub fn sum(&mut self) -> Sum {
         let mut buf = [0u8; BYTES];
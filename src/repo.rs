@@ -19,17 +19,26 @@
 
 use std::result;
 use std::collections::hash_map::{HashMap, Values, ValuesMut};
-use std::rc::Rc;
-use std::mem::swap;
+use std::collections::HashSet;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::mem::{swap, replace};
+use std::io::{Read, Write};
 
 // Re-export these. We pretend these are part of the same module while keeping files smaller.
 pub use repo_traits::{RepoIO, ClassifierT, ClassifyFallback, RepoT,
-    RepoDivideError, DummyClassifier};
-use {Partition, StateT, MutStateT, MutPartState};
+    RepoDivideError, DummyClassifier, Tier, median_key};
+use {Partition, PartStats, StateT, MutStateT, MutPartState, VerifyReport, RepairReport};
+use state::{EltIter, RedactionPolicy};
 use merge::TwoWaySolver;
-use {EltId, PartId, ElementT};
+use {EltId, PartId, ElementT, Sum};
 use commit::MakeMeta; 
-use error::{Result, OtherError, TipError, ElementOp};
+use readwrite::{validate_repo_name, read_journal, write_journal,
+        Manifest, ManifestPart, read_manifest, write_manifest,
+        PartIdAlloc, read_part_alloc, write_part_alloc,
+        read_replication, write_replication};
+use error::{Result, OtherError, ArgError, TipError, ElementOp, Cancelled, PartErrors,
+        AlreadyExists};
 
 /// Handle on a repository.
 /// 
@@ -53,6 +62,70 @@ pub struct Repository<C: ClassifierT, R: RepoT<C>> {
     name: String,
     /// List of loaded partitions, by their `PartId`.
     partitions: HashMap<PartId, Partition<C::Element>>,
+    /// Record of allocated partition identifiers
+    part_alloc: PartIdAlloc,
+    /// Flag checked by long operations (see `set_cancel_flag()`)
+    cancel: Option<Arc<AtomicBool>>,
+    /// Number of elements above which partitions are divided automatically
+    /// (see `set_auto_divide_threshold()`); 0 if disabled
+    auto_divide_threshold: usize,
+    /// Approximate memory usage above which partitions are unloaded (see
+    /// `set_memory_budget()`); 0 if unlimited
+    memory_budget: usize,
+    /// Number of partition accesses so far
+    access_count: u64,
+    /// Value of `access_count` at the last access of each partition
+    last_access: HashMap<PartId, u64>,
+}
+
+/// A consistent set of partition snapshots (see `Repository::checkpoint()`).
+#[derive(Clone, PartialEq, Debug)]
+pub struct Checkpoint {
+    /// Manifest listing the state written to a snapshot for each partition
+    /// (as its only tip)
+    pub manifest: Manifest,
+}
+impl Checkpoint {
+    /// Write the checkpoint's manifest (see `Repository::export_manifest()`).
+    pub fn write_manifest<W: Write>(&self, mut writer: W) -> Result<()> {
+        write_manifest(&self.manifest, &mut writer)
+    }
+}
+
+/// Repository-wide statistics (see `Repository::stats()`).
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct RepoStats {
+    /// Number of partitions
+    pub num_parts: usize,
+    /// Number of partitions loaded and ready for use
+    pub num_ready: usize,
+    /// Total number of elements, over partitions ready for use
+    pub num_elts: usize,
+    /// Total size of all partition files, in bytes
+    pub total_bytes: u64,
+    /// Number of partitions requiring a merge
+    pub num_merge_required: usize,
+    /// Number of partitions wanting a snapshot
+    pub num_want_snapshot: usize,
+    /// Statistics on each partition, in order of partition identifier
+    pub parts: Vec<PartStats>,
+}
+
+/// Selects partitions for compaction (see `Repository::compact_all()`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct CompactPolicy {
+    /// A partition is compacted when the commit logs of its latest snapshot
+    /// total more than this many bytes (see `Partition::log_bytes()`)
+    pub min_log_bytes: u64,
+}
+
+/// Outcome of `Repository::compact_all()`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompactReport {
+    /// Partitions compacted, in order of identifier
+    pub compacted: Vec<PartId>,
+    /// Total size of files removed, in bytes
+    pub bytes_reclaimed: u64,
 }
 
 // Non-member functions on Repository
@@ -67,15 +140,57 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
     /// 
     /// This creates an initial 'partition' ready for use (all contents must
     /// be kept within a `Partition`).
-    pub fn create<S: Into<String>>(mut repo_t: R, name: S,
+    /// 
+    /// Fails with `AlreadyExists`, before writing anything, if the `RepoIO`
+    /// already holds repository data: partitions, a record of allocated
+    /// partitions, or files for the initial partition (e.g. when re-running
+    /// creation in the same directory). See `create_force()`.
+    pub fn create<S: Into<String>>(repo_t: R, name: S,
             make_meta: Option<&MakeMeta>) -> Result<Repository<C, R>>
     {
-        let name: String = name.into();
+        Self::create_impl(repo_t, name.into(), make_meta, false)
+    }
+    
+    /// Create a new repository as with `create()`, but where repository
+    /// data already exists, delete it first (see `RepoIO::delete_part()`).
+    /// 
+    /// This is intended for deliberately starting over; existing data is
+    /// lost.
+    pub fn create_force<S: Into<String>>(repo_t: R, name: S,
+            make_meta: Option<&MakeMeta>) -> Result<Repository<C, R>>
+    {
+        Self::create_impl(repo_t, name.into(), make_meta, true)
+    }
+    
+    fn create_impl(mut repo_t: R, name: String, make_meta: Option<&MakeMeta>, force: bool)
+            -> Result<Repository<C, R>>
+    {
         info!("Creating repository: {}", name);
+        let existing = repo_t.io().parts();
+        if !force && (!existing.is_empty() || repo_t.io().read_part_alloc()?.is_some()) {
+            return AlreadyExists::err();
+        }
+        for part_id in existing {
+            warn!("Repository {}: deleting existing partition {}", name, part_id);
+            repo_t.io().delete_part(part_id)?;
+        }
+        
+        let mut part_alloc = PartIdAlloc::new();
+        repo_t.set_part_alloc(&part_alloc);
         let part_id = repo_t.init_first()?;
+        part_alloc.allocate(part_id);
         let suggestion = repo_t.suggest_part_prefix(part_id);
         let prefix = suggestion.unwrap_or_else(|| format!("pn{}", part_id));
-        repo_t.io().new_part(part_id, prefix)?;
+        repo_t.io().new_part(part_id, prefix.clone())?;
+        if repo_t.io().make_part_io(part_id)?.ss_len() > 0 {
+            if !force {
+                return AlreadyExists::err();
+            }
+            warn!("Repository {}: deleting existing files of partition {}", name, part_id);
+            repo_t.io().delete_part(part_id)?;
+            repo_t.io().new_part(part_id, prefix)?;
+        }
+        write_part_alloc_io(&part_alloc, repo_t.io())?;
         let part_io = repo_t.io().make_part_io(part_id)?;
         let part = Partition::create(part_io, &name, Some(&mut repo_t), make_meta)?;
         let mut partitions = HashMap::new();
@@ -84,15 +199,32 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
             repo_t: repo_t,
             name: name,
             partitions: partitions,
+            part_alloc: part_alloc,
+            cancel: None,
+            auto_divide_threshold: 0,
+            memory_budget: 0,
+            access_count: 0,
+            last_access: HashMap::new(),
         })
     }
     
     /// Open an existing repository.
     /// 
-    /// This does not automatically load partition data, however it must load
-    /// at least one header in order to identify the repository.
+    /// This does not automatically load partition data, however it reads a
+    /// snapshot header of each partition in order to check the repository
+    /// name (an interrupted `rename()` is accepted). Partitions
+    /// may be loaded all at once via `load_latest()` or on first access via
+    /// `load_part()`, `get_element()` or `clone_part_state()`.
+    /// 
+    /// If a transaction journal is found (see `write_atomic()`), the
+    /// interrupted write is completed first; this loads the affected
+    /// partitions.
+    /// 
+    /// The record of allocated partition identifiers is read if present (see
+    /// `part_alloc()`); otherwise it is reconstructed from the partitions
+    /// found.
     pub fn open(mut repo_t: R)-> Result<Repository<C, R>> {
-        let (name, parts) = {
+        let (name, parts, part_alloc) = {
             let io = repo_t.io();
             let mut part_nums = io.parts().into_iter();
             let num0 = if let Some(num) = part_nums.next() {
@@ -103,25 +235,85 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
             
             let part_io = io.make_part_io(num0)?;
             let mut part0 = Partition::open(part_io)?;
-            let name = part0.get_repo_name()?.to_string();
+            let mut name = part0.get_repo_name()?.to_string();
             
             let mut parts = HashMap::new();
             parts.insert(num0, part0);
+            // Former name, if partitions are found under two names
+            let mut old_name = None;
             for n in part_nums {
                 let part_io = io.make_part_io(n)?;
                 let mut part = Partition::open(part_io)?;
-                part.set_repo_name(&name)?;
+                let part_name = part.get_repo_name()?.to_string();
+                if part_name != name && old_name.as_ref() != Some(&part_name) {
+                    // Tolerate an interrupted rename: renamed partitions
+                    // record the name of those not yet renamed.
+                    if old_name.is_some() {
+                        return OtherError::err("repository name does not match when loading (wrong repo?)");
+                    } else if part.get_former_repo_name()?.as_ref() == Some(&name) {
+                        old_name = Some(replace(&mut name, part_name));
+                    } else {
+                        for p in parts.values() {
+                            if p.get_former_repo_name()?.as_ref() == Some(&part_name) {
+                                old_name = Some(part_name.clone());
+                                break;
+                            }
+                        }
+                        if old_name.is_none() {
+                            return OtherError::err("repository name does not match when loading (wrong repo?)");
+                        }
+                    }
+                }
                 parts.insert(n, part);
             }
-            (name, parts)
+            if let Some(ref old_name) = old_name {
+                warn!("Repository {} was not fully renamed from {}; call rename() to complete",
+                        name, old_name);
+            }
+            
+            let mut part_alloc = match io.read_part_alloc()? {
+                Some(data) => read_part_alloc(&mut &data[..])?,
+                None => PartIdAlloc::new(),
+            };
+            for id in parts.keys() {
+                if part_alloc.allocate(*id) {
+                    warn!("Partition {} was not recorded as allocated", id);
+                }
+            }
+            (name, parts, part_alloc)
         };
         
         info!("Opening repository with {} partitions: {}", parts.len(), name);
-        Ok(Repository{
+        let mut repo = Repository{
             repo_t: repo_t,
             name: name,
             partitions: parts,
-        })
+            part_alloc: part_alloc,
+            cancel: None,
+            auto_divide_threshold: 0,
+            memory_budget: 0,
+            access_count: 0,
+            last_access: HashMap::new(),
+        };
+        repo.recover()?;
+        Ok(repo)
+    }
+    
+    /// Import a manifest written by `export_manifest()`.
+    /// 
+    /// This passes the user fields recorded for each partition to
+    /// `repo_t.read_user_fields()`, thus restoring classification metadata
+    /// (e.g. after partitions have been divided) without loading partition
+    /// data. The manifest is returned so that the caller may inspect
+    /// partition identifiers and tips.
+    pub fn import_manifest(repo_t: &mut R, reader: &mut Read) -> Result<Manifest> {
+        let manifest = read_manifest(reader)?;
+        info!("Importing manifest of repository {} with {} partitions",
+                manifest.name, manifest.parts.len());
+        for part in &manifest.parts {
+            repo_t.read_user_fields(part.user.clone(), part.part_id, false);
+        }
+        Ok(manifest)
     }
 }
 
@@ -130,6 +322,148 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
     /// Get the repo name
     pub fn name(&self) -> &str { &self.name }
     
+    /// Change the repo name. The name is validated as in `create()`.
+    /// 
+    /// This loads any partitions not yet loaded, then writes a new snapshot
+    /// for each partition whose name differs (see `Partition::rename()`).
+    /// Should this be interrupted, the repository may be left with a mix of
+    /// names; `open()` accepts this (taking the new name) and loads each
+    /// partition under its own name, thus calling `rename` again completes
+    /// the operation.
+    pub fn rename(&mut self, new_name: &str) -> Result<()> {
+        validate_repo_name(new_name)?;
+        info!("Renaming repository {} to {}", self.name, new_name);
+        for (_, part) in &mut self.partitions {
+            part.rename(new_name, Some(&mut self.repo_t))?;
+        }
+        self.name = new_name.to_string();
+        Ok(())
+    }
+    
+    /// Get the record of allocated partition identifiers.
+    /// 
+    /// Identifiers are allocated when partitions are created (including by
+    /// division) and freed by `delete_partition()`. Where the `RepoIO`
+    /// supports this (see `RepoIO::write_part_alloc()`), the record is saved
+    /// on each change.
+    pub fn part_alloc(&self) -> &PartIdAlloc {
+        &self.part_alloc
+    }
+    
+    /// Delete a partition and its files, freeing its identifier.
+    /// 
+    /// The partition must be loaded and contain no elements, and must not be
+    /// the only partition. The caller must ensure that the classifier no
+    /// longer assigns elements to it. Records of elements moved out of the
+    /// partition are lost, thus old identifiers of such elements can no
+    /// longer be resolved.
+    pub fn delete_partition(&mut self, part_id: PartId) -> Result<()> {
+        if self.partitions.len() <= 1 {
+            return ArgError::err("cannot delete the only partition");
+        }
+        match self.partitions.get(&part_id) {
+            Some(part) => {
+                if part.tip()?.num_avail() > 0 {
+                    return ArgError::err("cannot delete a partition which is not empty");
+                }
+            },
+            None => return ArgError::err("no such partition"),
+        }
+        info!("Repository {}: deleting partition {}", self.name, part_id);
+        self.repo_t.io().delete_part(part_id)?;
+        self.partitions.remove(&part_id);
+        self.part_alloc.free(part_id);
+        write_part_alloc_io(&self.part_alloc, self.repo_t.io())?;
+        Ok(())
+    }
+    
+    /// Delete partitions which are empty and no longer needed, freeing their
+    /// identifiers for reuse (e.g. by later divisions). This is a maintenance
+    /// operation for repositories where division has left many retired
+    /// partitions.
+    /// 
+    /// All partitions are loaded and pending commits written. Each partition
+    /// holding neither elements nor move records is offered to
+    /// `RepoT::release_part()`, which
+    /// updates classification and partitioning data. A snapshot of each
+    /// remaining partition is then written, recording this data, before the
+    /// released partitions are deleted (as `delete_partition()`); thus an
+    /// interruption leaves either the old partitions or the new partitioning
+    /// data in place. At least one partition is always kept.
+    /// 
+    /// Element identifiers contain partition identifiers, thus partitions
+    /// holding elements are not renumbered. Partitions retired by
+    /// `RepoT::divide()` usually still hold records of the elements moved out
+    /// of them, which `get_element()` follows; these are kept so that old
+    /// identifiers keep resolving and are never reused for other elements.
+    /// Returns the identifiers freed.
+    pub fn reclaim_part_ids(&mut self) -> Result<Vec<PartId>> {
+        self.load_latest(None)?;
+        self.write_fast()?;
+        
+        let mut ids: Vec<PartId> = self.partitions.keys().cloned().collect();
+        ids.sort();
+        let mut released = vec![];
+        for id in ids {
+            if self.partitions.len() - released.len() <= 1 {
+                break;
+            }
+            let part = &self.partitions[&id];
+            let unused = part.tip().map_or(false, |tip|
+                    tip.num_avail() == 0 && tip.moved_iter().next().is_none());
+            if unused &&
+                self.repo_t.release_part(part)
+            {
+                released.push(id);
+            }
+        }
+        if released.is_empty() {
+            return Ok(released);
+        }
+        
+        // Record the new partitioning before deleting anything:
+        for (id, part) in &mut self.partitions {
+            if !released.contains(id) {
+                check_cancel(&self.cancel)?;
+                part.write_snapshot(Some(&mut self.repo_t))?;
+            }
+        }
+        for id in &released {
+            info!("Repository {}: deleting released partition {}", self.name, id);
+            self.repo_t.io().delete_part(*id)?;
+            self.partitions.remove(id);
+            self.part_alloc.free(*id);
+        }
+        write_part_alloc_io(&self.part_alloc, self.repo_t.io())?;
+        self.repo_t.set_part_alloc(&self.part_alloc);
+        self.repo_t.io().flush()?;
+        Ok(released)
+    }
+    
+    /// Move partition `part_id` to storage tier `tier` (see
+    /// `RepoIO::set_tier()`).
+    /// 
+    /// Unsaved commits of the partition are written first. The partition
+    /// then uses its files at the new location; loaded data and options set
+    /// on it are kept. Fails if the partition is not found or the `RepoIO`
+    /// does not support tiers.
+    pub fn set_tier(&mut self, part_id: PartId, tier: Tier) -> Result<()> {
+        let part = match self.partitions.get_mut(&part_id) {
+            Some(part) => part,
+            None => return ArgError::err("no such partition"),
+        };
+        part.write_fast(Some(&mut self.repo_t))?;
+        if self.repo_t.io().tier(part_id) == tier {
+            return Ok(());
+        }
+        info!("Repository {}: moving partition {} to tier {:?}", self.name, part_id, tier);
+        self.repo_t.io().set_tier(part_id, tier)?;
+        
+        let part_io = self.repo_t.io().make_part_io(part_id)?;
+        part.replace_io(part_io)?;
+        Ok(())
+    }
+    
     /// Iterate over all partitions.
     /// 
     /// These do not necessarily have data loaded; use `load_latest()`
@@ -146,32 +480,327 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
         PartIterMut { iter: self.partitions.values_mut() }
     }
     
-    /// Load the latest state of all partitions
+    /// Visit each partition with `f`, loading its latest state first if it is
+    /// not already loaded (as `load_part()`). This is intended for
+    /// maintenance operations over the whole repository, e.g. writing a
+    /// snapshot of or verifying each partition.
+    /// 
+    /// Partitions are visited in order of identifier. A failure (either
+    /// loading a partition or from `f`) does not stop other partitions from
+    /// being visited; if any occur, a `PartErrors` listing each is returned.
+    /// 
+    /// The cancellation flag (see `set_cancel_flag()`) is checked before each
+    /// partition.
+    pub fn for_each_partition_mut<F>(&mut self, mut f: F) -> Result<()>
+        where F: FnMut(PartId, &mut Partition<C::Element>) -> Result<()>
+    {
+        self.for_each_partition_with_repo_t(|part_id, part, _| f(part_id, part))
+    }
+    
+    // As `for_each_partition_mut()`, also passing `repo_t` (e.g. for writing
+    // header user fields)
+    fn for_each_partition_with_repo_t<F>(&mut self, mut f: F) -> Result<()>
+        where F: FnMut(PartId, &mut Partition<C::Element>, &mut R) -> Result<()>
+    {
+        let mut ids: Vec<PartId> = self.partitions.keys().cloned().collect();
+        ids.sort();
+        let mut errors = Vec::new();
+        for part_id in ids {
+            check_cancel(&self.cancel)?;
+            let part = self.partitions.get_mut(&part_id).expect("partition");
+            let repo_t = &mut self.repo_t;
+            let result = if part.is_loaded() {
+                Ok(())
+            } else {
+                part.load_latest(Some(repo_t), None)
+            };
+            if let Err(e) = result.and_then(|_| f(part_id, part, repo_t)) {
+                warn!("Repository {}: partition {}: {}", self.name, part_id, e);
+                errors.push((part_id, e));
+            }
+        }
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(Box::new(PartErrors::new(errors)))
+        }
+    }
+    
+    /// Iterate over all elements of all partitions, yielding the partition
+    /// identifier, element identifier and element for each.
+    /// 
+    /// This does not load anything: partitions which are not loaded or which
+    /// require a merge are skipped. Use `load_latest()` (and `merge()` if
+    /// necessary) first in order to visit every element.
+    /// 
+    /// Order is not defined.
+    pub fn iter_elements(&self) -> RepoEltIter<C::Element> {
+        RepoEltIter { parts: self.partitions.values(), current: None }
+    }
+    
+    /// Set (or clear) a cancellation flag.
+    /// 
+    /// Operations on all partitions (`load_latest()`, `write_fast()`,
+    /// `write_full()`, `write_snapshot_all()` and `for_each_partition_mut()`)
+    /// check this flag before
+    /// processing each partition; once it is set, they stop and return a
+    /// `Cancelled` error. Partitions already processed are left loaded or
+    /// written and usable; the others are untouched. The flag is not reset
+    /// automatically.
+    pub fn set_cancel_flag(&mut self, flag: Option<Arc<AtomicBool>>) {
+        self.cancel = flag;
+    }
+    
+    /// Set the number of elements above which a partition is divided
+    /// automatically. When `write_full()` finds a partition whose tip holds
+    /// more than `threshold` elements, it divides the partition as if
+    /// `RepoT::should_divide()` had returned true: the classifier is
+    /// consulted (see `ClassifierT::should_divide()`), then `RepoT::divide()`
+    /// is called and elements are moved to their new partitions.
+    /// 
+    /// Zero (the default) disables this; `RepoT::should_divide()` is always
+    /// consulted.
+    pub fn set_auto_divide_threshold(&mut self, threshold: usize) {
+        self.auto_divide_threshold = threshold;
+    }
+    
+    /// Set an approximate limit on the memory used by loaded partitions, in
+    /// bytes (as estimated by `Partition::approx_memory_usage()`).
+    /// 
+    /// Whenever loading or accessing a partition (via `load_latest()`,
+    /// `load_part()` or functions using it, such as `get_element()`) leaves
+    /// usage above the budget, the least-recently-accessed partitions are
+    /// unloaded until usage is within the budget again. Their files are kept
+    /// and they are loaded again on next access. Partitions with unsaved
+    /// changes are not unloaded, nor is the partition just accessed, thus
+    /// the budget may still be exceeded.
+    /// 
+    /// Zero (the default) disables this.
+    pub fn set_memory_budget(&mut self, budget: usize) {
+        self.memory_budget = budget;
+    }
+    
+    /// Load the latest state of all partitions.
+    /// 
+    /// With a memory budget (see `set_memory_budget()`), partitions loaded
+    /// earlier may be unloaded again to make room for later ones.
     pub fn load_latest(&mut self, make_meta: Option<&MakeMeta>) -> Result<()> {
-        for (_, part) in &mut self.partitions {
-            part.load_latest(Some(&mut self.repo_t), make_meta)?;
+        let ids: Vec<PartId> = self.partitions.keys().cloned().collect();
+        for part_id in ids {
+            check_cancel(&self.cancel)?;
+            self.partitions.get_mut(&part_id).expect("partition")
+                    .load_latest(Some(&mut self.repo_t), make_meta)?;
+            self.note_access(part_id);
+            self.apply_memory_budget(part_id);
         }
         Ok(())
     }
     
-    /// Write commits to the disk for all partitions.
+    /// True if partition `part_id` exists and is loaded.
+    pub fn is_loaded(&self, part_id: PartId) -> bool {
+        self.partitions.get(&part_id).map_or(false, |p| p.is_loaded())
+    }
+    
+    /// Get a partition, loading its latest state first if it is not already
+    /// loaded. Other partitions are not loaded.
+    /// 
+    /// Fails if the repository has no partition `part_id`.
+    pub fn load_part(&mut self, part_id: PartId, make_meta: Option<&MakeMeta>) ->
+            Result<&mut Partition<C::Element>>
+    {
+        self.access_part(part_id, make_meta)?;
+        self.apply_memory_budget(part_id);
+        Ok(self.partitions.get_mut(&part_id).expect("partition"))
+    }
+    
+    // Load partition `part_id` if not loaded and note the access, without
+    // applying the memory budget
+    fn access_part(&mut self, part_id: PartId, make_meta: Option<&MakeMeta>) -> Result<()> {
+        {
+            let part = match self.partitions.get_mut(&part_id) {
+                Some(part) => part,
+                None => return ArgError::err("no such partition"),
+            };
+            if !part.is_loaded() {
+                debug!("Repository {}: loading partition {} on demand", self.name, part_id);
+                part.load_latest(Some(&mut self.repo_t), make_meta)?;
+            }
+        }
+        self.note_access(part_id);
+        Ok(())
+    }
+    
+    fn note_access(&mut self, part_id: PartId) {
+        self.access_count += 1;
+        self.last_access.insert(part_id, self.access_count);
+    }
+    
+    // Unload least-recently-accessed partitions other than `keep` while
+    // memory usage exceeds the budget (see `set_memory_budget()`)
+    fn apply_memory_budget(&mut self, keep: PartId) {
+        if self.memory_budget == 0 {
+            return;
+        }
+        let mut usage: usize = self.partitions.values().map(|p| p.approx_memory_usage()).sum();
+        if usage <= self.memory_budget {
+            return;
+        }
+        let mut candidates: Vec<(u64, PartId)> = self.partitions.iter()
+                .filter(|&(id, part)| *id != keep && part.is_loaded())
+                .map(|(id, _)| (self.last_access.get(id).cloned().unwrap_or(0), *id))
+                .collect();
+        candidates.sort();
+        for (_, part_id) in candidates {
+            if usage <= self.memory_budget {
+                break;
+            }
+            let part = self.partitions.get_mut(&part_id).expect("partition");
+            let part_usage = part.approx_memory_usage();
+            if part.unload(false) {
+                debug!("Repository {}: unloaded partition {} to stay within memory budget",
+                        self.name, part_id);
+                usage -= part_usage;
+            }
+        }
+    }
+    
+    /// Get an element, loading its partition (as by `load_part()`) if
+    /// necessary.
+    /// 
+    /// No lookup table is needed to find the element's partition: element
+    /// identifiers are prefixed with the identifier of the partition which
+    /// assigned them (see `EltId::part_id()`), thus only this partition is
+    /// loaded. Where an element has since been moved to another partition
+    /// (by `move_element()`, `replace_classifier()` or division), the old
+    /// partition records the new identifier (see `PartState::is_moved()`);
+    /// this is followed, loading the new partition.
+    /// 
+    /// Fails with `ElementOp::NotFound` if the element's partition does not
+    /// exist or does not contain the element, and with a `TipError` if the
+    /// partition requires a merge.
+    pub fn get_element(&mut self, mut id: EltId) -> Result<Arc<C::Element>> {
+        // An element may pass through a partition more than once, but never
+        // has the same identifier twice unless the moves form a cycle
+        let mut visited = HashSet::new();
+        while visited.insert(id) {
+            if !self.partitions.contains_key(&id.part_id()) {
+                return Err(Box::new(ElementOp::NotFound));
+            }
+            let new_id = {
+                let tip = self.load_part(id.part_id(), None)?.tip()?;
+                match tip.get_rc(id) {
+                    Ok(elt) => return Ok(elt.clone()),
+                    Err(e) => match tip.is_moved(id) {
+                        Some(new_id) => new_id,
+                        None => return Err(Box::new(e)),
+                    },
+                }
+            };
+            trace!("Repository {}: element {} moved to {}", self.name, id, new_id);
+            id = new_id;
+        }
+        Err(Box::new(ElementOp::NotFound))
+    }
+    
+    /// Find elements for which `pred` returns true, loading partitions (as by
+    /// `load_part()`) where necessary.
+    /// 
+    /// If `part_hint` is given, only this partition is searched; typically
+    /// this is found by classifying a value similar to those sought (see
+    /// `ClassifierT::classify()`). Otherwise every partition is loaded and
+    /// searched.
+    /// 
+    /// Returns the partition identifier, element identifier and element of
+    /// each match, sorted by element identifier. Fails if a partition
+    /// searched does not exist or requires a merge.
+    /// 
+    /// Since matches may come from every partition searched, no partitions
+    /// are unloaded to meet the memory budget (see `set_memory_budget()`)
+    /// until the next access.
+    pub fn find<F>(&mut self, part_hint: Option<PartId>, pred: F)
+            -> Result<Vec<(PartId, EltId, &C::Element)>>
+        where F: Fn(&C::Element) -> bool
+    {
+        let ids: Vec<PartId> = match part_hint {
+            Some(part_id) => vec![part_id],
+            None => self.partitions.keys().cloned().collect(),
+        };
+        for part_id in &ids {
+            self.access_part(*part_id, None)?;
+        }
+        
+        let mut result = Vec::new();
+        for part_id in ids {
+            let tip = self.partitions[&part_id].tip()?;
+            for (id, elt) in tip.elts_iter() {
+                if pred(&**elt) {
+                    result.push((part_id, id, &**elt));
+                }
+            }
+        }
+        result.sort_by_key(|&(_, id, _)| id);
+        Ok(result)
+    }
+    
+    /// Get a copy of the state of partition `part_id`, loading it (as by
+    /// `load_part()`) if necessary. Unlike `clone_state()`, this gives
+    /// access to a single partition only.
+    pub fn clone_part_state(&mut self, part_id: PartId) ->
+            Result<MutPartState<C::Element>>
+    {
+        let part = self.load_part(part_id, None)?;
+        Ok(part.tip()?.clone_mut())
+    }
+    
+    /// Write commits to the disk for all partitions, then flush the
+    /// `RepoIO` (see `RepoIO::flush()`).
     /// 
     /// Also see the `write_full()` function.
     pub fn write_fast(&mut self) -> Result<()> {
         for (_, part) in &mut self.partitions {
+            check_cancel(&self.cancel)?;
             part.write_fast(Some(&mut self.repo_t))?;
         }
-        Ok(())
+        self.repo_t.io().flush()
     }
+    /// Write commits to the disk for all partitions, as a single transaction.
+    /// 
+    /// Unlike `write_fast()`, a failure part way through (e.g. a crash) cannot
+    /// leave some partitions updated and others not: the commits for all
+    /// partitions are first recorded in a journal (see
+    /// `RepoIO::write_journal()`), which is removed once all partitions have
+    /// been written. If a journal is found by `open()`, the write is
+    /// completed then.
+    /// 
+    /// Fails if the `RepoIO` does not support journals.
+    pub fn write_atomic(&mut self) -> Result<()> {
+        let mut journal = Vec::new();
+        {
+            let parts: Vec<(PartId, Vec<_>)> = self.partitions.iter()
+                    .filter(|&(_, part)| part.unsaved_len() > 0)
+                    .map(|(part_id, part)| (*part_id, part.unsaved_iter().collect()))
+                    .collect();
+            if parts.is_empty() {
+                return Ok(());
+            }
+            write_journal(&parts, &mut journal)?;
+        }
+        self.repo_t.io().write_journal(&journal)?;
+        self.write_fast()?;
+        self.repo_t.io().clear_journal()
+    }
+    
     /// Write commits to the disk for all partitions and do any needed
     /// maintenance operations.
     /// 
     /// This should be called at least occasionally, but such calls could be
-    /// scheduled during less busy periods.
+    /// scheduled during less busy periods. Like `write_fast()`, this flushes
+    /// the `RepoIO` when done.
     pub fn write_full(&mut self) -> Result<()> {
         // Write all logs first, in case we crash later
         self.write_fast()?;
         for (_, part) in &mut self.partitions {
+            check_cancel(&self.cancel)?;
             part.write_full(Some(&mut self.repo_t))?;
         }
         
@@ -181,8 +810,14 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
         // 1) we collect partition numbers of any partition needing repartitioning.
         let mut should_divide: Vec<PartId> = Vec::new();
         let mut need_reclassify: Vec<PartId> = Vec::new();
+        let classifier = self.classifier();
+        let threshold = self.auto_divide_threshold;
         for (id, part) in &self.partitions {
-            if self.repo_t.should_divide(*id, part) && part.is_ready() {
+            let over_threshold = threshold > 0 &&
+                    part.num_avail().map_or(false, |num| num > threshold);
+            if (over_threshold || self.repo_t.should_divide(*id, part)) && part.is_ready() &&
+                part.tip().map_or(false, |state| classifier.should_divide(state))
+            {
                 should_divide.push(*id);
             }
             if let Ok(ref state) = part.tip() {
@@ -194,6 +829,7 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
         
         while let Some(old_id) = should_divide.pop() {
             // Get new partition numbers and update classifiers. This gets saved later if successful.
+            self.repo_t.set_part_alloc(&self.part_alloc);
             let result = self.repo_t.divide(self.partitions.get(&old_id).expect("get partition"));
             let (new_parts, changed) = match result {
                 Ok(result) => result,
@@ -215,6 +851,15 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
                 }
             };
             
+            // Record new partition numbers before using them:
+            if new_parts.iter().any(|id| self.part_alloc.is_allocated(*id)) {
+                return OtherError::err("divide returned a partition identifier already allocated");
+            }
+            for new_id in &new_parts {
+                self.part_alloc.allocate(*new_id);
+            }
+            write_part_alloc_io(&self.part_alloc, self.repo_t.io())?;
+            
             // Mark partition as needing reclassification:
             {
                 let old_part = self.partitions.get_mut(&old_id).expect("has old part");
@@ -257,7 +902,7 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
         while let Some(old_id) = need_reclassify.pop() {
             // extract from partitions so as not to block it
             let mut old_part = self.partitions.remove(&old_id).expect("remove old part");
-            let classifier = self.repo_t.clone_classifier();
+            let classifier = self.classifier();
             
             // Check where elements need to be moved
             // for each partition, the elements to be moved there (old element ids)
@@ -283,7 +928,8 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
                     // TODO: if there are a lot of elements/data, we should stop and write a
                     // checkpoint from time to time.
                     if let Ok(elt) = old_state.remove(elt_id) {
-                        state.insert_rc_initial(elt_id.elt_num(), elt)?;
+                        let new_id = state.insert_rc_initial(elt_id.elt_num(), elt)?;
+                        old_state.set_move(elt_id, new_id);
                     }
                 }
                 part.push_state(state, None /*TODO: MakeMeta*/)?;
@@ -304,15 +950,244 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
             self.partitions.insert(old_id, old_part);
         }
         
-        Ok(())
+        self.repo_t.io().flush()
     }
     
-    /// Force all loaded partitions to write a snapshot.
+    /// Force all loaded partitions to write a snapshot, then flush the
+    /// `RepoIO`.
     pub fn write_snapshot_all(&mut self) -> Result<()> {
         for (_, part) in &mut self.partitions {
+            check_cancel(&self.cancel)?;
             part.write_snapshot(Some(&mut self.repo_t))?;
         }
-        Ok(())
+        self.repo_t.io().flush()
+    }
+    
+    /// Compact each partition whose log growth exceeds the threshold of
+    /// `policy` (see `Partition::compact()`), skipping the rest. Partitions
+    /// are visited (and loaded if necessary) as by `for_each_partition_mut()`.
+    /// 
+    /// Pending commits are written first, then the `RepoIO` is flushed after
+    /// compaction. On success, returns which partitions were compacted and
+    /// the number of bytes reclaimed.
+    pub fn compact_all(&mut self, policy: CompactPolicy) -> Result<CompactReport> {
+        self.write_fast()?;
+        let mut report = CompactReport {
+            compacted: vec![],
+            bytes_reclaimed: 0,
+        };
+        self.for_each_partition_with_repo_t(|part_id, part, repo_t| {
+            if part.log_bytes()? <= policy.min_log_bytes {
+                return Ok(());
+            }
+            let outcome = part.compact(Some(repo_t))?;
+            report.compacted.push(part_id);
+            report.bytes_reclaimed += outcome.bytes_reclaimed;
+            Ok(())
+        })?;
+        self.repo_t.io().flush()?;
+        Ok(report)
+    }
+    
+    /// Verify the files of every partition (see `Partition::verify()`),
+    /// without loading anything. Reports are returned in order of partition
+    /// identifier.
+    pub fn verify(&self) -> Result<Vec<VerifyReport>> {
+        let mut reports = Vec::with_capacity(self.partitions.len());
+        for part in self.partitions.values() {
+            check_cancel(&self.cancel)?;
+            reports.push(part.verify()?);
+        }
+        reports.sort_by_key(|r| r.part_id);
+        Ok(reports)
+    }
+    
+    /// Repair each partition which `verify()` reports as damaged (see
+    /// `Partition::repair()`), then flush the `RepoIO`. Undamaged partitions
+    /// are not touched.
+    /// 
+    /// Returns a report for each partition repaired, in order of identifier.
+    /// Fails on the first partition which cannot be repaired.
+    pub fn repair(&mut self) -> Result<Vec<(PartId, RepairReport)>> {
+        let mut repaired = vec![];
+        for report in self.verify()? {
+            if report.is_ok() {
+                continue;
+            }
+            check_cancel(&self.cancel)?;
+            let part = self.partitions.get_mut(&report.part_id).expect("partition");
+            repaired.push((report.part_id, part.repair(Some(&mut self.repo_t))?));
+        }
+        self.repo_t.io().flush()?;
+        Ok(repaired)
+    }
+    
+    /// Write a snapshot of every partition, recording the tips written in a
+    /// manifest.
+    /// 
+    /// Pending commits are written first. Since this takes `&mut self`, no
+    /// changes can be made while the checkpoint is in progress; thus all
+    /// snapshots correspond to the same logical moment, and the returned
+    /// manifest lists exactly one tip (the state written) per partition.
+    /// 
+    /// Fails if any partition is not loaded or requires a merge.
+    pub fn checkpoint(&mut self) -> Result<Checkpoint> {
+        if self.merge_required() {
+            return OtherError::err("cannot checkpoint while a merge is required");
+        }
+        self.write_fast()?;
+        
+        let mut parts = Vec::with_capacity(self.partitions.len());
+        for (id, part) in &mut self.partitions {
+            check_cancel(&self.cancel)?;
+            let tip = part.tip_key()?.clone();
+            part.write_snapshot(Some(&mut self.repo_t))?;
+            parts.push(ManifestPart {
+                part_id: *id,
+                tips: vec![tip],
+                user: self.repo_t.write_user_fields(*id, false),
+            });
+        }
+        parts.sort_by_key(|p| p.part_id);
+        self.repo_t.io().flush()?;
+        Ok(Checkpoint { manifest: Manifest { name: self.name.clone(), parts: parts } })
+    }
+    
+    /// Write a manifest describing the repository's structure: its name and,
+    /// for each partition, the identifier, the sums of all tips and the user
+    /// fields (as returned by `repo_t.write_user_fields()`). No element data
+    /// is written. See `import_manifest()`.
+    /// 
+    /// Tips are only listed for partitions which are loaded.
+    pub fn export_manifest<W: Write>(&mut self, mut writer: W) -> Result<()> {
+        let mut parts = Vec::with_capacity(self.partitions.len());
+        for (id, part) in &self.partitions {
+            let mut tips: Vec<_> = part.tips().iter().cloned().collect();
+            tips.sort();
+            parts.push(ManifestPart {
+                part_id: *id,
+                tips: tips,
+                user: self.repo_t.write_user_fields(*id, false),
+            });
+        }
+        parts.sort_by_key(|p| p.part_id);
+        let manifest = Manifest { name: self.name.clone(), parts: parts };
+        write_manifest(&manifest, &mut writer)
+    }
+    
+    /// Write a replication stream, allowing another copy of this repository
+    /// to catch up via `import_stream()`.
+    /// 
+    /// `sums` gives, for each partition, a state which the receiver is known
+    /// to have (e.g. a tip listed by its `export_manifest()`); all commits
+    /// since this state are written (see `Partition::commits_since()`). For
+    /// partitions not listed, the tip is written as a snapshot, thus these
+    /// must not require a merge. All partitions are loaded.
+    pub fn export_since(&mut self, sums: HashMap<PartId, Sum>, w: &mut Write) -> Result<()> {
+        let mut ids: Vec<PartId> = self.partitions.keys().cloned().collect();
+        ids.sort();
+        for id in &ids {
+            check_cancel(&self.cancel)?;
+            self.load_part(*id, None)?;
+        }
+        
+        let mut parts = Vec::with_capacity(ids.len());
+        for id in ids {
+            let part = &self.partitions[&id];
+            parts.push(match sums.get(&id) {
+                Some(sum) => (id, None, part.commits_since(sum)?),
+                None => (id, Some(part.tip()?), vec![]),
+            });
+        }
+        info!("Repository {}: exporting replication stream for {} partitions",
+                self.name, parts.len());
+        let parts: Vec<_> = parts.iter()
+                .map(|&(id, state, ref commits)| (id, state, commits.iter().collect()))
+                .collect();
+        write_replication(&self.name, &parts, w)
+    }
+    
+    /// Write a replication stream holding a redacted copy of each
+    /// partition's tip, as filtered by `policy` (see `PartState::redacted()`).
+    /// Loaded data is not modified.
+    /// 
+    /// Unlike `export_since()`, no commits are written (history cannot be
+    /// redacted consistently), thus the stream is suitable for creating a new
+    /// copy of the repository via `import_stream()`, but not for updating an
+    /// existing one. All partitions are loaded and must not require a merge.
+    pub fn export_redacted(&mut self, policy: &RedactionPolicy<C::Element>, w: &mut Write)
+            -> Result<()>
+    {
+        let mut ids: Vec<PartId> = self.partitions.keys().cloned().collect();
+        ids.sort();
+        for id in &ids {
+            check_cancel(&self.cancel)?;
+            self.load_part(*id, None)?;
+        }
+        
+        let mut states = Vec::with_capacity(ids.len());
+        for id in ids {
+            states.push((id, self.partitions[&id].tip()?.redacted(policy)?));
+        }
+        info!("Repository {}: exporting redacted copy of {} partitions",
+                self.name, states.len());
+        let parts: Vec<_> = states.iter()
+                .map(|&(id, ref state)| (id, Some(state), vec![]))
+                .collect();
+        write_replication(&self.name, &parts, w)
+    }
+    
+    /// Apply a replication stream written by `export_since()` from another
+    /// copy of this repository.
+    /// 
+    /// Commits are pushed to their partitions, loading these if necessary;
+    /// commits matching known states are ignored. Partitions not found are
+    /// created from the snapshot sent, allocating their identifiers. Changes
+    /// are not written; call `write_fast()` or similar afterwards.
+    /// 
+    /// Returns true when any further merge work is required. In this case
+    /// `merge()` should be called. Fails if the stream comes from a
+    /// repository with another name or a snapshot sent for a known
+    /// partition does not match a known state.
+    pub fn import_stream(&mut self, r: &mut Read) -> Result<bool> {
+        let (name, parts) = read_replication::<C::Element>(r)?;
+        if name != self.name {
+            return OtherError::err("replication stream is from a different repository");
+        }
+        info!("Repository {}: importing replication stream for {} partitions",
+                self.name, parts.len());
+        
+        let mut merge_required = false;
+        for (part_id, state, commits) in parts {
+            if self.partitions.contains_key(&part_id) {
+                let part = self.load_part(part_id, None)?;
+                if state.map_or(false, |state| part.state(state.statesum()).is_none()) {
+                    return OtherError::err("replicated snapshot does not match a known state");
+                }
+            } else {
+                let state = match state {
+                    Some(state) => state,
+                    None => return OtherError::err("replication stream has no snapshot for new partition"),
+                };
+                if self.part_alloc.allocate(part_id) {
+                    write_part_alloc_io(&self.part_alloc, self.repo_t.io())?;
+                }
+                let suggestion = self.repo_t.suggest_part_prefix(part_id);
+                let prefix = suggestion.unwrap_or_else(|| format!("pn{}", part_id));
+                self.repo_t.io().new_part(part_id, prefix)?;
+                let part_io = self.repo_t.io().make_part_io(part_id)?;
+                let part = Partition::create_from_state(part_io, &self.name,
+                        Some(&mut self.repo_t), state)?;
+                self.partitions.insert(part_id, part);
+            }
+            
+            let part = self.partitions.get_mut(&part_id).expect("has partition");
+            for commit in commits {
+                part.push_commit(commit)?;
+            }
+            if part.merge_required() { merge_required = true; }
+        }
+        Ok(merge_required)
     }
     
     /// Call `Partition::unload(force)` on all partitions.
@@ -329,6 +1204,28 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
         all
     }
     
+    /// Get statistics on the repository, aggregating `Partition::stats()` over
+    /// all partitions. This reads the headers of all partition files.
+    /// 
+    /// Element counts only include partitions ready for use; see
+    /// `RepoStats::num_ready`.
+    pub fn stats(&self) -> Result<RepoStats> {
+        let mut parts = Vec::with_capacity(self.partitions.len());
+        for part in self.partitions.values() {
+            parts.push(part.stats()?);
+        }
+        parts.sort_by_key(|p| p.part_id);
+        Ok(RepoStats {
+            num_parts: parts.len(),
+            num_ready: parts.iter().filter(|p| p.num_elts.is_some()).count(),
+            num_elts: parts.iter().filter_map(|p| p.num_elts).fold(0, |a, b| a + b),
+            total_bytes: parts.iter().map(|p| p.files.total_bytes()).fold(0, |a, b| a + b),
+            num_merge_required: parts.iter().filter(|p| p.merge_required()).count(),
+            num_want_snapshot: parts.iter().filter(|p| p.want_snapshot).count(),
+            parts: parts,
+        })
+    }
+    
     /// Returns true if any merge is required. This may be required after
     /// `merge_in()` or `sync()` is called, and can also be needed after
     /// loading data from an external resource.
@@ -339,6 +1236,21 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
         self.partitions.values().any(|p| p.merge_required())
     }
     
+    /// List the partitions requiring a merge (i.e. those for which
+    /// `Partition::merge_required()` returns true), in order of partition
+    /// identifier.
+    /// 
+    /// This allows merging only the affected partitions (via
+    /// `partitions_mut()`) instead of calling `merge()` on all.
+    pub fn merge_required_partitions(&self) -> Vec<PartId> {
+        let mut parts: Vec<PartId> = self.partitions.iter()
+                .filter(|&(_, p)| p.merge_required())
+                .map(|(id, _)| *id)
+                .collect();
+        parts.sort();
+        parts
+    }
+    
     /// Does any merge work requried.
     /// 
     /// Note that this is not the same as `merge_in()`, which integrates
@@ -375,7 +1287,7 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
     /// 
     /// TODO: a way to copy only some of the loaded partitions.
     pub fn clone_state(&self) -> result::Result<RepoState<C>, TipError> {
-        let mut rs = RepoState::new(self.repo_t.clone_classifier());
+        let mut rs = RepoState::new(self.classifier());
         for (num, part) in &self.partitions {
             if part.is_loaded() {
                 rs.add_part(*num, part.tip()?.clone_mut());
@@ -453,6 +1365,177 @@ impl<C: ClassifierT, R: RepoT<C>> Repository<C, R> {
         }
         Ok(merge_required)
     }
+    
+    /// Move an element from partition `from` to partition `to`, returning
+    /// its new identifier. `id` must be an identifier in partition `from`.
+    /// 
+    /// A new state is pushed to both partitions; the move is recorded in the
+    /// source partition (see `PartState::is_moved()`). The element keeps its
+    /// element number if this is free in the target partition. If `from` and
+    /// `to` are the same, nothing is changed and `id` is returned.
+    /// 
+    /// Both partitions must be loaded. Note that this does not check the
+    /// element's classification; if the classifier does not assign it to
+    /// `to`, it may be moved again during maintenance.
+    pub fn move_element(&mut self, id: EltId, from: PartId, to: PartId) -> Result<EltId> {
+        if id.part_id() != from {
+            return ArgError::err("element identifier is not in source partition");
+        }
+        let mut source = match self.partitions.get(&from) {
+            Some(part) => part.tip()?.clone_mut(),
+            None => return Err(Box::new(ElementOp::NotLoaded)),
+        };
+        if from == to {
+            // Only check that the element exists
+            source.get_rc(id)?;
+            return Ok(id);
+        }
+        let mut target = match self.partitions.get(&to) {
+            Some(part) => part.tip()?.clone_mut(),
+            None => return Err(Box::new(ElementOp::NotLoaded)),
+        };
+        
+        let elt = source.remove(id)?;
+        let new_id = match target.insert_with_id(to.elt_id(id.elt_num()), elt.clone()) {
+            Ok(new_id) => new_id,
+            Err(_) => target.insert_rc(elt)?,
+        };
+        source.set_move(id, new_id);
+        
+        // Both states are prepared, so neither push should fail:
+        self.partitions.get_mut(&to).expect("has target").push_state(target, None)?;
+        self.partitions.get_mut(&from).expect("has source").push_state(source, None)?;
+        Ok(new_id)
+    }
+    
+    /// Replace the classifier, moving elements as the new classification
+    /// requires. Returns the number of elements moved.
+    /// 
+    /// `classifier` must classify as the `RepoT` will once `install` has been
+    /// called on it (`on_load()` is called on it before use). `install`
+    /// should store the new classifier data, increasing its version number
+    /// so that it supersedes the old data when files are read.
+    /// 
+    /// First, every element is classified with `classifier`. The replacement
+    /// is rejected, without changing anything, if this would leave an
+    /// element unclassified (i.e. `classify()` returns `None` and the
+    /// fallback is `ClassifyFallback::Fail`) or assign it to a partition
+    /// which does not exist. Otherwise `install` is called, elements are
+    /// moved (one new state is pushed to each partition affected) and a
+    /// snapshot is written for each partition, thus recording the new
+    /// classifier data in file headers.
+    /// 
+    /// All partitions must be loaded and none may require a merge.
+    pub fn replace_classifier<F: FnOnce(&mut R)>(&mut self, mut classifier: C,
+            install: F) -> Result<usize>
+    {
+        classifier.on_load();
+        let mut moves: Vec<(EltId, PartId)> = Vec::new();
+        for (part_id, part) in &self.partitions {
+            for (id, elt) in part.tip()?.elts_iter() {
+                let target = match classifier.classify(&**elt) {
+                    Some(target) => target,
+                    None => match classifier.fallback() {
+                        ClassifyFallback::Default(target) => target,
+                        ClassifyFallback::ReplacedOrDefault(_) |
+                        ClassifyFallback::ReplacedOrFail => *part_id,
+                        ClassifyFallback::Fail => {
+                            return OtherError::err("new classifier cannot classify all elements");
+                        },
+                    },
+                };
+                if !self.partitions.contains_key(&target) {
+                    return OtherError::err("new classifier assigns elements to unknown partition");
+                }
+                if target != *part_id {
+                    moves.push((id, target));
+                }
+            }
+        }
+        
+        info!("Repository {}: replacing classifier; moving {} elements", self.name, moves.len());
+        install(&mut self.repo_t);
+        let mut states: HashMap<PartId, MutPartState<C::Element>> = HashMap::new();
+        for &(id, to) in &moves {
+            let from = id.part_id();
+            for part_id in &[from, to] {
+                if !states.contains_key(part_id) {
+                    let state = self.partitions[part_id].tip()?.clone_mut();
+                    states.insert(*part_id, state);
+                }
+            }
+            let elt = states.get_mut(&from).expect("has source").remove(id)?;
+            let new_id = {
+                let target = states.get_mut(&to).expect("has target");
+                match target.insert_with_id(to.elt_id(id.elt_num()), elt.clone()) {
+                    Ok(new_id) => new_id,
+                    Err(_) => target.insert_rc(elt)?,
+                }
+            };
+            states.get_mut(&from).expect("has source").set_move(id, new_id);
+        }
+        for (part_id, state) in states {
+            self.partitions.get_mut(&part_id).expect("has partition").push_state(state, None)?;
+        }
+        self.write_snapshot_all()?;
+        Ok(moves.len())
+    }
+    
+    // Complete any transaction interrupted during `write_atomic()`
+    fn recover(&mut self) -> Result<()> {
+        let journal = match self.repo_t.io().read_journal()? {
+            Some(journal) => journal,
+            None => return Ok(()),
+        };
+        let parts = read_journal::<C::Element>(&mut &journal[..])?;
+        if self.repo_t.io().readonly() {
+            warn!("Repository {}: read-only; not completing interrupted write to {} partitions",
+                self.name, parts.len());
+            return Ok(());
+        }
+        warn!("Repository {}: completing interrupted write to {} partitions",
+            self.name, parts.len());
+        for (part_id, commits) in parts {
+            let part = match self.partitions.get_mut(&part_id) {
+                Some(part) => part,
+                None => return OtherError::err("journal refers to unknown partition"),
+            };
+            if !part.is_loaded() {
+                part.load_latest(Some(&mut self.repo_t), None)?;
+            }
+            for commit in commits {
+                // Commits already written are recognised and ignored
+                part.push_commit(commit)?;
+            }
+            part.write_fast(Some(&mut self.repo_t))?;
+        }
+        self.repo_t.io().clear_journal()
+    }
+    
+    /// Get a copy of the classifier from the `RepoT`, ready for use (i.e.
+    /// `ClassifierT::on_load()` has been called).
+    /// 
+    /// This may be used to find which partitions are relevant to a query.
+    pub fn classifier(&self) -> C {
+        let mut classifier = self.repo_t.clone_classifier();
+        classifier.on_load();
+        classifier
+    }
+}
+
+// Save the record of allocated partition identifiers
+fn write_part_alloc_io(alloc: &PartIdAlloc, io: &mut RepoIO) -> Result<()> {
+    let mut buf = Vec::new();
+    write_part_alloc(alloc, &mut buf)?;
+    io.write_part_alloc(&buf)
+}
+
+// Fail with `Cancelled` if the flag is set
+fn check_cancel(flag: &Option<Arc<AtomicBool>>) -> Result<()> {
+    match *flag {
+        Some(ref flag) if flag.load(Ordering::SeqCst) => Cancelled::err(),
+        _ => Ok(()),
+    }
 }
 
 /// Provides read-write access to some or all partitions in a non-blocking
@@ -558,7 +1641,7 @@ impl<C: ClassifierT> StateT<C::Element> for RepoState<C> {
         let part_id = id.part_id();
         self.states.get(&part_id).map_or(false, |state| state.is_avail(id))
     }
-    fn get_rc(&self, id: EltId) -> Result<&Rc<C::Element>, ElementOp> {
+    fn get_rc(&self, id: EltId) -> Result<&Arc<C::Element>, ElementOp> {
         let part_id = id.part_id();
         match self.states.get(&part_id) {
             Some(state) => state.get_rc(id),
@@ -567,7 +1650,7 @@ impl<C: ClassifierT> StateT<C::Element> for RepoState<C> {
     }
 }
 impl<C: ClassifierT> MutStateT<C::Element> for RepoState<C> {
-    fn insert_rc_initial(&mut self, initial: u32, elt: Rc<C::Element>) -> Result<EltId, ElementOp> {
+    fn insert_rc_initial(&mut self, initial: u32, elt: Arc<C::Element>) -> Result<EltId, ElementOp> {
         let part_id = if let Some(part_id) = self.classifier.classify(&*elt) {
             part_id
         } else {
@@ -585,7 +1668,7 @@ impl<C: ClassifierT> MutStateT<C::Element> for RepoState<C> {
             Err(ElementOp::NotLoaded)
         }
     }
-    fn replace_rc(&mut self, id: EltId, elt: Rc<C::Element>) -> Result<Rc<C::Element>, ElementOp> {
+    fn replace_rc(&mut self, id: EltId, elt: Arc<C::Element>) -> Result<Arc<C::Element>, ElementOp> {
         let class_id = if let Some(class_id) = self.classifier.classify(&*elt) {
             class_id
         } else {
@@ -638,7 +1721,7 @@ impl<C: ClassifierT> MutStateT<C::Element> for RepoState<C> {
             }
         }
     }
-    fn remove(&mut self, id: EltId) -> Result<Rc<C::Element>, ElementOp> {
+    fn remove(&mut self, id: EltId) -> Result<Arc<C::Element>, ElementOp> {
         let part_id = id.part_id();
         if let Some(mut state) = self.states.get_mut(&part_id) {
             state.remove(id)
@@ -671,3 +1754,33 @@ impl<'a, E: ElementT> Iterator for PartIterMut<'a, E> {
     }
     fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
 }
+
+/// Iterator over elements of all (loaded) partitions.
+pub struct RepoEltIter<'a, E: ElementT+'a> {
+    parts: Values<'a, PartId, Partition<E>>,
+    current: Option<(PartId, EltIter<'a, E>)>,
+}
+impl<'a, E: ElementT> Iterator for RepoEltIter<'a, E> {
+    type Item = (PartId, EltId, &'a E);
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((part_id, ref mut iter)) = self.current {
+                if let Some((id, elt)) = iter.next() {
+                    return Some((part_id, id, &**elt));
+                }
+            }
+            // Current partition is exhausted (or there is none); find the
+            // next one which has a tip:
+            match self.parts.next() {
+                Some(part) => {
+                    self.current = part.tip().ok()
+                            .map(|state| (part.part_id(), state.elts_iter()));
+                },
+                None => {
+                    self.current = None;
+                    return None;
+                },
+            }
+        }
+    }
+}
@@ -7,9 +7,10 @@
 use std::marker::PhantomData;
 use std::any::Any;
 use std::{fmt, result};
+use std::cmp::min;
 
 use {PartIO, UserFields};
-use {ElementT, PartId, Partition};
+use {ElementT, PartId, Partition, PartState, PartIdAlloc};
 use error::{Error, Result, OtherError, ErrorTrait};
 
 
@@ -37,6 +38,10 @@ pub trait RepoIO {
     /// fails if it is already taken. `prefix` is the common part of the
     /// path/name of files for this partition; it must be unique from that of
     /// other partitions.
+    /// 
+    /// Should files with this prefix already exist, the `PartIO` made for
+    /// the partition should list these, thus pre-existing data can be
+    /// detected (see `Repository::create()`).
     fn new_part(&mut self, num: PartId, prefix: String) -> Result<()>;
     
     /// Construct and return a new PartIO for partition `num`.
@@ -44,6 +49,98 @@ pub trait RepoIO {
     /// Fails if construction of the PartIO fails (file-system or regex
     /// errors) or if the partition isn't found.
     fn make_part_io(&self, num: PartId) -> Result<Box<PartIO>>;
+    
+    /// Write the transaction journal (see `Repository::write_atomic()`),
+    /// replacing any existing journal. This should not return until the data
+    /// is on permanent storage (e.g. after a file sync), and should be atomic
+    /// where possible.
+    /// 
+    /// The default implementation fails (journals are not supported).
+    fn write_journal(&mut self, _data: &[u8]) -> Result<()> {
+        OtherError::err("RepoIO does not support journals")
+    }
+    
+    /// Read the transaction journal, if one exists.
+    /// 
+    /// The default implementation returns `Ok(None)`.
+    fn read_journal(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    
+    /// Remove the transaction journal. Succeeds if there is none.
+    /// 
+    /// The default implementation does nothing.
+    fn clear_journal(&mut self) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Write the record of allocated partition identifiers (see
+    /// `PartIdAlloc`), replacing any existing record. As with
+    /// `write_journal()`, this should be atomic where possible.
+    /// 
+    /// The default implementation does nothing; the repository then
+    /// reconstructs the record from `parts()` when opened, thus identifiers
+    /// of deleted partitions may be reused.
+    fn write_part_alloc(&mut self, _data: &[u8]) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Read the record written by `write_part_alloc()`, if one exists.
+    /// 
+    /// The default implementation returns `Ok(None)`.
+    fn read_part_alloc(&self) -> Result<Option<Vec<u8>>> {
+        Ok(None)
+    }
+    
+    /// Remove partition `num` and all its files (see
+    /// `Repository::delete_partition()`).
+    /// 
+    /// The default implementation fails.
+    fn delete_part(&mut self, _num: PartId) -> Result<()> {
+        OtherError::err("RepoIO does not support deleting partitions")
+    }
+    
+    /// Whether this only allows reading. `Repository::open()` does not
+    /// complete interrupted writes (see `Repository::write_atomic()`) when
+    /// the `RepoIO` is read-only.
+    /// 
+    /// The default implementation returns `false`.
+    fn readonly(&self) -> bool {
+        false
+    }
+    
+    /// Get the storage tier of partition `num` (see `set_tier()`).
+    /// 
+    /// The default implementation returns `Tier::Hot`.
+    fn tier(&self, _num: PartId) -> Tier {
+        Tier::Hot
+    }
+    
+    /// Move the files of partition `num` to the storage for `tier` (see
+    /// `Repository::set_tier()`). `PartIO`s made previously for this
+    /// partition may refer to the old location and should not be used.
+    /// 
+    /// The default implementation fails.
+    fn set_tier(&mut self, _num: PartId, _tier: Tier) -> Result<()> {
+        OtherError::err("RepoIO does not support storage tiers")
+    }
+    
+    /// Ensure that everything written via this object or its `PartIO`s is
+    /// on permanent storage. `Repository` calls this at the end of each write
+    /// operation (e.g. `Repository::write_fast()`); buffered or network
+    /// backends should implement this.
+    /// 
+    /// The default implementation does nothing.
+    fn flush(&mut self) -> Result<()> {
+        Ok(())
+    }
+    
+    /// Flush and release any resources held.
+    /// 
+    /// The default implementation calls `flush()`.
+    fn close(mut self) -> Result<()> where Self: Sized {
+        self.flush()
+    }
 }
 
 /// A classifier assigns each element to a partition. A repository may have
@@ -77,6 +174,62 @@ pub trait ClassifierT {
     /// despite classification not being available in all cases. The default
     /// implementation returns `ClassifyFallback::Fail`.
     fn fallback(&self) -> ClassifyFallback { ClassifyFallback::Fail }
+    
+    /// Called by the repository on each classifier obtained from
+    /// `RepoT::clone_classifier()`, before the classifier is used. Since
+    /// classification data is normally reconstructed from header user fields
+    /// while partitions are loaded, this is the place to rebuild any derived
+    /// state (e.g. lookup tables) from that data.
+    /// 
+    /// The default implementation does nothing.
+    fn on_load(&mut self) {}
+    
+    /// Called before a partition is divided automatically (once
+    /// `RepoT::should_divide()` has returned true), with the partition's
+    /// current state. Returning false vetoes the division. This allows policy
+    /// depending on the data (e.g. its distribution) to live with the
+    /// classifier.
+    /// 
+    /// The default implementation returns `true`.
+    fn should_divide(&self, _state: &PartState<Self::Element>) -> bool {
+        true
+    }
+    
+    /// Get an ordering key for an element, where classification is by ranges
+    /// of some key (e.g. a length or timestamp). This allows `RepoT::divide()`
+    /// implementations to choose a split point with `median_key()`.
+    /// 
+    /// The default implementation returns `None` (no key).
+    fn key(&self, _elt: &Self::Element) -> Option<u64> {
+        None
+    }
+}
+
+/// Choose a key at which to split the elements of `state` into two halves of
+/// roughly equal size, using `classifier.key()`. Elements with a key less
+/// than the result would go in one half and the rest in the other; both
+/// halves are non-empty. Elements without a key are ignored.
+/// 
+/// This is intended for use by `RepoT::divide()`. Returns `None` when no
+/// split is possible, i.e. when there are not at least two distinct keys.
+pub fn median_key<C: ClassifierT>(classifier: &C, state: &PartState<C::Element>)
+        -> Option<u64>
+{
+    let mut keys: Vec<u64> = state.elts_iter()
+            .filter_map(|(_, elt)| classifier.key(elt))
+            .collect();
+    keys.sort();
+    let (first, last) = match (keys.first(), keys.last()) {
+        (Some(first), Some(last)) if first < last => (*first, *last),
+        _ => return None,
+    };
+    let median = keys[keys.len() / 2];
+    if median > first {
+        Some(median)
+    } else {
+        // Many elements share the lowest key; split just above it
+        keys.into_iter().find(|key| *key > first).or(Some(last))
+    }
 }
 
 /// Specifies what to do when classification fails and an element is to be
@@ -97,6 +250,16 @@ pub enum ClassifyFallback {
     Fail,
 }
 
+/// Storage tier of a partition: a hint allowing rarely accessed partitions
+/// to be kept on slower storage (see `RepoIO::set_tier()`).
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Tier {
+    /// Frequently accessed; the default
+    Hot,
+    /// Rarely accessed
+    Cold,
+}
+
 /// Encapsulates a RepoIO and a ClassifierT, handling repartitioning and
 /// serialisation.
 /// 
@@ -120,6 +283,9 @@ pub trait RepoT<C: ClassifierT+Sized>: UserFields {
     /// `divide()`) of this object. Assuming this object is not repartitioned,
     /// both self and the returned object should return the same
     /// classifications.
+    /// 
+    /// The repository calls `ClassifierT::on_load()` on the returned object
+    /// before using it.
     fn clone_classifier(&self) -> C;
     
     /// This method is called once by `Repository::create()`. It should
@@ -138,6 +304,15 @@ pub trait RepoT<C: ClassifierT+Sized>: UserFields {
     /// or after any method besides `io()` has been called.
     fn init_first(&mut self) -> Result<PartId>;
     
+    /// Called with the repository's record of allocated partition
+    /// identifiers before `init_first()` and `divide()`, so that new
+    /// identifiers may be chosen from those free (e.g. via
+    /// `PartIdAlloc::next_free()`). Identifiers returned by those methods are
+    /// then marked allocated; division fails if any is already allocated.
+    /// 
+    /// The default implementation does nothing.
+    fn set_part_alloc(&mut self, _alloc: &PartIdAlloc) {}
+    
     /// Allows users to pick human-readable prefixes for partition file names.
     /// The default implementation returns `None`.
     /// 
@@ -150,7 +325,10 @@ pub trait RepoT<C: ClassifierT+Sized>: UserFields {
     
     /// Determines whether a partition should be divided.
     /// 
-    /// This is called by `Repository::write_all()` on all partitions.
+    /// This is called by `Repository::write_full()` on all partitions not
+    /// already over the automatic division threshold (see
+    /// `Repository::set_auto_divide_threshold()`). Where this returns true,
+    /// the classifier is consulted too (see `ClassifierT::should_divide()`).
     /// 
     /// The default implementation returns `false` (never divide). A simple
     /// working version could base its decision on the number of elements
@@ -204,6 +382,37 @@ pub trait RepoT<C: ClassifierT+Sized>: UserFields {
     /// versioning to determine which information is up-to-date.
     fn divide(&mut self, part: &Partition<C::Element>) ->
         Result<(Vec<PartId>, Vec<PartId>), RepoDivideError>;
+    
+    /// Resolve a conflict in classifier data. Implementations storing
+    /// versioned classifier data in `UserFields` should call this when
+    /// reading two versions of the data for partition `part_id` with the same
+    /// version number but different content (e.g. when two processes both
+    /// divided a partition). `ours` is the data currently held and `theirs`
+    /// the data just read; the result should be the data to use (which may be
+    /// either input or a combination of both).
+    /// 
+    /// The default implementation picks the lexicographically smaller of the
+    /// two so that the result is deterministic (independent of read order).
+    fn merge_classifier_conflict(&mut self, _part_id: PartId, ours: Vec<u8>,
+            theirs: Vec<u8>) -> Vec<u8>
+    {
+        min(ours, theirs)
+    }
+    
+    /// Called by `Repository::reclaim_part_ids()` for each partition holding
+    /// no elements. Return true if the partition is no longer needed: the
+    /// classifier never assigns elements to it, and partitioning data (e.g.
+    /// identifier ranges used by `divide()`) has been updated such that its
+    /// identifier may be reused. The partition is then deleted and its
+    /// identifier freed. Return false to keep the partition.
+    /// 
+    /// Changes should be recorded in `UserFields`; these are written to the
+    /// remaining partitions before any partition is deleted.
+    /// 
+    /// The default implementation returns `false`.
+    fn release_part(&mut self, _part: &Partition<C::Element>) -> bool {
+        false
+    }
 }
 
 /// Failures allowed for `ClassifierT::divide`.
@@ -0,0 +1,229 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Pippin: retrying of transient I/O failures.
+//! 
+//! `RetryPartIO` and `RetryRepoIO` wrap another `PartIO` / `RepoIO`
+//! implementation, retrying operations which fail with a transient error
+//! (see `is_transient()`) according to a `RetryPolicy`. Other errors (e.g.
+//! corrupt data or a missing file) are returned immediately.
+
+use std::io::{self, Read, Write, ErrorKind};
+use std::any::Any;
+use std::thread;
+use std::time::Duration;
+
+use {PartIO, PartId, RepoIO, Sum};
+use error::{Error, Result};
+
+
+/// True if an error is considered transient, i.e. the operation may succeed
+/// if retried. Currently this is the case only for `std::io::Error`s of kinds
+/// `Interrupted`, `WouldBlock`, `TimedOut`, `ConnectionReset`,
+/// `ConnectionAborted` and `NotConnected`.
+pub fn is_transient(e: &Error) -> bool {
+    match e.downcast_ref::<io::Error>() {
+        Some(e) => match e.kind() {
+            ErrorKind::Interrupted | ErrorKind::WouldBlock | ErrorKind::TimedOut |
+            ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted |
+            ErrorKind::NotConnected => true,
+            _ => false,
+        },
+        None => false,
+    }
+}
+
+/// Specifies how often and how quickly to retry failed operations.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct RetryPolicy {
+    /// Maximum number of attempts (including the first). One means no retry.
+    pub max_attempts: u32,
+    /// Delay before the first retry.
+    pub delay: Duration,
+    /// Factor by which the delay is multiplied after each retry.
+    pub backoff: u32,
+}
+impl RetryPolicy {
+    /// Create a policy with the given number of attempts and initial delay.
+    /// The delay is doubled after each retry.
+    pub fn new(max_attempts: u32, delay: Duration) -> RetryPolicy {
+        RetryPolicy { max_attempts: max_attempts, delay: delay, backoff: 2 }
+    }
+    
+    /// Run `f`, retrying while it fails with a transient error and attempts
+    /// remain. Returns the result of the last attempt.
+    pub fn run<T, F: FnMut() -> Result<T>>(&self, mut f: F) -> Result<T> {
+        let mut delay = self.delay;
+        let mut attempt = 1;
+        loop {
+            match f() {
+                Ok(result) => return Ok(result),
+                Err(e) => {
+                    if attempt >= self.max_attempts || !is_transient(&e) {
+                        return Err(e);
+                    }
+                    warn!("Transient I/O error (attempt {} of {}): {}",
+                            attempt, self.max_attempts, e);
+                    thread::sleep(delay);
+                    delay = delay * self.backoff;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+impl Default for RetryPolicy {
+    /// Three attempts, starting with a delay of 100ms.
+    fn default() -> RetryPolicy {
+        RetryPolicy::new(3, Duration::from_millis(100))
+    }
+}
+
+
+/// Wraps a `PartIO`, retrying operations which fail with transient errors.
+/// 
+/// Read operations are retried. Operations opening write streams are not,
+/// since these are not idempotent (a failed attempt may still have created
+/// the file). Errors while reading or writing the returned streams are not
+/// retried either.
+/// 
+/// `as_any()` returns the wrapped object, thus downcasting works as if the
+/// wrapper were not present.
+pub struct RetryPartIO {
+    io: Box<PartIO>,
+    policy: RetryPolicy,
+}
+impl RetryPartIO {
+    /// Wrap `io` with the given policy.
+    pub fn new(io: Box<PartIO>, policy: RetryPolicy) -> RetryPartIO {
+        RetryPartIO { io: io, policy: policy }
+    }
+    /// Unwrap, returning the inner `PartIO`.
+    pub fn into_inner(self) -> Box<PartIO> {
+        self.io
+    }
+}
+impl PartIO for RetryPartIO {
+    fn as_any(&self) -> &Any { self.io.as_any() }
+    fn part_id(&self) -> PartId { self.io.part_id() }
+    fn ss_len(&self) -> usize { self.io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        let io: &'a PartIO = &*self.io;
+        self.policy.run(move || io.read_ss(ss_num))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        let io: &'a PartIO = &*self.io;
+        self.policy.run(move || io.read_ss_cl(ss_num, cl_num))
+    }
+    fn ss_size(&self, ss_num: usize) -> Result<Option<u64>> {
+        self.policy.run(|| self.io.ss_size(ss_num))
+    }
+    fn ss_cl_size(&self, ss_num: usize, cl_num: usize) -> Result<Option<u64>> {
+        self.policy.run(|| self.io.ss_cl_size(ss_num, cl_num))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss(ss_num)
+    }
+    fn finish_ss(&mut self, ss_num: usize) -> Result<()> {
+        self.io.finish_ss(ss_num)
+    }
+    fn discard_ss(&mut self, ss_num: usize) -> Result<()> {
+        self.io.discard_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+    fn quarantine_ss(&mut self, ss_num: usize) -> Result<Option<String>> {
+        self.io.quarantine_ss(ss_num)
+    }
+    fn quarantine_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<Option<String>> {
+        self.io.quarantine_ss_cl(ss_num, cl_num)
+    }
+    fn remove_ss(&mut self, ss_num: usize) -> Result<bool> {
+        self.io.remove_ss(ss_num)
+    }
+    fn remove_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<bool> {
+        self.io.remove_ss_cl(ss_num, cl_num)
+    }
+    fn read_blob(&self, key: &Sum) -> Result<Option<Vec<u8>>> {
+        self.policy.run(|| self.io.read_blob(key))
+    }
+    fn write_blob(&mut self, key: &Sum, data: &[u8]) -> Result<()> {
+        // Storing a blob is idempotent, thus may be retried
+        let (io, policy) = (&mut self.io, &self.policy);
+        policy.run(|| io.write_blob(key, data))
+    }
+    fn clone_io(&self) -> Option<Box<PartIO>> {
+        self.io.clone_io().map(|io| Box::new(RetryPartIO::new(io, self.policy)) as Box<PartIO>)
+    }
+}
+
+
+/// Wraps a `RepoIO`, retrying operations which fail with transient errors.
+/// Partition I/O providers returned by `make_part_io()` are wrapped in a
+/// `RetryPartIO` using the same policy.
+/// 
+/// `as_any()` returns the wrapped object.
+pub struct RetryRepoIO<IO: RepoIO> {
+    io: IO,
+    policy: RetryPolicy,
+}
+impl<IO: RepoIO> RetryRepoIO<IO> {
+    /// Wrap `io` with the given policy.
+    pub fn new(io: IO, policy: RetryPolicy) -> RetryRepoIO<IO> {
+        RetryRepoIO { io: io, policy: policy }
+    }
+    /// Unwrap, returning the inner `RepoIO`.
+    pub fn into_inner(self) -> IO {
+        self.io
+    }
+}
+impl<IO: RepoIO> RepoIO for RetryRepoIO<IO> {
+    fn as_any(&self) -> &Any { self.io.as_any() }
+    fn num_parts(&self) -> usize { self.io.num_parts() }
+    fn parts(&self) -> Vec<PartId> { self.io.parts() }
+    fn has_part(&self, pn: PartId) -> bool { self.io.has_part(pn) }
+    fn new_part(&mut self, num: PartId, prefix: String) -> Result<()> {
+        let io = &mut self.io;
+        self.policy.run(|| io.new_part(num, prefix.clone()))
+    }
+    fn make_part_io(&self, num: PartId) -> Result<Box<PartIO>> {
+        let io = self.policy.run(|| self.io.make_part_io(num))?;
+        Ok(Box::new(RetryPartIO::new(io, self.policy)))
+    }
+    fn write_journal(&mut self, data: &[u8]) -> Result<()> {
+        let io = &mut self.io;
+        self.policy.run(|| io.write_journal(data))
+    }
+    fn read_journal(&self) -> Result<Option<Vec<u8>>> {
+        self.policy.run(|| self.io.read_journal())
+    }
+    fn clear_journal(&mut self) -> Result<()> {
+        let io = &mut self.io;
+        self.policy.run(|| io.clear_journal())
+    }
+    fn write_part_alloc(&mut self, data: &[u8]) -> Result<()> {
+        let io = &mut self.io;
+        self.policy.run(|| io.write_part_alloc(data))
+    }
+    fn read_part_alloc(&self) -> Result<Option<Vec<u8>>> {
+        self.policy.run(|| self.io.read_part_alloc())
+    }
+    fn delete_part(&mut self, num: PartId) -> Result<()> {
+        self.io.delete_part(num)
+    }
+    fn readonly(&self) -> bool { self.io.readonly() }
+    fn flush(&mut self) -> Result<()> {
+        let io = &mut self.io;
+        self.policy.run(|| io.flush())
+    }
+    fn close(self) -> Result<()> {
+        self.io.close()
+    }
+}
@@ -15,17 +15,23 @@
 //! This module also contains the `StateT` and `MutStateT` traits which
 //! abstract over operations on partition and repository states.
 
-use std::collections::{HashMap};
+use std::collections::{HashMap, BTreeMap};
 use std::collections::hash_map as hs;
+use std::vec;
 use std::clone::Clone;
-use std::rc::Rc;
+use std::sync::Arc;
+use std::mem::size_of;
+use std::cmp::min;
+use std::io::{self, Write};
 
 use hashindexed::KeyComparator;
 use rand::random;
 
 use {ElementT, Sum, PartId, EltId};
+use sum::BYTES;
 use commit::*;
-use error::{ElementOp, PatchOp};
+use merkle::{MerkleTree, DEFAULT_DEPTH};
+use error::{Result, ElementOp, PatchOp, ArgError};
 
 /// Trait abstracting over read operations on the state of a partition or
 /// repository.
@@ -63,7 +69,32 @@ pub trait StateT<E: ElementT> {
     }
     /// Low-level version of `get(id)`: returns a reference to the
     /// reference-counter wrapped container of the element.
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp>;
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp>;
+}
+
+// Call `ElementT::validate()`, logging the reason on failure
+fn check_valid<E: ElementT>(elt: &E) -> Result<(), ElementOp> {
+    elt.validate().map_err(|e| {
+        warn!("Element failed validation: {}", e);
+        ElementOp::Invalid
+    })
+}
+
+// Keeps `len` bytes of what is written after skipping `skip` bytes
+struct RangeWriter {
+    skip: usize,
+    buf: Vec<u8>,
+    len: usize,
+}
+impl Write for RangeWriter {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let skipped = min(self.skip, data.len());
+        self.skip -= skipped;
+        let n = min(self.len - self.buf.len(), data.len() - skipped);
+        self.buf.extend_from_slice(&data[skipped..skipped + n]);
+        Ok(data.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
 }
 
 /// Trait abstracting over write operations on the state of a partition or
@@ -82,12 +113,13 @@ pub trait MutStateT<E: ElementT>: StateT<E> {
     /// allowed since the partition is determined automatically and the
     /// partition number becomes part of the element identifier.
     fn insert(&mut self, elt: E) -> Result<EltId, ElementOp> {
-        self.insert_rc(Rc::new(elt))
+        self.insert_rc(Arc::new(elt))
     }
     /// Low-level version of `insert(elt)`: takes a reference-counter wrapper
     /// of an element.
-    fn insert_rc(&mut self, elt: Rc<E>) -> Result<EltId, ElementOp> {
+    fn insert_rc(&mut self, elt: Arc<E>) -> Result<EltId, ElementOp> {
         // #0049: configurable source of randomness?
+        check_valid(&*elt)?;
         let initial = random::<u32>() & 0xFF_FFFF;
         self.insert_rc_initial(initial, elt)
     }
@@ -95,12 +127,15 @@ pub trait MutStateT<E: ElementT>: StateT<E> {
     /// a number used to generate an identifier. See documentation of
     /// `MutPartState::id_from_initial()` for details.
     fn insert_initial(&mut self, initial: u32, elt: E) -> Result<EltId, ElementOp> {
-        self.insert_rc_initial(initial, Rc::new(elt))
+        check_valid(&elt)?;
+        self.insert_rc_initial(initial, Arc::new(elt))
     }
-    /// Lowest-level version of `insert(elt)`: takes an Rc-wrapped element and
+    /// Lowest-level version of `insert(elt)`: takes an Arc-wrapped element and
     /// allows specification of a number used to generate an identifier.
     /// See documentation of `MutPartState::id_from_initial()` for details.
-    fn insert_rc_initial(&mut self, initial: u32, elt: Rc<E>) -> Result<EltId, ElementOp>;
+    /// 
+    /// Unlike the other variants, this does not call `ElementT::validate()`.
+    fn insert_rc_initial(&mut self, initial: u32, elt: Arc<E>) -> Result<EltId, ElementOp>;
     
     /// Replace an existing element and return the identifier of the newly
     /// inserted element and the replaced element. Note that the identifier
@@ -112,14 +147,15 @@ pub trait MutStateT<E: ElementT>: StateT<E> {
     /// that the element has been moved, in which case `RepoState::locate(id)`
     /// may be helpful.
     /// 
-    /// Note that the returned `Rc<E>` cannot be unwrapped automatically since
+    /// Note that the returned `Arc<E>` cannot be unwrapped automatically since
     /// we do not know that we have the only reference.
-    fn replace(&mut self, id: EltId, elt: E) -> Result<Rc<E>, ElementOp> {
-        self.replace_rc(id, Rc::new(elt))
+    fn replace(&mut self, id: EltId, elt: E) -> Result<Arc<E>, ElementOp> {
+        check_valid(&elt)?;
+        self.replace_rc(id, Arc::new(elt))
     }
-    /// Low-level version of `replace(id, elt)` which takes an Rc-wrapped
-    /// element.
-    fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp>;
+    /// Low-level version of `replace(id, elt)` which takes an Arc-wrapped
+    /// element. Unlike `replace`, this does not call `ElementT::validate()`.
+    fn replace_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp>;
     
     /// Remove an element, returning the element removed or failing.
     /// 
@@ -128,9 +164,26 @@ pub trait MutStateT<E: ElementT>: StateT<E> {
     /// that the element has been moved, in which case `RepoState::locate(id)`
     /// may be helpful.
     /// 
-    /// Note that the returned `Rc<E>` cannot be unwrapped automatically since
+    /// Note that the returned `Arc<E>` cannot be unwrapped automatically since
     /// we do not know that we have the only reference.
-    fn remove(&mut self, id: EltId) -> Result<Rc<E>, ElementOp>;
+    fn remove(&mut self, id: EltId) -> Result<Arc<E>, ElementOp>;
+}
+
+/// Decides how each element appears in an exported copy of some data (see
+/// `PartState::redacted()` and `Repository::export_redacted()`), allowing a
+/// sanitized copy of a dataset to be shared.
+/// 
+/// This is implemented for functions and closures of matching signature.
+pub trait RedactionPolicy<E: ElementT> {
+    /// Given an element and its identifier, return the element to export in
+    /// its place (e.g. with some fields cleared), or `None` to omit it
+    /// entirely. Returning an element equal to `elt` exports it unchanged.
+    fn redact(&self, id: EltId, elt: &E) -> Option<E>;
+}
+impl<E: ElementT, F: Fn(EltId, &E) -> Option<E>> RedactionPolicy<E> for F {
+    fn redact(&self, id: EltId, elt: &E) -> Option<E> {
+        self(id, elt)
+    }
 }
 
 /// A 'state' is the set of elements in a partition at some point in time.
@@ -148,11 +201,27 @@ pub struct PartState<E: ElementT> {
     part_id: PartId,
     parents: Vec<Sum>,
     statesum: Sum,
-    elts: HashMap<EltId, Rc<E>>,
+    elts: HashMap<EltId, Arc<E>>,
     moved: HashMap<EltId, EltId>,
     meta: CommitMeta,
 }
 
+/// A shared, immutable handle on a `PartState` held by a `Partition` (see
+/// `Partition::tip_ref()` and `Partition::state_ref()`).
+/// 
+/// States are never modified once stored, so a reader holding one of these
+/// sees a consistent snapshot while the partition continues to accept new
+/// commits, write logs and snapshots, or merge. This gives snapshot isolation
+/// between readers and a writer without copying the element map. States and
+/// elements are held via `Arc`, thus where `E: Send + Sync` the handle may be
+/// passed to a reader on another thread.
+/// 
+/// Memory: the referenced state (and its elements) stays alive as long as
+/// any handle does, even after the partition has dropped it (e.g. via
+/// `Partition::unload()`), so long-lived handles can pin old states in
+/// memory. Release them promptly.
+pub type PartStateRef<E> = Arc<PartState<E>>;
+
 /// An editable version of `PartState`.
 ///
 /// Elements may be inserted, deleted or replaced. Direct modification is not
@@ -171,11 +240,28 @@ pub struct MutPartState<E: ElementT> {
     part_id: PartId,
     parent: Sum,
     elt_sum: Sum,
-    elts: HashMap<EltId, Rc<E>>,
+    elts: HashMap<EltId, Arc<E>>,
     moved: HashMap<EltId, EltId>,
     meta: CommitMetaPartial,
 }
 
+/// Compute the checksum of a set of elements, as used within state sums,
+/// without building a state. Each element's sum (`ElementT::sum()`) is
+/// combined via XOR, thus the result does not depend on order.
+/// 
+/// A state's sum additionally includes its metadata: for any state,
+/// `statesum()` equals this (applied to the state's elements) XORed with
+/// `metasum()`. Equivalently, this equals `MutPartState::elt_sum()`.
+pub fn compute_state_sum<E: ElementT>(elts: &BTreeMap<EltId, E>) -> [u8; BYTES] {
+    let mut sum = Sum::zero();
+    for (id, elt) in elts {
+        sum.permute(&elt.sum(*id));
+    }
+    let mut result = [0u8; BYTES];
+    sum.write(&mut &mut result[..]).expect("write to buf");
+    result
+}
+
 impl<E: ElementT> PartState<E> {
     /// Create a new state, with no elements or history.
     /// 
@@ -204,7 +290,7 @@ impl<E: ElementT> PartState<E> {
     /// This is for internal use; don't use externally unless you're really
     /// sure of what you're doing.
     pub fn new_explicit(part_id: PartId, parents: Vec<Sum>,
-            elts: HashMap<EltId, Rc<E>>, moves: HashMap<EltId, EltId>,
+            elts: HashMap<EltId, Arc<E>>, moves: HashMap<EltId, EltId>,
             meta: CommitMeta, elt_sum: Sum) -> PartState<E> {
         let metasum = Sum::state_meta_sum(part_id, &parents, &meta);
         PartState {
@@ -273,6 +359,14 @@ impl<E: ElementT> PartState<E> {
     }
     
     /// Get the state sum (depends on data and metadata)
+    /// 
+    /// This is the metadata sum (see `metasum()`) combined with the sum of
+    /// each element via XOR. Each element's sum is a hash of its identifier
+    /// and serialised data (see `Sum::elt_sum()`). Since XOR is commutative,
+    /// the result depends only on the elements present (with identifiers),
+    /// not on the order they were inserted or on how the state was reached.
+    /// Two states with the same elements, parents and commit metadata thus
+    /// have the same state sum.
     pub fn statesum(&self) -> &Sum { &self.statesum }
     /// Get the metadata sum (this is part of the statesum)
     /// 
@@ -296,10 +390,88 @@ impl<E: ElementT> PartState<E> {
     pub fn elts_iter(&self) -> EltIter<E> {
         EltIter { iter: self.elts.iter() }
     }
+    /// Iterate over all elements in order of identifier.
+    /// 
+    /// Unlike `elts_iter()`, order is deterministic. Identifiers are sorted
+    /// when the iterator is created.
+    pub fn iter_sorted(&self) -> SortedEltIter<E> {
+        let mut elts: Vec<_> = self.elts.iter().map(|(k, v)| (*k, v)).collect();
+        elts.sort_by_key(|&(id, _)| id);
+        SortedEltIter { iter: elts.into_iter() }
+    }
     /// Get a specific element, if contained
-    pub fn elt(&self, id: EltId) -> Option<&Rc<E>> {
+    pub fn elt(&self, id: EltId) -> Option<&Arc<E>> {
         self.elts.get(&id)
     }
+    /// Get the checksum of an element (see `Sum::elt_sum()`), if contained.
+    /// 
+    /// Element checksums depend only on the identifier and data, thus
+    /// unchanged elements have the same checksum in all states. This uses
+    /// `ElementT::sum()`, which serialises the element unless the element type
+    /// caches its sum.
+    pub fn element_checksum(&self, id: EltId) -> Option<Sum> {
+        self.elts.get(&id).map(|elt| elt.sum(id))
+    }
+    /// Build a Merkle tree of the given depth over element checksums (see
+    /// `MerkleTree`). Comparing the trees of two states finds the elements
+    /// which may differ without comparing every element.
+    /// 
+    /// Fails if `depth` exceeds `merkle::MAX_DEPTH`.
+    pub fn checksum_tree(&self, depth: u32) -> Result<MerkleTree> {
+        MerkleTree::from_state(self, depth)
+    }
+    /// Get the root of the checksum tree of depth `merkle::DEFAULT_DEPTH`
+    /// (see `checksum_tree()`).
+    /// 
+    /// Two states with equal roots contain the same elements. Unlike the
+    /// state sum, this does not depend on metadata.
+    pub fn merkle_root(&self) -> Sum {
+        self.checksum_tree(DEFAULT_DEPTH).expect("valid depth").root().clone()
+    }
+    
+    /// Get `len` bytes of the serialised form of an element (as written by
+    /// `ElementT::write_buf`), starting from byte `offset`.
+    /// 
+    /// This allows callers to examine part of a large element, for example
+    /// a slice of a long vector, with the help of some type-specific function
+    /// to decode the bytes.
+    /// 
+    /// Note: elements are held in memory in deserialised form and their
+    /// positions in files are not recorded, so this still serialises the
+    /// whole element; only the requested bytes are kept.
+    /// 
+    /// Fails if the element is not found, cannot be serialised or if the range
+    /// extends beyond the end of the data.
+    pub fn read_element_range(&self, id: EltId, offset: usize, len: usize) ->
+            Result<Vec<u8>>
+    {
+        let elt = self.elts.get(&id).ok_or(ElementOp::NotFound)?;
+        let mut w = RangeWriter { skip: offset, buf: Vec::new(), len: len };
+        elt.write_buf(&mut w)?;
+        if w.skip > 0 || w.buf.len() < len {
+            return ArgError::err("element range out of bounds");
+        }
+        Ok(w.buf)
+    }
+    
+    /// Get an approximation of the memory used by this state, in bytes.
+    /// 
+    /// This sums `ElementT::size_hint()` over all elements and adds an
+    /// estimate of the overhead of the maps holding elements and moved
+    /// element records. Elements are shared between states (copy-on-write),
+    /// thus summing this over multiple states may over-count.
+    pub fn approx_memory_usage(&self) -> usize {
+        // Per-entry overhead of a hash map: the key, value and stored hash
+        let elt_entry = size_of::<EltId>() + size_of::<Arc<E>>() + size_of::<u64>();
+        let move_entry = 2 * size_of::<EltId>() + size_of::<u64>();
+        // Each Arc allocation also holds strong and weak counts
+        let rc_overhead = 2 * size_of::<usize>();
+        
+        let elts: usize = self.elts.values().map(|elt| elt.size_hint() + rc_overhead).sum();
+        size_of::<PartState<E>>() + self.parents.len() * size_of::<Sum>() +
+            self.elts.capacity() * elt_entry + self.moved.capacity() * move_entry +
+            elts
+    }
     
     /// Get the number of "moved" elements.
     /// 
@@ -363,6 +535,25 @@ impl<E: ElementT> PartState<E> {
         }
     }
     
+    /// Make a redacted copy of this state: each element is passed to
+    /// `policy`, then replaced or removed accordingly. This state is not
+    /// modified.
+    /// 
+    /// As with `clone_mut()`, the result is a child of this state. Fails with
+    /// `ElementOp::Invalid` if a replacement element fails validation (see
+    /// `ElementT::validate()`).
+    pub fn redacted(&self, policy: &RedactionPolicy<E>) -> Result<PartState<E>, ElementOp> {
+        let mut state = self.clone_mut();
+        for (id, elt) in self.elts_iter() {
+            match policy.redact(id, elt) {
+                Some(ref new) if *new == **elt => {},
+                Some(new) => { state.replace(id, new)?; },
+                None => { state.remove(id)?; },
+            }
+        }
+        Ok(PartState::from_mut(state, None))
+    }
+    
     /// Clone the state, creating an exact copy. The new state will have the
     /// same parents as the current one.
     /// 
@@ -386,7 +577,8 @@ impl<E: ElementT> MutPartState<E> {
     pub fn part_id(&self) -> PartId { self.part_id }
     /// Get the parent's sum
     pub fn parent(&self) -> &Sum { &self.parent }
-    /// Get the "element sum". This is all element sums combined via XOR. The
+    /// Get the "element sum". This is all element sums combined via XOR
+    /// (thus independent of the order in which elements were inserted). The
     /// partition statesum is this XORed with the metadata sum.
     pub fn elt_sum(&self) -> &Sum { &self.elt_sum }
     
@@ -399,7 +591,7 @@ impl<E: ElementT> MutPartState<E> {
         EltIter { iter: self.elts.iter() }
     }
     /// Get a specific element, if contained
-    pub fn elt(&self, id: EltId) -> Option<&Rc<E>> {
+    pub fn elt(&self, id: EltId) -> Option<&Arc<E>> {
         self.elts.get(&id)
     }
     
@@ -453,7 +645,7 @@ impl<E: ElementT> MutPartState<E> {
     /// if the id is already in use.
     /// It is suggested to use insert() instead if you do not need to specify
     /// the identifier.
-    pub fn insert_with_id(&mut self, id: EltId, elt: Rc<E>) -> Result<EltId, ElementOp> {
+    pub fn insert_with_id(&mut self, id: EltId, elt: Arc<E>) -> Result<EltId, ElementOp> {
         if id.part_id() != self.part_id { return Err(ElementOp::WrongPartition); }
         if self.elts.contains_key(&id) { return Err(ElementOp::IdClash); }
         self.elt_sum.permute(&elt.sum(id));
@@ -461,6 +653,17 @@ impl<E: ElementT> MutPartState<E> {
         Ok(id)
     }
     
+    /// Remove all elements. Records of moved elements are kept.
+    /// 
+    /// When committed, each element previously present is recorded as
+    /// deleted, thus this merges like calling `remove()` on each element. The
+    /// committed state is distinct from a never-populated state (its
+    /// metadata and parents differ) although both are empty.
+    pub fn clear(&mut self) {
+        self.elts.clear();
+        self.elt_sum = Sum::zero();
+    }
+    
     /// Add a note about where an element has been moved to.
     /// 
     /// The point of doing this is that someone looking for the element later
@@ -496,7 +699,7 @@ impl<E: ElementT> StateT<E> for PartState<E> {
     fn is_avail(&self, id: EltId) -> bool {
         self.elts.contains_key(&id)
     }
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp> {
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
         self.elts.get(&id).ok_or(ElementOp::NotFound)
     }
 }
@@ -510,16 +713,16 @@ impl<E: ElementT> StateT<E> for MutPartState<E> {
     fn is_avail(&self, id: EltId) -> bool {
         self.elts.contains_key(&id)
     }
-    fn get_rc(&self, id: EltId) -> Result<&Rc<E>, ElementOp> {
+    fn get_rc(&self, id: EltId) -> Result<&Arc<E>, ElementOp> {
         self.elts.get(&id).ok_or(ElementOp::NotFound)
     }
 }
 impl<E: ElementT> MutStateT<E> for MutPartState<E> {
-    fn insert_rc_initial(&mut self, initial: u32, elt: Rc<E>) -> Result<EltId, ElementOp> {
+    fn insert_rc_initial(&mut self, initial: u32, elt: Arc<E>) -> Result<EltId, ElementOp> {
         let id = self.id_from_initial(initial)?;
         self.insert_with_id(id, elt)
     }
-    fn replace_rc(&mut self, id: EltId, elt: Rc<E>) -> Result<Rc<E>, ElementOp> {
+    fn replace_rc(&mut self, id: EltId, elt: Arc<E>) -> Result<Arc<E>, ElementOp> {
         self.elt_sum.permute(&elt.sum(id));
         match self.elts.insert(id, elt) {
             None => Err(ElementOp::NotFound),
@@ -529,7 +732,7 @@ impl<E: ElementT> MutStateT<E> for MutPartState<E> {
             }
         }
     }
-    fn remove(&mut self, id: EltId) -> Result<Rc<E>, ElementOp> {
+    fn remove(&mut self, id: EltId) -> Result<Arc<E>, ElementOp> {
         match self.elts.remove(&id) {
             None => Err(ElementOp::NotFound),
             Some(removed) => {
@@ -542,7 +745,7 @@ impl<E: ElementT> MutStateT<E> for MutPartState<E> {
 
 /// Wrapper around underlying iterator structure
 pub struct EltIter<'a, E: 'a> {
-    iter: hs::Iter<'a, EltId, Rc<E>>
+    iter: hs::Iter<'a, EltId, Arc<E>>
 }
 impl<'a, E> Clone for EltIter<'a, E> {
     fn clone(&self) -> EltIter<'a, E> {
@@ -550,8 +753,8 @@ impl<'a, E> Clone for EltIter<'a, E> {
     }
 }
 impl<'a, E> Iterator for EltIter<'a, E> {
-    type Item = (EltId, &'a Rc<E>);
-    fn next(&mut self) -> Option<(EltId, &'a Rc<E>)> {
+    type Item = (EltId, &'a Arc<E>);
+    fn next(&mut self) -> Option<(EltId, &'a Arc<E>)> {
         self.iter.next().map(|(k,v)| (*k, v))
     }
 }
@@ -561,6 +764,20 @@ impl<'a, E> ExactSizeIterator for EltIter<'a, E> {
     }
 }
 
+/// Iterator over elements in order of identifier (see
+/// `PartState::iter_sorted()`)
+pub struct SortedEltIter<'a, E: 'a> {
+    iter: vec::IntoIter<(EltId, &'a Arc<E>)>
+}
+impl<'a, E> Iterator for SortedEltIter<'a, E> {
+    type Item = (EltId, &'a Arc<E>);
+    fn next(&mut self) -> Option<(EltId, &'a Arc<E>)> {
+        self.iter.next()
+    }
+    fn size_hint(&self) -> (usize, Option<usize>) { self.iter.size_hint() }
+}
+impl<'a, E> ExactSizeIterator for SortedEltIter<'a, E> {}
+
 /// Wrapper around underlying iterator structure
 pub struct EltIdIter<'a> {
     iter: hs::Iter<'a, EltId, EltId>
@@ -589,3 +806,179 @@ impl<E: ElementT> KeyComparator<PartState<E>, Sum> for PartStateSumComparator {
         value.statesum()
     }
 }
+impl<E: ElementT> KeyComparator<Arc<PartState<E>>, Sum> for PartStateSumComparator {
+    fn extract_key(value: &Arc<PartState<E>>) -> &Sum {
+        value.statesum()
+    }
+}
+
+#[test]
+fn approx_memory_usage() {
+    let part_id = PartId::from_num(1);
+    let mut state = PartState::<String>::new(part_id, None);
+    let mut usage = vec![state.approx_memory_usage()];
+    for i in 0..4 {
+        let mut mut_state = state.clone_mut();
+        for j in 0..1000 {
+            let elt = format!("element {:4} of block {}; some padding text here", j, i);
+            mut_state.insert_with_id(part_id.elt_id(i * 1000 + j + 1), Arc::new(elt)).unwrap();
+        }
+        state = PartState::from_mut(mut_state, None);
+        usage.push(state.approx_memory_usage());
+    }
+    
+    // Each block of 1000 elements (over 50 bytes each) should add a similar
+    // amount; map capacity grows in steps, so allow some slack.
+    let step = (usage[4] - usage[0]) / 4;
+    assert!(step > 50 * 1000);
+    for i in 0..4 {
+        let diff = usage[i + 1] - usage[i];
+        assert!(diff > step / 2 && diff < step * 2, "usage: {:?}", usage);
+    }
+}
+
+#[test]
+fn statesum_order_independent() {
+    struct FixedTime;
+    impl MakeMeta for FixedTime {
+        fn make_timestamp(&self) -> i64 { 1_000_000 }
+    }
+    
+    let part_id = PartId::from_num(1);
+    let parent = PartState::<String>::new(part_id, Some(&FixedTime));
+    let elts: Vec<_> = (1..6).map(|i| (part_id.elt_id(i), format!("element {}", i))).collect();
+    
+    let mut state1 = parent.clone_mut();
+    for &(id, ref elt) in &elts {
+        state1.insert_with_id(id, Arc::new(elt.clone())).unwrap();
+    }
+    // Reverse order, with a temporary element and a replacement on the way:
+    let mut state2 = parent.clone_mut();
+    let temp = part_id.elt_id(99);
+    state2.insert_with_id(temp, Arc::new("temporary".to_string())).unwrap();
+    for &(id, ref elt) in elts.iter().rev() {
+        state2.insert_with_id(id, Arc::new("old value".to_string())).unwrap();
+        state2.replace_rc(id, Arc::new(elt.clone())).unwrap();
+    }
+    state2.remove(temp).unwrap();
+    assert_eq!(state1.elt_sum(), state2.elt_sum());
+    
+    let state1 = PartState::from_mut(state1, Some(&FixedTime));
+    let state2 = PartState::from_mut(state2, Some(&FixedTime));
+    assert_eq!(state1.statesum(), state2.statesum());
+    assert!(state1.statesum() != parent.statesum());
+}
+
+#[test]
+fn iter_sorted() {
+    let part_id = PartId::from_num(1);
+    let mut mut_state = PartState::<String>::new(part_id, None).clone_mut();
+    for n in &[7, 3, 12, 1, 9, 4] {
+        mut_state.insert_with_id(part_id.elt_id(*n), Arc::new(format!("elt {}", n))).unwrap();
+    }
+    let state = PartState::from_mut(mut_state, None);
+    let ids: Vec<EltId> = state.iter_sorted().map(|(id, _)| id).collect();
+    let expected: Vec<EltId> = [1, 3, 4, 7, 9, 12].iter().map(|n| part_id.elt_id(*n)).collect();
+    assert_eq!(ids, expected);
+    assert_eq!(state.iter_sorted().len(), 6);
+    for (id, elt) in state.iter_sorted() {
+        assert_eq!(**elt, format!("elt {}", id.elt_num()));
+    }
+}
+
+#[test]
+fn element_checksum() {
+    let part_id = PartId::from_num(1);
+    let (id1, id2) = (part_id.elt_id(1), part_id.elt_id(2));
+    let mut mut_state = PartState::<String>::new(part_id, None).clone_mut();
+    mut_state.insert_with_id(id1, Arc::new("unchanged".to_string())).unwrap();
+    mut_state.insert_with_id(id2, Arc::new("old".to_string())).unwrap();
+    let state1 = PartState::from_mut(mut_state, None);
+    
+    let mut mut_state = state1.clone_mut();
+    mut_state.replace(id2, "new".to_string()).unwrap();
+    let state2 = PartState::from_mut(mut_state, None);
+    
+    let sum1 = state1.element_checksum(id1).expect("has element");
+    assert_eq!(sum1, Sum::elt_sum(id1, b"unchanged"));
+    assert_eq!(state2.element_checksum(id1), Some(sum1));
+    assert!(state1.element_checksum(id2) != state2.element_checksum(id2));
+    assert_eq!(state2.element_checksum(part_id.elt_id(3)), None);
+}
+
+#[test]
+fn redacted() {
+    let part_id = PartId::from_num(1);
+    let mut mut_state = PartState::<String>::new(part_id, None).clone_mut();
+    for n in 1..7 {
+        mut_state.insert_with_id(part_id.elt_id(n), Arc::new(format!("elt {}", n))).unwrap();
+    }
+    let state = PartState::from_mut(mut_state, None);
+    
+    // Omit even elements and mask element 3:
+    let policy = |id: EltId, elt: &String| match id.elt_num() {
+        3 => Some("(redacted)".to_string()),
+        n if n % 2 == 0 => None,
+        _ => Some(elt.clone()),
+    };
+    let redacted = state.redacted(&policy).expect("redacted");
+    let elts: Vec<_> = redacted.iter_sorted().map(|(id, elt)| (id.elt_num(), (**elt).clone())).collect();
+    assert_eq!(elts, vec![(1, "elt 1".to_string()), (3, "(redacted)".to_string()),
+            (5, "elt 5".to_string())]);
+    assert_eq!(redacted.parents(), &[state.statesum().clone()]);
+    assert_eq!(state.elts_len(), 6);
+    assert_eq!(state.get(part_id.elt_id(3)).unwrap(), "elt 3");
+}
+
+#[test]
+fn compute_state_sum_matches() {
+    let part_id = PartId::from_num(1);
+    let mut mut_state = PartState::<String>::new(part_id, None).clone_mut();
+    let mut elts = BTreeMap::new();
+    for n in &[5, 2, 9, 1] {
+        let elt = format!("element {}", n);
+        mut_state.insert_with_id(part_id.elt_id(*n), Arc::new(elt.clone())).unwrap();
+        elts.insert(part_id.elt_id(*n), elt);
+    }
+    let state = PartState::from_mut(mut_state, None);
+    
+    let expected = state.statesum() ^ &state.metasum();
+    assert!(expected.eq(&compute_state_sum(&elts)));
+    assert_eq!(compute_state_sum(&BTreeMap::<EltId, String>::new()), [0u8; BYTES]);
+    elts.remove(&part_id.elt_id(9));
+    assert!(!expected.eq(&compute_state_sum(&elts)));
+}
+
+#[test]
+fn meta_sum_ext_data() {
+    let part_id = PartId::from_num(1);
+    // Metadata with fixed number and timestamp, and extension data set by `f`
+    let make_meta = |f: &Fn(&mut CommitMetaPartial)| {
+        let base = CommitMeta::new_explicit(0, 0, MetaFlags::zero(), vec![], ExtraMeta::None).unwrap();
+        let mut partial = CommitMeta::new_partial(&base);
+        f(&mut partial);
+        let ext_data = CommitMeta::from_partial(partial, &vec![], None).ext_data();
+        CommitMeta::new_explicit(1, 1000, MetaFlags::zero(), ext_data, ExtraMeta::None).unwrap()
+    };
+    let plain = make_meta(&|_| {});
+    assert!(plain.ext_data().is_empty());
+    let sum = Sum::state_meta_sum(part_id, &[], &plain);
+    assert_eq!(sum, Sum::state_meta_sum(part_id, &[], &make_meta(&|_| {})));
+    
+    let on_branch = |name: &'static str| make_meta(&move |p| {
+        p.set_branch(Some(name.to_string())).unwrap();
+    });
+    let sum_a = Sum::state_meta_sum(part_id, &[], &on_branch("a"));
+    assert!(sum_a != sum);
+    assert!(sum_a != Sum::state_meta_sum(part_id, &[], &on_branch("b")));
+    assert_eq!(sum_a, Sum::state_meta_sum(part_id, &[], &on_branch("a")));
+    
+    let tagged = |key: &'static str, value: &'static [u8]| make_meta(&move |p| {
+        p.set_tag(key.to_string(), value.to_vec()).unwrap();
+    });
+    let sum_t = Sum::state_meta_sum(part_id, &[], &tagged("key", b"x"));
+    assert!(sum_t != sum && sum_t != sum_a);
+    assert!(sum_t != Sum::state_meta_sum(part_id, &[], &tagged("key", b"y")));
+    assert!(sum_t != Sum::state_meta_sum(part_id, &[], &tagged("other", b"x")));
+    assert_eq!(sum_t, Sum::state_meta_sum(part_id, &[], &tagged("key", b"x")));
+}
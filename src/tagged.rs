@@ -0,0 +1,232 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Elements of several types within one partition
+//! 
+//! A `Partition<E>` holds elements of a single type `E`. To store values of
+//! several types, use `E = TaggedElement<R>`: each value is stored with a
+//! `u16` type tag, and `R: Registry` maps tags back to deserialisers.
+//! 
+//! Each type stored implements `ElementT` and `Tagged`. The registry is a
+//! type implementing `Registry`, typically via `read_as`:
+//! 
+//! ```
+//! use std::io::Write;
+//! use pippin::{ElementT, Result};
+//! use pippin::tagged::{Tagged, Registry, ReadFn, TaggedElement, read_as};
+//! 
+//! #[derive(PartialEq, Debug)]
+//! struct Name(String);
+//! impl ElementT for Name {
+//!     fn write_buf(&self, writer: &mut Write) -> Result<()> {
+//!         writer.write_all(self.0.as_bytes())?;
+//!         Ok(())
+//!     }
+//!     fn read_buf(buf: &[u8]) -> Result<Self> {
+//!         Ok(Name(String::from_utf8(buf.to_vec())?))
+//!     }
+//! }
+//! impl Tagged for Name { fn tag() -> u16 { 1 } }
+//! 
+//! struct MyTypes;
+//! impl Registry for MyTypes {
+//!     fn reader(tag: u16) -> Option<ReadFn> {
+//!         match tag {
+//!             1 => Some(read_as::<Name>),
+//!             _ => None,
+//!         }
+//!     }
+//! }
+//! 
+//! let elt = TaggedElement::<MyTypes>::new(Name("Alice".to_string()));
+//! assert_eq!(elt.tag(), 1);
+//! assert_eq!(elt.downcast_ref::<Name>(), Some(&Name("Alice".to_string())));
+//! ```
+//! 
+//! Serialised data consists of the tag (two bytes, big-endian) followed by the
+//! value's own serialisation.
+
+use std::io::Write;
+use std::any::Any;
+use std::fmt::{self, Debug};
+use std::marker::PhantomData;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use ElementT;
+use error::{Result, ReadError, OtherError};
+
+/// Assigns a type tag to a type stored within a `TaggedElement`. Tags must be
+/// unique within a `Registry` and must not change once data has been written.
+pub trait Tagged {
+    /// The type's tag
+    fn tag() -> u16;
+}
+
+/// A value stored within a `TaggedElement`. This is implemented for all types
+/// implementing `ElementT`, `Tagged` and `Any`.
+pub trait TaggedData: Debug {
+    /// Get the value's type tag
+    fn data_tag(&self) -> u16;
+    /// Serialise the value (without tag)
+    fn write_data(&self, writer: &mut Write) -> Result<()>;
+    /// True if `other` has the same type and compares equal
+    fn eq_data(&self, other: &TaggedData) -> bool;
+    /// Check the value (see `ElementT::validate()`)
+    fn validate_data(&self) -> Result<()>;
+    /// Convert to `&Any`, allowing downcasting
+    fn as_any(&self) -> &Any;
+}
+impl<T: ElementT + Tagged + Any> TaggedData for T {
+    fn data_tag(&self) -> u16 {
+        T::tag()
+    }
+    fn write_data(&self, writer: &mut Write) -> Result<()> {
+        self.write_buf(writer)
+    }
+    fn eq_data(&self, other: &TaggedData) -> bool {
+        other.as_any().downcast_ref::<T>().map_or(false, |other| self == other)
+    }
+    fn validate_data(&self) -> Result<()> {
+        self.validate()
+    }
+    fn as_any(&self) -> &Any {
+        self
+    }
+}
+
+/// Deserialiser for a tagged value (excluding the tag).
+pub type ReadFn = fn(&[u8]) -> Result<Box<TaggedData>>;
+
+/// Deserialise as type `T`; usable as a `ReadFn`.
+pub fn read_as<T: ElementT + Tagged + Any>(buf: &[u8]) -> Result<Box<TaggedData>> {
+    Ok(Box::new(T::read_buf(buf)?))
+}
+
+/// Maps type tags to deserialisers.
+pub trait Registry {
+    /// Get the deserialiser for `tag`, or `None` if the tag is unknown.
+    fn reader(tag: u16) -> Option<ReadFn>;
+}
+
+/// An element holding a value of any type known to registry `R`.
+/// 
+/// Elements cannot be modified in place; make a new `TaggedElement` and
+/// replace the old one with it.
+pub struct TaggedElement<R: Registry> {
+    data: Box<TaggedData>,
+    registry: PhantomData<R>,
+}
+impl<R: Registry> TaggedElement<R> {
+    /// Create, wrapping `value`.
+    /// 
+    /// This does not check that `R` knows the value's tag; if not, reading
+    /// the element back will fail (`ElementT::validate()` does check this).
+    pub fn new<T: ElementT + Tagged + Any>(value: T) -> TaggedElement<R> {
+        TaggedElement { data: Box::new(value), registry: PhantomData }
+    }
+    /// Get the type tag of the value held
+    pub fn tag(&self) -> u16 {
+        self.data.data_tag()
+    }
+    /// True if the value held has type `T`
+    pub fn is<T: Any>(&self) -> bool {
+        self.data.as_any().is::<T>()
+    }
+    /// Get the value held if it has type `T`
+    pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+        self.data.as_any().downcast_ref::<T>()
+    }
+    /// Get the value held
+    pub fn data(&self) -> &TaggedData {
+        &*self.data
+    }
+}
+impl<R: Registry> PartialEq for TaggedElement<R> {
+    fn eq(&self, other: &Self) -> bool {
+        self.data.eq_data(&*other.data)
+    }
+}
+impl<R: Registry> Debug for TaggedElement<R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TaggedElement({}: {:?})", self.tag(), self.data)
+    }
+}
+
+impl<R: Registry> ElementT for TaggedElement<R> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_u16::<BigEndian>(self.tag())?;
+        self.data.write_data(writer)
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        if buf.len() < 2 {
+            return ReadError::err("TaggedElement: data too short for tag", 0, (0, buf.len()));
+        }
+        let tag = BigEndian::read_u16(&buf[0..2]);
+        let read = match R::reader(tag) {
+            Some(read) => read,
+            None => return ReadError::err("TaggedElement: unknown type tag", 0, (0, 2)),
+        };
+        let data = read(&buf[2..])?;
+        if data.data_tag() != tag {
+            return ReadError::err("TaggedElement: registry returned wrong type", 0, (0, 2));
+        }
+        Ok(TaggedElement { data: data, registry: PhantomData })
+    }
+    fn validate(&self) -> Result<()> {
+        if R::reader(self.tag()).is_none() {
+            return OtherError::err("TaggedElement: type tag not in registry");
+        }
+        self.data.validate_data()
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use counter::GCounter;
+    
+    impl Tagged for String { fn tag() -> u16 { 1 } }
+    impl Tagged for GCounter { fn tag() -> u16 { 2 } }
+    
+    struct TestTypes;
+    impl Registry for TestTypes {
+        fn reader(tag: u16) -> Option<ReadFn> {
+            match tag {
+                1 => Some(read_as::<String>),
+                2 => Some(read_as::<GCounter>),
+                _ => None,
+            }
+        }
+    }
+    type Elt = TaggedElement<TestTypes>;
+    
+    #[test]
+    fn read_write() {
+        let a = Elt::new("text".to_string());
+        let b = Elt::new(GCounter::new().incremented(5));
+        assert!(a != b);
+        
+        let mut buf = Vec::new();
+        a.write_buf(&mut buf).unwrap();
+        assert_eq!(&buf[..], b"\x00\x01text");
+        let a2 = Elt::read_buf(&buf).unwrap();
+        assert_eq!(a2, a);
+        assert!(a2.is::<String>());
+        assert_eq!(a2.downcast_ref::<String>().unwrap(), "text");
+        
+        let mut buf = Vec::new();
+        b.write_buf(&mut buf).unwrap();
+        let b2 = Elt::read_buf(&buf).unwrap();
+        assert_eq!(b2, b);
+        assert_eq!(b2.downcast_ref::<GCounter>().unwrap().value(), 5);
+        assert!(b2.downcast_ref::<String>().is_none());
+        
+        // Unknown tag:
+        buf[1] = 9;
+        assert!(Elt::read_buf(&buf).is_err());
+        assert!(Elt::read_buf(&buf[0..1]).is_err());
+    }
+}
@@ -0,0 +1,208 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Numeric vector element type
+//! 
+//! `VecElement<T>` stores a vector of numbers (`f64`, `f32`, `i64` or `u64`)
+//! as an element. Values are serialised in big-endian order, thus files are
+//! portable between platforms.
+
+use std::io::Write;
+use std::fmt::Debug;
+use std::mem::size_of;
+
+use byteorder::{ByteOrder, BigEndian, WriteBytesExt};
+
+use ElementT;
+use merge::EltMergeT;
+use error::{Result, ReadError, OtherError};
+
+/// Numeric types usable within a `VecElement`.
+pub trait Numeric: Copy + PartialEq + Debug {
+    /// Number of bytes used to serialise a value
+    fn size() -> usize;
+    /// Serialise a value (big-endian)
+    fn write_value(self, writer: &mut Write) -> Result<()>;
+    /// Deserialise a value from a buffer of exactly `size()` bytes
+    fn read_value(buf: &[u8]) -> Self;
+    /// Check whether a value may be stored. The default implementation
+    /// accepts all values; floating-point types reject NaN (which cannot be
+    /// compared).
+    fn is_valid(self) -> bool {
+        true
+    }
+}
+
+impl Numeric for f64 {
+    fn size() -> usize { 8 }
+    fn write_value(self, writer: &mut Write) -> Result<()> {
+        writer.write_f64::<BigEndian>(self)?;
+        Ok(())
+    }
+    fn read_value(buf: &[u8]) -> Self { BigEndian::read_f64(buf) }
+    fn is_valid(self) -> bool { !self.is_nan() }
+}
+impl Numeric for f32 {
+    fn size() -> usize { 4 }
+    fn write_value(self, writer: &mut Write) -> Result<()> {
+        writer.write_f32::<BigEndian>(self)?;
+        Ok(())
+    }
+    fn read_value(buf: &[u8]) -> Self { BigEndian::read_f32(buf) }
+    fn is_valid(self) -> bool { !self.is_nan() }
+}
+impl Numeric for i64 {
+    fn size() -> usize { 8 }
+    fn write_value(self, writer: &mut Write) -> Result<()> {
+        writer.write_i64::<BigEndian>(self)?;
+        Ok(())
+    }
+    fn read_value(buf: &[u8]) -> Self { BigEndian::read_i64(buf) }
+}
+impl Numeric for u64 {
+    fn size() -> usize { 8 }
+    fn write_value(self, writer: &mut Write) -> Result<()> {
+        writer.write_u64::<BigEndian>(self)?;
+        Ok(())
+    }
+    fn read_value(buf: &[u8]) -> Self { BigEndian::read_u64(buf) }
+}
+
+/// An element holding a vector of numbers.
+/// 
+/// Elements cannot be modified in place; make a new `VecElement` and replace
+/// the old one with it.
+/// 
+/// Supports merging via `merge::EltMergeSolver2W`: where both versions have
+/// the same length as the common ancestor, each value changed on only one
+/// side is taken; otherwise no automatic merge is possible.
+#[derive(PartialEq, Debug, Clone, Default)]
+pub struct VecElement<T: Numeric> {
+    v: Vec<T>,
+}
+impl<T: Numeric> VecElement<T> {
+    /// Create from a vector of values
+    pub fn new(v: Vec<T>) -> VecElement<T> {
+        VecElement { v: v }
+    }
+    /// Get the number of values
+    pub fn len(&self) -> usize {
+        self.v.len()
+    }
+    /// True if there are no values
+    pub fn is_empty(&self) -> bool {
+        self.v.is_empty()
+    }
+    /// Get the values
+    pub fn values(&self) -> &[T] {
+        &self.v
+    }
+    /// Unwrap, returning the vector of values
+    pub fn into_vec(self) -> Vec<T> {
+        self.v
+    }
+}
+impl<T: Numeric> From<Vec<T>> for VecElement<T> {
+    fn from(v: Vec<T>) -> Self {
+        VecElement::new(v)
+    }
+}
+
+impl<T: Numeric> ElementT for VecElement<T> {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        for x in &self.v {
+            x.write_value(writer)?;
+        }
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        let size = T::size();
+        if buf.len() % size != 0 {
+            return ReadError::err("VecElement: length not a multiple of value size",
+                    0, (0, buf.len()));
+        }
+        let v = buf.chunks(size).map(|b| T::read_value(b)).collect();
+        Ok(VecElement { v: v })
+    }
+    fn read_into(buf: &[u8], target: &mut Self) -> Result<()> {
+        let size = T::size();
+        if buf.len() % size != 0 {
+            return ReadError::err("VecElement: length not a multiple of value size",
+                    0, (0, buf.len()));
+        }
+        target.v.clear();
+        target.v.extend(buf.chunks(size).map(|b| T::read_value(b)));
+        Ok(())
+    }
+    fn size_hint(&self) -> usize {
+        size_of::<Self>() + self.v.capacity() * size_of::<T>()
+    }
+    fn validate(&self) -> Result<()> {
+        if self.v.iter().any(|x| !x.is_valid()) {
+            return OtherError::err("VecElement: invalid value");
+        }
+        Ok(())
+    }
+}
+impl<T: Numeric> EltMergeT for VecElement<T> {
+    fn merge3(a: &Self, b: &Self, c: Option<&Self>) -> Option<Self> {
+        if a == b {
+            return Some(a.clone());
+        }
+        let c = match c {
+            Some(c) if a.len() == c.len() && b.len() == c.len() => c,
+            _ => return None,
+        };
+        let mut v = Vec::with_capacity(c.len());
+        for ((x, y), z) in a.v.iter().zip(b.v.iter()).zip(c.v.iter()) {
+            if x == z {
+                v.push(*y);
+            } else if y == z || x == y {
+                v.push(*x);
+            } else {
+                return None;
+            }
+        }
+        Some(VecElement { v: v })
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    
+    #[test]
+    fn read_write() {
+        let e = VecElement::new(vec![1.5f64, -0.25, 1e300, 0.0]);
+        let mut buf = Vec::new();
+        e.write_buf(&mut buf).unwrap();
+        assert_eq!(buf.len(), 32);
+        assert_eq!(VecElement::<f64>::read_buf(&buf).unwrap(), e);
+        
+        let e = VecElement::new(vec![-7i64, 0, i64::max_value()]);
+        let mut buf = Vec::new();
+        e.write_buf(&mut buf).unwrap();
+        assert_eq!(&buf[0..8], &[0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xF9]);
+        assert_eq!(VecElement::<i64>::read_buf(&buf).unwrap(), e);
+        
+        // Length must be a multiple of the value size:
+        assert!(VecElement::<i64>::read_buf(&buf[0..12]).is_err());
+        assert!(VecElement::<f32>::read_buf(&buf[0..12]).is_ok());
+        
+        assert!(VecElement::new(vec![1.0f32, ::std::f32::NAN]).validate().is_err());
+    }
+    
+    #[test]
+    fn merge() {
+        let c = VecElement::new(vec![1u64, 2, 3]);
+        let a = VecElement::new(vec![5u64, 2, 3]);
+        let b = VecElement::new(vec![1u64, 2, 7]);
+        assert_eq!(VecElement::merge3(&a, &b, Some(&c)),
+                Some(VecElement::new(vec![5, 2, 7])));
+        let b = VecElement::new(vec![6u64, 2, 3]);
+        assert_eq!(VecElement::merge3(&a, &b, Some(&c)), None);
+        assert_eq!(VecElement::merge3(&a, &b, None), None);
+    }
+}
@@ -0,0 +1,69 @@
+/* This Source Code Form is subject to the terms of the Mozilla Public
+ * License, v. 2.0. If a copy of the MPL was not distributed with this
+ * file, You can obtain one at http://mozilla.org/MPL/2.0/. */
+
+//! Test log messages emitted by Pippin
+
+extern crate pippin;
+extern crate log;
+extern crate rand;
+
+use std::env;
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use log::{Log, LogRecord, LogLevel, LogLevelFilter, LogMetadata};
+
+use pippin::{PartId, Partition, MutStateT};
+use pippin::fileio::PartFileIO;
+
+/// Logger storing all messages of level `Info` or more important.
+struct CaptureLogger {
+    records: Arc<Mutex<Vec<(LogLevel, String)>>>,
+}
+impl Log for CaptureLogger {
+    fn enabled(&self, metadata: &LogMetadata) -> bool {
+        metadata.level() <= LogLevel::Info
+    }
+    fn log(&self, record: &LogRecord) {
+        if self.enabled(record.metadata()) {
+            self.records.lock().unwrap().push((record.level(), format!("{}", record.args())));
+        }
+    }
+}
+
+#[test]
+fn load_reports_commits() {
+    let records = Arc::new(Mutex::new(Vec::new()));
+    let logger_records = records.clone();
+    log::set_logger(move |max_level| {
+        max_level.set(LogLevelFilter::Info);
+        Box::new(CaptureLogger { records: logger_records })
+    }).expect("set logger");
+    
+    let dir = env::temp_dir().join(format!("pippin-logging-{}", rand::random::<u32>()));
+    fs::create_dir_all(&dir).expect("create dir");
+    
+    let io = PartFileIO::new_empty(PartId::from_num(1), dir.join("logging"));
+    let mut part = Partition::<String>::create(Box::new(io), "logging", None, None)
+            .expect("create partition");
+    for n in 0..3 {
+        let mut state = part.tip().expect("tip").clone_mut();
+        state.insert(format!("element {}", n)).expect("insert");
+        part.push_state(state, None).expect("push state");
+    }
+    part.write_fast(None).expect("write");
+    let io = part.unwrap_io();
+    
+    records.lock().unwrap().clear();
+    let mut part = Partition::<String>::open(io).expect("open partition");
+    part.load_latest(None, None).expect("load");
+    {
+        let records = records.lock().unwrap();
+        assert!(records.iter().any(|&(level, ref msg)|
+            level == LogLevel::Info && msg.contains("applied 3 commits")),
+            "no 'applied 3 commits' message in: {:?}", *records);
+    }
+    
+    fs::remove_dir_all(&dir).expect("remove dir");
+}
@@ -10,14 +10,21 @@ extern crate vec_map;
 extern crate log;
 extern crate env_logger;
 
-use std::io::{Read, Write, ErrorKind};
+use std::io::{self, Read, Write, Cursor, ErrorKind};
 use std::any::Any;
+use std::cell::{Cell, RefCell};
+use std::rc::Rc;
+use std::collections::BTreeMap;
+use std::time::{Duration, UNIX_EPOCH};
+use std::thread;
 
 use vec_map::VecMap;
 
-use pippin::PartId;
-use pippin::{Partition, PartIO, MutStateT};
+use pippin::{PartId, Sum, SumType, SumKey, read_head, blob_key};
+use pippin::{Partition, PartitionBuilder, PartIO, ElementT, StateT, MutStateT};
 use pippin::error::{make_io_err, Result};
+use pippin::retry::{RetryPartIO, RetryPolicy};
+use pippin::tagged::{Tagged, Registry, ReadFn, TaggedElement, read_as};
 
 /// Allows writing to in-memory streams. Refers to external data so that it
 /// can be recovered after the `Partition` is destroyed in the tests.
@@ -66,8 +73,7 @@ impl PartIO for PartitionStreams {
     }
     fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
         if let Some(data) = self.ss.get_mut(ss_num).and_then(|&mut (_, ref mut logs)| logs.get_mut(cl_num)) {
-            let len = data.len();
-            Ok(Some(Box::new(&mut data[len..])))
+            Ok(Some(Box::new(data)))
         } else {
             Ok(None)
         }
@@ -86,6 +92,211 @@ impl PartIO for PartitionStreams {
     }
 }
 
+/// Wraps another `PartIO`, failing read operations with an error of the given
+/// kind until `failures` reaches zero.
+struct FlakyPartIO {
+    io: Box<PartIO>,
+    kind: ErrorKind,
+    failures: Cell<usize>,
+    // Number of read operations attempted
+    calls: Cell<usize>,
+}
+impl FlakyPartIO {
+    fn fail(&self) -> Result<()> {
+        self.calls.set(self.calls.get() + 1);
+        if self.failures.get() > 0 {
+            self.failures.set(self.failures.get() - 1);
+            make_io_err(self.kind, "flaky I/O")
+        } else {
+            Ok(())
+        }
+    }
+}
+impl PartIO for FlakyPartIO {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.io.part_id() }
+    fn ss_len(&self) -> usize { self.io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.fail()?;
+        self.io.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.fail()?;
+        self.io.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+}
+
+/// Wraps another `PartIO`, simulating high-latency reads: each call to
+/// `read()` on a returned stream sleeps for `delay` and is counted.
+struct LatentPartIO {
+    io: Box<PartIO>,
+    delay: Duration,
+    reads: Rc<Cell<usize>>,
+}
+struct LatentReader<'a> {
+    inner: Box<Read+'a>,
+    delay: Duration,
+    reads: Rc<Cell<usize>>,
+}
+impl<'a> Read for LatentReader<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.reads.set(self.reads.get() + 1);
+        thread::sleep(self.delay);
+        self.inner.read(buf)
+    }
+}
+impl LatentPartIO {
+    fn wrap<'a>(&self, r: Option<Box<Read+'a>>) -> Option<Box<Read+'a>> {
+        r.map(|r| Box::new(LatentReader { inner: r, delay: self.delay, reads: self.reads.clone() })
+                as Box<Read+'a>)
+    }
+}
+impl PartIO for LatentPartIO {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.io.part_id() }
+    fn ss_len(&self) -> usize { self.io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.wrap(self.io.read_ss(ss_num)?))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.wrap(self.io.read_ss_cl(ss_num, cl_num)?))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+}
+
+/// In-memory streams which may be shared by several partitions, as if these
+/// were files on a common disk. Keys are snapshot number and log number (`None`
+/// for the snapshot itself). Data is copied on reading. Quarantined files are
+/// discarded.
+#[derive(Clone)]
+struct SharedStreams {
+    part_id: PartId,
+    files: Rc<RefCell<BTreeMap<(usize, Option<usize>), Vec<u8>>>>,
+}
+impl SharedStreams {
+    fn read<'a>(&self, key: (usize, Option<usize>)) -> Result<Option<Box<Read+'a>>> {
+        Ok(self.files.borrow().get(&key)
+            .map(|data| Box::new(Cursor::new(data.clone())) as Box<Read+'a>))
+    }
+    fn write<'a>(&self, key: (usize, Option<usize>), new: bool) -> Result<Option<Box<Write+'a>>> {
+        let exists = self.files.borrow().contains_key(&key);
+        if exists == new {
+            return Ok(None);
+        }
+        self.files.borrow_mut().entry(key).or_insert_with(Vec::new);
+        Ok(Some(Box::new(SharedWriter { files: self.files.clone(), key: key })))
+    }
+}
+struct SharedWriter {
+    files: Rc<RefCell<BTreeMap<(usize, Option<usize>), Vec<u8>>>>,
+    key: (usize, Option<usize>),
+}
+impl Write for SharedWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.files.borrow_mut().get_mut(&self.key).expect("file").extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> { Ok(()) }
+}
+impl PartIO for SharedStreams {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.part_id }
+    fn ss_len(&self) -> usize {
+        self.files.borrow().keys().map(|&(ss, _)| ss + 1).max().unwrap_or(0)
+    }
+    fn ss_cl_len(&self, ss_num: usize) -> usize {
+        self.files.borrow().keys()
+            .filter_map(|&(ss, cl)| if ss == ss_num { cl.map(|cl| cl + 1) } else { None })
+            .max().unwrap_or(0)
+    }
+    fn has_ss(&self, ss_num: usize) -> bool {
+        self.files.borrow().contains_key(&(ss_num, None))
+    }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read((ss_num, None))
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.read((ss_num, Some(cl_num)))
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.write((ss_num, None), true)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.write((ss_num, Some(cl_num)), false)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.write((ss_num, Some(cl_num)), true)
+    }
+    fn quarantine_ss(&mut self, ss_num: usize) -> Result<Option<String>> {
+        Ok(self.files.borrow_mut().remove(&(ss_num, None)).map(|_| format!("ss{}", ss_num)))
+    }
+    fn quarantine_ss_cl(&mut self, ss_num: usize, cl_num: usize) -> Result<Option<String>> {
+        Ok(self.files.borrow_mut().remove(&(ss_num, Some(cl_num)))
+            .map(|_| format!("ss{}-cl{}", ss_num, cl_num)))
+    }
+    fn clone_io(&self) -> Option<Box<PartIO>> {
+        Some(Box::new(self.clone()))
+    }
+}
+
+/// Wraps `SharedStreams`, adding a blob store. Counts calls to `write_blob()`.
+struct BlobStreams {
+    io: SharedStreams,
+    blobs: Rc<RefCell<BTreeMap<Sum, Vec<u8>>>>,
+    writes: Rc<Cell<usize>>,
+}
+impl PartIO for BlobStreams {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.io.part_id() }
+    fn ss_len(&self) -> usize { self.io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+    fn read_blob(&self, key: &Sum) -> Result<Option<Vec<u8>>> {
+        Ok(self.blobs.borrow().get(key).cloned())
+    }
+    fn write_blob(&mut self, key: &Sum, data: &[u8]) -> Result<()> {
+        self.writes.set(self.writes.get() + 1);
+        self.blobs.borrow_mut().entry(key.clone()).or_insert_with(|| data.to_vec());
+        Ok(())
+    }
+}
+
 #[test]
 fn create_small() {
     env_logger::init().unwrap();
@@ -158,8 +369,8 @@ fn create_small() {
         // in which elements occur can and does vary (thanks to Rust's hash
         // function randomisation). Instead we compare file length here and
         // read the files back below.
-        assert_eq!(ss_data.as_ref().map_or(0, |d| d.len()), 224);
-        assert_eq!(log.len(), 1184);
+        assert_eq!(ss_data.as_ref().map_or(0, |d| d.len()), 256);
+        assert_eq!(log.len(), 1216);
     }
     
     // 5 Read streams back again and compare
@@ -169,3 +380,1323 @@ fn create_small() {
         *part2.state(state1.statesum()).expect("get state1 by sum"));
     assert_eq!(state3, *part2.tip().expect("part2 tip"));
 }
+
+#[test]
+fn mixed_sum_types() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(12),
+            ss: VecMap::new() };
+    // Snapshot 0 uses the default checksum (BLAKE2b)
+    let mut part = Partition::<String>::create(Box::new(part_streams),
+        "mixed_sum_types", None, None).expect("creating partition");
+    
+    // Commit log and snapshot 1 use SHA-256
+    part.set_sum_type(SumType::Sha256);
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("one".to_string()).expect("inserting");
+    state.insert("two".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let state1 = part.tip().expect("has tip").clone_exact();
+    part.write_fast(None).expect("writing log");
+    part.write_snapshot(None).expect("writing snapshot");
+    
+    // Second commit log uses BLAKE2b again
+    part.set_sum_type(SumType::Blake2b);
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("three".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let state2 = part.tip().expect("has tip").clone_exact();
+    part.write_fast(None).expect("writing log");
+    let boxed_io = part.unwrap_io();
+    
+    {
+        fn sum_type<'a>(r: Option<Box<Read + 'a>>) -> SumType {
+            read_head(&mut *r.expect("file exists")).expect("reading header").sum_type
+        }
+        assert_eq!(sum_type(boxed_io.read_ss(0).unwrap()), SumType::Blake2b);
+        assert_eq!(sum_type(boxed_io.read_ss_cl(0, 0).unwrap()), SumType::Sha256);
+        assert_eq!(sum_type(boxed_io.read_ss(1).unwrap()), SumType::Sha256);
+        assert_eq!(sum_type(boxed_io.read_ss_cl(1, 0).unwrap()), SumType::Blake2b);
+    }
+    
+    let mut part2 = Partition::<String>::open(boxed_io).expect("opening partition");
+    part2.load_all(None, None).expect("part2.load");
+    assert_eq!(state1,
+        *part2.state(state1.statesum()).expect("get state1 by sum"));
+    assert_eq!(state2, *part2.tip().expect("part2 tip"));
+}
+
+#[test]
+fn keyed_sum() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(13),
+            ss: VecMap::new() };
+    let key = SumKey::new([0x42; 32]);
+    let mut part = PartitionBuilder::new()
+            .sum_type(SumType::Blake2bKeyed(key))
+            .create::<String>(Box::new(part_streams), "keyed_sum")
+            .expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("secret".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    part.write_fast(None).expect("writing log");
+    let io = part.unwrap_io();
+    
+    // Without the key, verification fails:
+    assert!(read_head(&mut *io.read_ss(0).unwrap().expect("has ss")).is_err());
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    assert!(part.load_latest(None, None).is_err());
+    let io = part.unwrap_io();
+    
+    // Likewise with the wrong key:
+    let wrong_key = SumKey::new([0x43; 32]);
+    let mut part = PartitionBuilder::new()
+            .sum_type(SumType::Blake2bKeyed(wrong_key))
+            .open::<String>(io)
+            .expect("opening partition");
+    assert!(part.load_latest(None, None).is_err());
+    let io = part.unwrap_io();
+    
+    // With the correct key, the partition loads:
+    let part = PartitionBuilder::new()
+            .sum_type(SumType::Blake2bKeyed(key))
+            .load_latest(true)
+            .open::<String>(io)
+            .expect("loading with key");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    
+    // Summarising files also requires the key:
+    let stats = part.stats().expect("stats with key");
+    assert_eq!((stats.files.num_ss, stats.files.num_cl), (1, 1));
+    let io = part.unwrap_io();
+    assert!(Partition::<String>::inspect(&*io).is_err());
+}
+
+#[test]
+fn open_or_create() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(3),
+            ss: VecMap::new() };
+    let (mut part, created) = Partition::<String>::open_or_create(Box::new(part_streams),
+        "open_or_create", None, None).expect("open_or_create (empty)");
+    assert!(created);
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("an element".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    part.write_fast(None).expect("writing");
+    let io = part.unwrap_io();
+    
+    let (part, created) = Partition::<String>::open_or_create(io,
+        "open_or_create", None, None).expect("open_or_create (existing)");
+    assert!(!created);
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    
+    let io = part.unwrap_io();
+    assert!(Partition::<String>::open_or_create(io, "wrong name", None, None).is_err());
+}
+
+#[test]
+fn retry_transient() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(7),
+            ss: VecMap::new() };
+    let mut part = Partition::<String>::create(Box::new(part_streams),
+        "retry_transient", None, None).expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("retried".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    part.write_fast(None).expect("writing");
+    
+    // Fails twice then succeeds
+    let flaky = FlakyPartIO {
+        io: part.unwrap_io(),
+        kind: ErrorKind::Interrupted,
+        failures: Cell::new(2),
+        calls: Cell::new(0),
+    };
+    let policy = RetryPolicy::new(3, Duration::from_millis(1));
+    let mut part2 = Partition::<String>::open(Box::new(RetryPartIO::new(Box::new(flaky), policy)))
+            .expect("opening partition");
+    part2.load_latest(None, None).expect("loading with retries");
+    assert_eq!(tip, *part2.tip().expect("part2 tip"));
+    
+    // Permanent errors are not retried
+    let flaky = FlakyPartIO {
+        io: part2.unwrap_io(),
+        kind: ErrorKind::InvalidData,
+        failures: Cell::new(1),
+        calls: Cell::new(0),
+    };
+    let io = RetryPartIO::new(Box::new(flaky), policy);
+    assert!(io.read_ss(0).is_err());
+    let flaky = io.as_any().downcast_ref::<FlakyPartIO>().expect("downcasting io");
+    assert_eq!(flaky.calls.get(), 1);
+}
+
+#[test]
+fn partition_builder() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(9),
+            ss: VecMap::new() };
+    let mut part = PartitionBuilder::new()
+            .sum_type(SumType::Sha256)
+            .create::<String>(Box::new(part_streams), "builder")
+            .expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("built".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    part.write_fast(None).expect("writing");
+    let io = part.unwrap_io();
+    {
+        let head = read_head(&mut *io.read_ss(0).unwrap().expect("has snapshot")).expect("header");
+        assert_eq!(head.sum_type, SumType::Sha256);
+    }
+    
+    // Checks repository name when loading:
+    let mut part = PartitionBuilder::new()
+            .repo_name("other name")
+            .open::<String>(io)
+            .expect("opening partition");
+    assert!(part.load_latest(None, None).is_err());
+    let io = part.unwrap_io();
+    
+    // Loads via transiently failing IO with retries, writes with BLAKE2b:
+    let flaky = FlakyPartIO {
+        io: io,
+        kind: ErrorKind::TimedOut,
+        failures: Cell::new(2),
+        calls: Cell::new(0),
+    };
+    let mut part = PartitionBuilder::new()
+            .repo_name("builder")
+            .retry(RetryPolicy::new(3, Duration::from_millis(1)))
+            .load_latest(true)
+            .open::<String>(Box::new(flaky))
+            .expect("opening partition");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("more".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    part.write_fast(None).expect("writing");
+    let io = part.unwrap_io();
+    let head = read_head(&mut *io.read_ss_cl(0, 1).unwrap().expect("has log")).expect("header");
+    assert_eq!(head.sum_type, SumType::Blake2b);
+}
+
+#[test]
+fn catch_up() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(3),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut writer = Partition::<String>::create(Box::new(streams.clone()),
+        "catch_up", None, None).expect("creating partition");
+    let mut reader = Partition::<String>::open(Box::new(streams.clone()))
+            .expect("opening partition");
+    assert_eq!(reader.catch_up(None, None).expect("loading"), 0);
+    
+    for elt in &["one", "two"] {
+        let mut state = writer.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        writer.push_state(state, None).expect("committing");
+        writer.write_fast(None).expect("writing");
+        
+        // Applies only the new commit:
+        assert_eq!(reader.catch_up(None, None).expect("catching up"), 1);
+        assert_eq!(*reader.tip().expect("reader tip"), *writer.tip().expect("writer tip"));
+    }
+    assert_eq!(reader.catch_up(None, None).expect("catching up"), 0);
+    
+    // Merge the second log into the first; since a log already read was
+    // removed, everything is reloaded:
+    {
+        let mut files = streams.files.borrow_mut();
+        let log1 = files.remove(&(0, Some(1))).expect("has log 1");
+        let mut r = &log1[..];
+        read_head(&mut r).expect("reading header");
+        files.get_mut(&(0, Some(0))).expect("has log 0").extend_from_slice(&r[16..]);
+    }
+    assert_eq!(reader.catch_up(None, None).expect("reloading"), 2);
+    assert_eq!(*reader.tip().expect("reader tip"), *writer.tip().expect("writer tip"));
+}
+
+#[test]
+fn repair() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(9),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<String>::create(Box::new(streams.clone()),
+        "repair", None, None).expect("creating partition");
+    // Each write creates a new log; the last holds two commits:
+    let batches = vec![vec!["one"], vec!["two"], vec!["three"], vec!["four", "five"]];
+    for batch in &batches {
+        for elt in batch {
+            let mut state = part.tip().expect("has tip").clone_mut();
+            state.insert(elt.to_string()).expect("inserting");
+            part.push_state(state, None).expect("committing");
+        }
+        part.write_fast(None).expect("writing");
+    }
+    assert_eq!(streams.ss_cl_len(0), 4);
+    
+    // Damage the last commit:
+    {
+        let mut files = streams.files.borrow_mut();
+        let log = files.get_mut(&(0, Some(3))).expect("has log 3");
+        let last = log.len() - 1;
+        log[last] ^= 0xFF;
+    }
+    let mut part = Partition::<String>::open(Box::new(streams.clone()))
+            .expect("opening partition");
+    assert!(part.load_latest(None, None).is_err());
+    
+    let report = part.repair(None).expect("repairing");
+    assert_eq!(report.commits_recovered, 4);
+    assert_eq!(report.commits_dropped, 0);
+    assert_eq!(report.snapshot, 1);
+    assert_eq!(report.quarantined, vec!["ss0-cl3".to_string()]);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 4);
+    
+    // The result loads cleanly:
+    let mut part2 = Partition::<String>::open(Box::new(streams.clone()))
+            .expect("opening partition");
+    part2.load_latest(None, None).expect("loading repaired partition");
+    assert_eq!(*part2.tip().expect("has tip"), *part.tip().expect("has tip"));
+    assert!(!streams.files.borrow().contains_key(&(0, Some(3))));
+}
+
+#[test]
+fn duplicate_log() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(12),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<String>::create(Box::new(streams.clone()),
+        "duplicate_log", None, None).expect("creating partition");
+    for elt in &["one", "two", "three"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+    }
+    part.write_fast(None).expect("writing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    // Copy the log, as a bad backup might:
+    {
+        let mut files = streams.files.borrow_mut();
+        let log = files.get(&(0, Some(0))).expect("has log 0").clone();
+        files.insert((0, Some(1)), log);
+    }
+    assert_eq!(streams.ss_cl_len(0), 2);
+    
+    let mut part = Partition::<String>::open(Box::new(streams.clone()))
+            .expect("opening partition");
+    assert_eq!(part.catch_up(None, None).expect("loading"), 3);
+    assert_eq!(*part.tip().expect("has tip"), tip);
+    assert_eq!(part.states_len(), 4);
+    
+    let result = PartitionBuilder::new()
+            .reject_duplicates(true)
+            .load_latest(true)
+            .open::<String>(Box::new(streams.clone()));
+    assert!(result.is_err());
+}
+
+/// Element type which panics if deserialised.
+#[derive(PartialEq, Debug)]
+struct Unreadable;
+impl ElementT for Unreadable {
+    fn write_buf(&self, _: &mut Write) -> Result<()> { Ok(()) }
+    fn read_buf(_: &[u8]) -> Result<Self> {
+        panic!("element deserialised")
+    }
+}
+
+#[test]
+fn inspect() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(4),
+            ss: VecMap::new() };
+    let mut part = Partition::<String>::create(Box::new(part_streams),
+        "inspect", None, None).expect("creating partition");
+    for elt in &["one", "two"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        part.write_fast(None).expect("writing");
+    }
+    part.write_snapshot(None).expect("writing snapshot");
+    let io = part.unwrap_io();
+    
+    let inspection = Partition::<Unreadable>::inspect(&*io).expect("inspecting");
+    assert_eq!(inspection.part_id, PartId::from_num(4));
+    assert_eq!(inspection.repo_name, "inspect");
+    assert_eq!((inspection.num_ss, inspection.num_cl), (2, 2));
+    assert_eq!(inspection.latest_ss, Some(1));
+    
+    let streams = io.as_any().downcast_ref::<PartitionStreams>().expect("downcasting io");
+    let mut ss_bytes = 0;
+    let mut cl_bytes = 0;
+    for &(ref ss, ref logs) in streams.ss.values() {
+        ss_bytes += ss.as_ref().map_or(0, |data| data.len() as u64);
+        cl_bytes += logs.values().map(|data| data.len() as u64).fold(0, |a, b| a + b);
+    }
+    assert_eq!((inspection.ss_bytes, inspection.cl_bytes), (ss_bytes, cl_bytes));
+    assert_eq!(inspection.total_bytes(), ss_bytes + cl_bytes);
+}
+
+#[test]
+fn append_log() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(5),
+            ss: VecMap::new() };
+    let mut part = PartitionBuilder::new()
+            .max_log_size(1 << 20)
+            .create::<String>(Box::new(part_streams), "append_log")
+            .expect("creating partition");
+    for elt in &["one", "two", "three"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        part.write_fast(None).expect("writing");
+    }
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.unwrap_io();
+    assert_eq!(io.ss_cl_len(0), 1);
+    
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    assert_eq!(part.catch_up(None, None).expect("loading"), 3);
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    assert_eq!(part.tip().expect("has tip").num_avail(), 3);
+}
+
+#[test]
+fn append_log_sum_type() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(5),
+            ss: VecMap::new() };
+    let mut part = PartitionBuilder::new()
+            .max_log_size(1 << 20)
+            .create::<String>(Box::new(part_streams), "append_sum_type")
+            .expect("creating partition");
+    for (i, elt) in ["one", "two", "three"].iter().enumerate() {
+        // The log header declares the checksum type, so changing it must
+        // start a new log:
+        if i == 2 {
+            part.set_sum_type(SumType::Sha256);
+        }
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        part.write_fast(None).expect("writing");
+    }
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.unwrap_io();
+    assert_eq!(io.ss_cl_len(0), 2);
+    
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    assert_eq!(part.catch_up(None, None).expect("loading"), 3);
+    assert_eq!(tip, *part.tip().expect("has tip"));
+}
+
+/// Wraps another `PartIO`, failing the next call to `append_ss_cl()` if
+/// `fail_append` is set.
+struct AppendFailIO {
+    io: Box<PartIO>,
+    fail_append: Rc<Cell<bool>>,
+}
+impl PartIO for AppendFailIO {
+    fn as_any(&self) -> &Any { self }
+    fn part_id(&self) -> PartId { self.io.part_id() }
+    fn ss_len(&self) -> usize { self.io.ss_len() }
+    fn ss_cl_len(&self, ss_num: usize) -> usize { self.io.ss_cl_len(ss_num) }
+    fn has_ss(&self, ss_num: usize) -> bool { self.io.has_ss(ss_num) }
+    fn read_ss<'a>(&'a self, ss_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss(ss_num)
+    }
+    fn read_ss_cl<'a>(&'a self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Read+'a>>> {
+        self.io.read_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss<'a>(&'a mut self, ss_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss(ss_num)
+    }
+    fn append_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        if self.fail_append.replace(false) {
+            return make_io_err(ErrorKind::Other, "append failed");
+        }
+        self.io.append_ss_cl(ss_num, cl_num)
+    }
+    fn new_ss_cl<'a>(&'a mut self, ss_num: usize, cl_num: usize) -> Result<Option<Box<Write+'a>>> {
+        self.io.new_ss_cl(ss_num, cl_num)
+    }
+}
+
+#[test]
+fn append_log_failure() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(5),
+            ss: VecMap::new() };
+    let fail_append = Rc::new(Cell::new(false));
+    let io = AppendFailIO { io: Box::new(part_streams), fail_append: fail_append.clone() };
+    let mut part = PartitionBuilder::new()
+            .max_log_size(1 << 20)
+            .create::<String>(Box::new(io), "append_failure")
+            .expect("creating partition");
+    fn write(part: &mut Partition<String>, elt: &str) -> Result<bool> {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        part.write_fast(None)
+    }
+    write(&mut part, "one").expect("writing");
+    fail_append.set(true);
+    assert!(write(&mut part, "two").is_err());
+    // The log may be damaged, so the retry must not append to it:
+    part.write_fast(None).expect("writing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.unwrap_io();
+    assert_eq!(io.ss_cl_len(0), 2);
+    
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+}
+
+#[test]
+fn into_io() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(16),
+            ss: VecMap::new() };
+    let mut part = Partition::<String>::create(Box::new(part_streams), "into_io", None, None)
+            .expect("creating partition");
+    for elt in &["one", "two"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+    }
+    part.write_fast(None).expect("writing");
+    // This commit is not written until into_io():
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("three".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.into_io(None).expect("into_io");
+    
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    assert_eq!(part.tip().expect("has tip").num_avail(), 3);
+}
+
+#[test]
+fn read_buffer_size() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(17),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    {
+        let mut part = Partition::<String>::create(Box::new(streams.clone()),
+            "read_buffer", None, None).expect("creating partition");
+        for i in 0..25 {
+            let mut state = part.tip().expect("has tip").clone_mut();
+            state.insert(format!("element {}", i)).expect("inserting");
+            part.push_state(state, None).expect("committing");
+            part.write_fast(None).expect("writing");
+            if i == 19 {
+                part.write_snapshot(None).expect("writing snapshot");
+            }
+        }
+    }
+    
+    // Load the latest snapshot and a log of five commits, simulating latency
+    // on each read from the PartIO:
+    let load = |size| {
+        let reads = Rc::new(Cell::new(0));
+        let io = LatentPartIO {
+            io: Box::new(streams.clone()),
+            delay: Duration::new(0, 20_000),
+            reads: reads.clone(),
+        };
+        let part = PartitionBuilder::new()
+                .read_buffer_size(size)
+                .load_latest(true)
+                .open::<String>(Box::new(io))
+                .expect("opening partition");
+        (part.tip().expect("has tip").clone_exact(), reads.get())
+    };
+    let (tip_unbuffered, reads_unbuffered) = load(0);
+    let (tip_buffered, reads_buffered) = load(1 << 16);
+    assert_eq!(tip_unbuffered, tip_buffered);
+    assert_eq!(tip_buffered.num_avail(), 25);
+    assert!(reads_buffered * 5 < reads_unbuffered);
+}
+
+#[test]
+fn snapshot_interval() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(15),
+            ss: VecMap::new() };
+    let mut part = PartitionBuilder::new()
+            .snapshot_interval(2)
+            .create::<String>(Box::new(part_streams), "ss_interval")
+            .expect("creating partition");
+    let mut snapshots = vec![];
+    for elt in &["one", "two", "three", "four", "five"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        snapshots.push(part.write(None).expect("writing"));
+    }
+    assert_eq!(snapshots, vec![false, true, false, true, false]);
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.unwrap_io();
+    assert_eq!(io.ss_len(), 3);
+    
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+}
+
+#[test]
+fn commit_tags() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(19),
+            ss: VecMap::new() };
+    let mut part = Partition::<String>::create(Box::new(part_streams), "commit_tags", None, None)
+            .expect("creating partition");
+    let mut sums = vec![];
+    for (i, elt) in ["one", "two"].iter().enumerate() {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        state.meta_mut().set_tag("request".to_string(), vec![i as u8]).expect("tagging");
+        state.meta_mut().set_tag("source".to_string(), b"test".to_vec()).expect("tagging");
+        part.push_state(state, None).expect("committing");
+        sums.push(part.tip_key().expect("has tip").clone());
+    }
+    part.write_fast(None).expect("writing");
+    // Compact history into a snapshot:
+    part.write_snapshot(None).expect("writing snapshot");
+    let io = part.unwrap_io();
+    
+    let tags = |i: u8| {
+        let mut tags = BTreeMap::new();
+        tags.insert("request".to_string(), vec![i]);
+        tags.insert("source".to_string(), b"test".to_vec());
+        tags
+    };
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    part.load_all(None, None).expect("loading");
+    for (i, sum) in sums.iter().enumerate() {
+        assert_eq!(part.state(sum).expect("has state").meta().tags(), &tags(i as u8));
+    }
+    
+    let mut part = Partition::<String>::open(part.unwrap_io()).expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(part.tip_key().expect("has tip"), &sums[1]);
+    assert_eq!(part.tip().expect("has tip").meta().tags(), &tags(1));
+}
+
+#[test]
+fn clear_state() {
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(6),
+            ss: VecMap::new() };
+    let mut part = Partition::<String>::create(Box::new(part_streams),
+        "clear_state", None, None).expect("creating partition");
+    let initial = part.tip().expect("has tip").clone_exact();
+    let mut state = part.tip().expect("has tip").clone_mut();
+    let id = state.insert("one".to_string()).expect("inserting");
+    state.insert("two".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.clear();
+    assert_eq!(state.num_avail(), 0);
+    assert!(!state.is_avail(id));
+    part.push_state(state, None).expect("committing");
+    let tip = part.tip().expect("has tip").clone_exact();
+    assert_eq!(tip.num_avail(), 0);
+    assert!(tip.statesum() != initial.statesum());
+    part.write_fast(None).expect("writing");
+    
+    let mut part = Partition::<String>::open(part.unwrap_io()).expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    assert_eq!(part.tip().expect("has tip").num_avail(), 0);
+}
+
+thread_local!(static LIVE_TRACKED: Cell<usize> = Cell::new(0));
+
+/// Element type counting how many instances are alive (per thread).
+#[derive(PartialEq, Debug)]
+struct Tracked(Vec<u8>);
+impl Tracked {
+    fn new(data: Vec<u8>) -> Tracked {
+        LIVE_TRACKED.with(|n| n.set(n.get() + 1));
+        Tracked(data)
+    }
+    fn live() -> usize {
+        LIVE_TRACKED.with(|n| n.get())
+    }
+}
+impl Drop for Tracked {
+    fn drop(&mut self) {
+        LIVE_TRACKED.with(|n| n.set(n.get() - 1));
+    }
+}
+impl ElementT for Tracked {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_all(&self.0)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        Ok(Tracked::new(buf.to_vec()))
+    }
+}
+
+#[test]
+fn stream_snapshot() {
+    let part_id = PartId::from_num(14);
+    let files = Rc::new(RefCell::new(BTreeMap::new()));
+    let io = SharedStreams { part_id: part_id, files: files.clone() };
+    let mut part = Partition::<Tracked>::create(Box::new(io),
+        "stream_snapshot", None, None).expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_mut();
+    for i in 0..2000 {
+        state.insert(Tracked::new(vec![(i % 251) as u8; 1000])).expect("inserting");
+    }
+    part.push_state(state, None).expect("committing");
+    part.write_snapshot(None).expect("writing snapshot");
+    let io = part.unwrap_io();
+    assert_eq!(Tracked::live(), 0);
+    
+    // Only one element is held at any time:
+    {
+        let iter = Partition::<Tracked>::stream_snapshot_elements(&*io).expect("streaming");
+        assert_eq!(iter.num_elts(), 2000);
+        let mut n = 0;
+        for item in iter {
+            let (id, elt) = item.expect("reading element");
+            assert_eq!(id.part_id(), part_id);
+            assert_eq!(elt.0.len(), 1000);
+            assert_eq!(Tracked::live(), 1);
+            n += 1;
+        }
+        assert_eq!(n, 2000);
+    }
+    
+    // Likewise when reading into a single element:
+    {
+        let mut iter = Partition::<Tracked>::stream_snapshot_elements(&*io).expect("streaming");
+        let mut elt = Tracked::new(vec![]);
+        let mut n = 0;
+        while let Some(result) = iter.next_into(&mut elt) {
+            assert_eq!(result.expect("reading element").part_id(), part_id);
+            assert_eq!(elt.0.len(), 1000);
+            assert_eq!(Tracked::live(), 1);
+            n += 1;
+        }
+        assert_eq!(n, 2000);
+    }
+    
+    // A bad file checksum is reported after the last element:
+    {
+        let mut files = files.borrow_mut();
+        let data = files.get_mut(&(1, None)).expect("snapshot 1");
+        let len = data.len();
+        data[len - 1] ^= 1;
+    }
+    let results: Vec<bool> = Partition::<Tracked>::stream_snapshot_elements(&*io)
+            .expect("streaming").map(|r| r.is_ok()).collect();
+    assert_eq!(results.len(), 2001);
+    assert!(results[0..2000].iter().all(|ok| *ok));
+    assert!(!results[2000]);
+}
+
+/// Text, stored within a `TaggedElement`
+#[derive(PartialEq, Debug)]
+struct Label(String);
+impl ElementT for Label {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_all(self.0.as_bytes())?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        Ok(Label(String::from_utf8(buf.to_vec())?))
+    }
+}
+impl Tagged for Label { fn tag() -> u16 { 1 } }
+/// Raw bytes, stored within a `TaggedElement`
+#[derive(PartialEq, Debug)]
+struct Bytes(Vec<u8>);
+impl ElementT for Bytes {
+    fn write_buf(&self, writer: &mut Write) -> Result<()> {
+        writer.write_all(&self.0)?;
+        Ok(())
+    }
+    fn read_buf(buf: &[u8]) -> Result<Self> {
+        Ok(Bytes(buf.to_vec()))
+    }
+}
+impl Tagged for Bytes { fn tag() -> u16 { 2 } }
+struct TestTypes;
+impl Registry for TestTypes {
+    fn reader(tag: u16) -> Option<ReadFn> {
+        match tag {
+            1 => Some(read_as::<Label>),
+            2 => Some(read_as::<Bytes>),
+            _ => None,
+        }
+    }
+}
+
+#[test]
+fn tagged_elements() {
+    type Elt = TaggedElement<TestTypes>;
+    let streams = SharedStreams {
+            part_id: PartId::from_num(18),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<Elt>::create(Box::new(streams.clone()), "tagged", None, None)
+            .expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_mut();
+    let id1 = state.insert(Elt::new(Label("text".to_string()))).expect("inserting");
+    let id2 = state.insert(Elt::new(Bytes(vec![0xFF, 0, 7]))).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    part.write_fast(None).expect("writing log");
+    part.write_snapshot(None).expect("writing snapshot");
+    
+    // Read back from snapshot and from commit log:
+    let mut part = Partition::<Elt>::open(Box::new(streams.clone())).expect("opening partition");
+    part.load_all(None, None).expect("loading");
+    let tip = part.tip().expect("has tip");
+    let label = tip.get(id1).expect("get label");
+    assert_eq!(label.tag(), 1);
+    assert_eq!(label.downcast_ref::<Label>(), Some(&Label("text".to_string())));
+    let bytes = tip.get(id2).expect("get bytes");
+    assert_eq!(bytes.downcast_ref::<Bytes>(), Some(&Bytes(vec![0xFF, 0, 7])));
+    assert!(bytes.downcast_ref::<Label>().is_none());
+}
+
+#[test]
+fn blob_store() {
+    let files = Rc::new(RefCell::new(BTreeMap::new()));
+    let blobs = Rc::new(RefCell::new(BTreeMap::new()));
+    let io = BlobStreams {
+            io: SharedStreams { part_id: PartId::from_num(19), files: files.clone() },
+            blobs: blobs.clone(),
+            writes: Rc::new(Cell::new(0)) };
+    let mut part = PartitionBuilder::new()
+            .blob_threshold(Some(256))
+            .create::<String>(Box::new(io), "blob_store")
+            .expect("creating partition");
+    let big: String = (0..1000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    
+    // The same data is inserted by two commits, then included in a snapshot:
+    let mut ids = vec![];
+    for elt in &[&big[..], "small", &big[..]] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        ids.push(state.insert(elt.to_string()).expect("inserting"));
+        part.push_state(state, None).expect("committing");
+        part.write_fast(None).expect("writing");
+    }
+    part.write_snapshot(None).expect("writing snapshot");
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.unwrap_io();
+    assert!(io.as_any().downcast_ref::<BlobStreams>().expect("downcast").writes.get() >= 3);
+    
+    // Stored once, referred to from logs and snapshot:
+    assert_eq!(blobs.borrow().len(), 1);
+    assert_eq!(blobs.borrow().get(&blob_key(big.as_bytes())), Some(&big.as_bytes().to_vec()));
+    for data in files.borrow().values() {
+        assert!(!data.windows(big.len()).any(|w| w == big.as_bytes()));
+    }
+    
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    part.load_all(None, None).expect("loading");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    assert_eq!(part.tip().expect("has tip").get(ids[0]).expect("get"), &big);
+    assert_eq!(part.tip().expect("has tip").get(ids[2]).expect("get"), &big);
+    
+    // Without the blob, loading fails:
+    blobs.borrow_mut().clear();
+    let io = part.unwrap_io();
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    assert!(part.load_latest(None, None).is_err());
+}
+
+#[test]
+fn upgrade_format() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(20),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<String>::create(Box::new(streams.clone()),
+        "upgrade_format", None, None).expect("creating partition");
+    for elt in &["one", "two", "three"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+    }
+    part.write_fast(None).expect("writing");
+    part.write_snapshot(None).expect("writing snapshot");
+    let tip = part.tip().expect("has tip").clone_exact();
+    let ver = read_head(&mut *streams.read_ss(0).unwrap().expect("has snapshot"))
+            .expect("header").ftype.ver();
+    assert!(ver > 2016_05_16);
+    
+    // Rewrite snapshot 1 in format 2016_05_16, which stores the commit number
+    // in metadata as "CNUM" instead of "F" with extension length and flags:
+    {
+        let mut files = streams.files.borrow_mut();
+        let data = files.get_mut(&(1, None)).expect("has snapshot 1");
+        // header: ident, name, partition identifier, config (two lines),
+        // checksum type, checksum
+        let body = 16 * 6 + 32;
+        data[8..16].copy_from_slice(b"20160516");
+        let sum = Sum::calculate(&data[0..96]);
+        sum.write(&mut &mut data[96..body]).expect("writing sum");
+        assert_eq!(&data[body + 16..body + 20], b"F\x00\x00\x00");
+        data[body + 16..body + 20].copy_from_slice(b"CNUM");
+        let len = data.len();
+        let sum = Sum::calculate(&data[body..len - 32]);
+        sum.write(&mut &mut data[len - 32..len]).expect("writing sum");
+    }
+    
+    let mut part = PartitionBuilder::new()
+            .upgrade_on_write(true)
+            .load_latest(true)
+            .open::<String>(Box::new(streams.clone()))
+            .expect("opening partition");
+    assert_eq!(part.format_version(), Some(2016_05_16));
+    assert_eq!(*part.tip().expect("has tip"), tip);
+    assert!(part.want_snapshot());
+    assert!(part.write(None).expect("writing"));
+    assert_eq!(streams.ss_len(), 3);
+    
+    let head = read_head(&mut *streams.read_ss(2).unwrap().expect("has snapshot")).expect("header");
+    assert_eq!(head.ftype.ver(), ver);
+    let mut part = Partition::<String>::open(Box::new(streams.clone()))
+            .expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(part.format_version(), Some(ver));
+    assert_eq!(*part.tip().expect("has tip"), tip);
+}
+
+#[test]
+fn branches() {
+    use pippin::merge::AncestorSolver2W;
+    
+    let streams = SharedStreams {
+            part_id: PartId::from_num(21),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<String>::create(Box::new(streams.clone()),
+        "branches", None, None).expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("one".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let main_tip = part.tip_key().expect("has tip").clone();
+    
+    part.branch("what-if").expect("branching");
+    assert!(part.branch("what-if").is_err());
+    assert!(part.checkout(Some("unknown")).is_err());
+    part.checkout(Some("what-if")).expect("checking out");
+    assert_eq!(part.current_branch(), Some("what-if"));
+    let mut state = part.head().expect("has head").clone_mut();
+    state.insert("two".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    assert_eq!(part.head().expect("has head").elts_len(), 2);
+    assert_eq!(part.head().expect("has head").meta().branch(), Some("what-if"));
+    
+    // The main line is unaffected:
+    part.checkout(None).expect("checking out");
+    assert_eq!(part.tip_key().expect("has tip"), &main_tip);
+    assert_eq!(part.head_key().expect("has head"), &main_tip);
+    assert!(!part.merge_required());
+    assert_eq!(part.tip().expect("has tip").elts_len(), 1);
+    
+    // Branches survive writing a snapshot and reloading:
+    part.write_fast(None).expect("writing");
+    part.write_snapshot(None).expect("writing snapshot");
+    let mut part = Partition::<String>::open(Box::new(streams.clone()))
+            .expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(part.tip_key().expect("has tip"), &main_tip);
+    let head = part.branches().get("what-if").expect("has branch").clone();
+    assert_eq!(part.state(&head).expect("has state").elts_len(), 2);
+    
+    // Merging back brings the branch's changes into the main line:
+    part.merge_branch("what-if", &AncestorSolver2W::new(), None).expect("merging");
+    assert!(!part.merge_required());
+    let mut elts: Vec<String> = part.tip().expect("has tip").elts_iter()
+            .map(|(_, elt)| (**elt).clone()).collect();
+    elts.sort();
+    assert_eq!(elts, vec!["one".to_string(), "two".to_string()]);
+}
+
+#[test]
+fn write_buffering() {
+    // Unbuffered, commits split over several chunks, and all in one chunk:
+    for &size in &[0, 100, 64 * 1024] {
+        let part_streams = PartitionStreams {
+                part_id: PartId::from_num(22),
+                ss: VecMap::new() };
+        let mut part = PartitionBuilder::new()
+                .write_buffer_size(size)
+                .create::<String>(Box::new(part_streams), "write_buffer")
+                .expect("creating partition");
+        for i in 0..20 {
+            let mut state = part.tip().expect("has tip").clone_mut();
+            state.insert(format!("element {}", i)).expect("inserting");
+            part.push_state(state, None).expect("committing");
+        }
+        assert!(part.write_fast(None).expect("writing"));
+        assert_eq!(part.unsaved_len(), 0);
+        let tip = part.tip().expect("has tip").clone_exact();
+        
+        let mut part = Partition::<String>::open(part.unwrap_io()).expect("opening partition");
+        part.load_latest(None, None).expect("loading");
+        assert_eq!(tip, *part.tip().expect("has tip"));
+        
+        part.set_write_buffer_size(size);
+        part.write_snapshot(None).expect("writing snapshot");
+        let mut part = Partition::<String>::open(part.unwrap_io()).expect("opening partition");
+        part.load_latest(None, None).expect("loading");
+        assert_eq!(part.tip().expect("has tip").num_avail(), 20);
+    }
+}
+
+#[test]
+fn read_ignoring_checksum() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(23),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<String>::create(Box::new(streams.clone()),
+        "ignore_sum", None, None).expect("creating partition");
+    for elt in &["one", "two"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+    }
+    part.write_snapshot(None).expect("writing snapshot");
+    let tip = part.tip().expect("has tip").clone_exact();
+    
+    // Damage the snapshot's file checksum (the last bytes):
+    {
+        let mut files = streams.files.borrow_mut();
+        let data = files.get_mut(&(1, None)).expect("snapshot 1");
+        let len = data.len();
+        data[len - 1] ^= 1;
+    }
+    
+    // By default this is an error:
+    let mut part = Partition::<String>::open(Box::new(streams.clone()))
+            .expect("opening partition");
+    assert!(part.load_latest(None, None).is_err());
+    assert!(!part.is_unverified());
+    
+    // Best effort reading yields the elements, flagged as unverified:
+    let mut part = PartitionBuilder::new()
+            .read_ignoring_checksum(true)
+            .open::<String>(Box::new(streams.clone()))
+            .expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert!(part.is_unverified());
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    part.unload(true);
+    assert!(!part.is_unverified());
+}
+
+#[test]
+fn clone_shallow() {
+    use pippin::merge::AncestorSolver2W;
+    
+    let streams = SharedStreams {
+            part_id: PartId::from_num(24),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut a = Partition::<String>::create(Box::new(streams.clone()),
+        "clone_shallow", None, None).expect("creating partition");
+    let mut state = a.tip().expect("has tip").clone_mut();
+    state.insert("one".to_string()).expect("inserting");
+    a.push_state(state, None).expect("committing");
+    a.write_fast(None).expect("writing");
+    
+    let mut b = a.clone_shallow().expect("clone_shallow");
+    assert_eq!(*b.tip().expect("has tip"), *a.tip().expect("has tip"));
+    assert_eq!(b.unsaved_len(), 0);
+    
+    // Each handle makes and writes a different commit:
+    for (part, elt) in vec![(&mut a, "two"), (&mut b, "three")] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        part.write_fast(None).expect("writing");
+    }
+    assert_eq!(a.tip().expect("has tip").num_avail(), 2);
+    assert_eq!(b.tip().expect("has tip").num_avail(), 2);
+    assert!(a.tip().expect("has tip").statesum() != b.tip().expect("has tip").statesum());
+    
+    // Reading the other's commits requires a merge:
+    assert_eq!(a.catch_up(None, None).expect("catching up"), 1);
+    assert!(a.merge_required());
+    a.merge(&AncestorSolver2W::new(), false, None).expect("merging");
+    assert_eq!(a.tip().expect("has tip").num_avail(), 3);
+    a.write_fast(None).expect("writing");
+    
+    // The same result is found by loading from scratch:
+    let mut c = Partition::<String>::open(Box::new(streams.clone())).expect("opening");
+    c.load_latest(None, None).expect("loading");
+    assert_eq!(*c.tip().expect("has tip"), *a.tip().expect("has tip"));
+    
+    // Not all `PartIO`s support this:
+    let part_streams = PartitionStreams {
+            part_id: PartId::from_num(24),
+            ss: VecMap::new() };
+    let part = Partition::<String>::create(Box::new(part_streams), "clone_shallow", None, None)
+            .expect("creating partition");
+    assert!(part.clone_shallow().is_err());
+}
+
+#[test]
+fn load_asof() {
+    use pippin::commit::Clock;
+    
+    // A clock advancing ten seconds each time it is read:
+    struct StepClock(Cell<i64>);
+    impl Clock for StepClock {
+        fn now(&self) -> i64 {
+            let t = self.0.get();
+            self.0.set(t + 10);
+            t
+        }
+    }
+    
+    let streams = SharedStreams {
+            part_id: PartId::from_num(25),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = PartitionBuilder::new()
+            .clock(Rc::new(StepClock(Cell::new(1000))))
+            .create::<String>(Box::new(streams.clone()), "load_asof").expect("creating partition");
+    // Commits at times 1010, 1020, 1030:
+    let mut sums = vec![];
+    for elt in &["one", "two", "three"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        sums.push(part.tip().expect("has tip").statesum().clone());
+    }
+    part.write_fast(None).expect("writing");
+    
+    let mut part = Partition::<String>::open(Box::new(streams.clone())).expect("opening");
+    let at = |millis: u64| UNIX_EPOCH + Duration::from_millis(millis);
+    // Between the second and third commits, and exactly at the second:
+    for time in &[at(1025_000), at(1020_000), at(1020_500)] {
+        part.load_asof(*time, None, None).expect("loading");
+        assert_eq!(part.tips_len(), 1);
+        assert_eq!(*part.tip().expect("has tip").statesum(), sums[1]);
+        assert_eq!(part.tip().expect("has tip").num_avail(), 2);
+    }
+    // Before any commit, only the initial state is found:
+    part.load_asof(at(1005_000), None, None).expect("loading");
+    assert_eq!(part.tip().expect("has tip").meta().timestamp(), 1000);
+    assert_eq!(part.tip().expect("has tip").num_avail(), 0);
+    // Before the partition was created, the state is empty:
+    part.load_asof(at(900_000), None, None).expect("loading");
+    assert_eq!(part.tip().expect("has tip").num_avail(), 0);
+    assert_eq!(part.states_len(), 1);
+    
+    part.unload(true);
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(*part.tip().expect("has tip").statesum(), sums[2]);
+}
+
+#[test]
+fn load_asof_branches() {
+    use pippin::commit::Clock;
+    
+    struct StepClock(Cell<i64>);
+    impl Clock for StepClock {
+        fn now(&self) -> i64 {
+            let t = self.0.get();
+            self.0.set(t + 10);
+            t
+        }
+    }
+    
+    let streams = SharedStreams {
+            part_id: PartId::from_num(29),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = PartitionBuilder::new()
+            .clock(Rc::new(StepClock(Cell::new(1000))))
+            .create::<String>(Box::new(streams.clone()), "asof_branch").expect("creating partition");
+    let insert = |part: &mut Partition<String>, elt: &str| {
+        let mut state = part.head().expect("has head").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+    };
+    // "one" at 1010, branch "b" at 1020, "two" on b at 1030, "three" at 1040:
+    insert(&mut part, "one");
+    let one = part.tip_key().expect("has tip").clone();
+    part.branch("b").expect("branching");
+    part.checkout(Some("b")).expect("checkout");
+    insert(&mut part, "two");
+    part.checkout(None).expect("checkout");
+    insert(&mut part, "three");
+    part.write_fast(None).expect("writing");
+    
+    let mut part = Partition::<String>::open(Box::new(streams.clone())).expect("opening");
+    // The branch head is rewound, and is never taken as the tip:
+    let at = |secs: u64| UNIX_EPOCH + Duration::from_secs(secs);
+    for &(time, n) in &[(1035, 2), (1025, 1)] {
+        part.load_asof(at(time), None, None).expect("loading");
+        assert_eq!(part.tips_len(), 1);
+        assert_eq!(*part.tip_key().expect("has tip"), one);
+        assert_eq!(part.branches().len(), 1);
+        part.checkout(Some("b")).expect("checkout");
+        assert_eq!(part.head().expect("has head").num_avail(), n);
+    }
+    // Before the branch was created, it does not exist:
+    part.load_asof(at(1015), None, None).expect("loading");
+    assert_eq!(*part.tip_key().expect("has tip"), one);
+    assert!(part.branches().is_empty());
+    assert!(part.checkout(Some("b")).is_err());
+    assert_eq!(part.head().expect("has head").num_avail(), 1);
+}
+
+#[test]
+fn verify_against() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(26),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<String>::create(Box::new(streams.clone()), "verify_against",
+            None, None).expect("creating partition");
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("recorded".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    part.write_fast(None).expect("writing");
+    let recorded = part.tip().expect("has tip").statesum().clone();
+    
+    let mut other = Partition::<String>::open(Box::new(streams.clone())).expect("opening");
+    other.verify_against(&recorded).expect("tip matches");
+    
+    // Data changed since the tip was recorded:
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert("later".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    let found = part.tip().expect("has tip").statesum().clone();
+    assert!(part.verify_against(&recorded).is_err());    // unsaved changes
+    part.write_fast(None).expect("writing");
+    
+    let e = other.verify_against(&recorded).unwrap_err();
+    let msg = e.to_string();
+    assert!(msg.contains("does not match"), "message: {}", msg);
+    assert!(msg.contains(&recorded.to_string()) && msg.contains(&found.to_string()),
+            "message: {}", msg);
+    other.verify_against(&found).expect("tip matches");
+}
+
+#[test]
+fn log_order() {
+    let streams = SharedStreams {
+            part_id: PartId::from_num(27),
+            files: Rc::new(RefCell::new(BTreeMap::new())) };
+    let mut part = Partition::<String>::create(Box::new(streams.clone()), "log_order",
+            None, None).expect("creating partition");
+    // One log file per commit:
+    for elt in &["one", "two", "three", "four"] {
+        let mut state = part.tip().expect("has tip").clone_mut();
+        state.insert(elt.to_string()).expect("inserting");
+        part.push_state(state, None).expect("committing");
+        part.write_fast(None).expect("writing");
+    }
+    let tip = part.tip().expect("has tip").clone_exact();
+    let logs: Vec<_> = (0..4).map(|cl| streams.files.borrow()[&(0, Some(cl))].clone()).collect();
+    
+    // Logs found out of order:
+    for order in &[[3, 2, 1, 0], [2, 0, 3, 1]] {
+        for (cl, &i) in order.iter().enumerate() {
+            streams.files.borrow_mut().insert((0, Some(cl)), logs[i].clone());
+        }
+        let mut part = Partition::<String>::open(Box::new(streams.clone())).expect("opening");
+        part.load_latest(None, None).expect("loading");
+        assert_eq!(*part.tip().expect("has tip"), tip);
+        assert_eq!(part.states_len(), 5);
+    }
+    
+    // A missing log (with the second commit) leaves a gap:
+    streams.files.borrow_mut().remove(&(0, Some(3)));
+    let mut part = Partition::<String>::open(Box::new(streams.clone())).expect("opening");
+    let e = part.load_latest(None, None).unwrap_err();
+    assert!(e.to_string().contains("parent"), "error: {}", e);
+}
+
+#[test]
+fn stored_config() {
+    use pippin::PartConfig;
+    
+    let files = Rc::new(RefCell::new(BTreeMap::new()));
+    let blobs = Rc::new(RefCell::new(BTreeMap::new()));
+    let io = BlobStreams {
+            io: SharedStreams { part_id: PartId::from_num(28), files: files.clone() },
+            blobs: blobs.clone(),
+            writes: Rc::new(Cell::new(0)) };
+    let config = PartConfig { snapshot_dedup: true, blob_min: Some(256) };
+    let mut part = PartitionBuilder::new()
+            .snapshot_dedup(true)
+            .blob_threshold(Some(256))
+            .create::<String>(Box::new(io), "stored_config")
+            .expect("creating partition");
+    assert_eq!(part.config(), config);
+    let big1: String = (0..1000).map(|i| (b'a' + (i % 26) as u8) as char).collect();
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert(big1.clone()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    part.write_fast(None).expect("writing");
+    let io = part.unwrap_io();
+    {
+        let head = read_head(&mut *io.read_ss(0).unwrap().expect("snapshot")).expect("header");
+        assert_eq!(head.config, Some(config));
+    }
+    
+    // Opened without specifying settings, these are restored on loading:
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    assert_eq!(part.config(), PartConfig::default());
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(part.config(), config);
+    
+    let big2: String = (0..1000).map(|i| (b'z' - (i % 26) as u8) as char).collect();
+    let mut state = part.tip().expect("has tip").clone_mut();
+    state.insert(big2.clone()).expect("inserting");
+    state.insert("shared".to_string()).expect("inserting");
+    state.insert("shared".to_string()).expect("inserting");
+    part.push_state(state, None).expect("committing");
+    part.write_fast(None).expect("writing");
+    part.write_snapshot(None).expect("writing snapshot");
+    let tip = part.tip().expect("has tip").clone_exact();
+    let io = part.unwrap_io();
+    
+    // Both large strings are stored as blobs; the new snapshot uses
+    // de-duplication:
+    assert_eq!(blobs.borrow().len(), 2);
+    for data in files.borrow().values() {
+        assert!(!data.windows(big2.len()).any(|w| w == big2.as_bytes()));
+    }
+    let ss1 = files.borrow()[&(1, None)].clone();
+    assert!(ss1.windows(8).any(|w| w == b"SAMEAS\x00\x00"));
+    
+    let mut part = Partition::<String>::open(io).expect("opening partition");
+    part.load_latest(None, None).expect("loading");
+    assert_eq!(tip, *part.tip().expect("has tip"));
+    assert_eq!(part.config(), config);
+    let io = part.unwrap_io();
+    
+    // Settings given explicitly take precedence over those recorded:
+    let mut part = PartitionBuilder::new()
+            .snapshot_dedup(false)
+            .load_latest(true)
+            .open::<String>(io)
+            .expect("opening partition");
+    assert_eq!(part.config(), PartConfig { snapshot_dedup: false, blob_min: Some(256) });
+    part.set_blob_threshold(None);
+    part.load_all(None, None).expect("loading");
+    assert_eq!(part.config(), PartConfig::default());
+}